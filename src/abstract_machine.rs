@@ -1,14 +1,58 @@
+use std::any::Any;
+
 use crate::device::MMIODevice;
 
 
 pub trait RegInfo {
     fn get_reg_value(&self, i: &str) -> Option<u64>;
+    /// `None` for unknown or read-only registers.
+    fn set_reg_value(&self, i: &str, value: u64) -> Option<()>;
+    /// General purpose registers plus pc, in `info r` order.
+    fn reg_names(&self) -> &'static [&'static str];
+    /// Control registers worth showing in `info csr`.
+    fn csr_names(&self) -> &'static [&'static str];
+}
+
+pub trait Disassembler {
+    /// Raw bytes and text of the instruction at `addr`.
+    fn disassemble(&self, memory: &dyn MMIODevice, addr: u64) -> Option<(Vec<u8>, String)>;
+}
+
+/// Whole-hart state for reverse execution checkpoints.
+pub trait Snapshot {
+    fn snapshot(&self) -> Box<dyn Any>;
+    /// Ignores states taken from a different kind of machine.
+    fn restore(&self, state: &dyn Any);
+}
+
+pub trait InstRing {
+    /// Prints the last executed instructions to stderr, oldest first.
+    fn dump_inst_ring(&self);
+}
+
+/// The harts of a machine, of which the monitor shows one.
+pub trait Harts {
+    fn hart_count(&self) -> usize {
+        1
+    }
+
+    fn current_hart(&self) -> usize {
+        0
+    }
+
+    /// Shows hart `n` from now on, `None` if there is no such hart.
+    fn select_hart(&self, n: usize) -> Option<()> {
+        (n == 0).then_some(())
+    }
 }
 
 pub trait LengthInfo {
     fn get_length(&self) -> usize;
 }
 
+/// Accesses of one width each: a device only implements those it takes,
+/// and the bus splits wider ones where `MMIODevice::width_policy` asks for
+/// it, never these defaults.
 pub trait Readable {
 
     #[inline]
@@ -17,24 +61,18 @@ pub trait Readable {
     }
 
     #[inline]
-    fn read_u16(&self, addr: usize) -> Option<u16> {
-        let l = self.read_u8(addr)? as u16;
-        let h = self.read_u8(addr+1)? as u16;
-        Some((h << 8) | l)
+    fn read_u16(&self, _addr: usize) -> Option<u16> {
+        None
     }
 
     #[inline]
-    fn read_u32(&self, addr: usize) -> Option<u32> {
-        let l = self.read_u16(addr)? as u32;
-        let h = self.read_u16(addr+2)? as u32;
-        Some((h << 16) | l)
+    fn read_u32(&self, _addr: usize) -> Option<u32> {
+        None
     }
 
     #[inline]
-    fn read_u64(&self, addr: usize) -> Option<u64> {
-        let l = self.read_u32(addr)? as u64;
-        let h = self.read_u32(addr+4)? as u64;
-        Some((h << 32) | l)
+    fn read_u64(&self, _addr: usize) -> Option<u64> {
+        None
     }
 
     #[inline]
@@ -42,6 +80,7 @@ pub trait Readable {
         self.read_u8(addr).unwrap()
     }
 
+    /// Composed of bytes, little-endian, like the wider ones.
     #[inline]
     unsafe fn unchecked_read_u16(&self, addr: usize) -> u16 {
         let l = self.unchecked_read_u8(addr) as u16;
@@ -70,24 +109,18 @@ pub trait Writeable {
     }
 
     #[inline]
-    fn write_u16(&self, addr: usize, value: u16) -> Option<()> {
-        self.write_u8(addr, value as u8)?;
-        self.write_u8(addr+1, (value >> 8) as u8)?;
-        Some(())
+    fn write_u16(&self, _addr: usize, _value: u16) -> Option<()> {
+        None
     }
 
     #[inline]
-    fn write_u32(&self, addr: usize, value: u32) -> Option<()> {
-        self.write_u16(addr, value as u16)?;
-        self.write_u16(addr+2, (value >> 16) as u16)?;
-        Some(())
+    fn write_u32(&self, _addr: usize, _value: u32) -> Option<()> {
+        None
     }
 
     #[inline]
-    fn write_u64(&self, addr: usize, value: u64) -> Option<()> {
-        self.write_u32(addr, value as u32)?;
-        self.write_u32(addr+4, (value >> 32) as u32)?;
-        Some(())
+    fn write_u64(&self, _addr: usize, _value: u64) -> Option<()> {
+        None
     }
 
     #[inline]
@@ -95,33 +128,47 @@ pub trait Writeable {
         self.write_u8(addr, value).unwrap()
     }
 
+    /// Split into bytes, little-endian, like the wider ones.
     #[inline]
     unsafe fn unchecked_write_u16(&self, addr: usize, value: u16) {
-        self.write_u8(addr, value as u8);
-        self.write_u8(addr+1, (value >> 8) as u8);
+        self.unchecked_write_u8(addr, value as u8);
+        self.unchecked_write_u8(addr+1, (value >> 8) as u8);
     }
 
     #[inline]
     unsafe fn unchecked_write_u32(&self, addr: usize, value: u32) {
-        self.write_u16(addr, value as u16);
-        self.write_u16(addr+2, (value >> 16) as u16);
+        self.unchecked_write_u16(addr, value as u16);
+        self.unchecked_write_u16(addr+2, (value >> 16) as u16);
     }
 
     #[inline]
     unsafe fn unchecked_write_u64(&self, addr: usize, value: u64) {
-        self.write_u32(addr, value as u32);
-        self.write_u32(addr+4, (value >> 32) as u32);
+        self.unchecked_write_u32(addr, value as u32);
+        self.unchecked_write_u32(addr+4, (value >> 32) as u32);
     }
 }
 
 
 pub trait ExceptionAttr {
     fn is_debugger_trap(&self) -> bool;
+    /// Faults a guest is not expected to recover from, worth a backtrace.
+    fn is_fatal(&self) -> bool {
+        false
+    }
 }
 
 pub trait Execable<E: ExceptionAttr + Clone>: ExceptionProcessable<E> {
     fn exec_once(&self, memory: &dyn MMIODevice) -> Result<(), E>;
 
+    /// Memory changed behind the guest's back, forget decoded code.
+    fn flush_code_cache(&self) {}
+
+    /// The cause of the last interrupt taken ahead of an instruction, with
+    /// the interrupt bit set, since the last call.
+    fn take_interrupt(&self) -> Option<u64> {
+        None
+    }
+
     fn exec_catch_interrupt_loop(&self, memory: &dyn MMIODevice) -> Result<(), E> {
         loop {
             self.exec_once(memory)?;
@@ -154,4 +201,36 @@ pub trait ExceptionProcessable<E> {
     fn logged_process_exception(&self, memory: &dyn MMIODevice, e: Result<(), E>) {
         self.process_exception(self.exception_log(memory, e));
     }
-}
\ No newline at end of file
+}
+
+/// Everything the monitor needs from a hart.
+pub trait Debuggable<E: ExceptionAttr + Clone>: RegInfo + Disassembler + Snapshot + InstRing + Harts + Execable<E> + ExceptionProcessable<E> {}
+
+impl<E: ExceptionAttr + Clone, T: RegInfo + Disassembler + Snapshot + InstRing + Harts + Execable<E> + ExceptionProcessable<E>> Debuggable<E> for T {}
+
+
+#[test]
+fn test_unchecked_bytes() {
+    use std::cell::RefCell;
+    /// takes bytes only
+    struct Bytes(RefCell<[u8; 8]>);
+    impl Readable for Bytes {
+        fn read_u8(&self, addr: usize) -> Option<u8> {
+            self.0.borrow().get(addr).copied()
+        }
+    }
+    impl Writeable for Bytes {
+        fn write_u8(&self, addr: usize, value: u8) -> Option<()> {
+            *self.0.borrow_mut().get_mut(addr)? = value;
+            Some(())
+        }
+    }
+    let dev = Bytes(RefCell::new([0; 8]));
+    unsafe {
+        dev.unchecked_write_u64(0, 0x0807060504030201);
+        dev.unchecked_write_u16(2, 0xbbaa);
+        assert_eq!(dev.unchecked_read_u64(0), 0x08070605bbaa0201);
+        assert_eq!(dev.unchecked_read_u32(4), 0x08070605);
+    }
+    assert_eq!(dev.read_u32(0), None);
+}
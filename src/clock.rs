@@ -0,0 +1,250 @@
+//! The virtual clock: time as the harts advance it, a tick for every
+//! instruction they run or trap on, and the events devices schedule on it.
+//! Optionally kept in step with the host clock.
+
+use std::{
+    cell::{Cell, RefCell},
+    collections::BTreeMap,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+
+/// Ticks between two looks at the host clock in realtime mode, as a
+/// fraction of a second.
+const SYNCS_PER_SECOND: u64 = 1000;
+
+/// An event, for `Clock::cancel`; earlier ones sort first, and events due
+/// at the same tick in the order they were scheduled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct EventId {
+    pub at: u64,
+    seq: u64,
+}
+
+/// Shared so checkpoints can keep the queue; an event runs once all the
+/// same, unless execution is rolled back past it.
+type Callback = Rc<dyn Fn(&Clock)>;
+
+type Queue = BTreeMap<EventId, Callback>;
+
+/// The callback of `every`, which keeps its state across runs.
+type Repeating = Rc<RefCell<dyn FnMut(&Clock) -> bool>>;
+
+struct Realtime {
+    hz: u64,
+    /// the host and virtual time pacing started at
+    start: Instant,
+    base: u64,
+    next_sync: u64,
+}
+
+pub struct Clock {
+    now: Cell<u64>,
+    /// the tick the earliest event or realtime sync is due at
+    deadline: Cell<u64>,
+    events: RefCell<Queue>,
+    seq: Cell<u64>,
+    realtime: RefCell<Option<Realtime>>,
+    /// `now`, `seq` and the queue at each reverse execution checkpoint
+    marks: RefCell<Vec<(u64, u64, Queue)>>,
+}
+
+impl Default for Clock {
+    fn default() -> Clock {
+        Clock {
+            now: Cell::new(0),
+            deadline: Cell::new(u64::MAX),
+            events: RefCell::new(BTreeMap::new()),
+            seq: Cell::new(0),
+            realtime: RefCell::new(None),
+            marks: RefCell::new(Vec::new()),
+        }
+    }
+}
+
+impl Clock {
+    #[inline]
+    pub fn now(&self) -> u64 {
+        self.now.get()
+    }
+
+    /// Ticks that may pass before something is due, 0 if it already is.
+    #[inline]
+    pub fn until_next(&self) -> u64 {
+        self.deadline.get().saturating_sub(self.now.get())
+    }
+
+    /// `n` ticks went by; runs the events that fell due, in order.
+    #[inline]
+    pub fn advance(&self, n: u64) {
+        self.now.set(self.now.get() + n);
+        if self.now.get() >= self.deadline.get() {
+            self.run_due();
+        }
+    }
+
+    /// Runs `f` once the clock reaches `at`, at the next advance if it
+    /// already has.
+    pub fn schedule(&self, at: u64, f: impl Fn(&Clock) + 'static) -> EventId {
+        let id = EventId { at, seq: self.seq.get() };
+        self.seq.set(id.seq + 1);
+        self.events.borrow_mut().insert(id, Rc::new(f));
+        self.deadline.set(self.deadline.get().min(at));
+        id
+    }
+
+    pub fn schedule_in(&self, delay: u64, f: impl Fn(&Clock) + 'static) -> EventId {
+        self.schedule(self.now.get().saturating_add(delay), f)
+    }
+
+    /// Runs `f` every `period` ticks for as long as it returns true.
+    pub fn every(&self, period: u64, f: impl FnMut(&Clock) -> bool + 'static) {
+        self.repeat(period, Rc::new(RefCell::new(f)));
+    }
+
+    fn repeat(&self, period: u64, f: Repeating) {
+        self.schedule_in(period, move |clock| {
+            if (f.borrow_mut())(clock) {
+                clock.repeat(period, f.clone());
+            }
+        });
+    }
+
+    /// Whether the event was still to come.
+    pub fn cancel(&self, id: EventId) -> bool {
+        let found = self.events.borrow_mut().remove(&id).is_some();
+        self.update_deadline();
+        found
+    }
+
+    /// Paces the clock to `hz` ticks a host second from now on, by sleeping
+    /// when the guest runs ahead and skipping ticks when it lags behind;
+    /// `None` lets it run free, one tick per instruction.
+    pub fn set_realtime(&self, hz: Option<u64>) {
+        *self.realtime.borrow_mut() = hz.map(|hz| Realtime {
+            hz,
+            start: Instant::now(),
+            base: self.now.get(),
+            next_sync: self.now.get(),
+        });
+        self.update_deadline();
+    }
+
+    /// Starts a reverse execution checkpoint.
+    pub fn checkpoint(&self) {
+        let events = self.events.borrow().clone();
+        self.marks.borrow_mut().push((self.now.get(), self.seq.get(), events));
+    }
+
+    /// Back to the time and events of the `n`th checkpoint, which stays,
+    /// later ones are dropped.
+    pub fn rollback(&self, n: usize) {
+        let mut marks = self.marks.borrow_mut();
+        marks.truncate(n + 1);
+        let (now, seq, events) = &marks[n];
+        self.now.set(*now);
+        self.seq.set(*seq);
+        *self.events.borrow_mut() = events.clone();
+        drop(marks);
+        // pacing starts over from here
+        let hz = self.realtime.borrow().as_ref().map(|rt| rt.hz);
+        self.set_realtime(hz);
+    }
+
+    pub fn drop_checkpoint(&self, n: usize) {
+        self.marks.borrow_mut().remove(n);
+    }
+
+    pub fn discard_checkpoints(&self) {
+        self.marks.borrow_mut().clear();
+    }
+
+    fn run_due(&self) {
+        self.sync();
+        loop {
+            let event = {
+                let mut events = self.events.borrow_mut();
+                match events.first_key_value() {
+                    Some((id, _)) if id.at <= self.now.get() => events.pop_first(),
+                    _ => None,
+                }
+            };
+            // callbacks may schedule or cancel events themselves
+            match event {
+                Some((_, f)) => f(self),
+                None => break,
+            }
+        }
+        self.update_deadline();
+    }
+
+    fn sync(&self) {
+        let mut realtime = self.realtime.borrow_mut();
+        let rt = match realtime.as_mut() {
+            Some(rt) if self.now.get() >= rt.next_sync => rt,
+            _ => return,
+        };
+        let elapsed = rt.start.elapsed().as_nanos();
+        let target = rt.base + (elapsed * rt.hz as u128 / 1_000_000_000) as u64;
+        let now = self.now.get();
+        if now > target {
+            std::thread::sleep(Duration::from_nanos(((now - target) as u128 * 1_000_000_000 / rt.hz as u128) as u64));
+        } else if target - now <= rt.hz {
+            self.now.set(target);
+        } else {
+            // more than a second behind: the guest was stopped, e.g. in
+            // the monitor, so start over rather than rush its timers
+            rt.start = Instant::now();
+            rt.base = now;
+        }
+        rt.next_sync = self.now.get() + (rt.hz / SYNCS_PER_SECOND).max(1);
+    }
+
+    fn update_deadline(&self) {
+        let event = self.events.borrow().first_key_value().map_or(u64::MAX, |(id, _)| id.at);
+        let sync = self.realtime.borrow().as_ref().map_or(u64::MAX, |rt| rt.next_sync);
+        self.deadline.set(event.min(sync));
+    }
+}
+
+
+#[test]
+fn test_clock() {
+    use std::rc::Rc;
+    let clock = Clock::default();
+    let log = Rc::new(RefCell::new(Vec::new()));
+    let push = |name: &'static str| {
+        let log = log.clone();
+        move |clock: &Clock| log.borrow_mut().push((name, clock.now()))
+    };
+    clock.schedule(10, push("b"));
+    clock.schedule(5, push("a"));
+    clock.schedule(10, push("c"));
+    let gone = clock.schedule_in(7, push("gone"));
+    assert_eq!(clock.until_next(), 5);
+    assert!(clock.cancel(gone));
+    assert!(!clock.cancel(gone));
+    clock.advance(4);
+    assert!(log.borrow().is_empty());
+    // late events run at the first advance past them, in order
+    clock.advance(8);
+    assert_eq!(*log.borrow(), [("a", 12), ("b", 12), ("c", 12)]);
+    assert_eq!(clock.until_next(), u64::MAX - 12);
+    // three times, then it stops
+    let mut left = 3;
+    let ticks = log.clone();
+    clock.every(100, move |clock| {
+        ticks.borrow_mut().push(("tick", clock.now()));
+        left -= 1;
+        left > 0
+    });
+    (0..1000).for_each(|_| clock.advance(1));
+    assert_eq!(log.borrow()[3..], [("tick", 112), ("tick", 212), ("tick", 312)]);
+    assert_eq!(clock.until_next(), u64::MAX - 1012);
+    // a guest running far ahead of 1 MHz waits for the host
+    clock.set_realtime(Some(1_000_000));
+    let t = Instant::now();
+    clock.advance(5_000);
+    assert!(t.elapsed() >= Duration::from_millis(4));
+}
@@ -0,0 +1,80 @@
+//! Interrupt wiring: lines from devices into interrupt controllers, and the
+//! pins the controllers drive on each hart.
+
+use std::{cell::Cell, rc::Rc};
+
+
+/// How a controller input reacts to its line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trigger {
+    /// pending for as long as the line is high
+    Level,
+    /// pending once for every rising edge
+    Edge,
+}
+
+/// The input side of an interrupt controller.
+pub trait IrqSink {
+    fn set_irq(&self, source: usize, level: bool);
+}
+
+/// An interrupt line into input `source` of a controller, as held by the
+/// device that drives it.
+#[derive(Clone)]
+pub struct IrqLine {
+    sink: Rc<dyn IrqSink>,
+    source: usize,
+}
+
+impl IrqLine {
+    pub fn new(sink: Rc<dyn IrqSink>, source: usize) -> IrqLine {
+        IrqLine { sink, source }
+    }
+
+    #[inline]
+    pub fn set(&self, level: bool) {
+        self.sink.set_irq(self.source, level);
+    }
+
+    #[inline]
+    pub fn raise(&self) {
+        self.set(true);
+    }
+
+    #[inline]
+    pub fn lower(&self) {
+        self.set(false);
+    }
+
+    /// An edge for `Trigger::Edge` inputs.
+    pub fn pulse(&self) {
+        self.raise();
+        self.lower();
+    }
+}
+
+/// The interrupt inputs of a hart: the `mip` bits the controllers drive,
+/// by cause number.
+#[derive(Debug, Default)]
+pub struct HartIrq {
+    bits: Cell<u64>,
+}
+
+impl HartIrq {
+    #[inline]
+    pub fn get(&self) -> u64 {
+        self.bits.get()
+    }
+
+    #[inline]
+    pub fn set(&self, cause: u64, level: bool) {
+        let bit = 1 << cause;
+        self.bits.set(if level { self.bits.get() | bit } else { self.bits.get() & !bit });
+    }
+
+    /// Every bit at once, as saved with `get`.
+    #[inline]
+    pub fn load(&self, bits: u64) {
+        self.bits.set(bits);
+    }
+}
@@ -1,111 +1,935 @@
-// pub mod riscv;
+pub mod riscv;
 pub mod ns16550a;
+pub mod irq;
 
 
-use std::collections::BTreeMap;
+use std::{cell::{Cell, RefCell}, collections::BTreeMap, fmt::Display, rc::Rc};
 
-use crate::abstract_machine::{Readable, Writeable, LengthInfo};
+use crate::{abstract_machine::{Readable, Writeable, LengthInfo}, clock::Clock};
 
 
-pub trait MMIODevice: LengthInfo + Readable + Writeable {}
+/// Ticks between the `MMIODevice::tick` calls of a bus.
+pub const TICK_PERIOD: u64 = 100_000;
+
+
+/// Physical memory attributes: what the bus lets a region be used for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pma {
+    pub read: bool,
+    pub write: bool,
+    pub exec: bool,
+    /// copies may be kept, e.g. decoded or translated code
+    pub cacheable: bool,
+    /// reads have no side effects and may be repeated
+    pub idempotent: bool,
+    pub amo: bool,
+    /// misaligned accesses are carried out rather than faulting
+    pub misaligned: bool,
+}
+
+impl Pma {
+    pub const RAM: Pma = Pma {
+        read: true, write: true, exec: true,
+        cacheable: true, idempotent: true, amo: true, misaligned: true,
+    };
+    pub const ROM: Pma = Pma { write: false, amo: false, ..Pma::RAM };
+    pub const IO: Pma = Pma {
+        read: true, write: true, exec: false,
+        cacheable: false, idempotent: false, amo: false, misaligned: false,
+    };
+
+    /// Whether an `access` of `size` bytes at `addr` is allowed.
+    #[inline]
+    pub fn allows(&self, access: Access, addr: usize, size: usize) -> bool {
+        let kind = match access {
+            Access::Read => self.read,
+            Access::Write => self.write,
+            Access::Exec => self.exec,
+            Access::Amo => self.amo && self.read && self.write,
+        };
+        kind && (self.misaligned || addr.is_multiple_of(size))
+    }
+}
+
+/// `rwx` then `c`acheable, `i`dempotent, `a`mo and `m`isaligned, with `-`
+/// for what is missing.
+impl Display for Pma {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let flags = [
+            (self.read, 'r'), (self.write, 'w'), (self.exec, 'x'),
+            (self.cacheable, 'c'), (self.idempotent, 'i'), (self.amo, 'a'), (self.misaligned, 'm'),
+        ];
+        flags.iter().try_for_each(|(on, c)| write!(f, "{}", if *on { *c } else { '-' }))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    Exec,
+    Amo,
+}
+
+/// Why the bus could not carry out an access, and where it failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BusError {
+    /// nothing is mapped there
+    Unmapped(usize),
+    /// a device is, but refused the access
+    Rejected(usize),
+    /// a device is, but does not take accesses that wide
+    Width(usize),
+}
+
+impl BusError {
+    pub fn addr(&self) -> usize {
+        match *self {
+            BusError::Unmapped(addr) | BusError::Rejected(addr) | BusError::Width(addr) => addr,
+        }
+    }
+
+    /// The same error `by` bytes further up, for a device mapped there.
+    fn offset(self, by: usize) -> BusError {
+        match self {
+            BusError::Unmapped(addr) => BusError::Unmapped(addr + by),
+            BusError::Rejected(addr) => BusError::Rejected(addr + by),
+            BusError::Width(addr) => BusError::Width(addr + by),
+        }
+    }
+}
+
+impl Display for BusError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BusError::Unmapped(addr) => write!(f, "nothing is mapped at 0x{:x}", addr),
+            BusError::Rejected(addr) => write!(f, "the device at 0x{:x} rejected the access", addr),
+            BusError::Width(addr) => write!(f, "the device at 0x{:x} does not take accesses that wide", addr),
+        }
+    }
+}
+
+/// What the bus does with an access of a size the device does not take.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WidthPolicy {
+    /// `BusError::Width`
+    Fault,
+    /// the widest narrower accesses it takes, lowest address first; still
+    /// `BusError::Width` if there are none
+    Split,
+}
+
+pub trait MMIODevice: LengthInfo + Readable + Writeable {
+    /// What `info mtree` calls the device, its type unless overridden.
+    fn name(&self) -> &str {
+        let name = std::any::type_name::<Self>();
+        name.rsplit("::").next().unwrap_or(name)
+    }
+
+    /// Back to the state at power on.
+    fn reset(&self) {}
+
+    /// The virtual clock reached `now`; devices that keep time catch up.
+    /// A bus calls it every `TICK_PERIOD`, finer timing wants events.
+    fn tick(&self, _now: u64) {}
+
+    /// The clock of a bus, for harts to advance and devices to schedule
+    /// events on.
+    fn clock(&self) -> Option<Rc<Clock>> {
+        None
+    }
+
+    /// The access sizes in bytes the device takes, or'ed together. Registers
+    /// with side effects rarely want more than their own width.
+    #[inline]
+    fn access_sizes(&self) -> usize {
+        1
+    }
+
+    #[inline]
+    fn width_policy(&self) -> WidthPolicy {
+        WidthPolicy::Fault
+    }
+
+    /// `size` bytes at `addr`, little endian, as the bus reads them after
+    /// applying `access_sizes`. By default a `None` from `Readable` is the
+    /// device rejecting it.
+    #[inline]
+    fn load(&self, addr: usize, size: usize) -> Result<u64, BusError> {
+        if addr.checked_add(size).is_none_or(|end| end > self.get_length()) {
+            return Err(BusError::Unmapped(addr));
+        }
+        let value = match size {
+            1 => self.read_u8(addr).map(u64::from),
+            2 => self.read_u16(addr).map(u64::from),
+            4 => self.read_u32(addr).map(u64::from),
+            _ => self.read_u64(addr),
+        };
+        value.ok_or(BusError::Rejected(addr))
+    }
+
+    /// `load` for writes.
+    #[inline]
+    fn store(&self, addr: usize, size: usize, value: u64) -> Result<(), BusError> {
+        if addr.checked_add(size).is_none_or(|end| end > self.get_length()) {
+            return Err(BusError::Unmapped(addr));
+        }
+        let r = match size {
+            1 => self.write_u8(addr, value as u8),
+            2 => self.write_u16(addr, value as u16),
+            4 => self.write_u32(addr, value as u32),
+            _ => self.write_u64(addr, value),
+        };
+        r.ok_or(BusError::Rejected(addr))
+    }
+
+    /// Starts a reverse execution checkpoint; stateless devices keep the
+    /// default.
+    fn checkpoint(&self) {}
+
+    /// Back to the `n`th checkpoint, which stays, later ones are dropped.
+    fn rollback(&self, _n: usize) {}
+
+    /// Forgets the `n`th checkpoint, `n > 0`: rolling back to the one
+    /// before it then also undoes what happened since.
+    fn drop_checkpoint(&self, _n: usize) {}
+
+    fn discard_checkpoints(&self) {}
+
+    /// Reads depend on the outside world (input, time), so they are logged
+    /// while recording and fed back on replay.
+    #[inline]
+    fn is_volatile(&self) -> bool {
+        false
+    }
+
+    /// RAM accesses are left to mtrace.
+    #[inline]
+    fn is_ram(&self) -> bool {
+        false
+    }
+
+    /// The attributes at `addr`, None where nothing is mapped.
+    #[inline]
+    fn pma(&self, _addr: usize) -> Option<Pma> {
+        Some(if self.is_ram() { Pma::RAM } else { Pma::IO })
+    }
+
+    /// Whether the bus lets an `access` of `size` bytes at `addr` through;
+    /// the monitor and loaders are not held to it.
+    #[inline]
+    fn allows(&self, access: Access, addr: usize, size: usize) -> bool {
+        self.pma(addr).is_some_and(|x| x.allows(access, addr, size))
+    }
+
+    /// The regions of a bus, by address.
+    fn regions(&self) -> Vec<RegionInfo> {
+        Vec::new()
+    }
+
+    /// RAM as (start, length), for copying guest memory elsewhere.
+    fn ram_regions(&self) -> Vec<(usize, usize)> {
+        if self.is_ram() {
+            vec![(0, self.get_length())]
+        } else {
+            Vec::new()
+        }
+    }
+
+    /// RAM as (start, length, host bytes it takes).
+    fn ram_usage(&self) -> Vec<(usize, usize, usize)> {
+        self.ram_regions().into_iter().map(|(start, len)| (start, len, len)).collect()
+    }
+
+    /// RAM that may be read and written directly, as (start, length, host
+    /// address); none while checkpoints are kept, as writes then have to
+    /// go through the undo log. Only `Pma::RAM` regions belong here.
+    fn host_ram(&self) -> Vec<(usize, usize, *mut u8)> {
+        Vec::new()
+    }
+
+    /// Whether anything but RAM was accessed since the last call.
+    #[inline]
+    fn take_device_access(&self) -> bool {
+        false
+    }
+}
+
+/// A window of the bus onto a device; a device may have several.
+pub struct Region {
+    pub name: String,
+    pub device: Rc<dyn MMIODevice>,
+}
+
+/// A region as listed by `info mtree`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RegionInfo {
+    pub start: usize,
+    pub len: usize,
+    pub name: String,
+    /// what the device calls itself
+    pub device: String,
+    pub pma: Option<Pma>,
+    /// the region mapping the same device at the lowest address, if not
+    /// this one
+    pub alias_of: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MapError {
+    Empty(String),
+    OutOfRange(String),
+    Overlap(String, String),
+    Exists(String),
+    NotFound(String),
+}
+
+impl Display for MapError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MapError::Empty(name) => write!(f, "`{}` is empty", name),
+            MapError::OutOfRange(name) => write!(f, "`{}` runs past the end of the address space", name),
+            MapError::Overlap(name, other) => write!(f, "`{}` overlaps `{}`", name, other),
+            MapError::Exists(name) => write!(f, "a region named `{}` is already mapped", name),
+            MapError::NotFound(name) => write!(f, "no region named `{}`", name),
+        }
+    }
+}
+
+type Table = BTreeMap<usize, Region>;
 
 pub struct Device {
-    pub device_table: BTreeMap<usize, Box<dyn MMIODevice>>
+    /// regions by start address; they never overlap
+    device_table: Rc<RefCell<Table>>,
+    clock: Rc<Clock>,
+    /// `host_ram` of the table, for accesses that skip the devices
+    ram: RefCell<Vec<(usize, usize, *mut u8)>>,
+    /// cleared while checkpoints are kept, so RAM writes reach the undo log
+    direct: Cell<bool>,
+    /// volatile reads seen while recording, with the replay position and
+    /// its value at each checkpoint
+    inputs: RefCell<Vec<Result<u64, BusError>>>,
+    input_pos: Cell<usize>,
+    input_marks: RefCell<Vec<usize>>,
+    device_access: Cell<bool>,
 }
 
 impl Device {
     pub fn new() -> Device {
+        let device_table = Rc::new(RefCell::new(BTreeMap::new()));
+        let clock = Rc::new(Clock::default());
+        let table = Rc::downgrade(&device_table);
+        clock.every(TICK_PERIOD, move |clock| match table.upgrade() {
+            Some(table) => {
+                let devices = devices(&table.borrow());
+                devices.iter().for_each(|d| d.tick(clock.now()));
+                true
+            },
+            None => false,
+        });
         Device {
-            device_table: BTreeMap::new()
+            device_table,
+            clock,
+            ram: RefCell::new(Vec::new()),
+            direct: Cell::new(true),
+            inputs: RefCell::new(Vec::new()),
+            input_pos: Cell::new(0),
+            input_marks: RefCell::new(Vec::new()),
+            device_access: Cell::new(false),
         }
     }
 
-    pub fn add_device(&mut self, start_addr: usize, device: Box<dyn MMIODevice>) {
-        self.device_table.insert(start_addr, device);
+    #[inline]
+    fn input(&self, device: &dyn MMIODevice, read: impl FnOnce() -> Result<u64, BusError>) -> Result<u64, BusError> {
+        if !device.is_volatile() || self.input_marks.borrow().is_empty() {
+            return read();
+        }
+        let pos = self.input_pos.get();
+        self.input_pos.set(pos + 1);
+        let mut inputs = self.inputs.borrow_mut();
+        match inputs.get(pos) {
+            Some(x) => *x,
+            None => {
+                let x = read();
+                inputs.push(x);
+                x
+            },
+        }
     }
-}
 
-impl Readable for Device {
-    fn read_u8(&self, addr: usize) -> Option<u8> {
-        for (start_addr, i) in self.device_table.range(0..addr+1) {
-            if addr >= *start_addr && addr < *start_addr + i.get_length() {
-                return Some(unsafe {i.unchecked_read_u8(addr - start_addr)});
-            }
+    #[inline]
+    #[allow(unused_variables)]
+    fn trace_access(&self, device: &dyn MMIODevice, op: &str, addr: usize, value: u64) {
+        if !device.is_ram() {
+            self.device_access.set(true);
+            dtrace!("{} 0x{:016x} = 0x{:x}", op, addr, value);
         }
-        None
     }
-    fn read_u16(&self, addr: usize) -> Option<u16> {
-        for (start_addr, i) in self.device_table.range(0..addr+1) {
-            if addr >= *start_addr && addr + 1 < *start_addr + i.get_length() {
-                return Some(unsafe {i.unchecked_read_u16(addr - start_addr)});
-            }
+
+    /// `f` on the device the `size` bytes at `addr` fall in, and the
+    /// offset into it. The table is not borrowed meanwhile, so the device
+    /// may remap itself, e.g. when a BAR is written.
+    #[inline]
+    fn with<R>(&self, addr: usize, size: usize, f: impl FnOnce(&dyn MMIODevice, usize) -> R) -> Option<R> {
+        let (start, device) = {
+            let table = self.device_table.borrow();
+            let (start, region) = table.range(..=addr).next_back()?;
+            (*start, region.device.clone())
+        };
+        let offset = addr - start;
+        if offset.checked_add(size)? <= device.get_length() {
+            Some(f(device.as_ref(), offset))
+        } else {
+            None
         }
-        None
     }
-    fn read_u32(&self, addr: usize) -> Option<u32> {
-        for (start_addr, i) in self.device_table.range(0..addr+1) {
-            if addr >= *start_addr && addr + 3 < *start_addr + i.get_length() {
-                return Some(unsafe {i.unchecked_read_u32(addr - start_addr)});
-            }
+
+    /// Where the `size` bytes at `addr` are on the host, if they are RAM
+    /// that can be accessed directly.
+    #[inline]
+    fn direct(&self, addr: usize, size: usize) -> Option<*mut u8> {
+        if !self.direct.get() {
+            return None;
+        }
+        self.host(addr, size)
+    }
+
+    /// `direct`, checkpoints or not.
+    #[inline]
+    fn host(&self, addr: usize, size: usize) -> Option<*mut u8> {
+        let ram = self.ram.borrow();
+        let i = ram.partition_point(|x| x.0 <= addr).checked_sub(1)?;
+        let (start, len, host) = ram[i];
+        let offset = addr - start;
+        if offset.checked_add(size)? <= len {
+            Some(unsafe { host.add(offset) })
+        } else {
+            None
         }
-        None
     }
-    fn read_u64(&self, addr: usize) -> Option<u64> {
-        for (start_addr, i) in self.device_table.range(0..addr+1) {
-            if addr >= *start_addr && addr + 7 < *start_addr + i.get_length() {
-                return Some(unsafe {i.unchecked_read_u64(addr - start_addr)});
+
+    pub fn add_device(&self, name: &str, start: usize, device: Box<dyn MMIODevice>) -> Result<(), MapError> {
+        self.insert(name, start, Rc::from(device))
+    }
+
+    /// `add_device` for a device the caller keeps a handle on, e.g. to
+    /// wire interrupt lines to it.
+    pub fn add_shared(&self, name: &str, start: usize, device: Rc<dyn MMIODevice>) -> Result<(), MapError> {
+        self.insert(name, start, device)
+    }
+
+    /// Maps the device behind region `of` once more, at `start`.
+    pub fn alias(&self, name: &str, start: usize, of: &str) -> Result<(), MapError> {
+        let device = self.find_region(of)?.1;
+        self.insert(name, start, device)
+    }
+
+    /// Unmaps region `name`, handing back its device, which stays mapped
+    /// under any other names it has.
+    pub fn remove(&self, name: &str) -> Result<Rc<dyn MMIODevice>, MapError> {
+        let (start, _) = self.find_region(name)?;
+        let region = self.device_table.borrow_mut().remove(&start).unwrap();
+        self.update_ram();
+        Ok(region.device)
+    }
+
+    /// Moves region `name` to `start`; it stays where it was if it does
+    /// not fit there.
+    pub fn remap(&self, name: &str, start: usize) -> Result<(), MapError> {
+        let (old, _) = self.find_region(name)?;
+        let region = self.device_table.borrow_mut().remove(&old).unwrap();
+        let r = self.insert(name, start, region.device.clone());
+        if r.is_err() {
+            self.device_table.borrow_mut().insert(old, region);
+        }
+        self.update_ram();
+        r
+    }
+
+    fn find_region(&self, name: &str) -> Result<(usize, Rc<dyn MMIODevice>), MapError> {
+        self.device_table.borrow().iter()
+            .find(|(_, x)| x.name == name)
+            .map(|(start, x)| (*start, x.device.clone()))
+            .ok_or_else(|| MapError::NotFound(name.to_string()))
+    }
+
+    fn insert(&self, name: &str, start: usize, device: Rc<dyn MMIODevice>) -> Result<(), MapError> {
+        let len = device.get_length();
+        if len == 0 {
+            return Err(MapError::Empty(name.to_string()));
+        }
+        let end = start.checked_add(len).ok_or_else(|| MapError::OutOfRange(name.to_string()))?;
+        let mut table = self.device_table.borrow_mut();
+        if table.values().any(|x| x.name == name) {
+            return Err(MapError::Exists(name.to_string()));
+        }
+        // regions are disjoint, so only the last one starting below `end`
+        // can reach into [start, end)
+        if let Some((below, region)) = table.range(..end).next_back() {
+            if below + region.device.get_length() > start {
+                return Err(MapError::Overlap(name.to_string(), region.name.clone()));
             }
         }
-        None
+        table.insert(start, Region { name: name.to_string(), device });
+        drop(table);
+        self.update_ram();
+        Ok(())
+    }
+
+    fn update_ram(&self) {
+        let ram = self.host_ram();
+        *self.ram.borrow_mut() = ram;
+    }
+
+    fn devices(&self) -> Vec<Rc<dyn MMIODevice>> {
+        devices(&self.device_table.borrow())
+    }
+}
+
+/// Every device once, however many regions it has.
+fn devices(table: &Table) -> Vec<Rc<dyn MMIODevice>> {
+    let mut devices: Vec<Rc<dyn MMIODevice>> = Vec::new();
+    for region in table.values() {
+        if !devices.iter().any(|x| Rc::ptr_eq(x, &region.device)) {
+            devices.push(region.device.clone());
+        }
+    }
+    devices
+}
+
+/// The size of the accesses the bus turns one of `size` bytes at `offset`
+/// into for `device`.
+#[inline]
+fn width(device: &dyn MMIODevice, offset: usize, size: usize) -> Result<usize, BusError> {
+    let sizes = device.access_sizes();
+    if sizes & size != 0 {
+        return Ok(size);
+    }
+    let narrower = sizes & (size - 1);
+    match device.width_policy() {
+        WidthPolicy::Split if narrower != 0 => Ok(1 << narrower.ilog2()),
+        _ => Err(BusError::Width(offset)),
+    }
+}
+
+/// `size` bytes at `host`, little endian.
+#[inline]
+unsafe fn read_host(host: *const u8, size: usize) -> u64 {
+    match size {
+        1 => host.read() as u64,
+        2 => u16::from_le((host as *const u16).read_unaligned()) as u64,
+        4 => u32::from_le((host as *const u32).read_unaligned()) as u64,
+        _ => u64::from_le((host as *const u64).read_unaligned()),
     }
 }
 
+#[inline]
+unsafe fn write_host(host: *mut u8, size: usize, value: u64) {
+    match size {
+        1 => host.write(value as u8),
+        2 => (host as *mut u16).write_unaligned((value as u16).to_le()),
+        4 => (host as *mut u32).write_unaligned((value as u32).to_le()),
+        _ => (host as *mut u64).write_unaligned(value.to_le()),
+    }
+}
+
+macro_rules! bus_read {
+    ($name:ident, $t:ty) => {
+        #[inline]
+        fn $name(&self, addr: usize) -> Option<$t> {
+            self.load(addr, std::mem::size_of::<$t>()).ok().map(|x| x as $t)
+        }
+    };
+}
+
+macro_rules! bus_write {
+    ($name:ident, $t:ty) => {
+        #[inline]
+        fn $name(&self, addr: usize, value: $t) -> Option<()> {
+            self.store(addr, std::mem::size_of::<$t>(), value as u64).ok()
+        }
+    };
+}
+
+/// The monitor and loaders get the same accesses as harts, minus the PMAs.
+impl Readable for Device {
+    bus_read!(read_u8, u8);
+    bus_read!(read_u16, u16);
+    bus_read!(read_u32, u32);
+    bus_read!(read_u64, u64);
+}
+
 impl Writeable for Device {
-    fn write_u8(&self, addr: usize, value: u8) -> Option<()> {
-        for (start_addr, i) in self.device_table.range(0..addr+1) {
-            if addr >= *start_addr && addr < *start_addr + i.get_length() {
-                unsafe {i.unchecked_write_u8(addr - start_addr, value)};
-                return Some(());
+    bus_write!(write_u8, u8);
+    bus_write!(write_u16, u16);
+    bus_write!(write_u32, u32);
+    bus_write!(write_u64, u64);
+}
+
+impl LengthInfo for Device {
+    /// The end of the highest region; aliases and gaps are within it.
+    fn get_length(&self) -> usize {
+        self.device_table.borrow().iter().next_back().map_or(0, |(start, x)| start + x.device.get_length())
+    }
+}
+
+impl MMIODevice for Device {
+    fn name(&self) -> &str {
+        "bus"
+    }
+
+    fn reset(&self) {
+        self.devices().iter().for_each(|d| d.reset());
+    }
+
+    fn tick(&self, now: u64) {
+        self.devices().iter().for_each(|d| d.tick(now));
+    }
+
+    fn clock(&self) -> Option<Rc<Clock>> {
+        Some(self.clock.clone())
+    }
+
+    /// Widths are up to the devices.
+    #[inline]
+    fn access_sizes(&self) -> usize {
+        1 | 2 | 4 | 8
+    }
+
+    #[inline]
+    fn load(&self, addr: usize, size: usize) -> Result<u64, BusError> {
+        if let Some(host) = self.direct(addr, size) {
+            return Ok(unsafe { read_host(host, size) });
+        }
+        self.with(addr, size, |device, offset| {
+            let value = self.input(device, || {
+                let width = width(device, offset, size)?;
+                (0..size).step_by(width).try_fold(0, |value, i| Ok(value | device.load(offset + i, width)? << (8 * i)))
+            });
+            if let Ok(value) = value {
+                self.trace_access(device, "read ", addr, value);
             }
+            value.map_err(|e| e.offset(addr - offset))
+        }).unwrap_or(Err(BusError::Unmapped(addr)))
+    }
+
+    #[inline]
+    fn store(&self, addr: usize, size: usize, value: u64) -> Result<(), BusError> {
+        if let Some(host) = self.direct(addr, size) {
+            unsafe { write_host(host, size, value) };
+            return Ok(());
         }
-        None
+        self.with(addr, size, |device, offset| {
+            self.trace_access(device, "write", addr, value);
+            width(device, offset, size)
+                .and_then(|width| (0..size).step_by(width).try_for_each(|i| device.store(offset + i, width, value >> (8 * i))))
+                .map_err(|e| e.offset(addr - offset))
+        }).unwrap_or(Err(BusError::Unmapped(addr)))
     }
 
-    fn write_u16(&self, addr: usize, value: u16) -> Option<()> {
-        for (start_addr, i) in self.device_table.range(0..addr+1) {
-            if addr >= *start_addr && addr + 1 < *start_addr + i.get_length() {
-                unsafe {i.unchecked_write_u16(addr - start_addr, value)};
-                return Some(());
-            }
+    fn checkpoint(&self) {
+        self.direct.set(false);
+        self.clock.checkpoint();
+        self.devices().iter().for_each(|d| d.checkpoint());
+        self.input_marks.borrow_mut().push(self.input_pos.get());
+    }
+
+    fn rollback(&self, n: usize) {
+        self.clock.rollback(n);
+        self.devices().iter().for_each(|d| d.rollback(n));
+        let mut marks = self.input_marks.borrow_mut();
+        marks.truncate(n + 1);
+        self.input_pos.set(marks[n]);
+    }
+
+    fn drop_checkpoint(&self, n: usize) {
+        self.clock.drop_checkpoint(n);
+        self.devices().iter().for_each(|d| d.drop_checkpoint(n));
+        self.input_marks.borrow_mut().remove(n);
+    }
+
+    fn discard_checkpoints(&self) {
+        self.direct.set(true);
+        self.clock.discard_checkpoints();
+        self.devices().iter().for_each(|d| d.discard_checkpoints());
+        self.inputs.borrow_mut().clear();
+        self.input_pos.set(0);
+        self.input_marks.borrow_mut().clear();
+    }
+
+    #[inline]
+    fn pma(&self, addr: usize) -> Option<Pma> {
+        self.with(addr, 1, |device, offset| device.pma(offset))?
+    }
+
+    #[inline]
+    fn allows(&self, access: Access, addr: usize, size: usize) -> bool {
+        // host RAM is always `Pma::RAM`
+        if self.host(addr, size).is_some() {
+            return true;
         }
-        None
+        self.with(addr, size, |device, offset| device.allows(access, offset, size)).unwrap_or(false)
     }
 
-    fn write_u32(&self, addr: usize, value: u32) -> Option<()> {
-        for (start_addr, i) in self.device_table.range(0..addr+1) {
-            if addr >= *start_addr && addr + 3 < *start_addr + i.get_length() {
-                unsafe {i.unchecked_write_u32(addr - start_addr, value)};
-                return Some(());
-            }
+    fn regions(&self) -> Vec<RegionInfo> {
+        let table = self.device_table.borrow();
+        table.iter().map(|(start, region)| RegionInfo {
+            start: *start,
+            len: region.device.get_length(),
+            name: region.name.clone(),
+            device: region.device.name().to_string(),
+            pma: region.device.pma(0),
+            alias_of: table.values()
+                .find(|x| Rc::ptr_eq(&x.device, &region.device))
+                .filter(|x| x.name != region.name)
+                .map(|x| x.name.clone()),
+        }).collect()
+    }
+
+    fn ram_regions(&self) -> Vec<(usize, usize)> {
+        self.device_table.borrow().iter()
+            .flat_map(|(start, x)| x.device.ram_regions().into_iter().map(move |(x, len)| (start + x, len)))
+            .collect()
+    }
+
+    fn ram_usage(&self) -> Vec<(usize, usize, usize)> {
+        self.device_table.borrow().iter()
+            .flat_map(|(start, x)| x.device.ram_usage().into_iter().map(move |(x, len, resident)| (start + x, len, resident)))
+            .collect()
+    }
+
+    fn host_ram(&self) -> Vec<(usize, usize, *mut u8)> {
+        self.device_table.borrow().iter()
+            .flat_map(|(start, x)| x.device.host_ram().into_iter().map(move |(x, len, host)| (start + x, len, host)))
+            .collect()
+    }
+
+    #[inline]
+    fn take_device_access(&self) -> bool {
+        self.device_access.replace(false)
+    }
+}
+
+#[test]
+fn test_device_lookup() {
+    use crate::memory::Memory;
+    struct Port;
+    impl LengthInfo for Port {
+        fn get_length(&self) -> usize {
+            8
+        }
+    }
+    impl Readable for Port {
+        fn read_u8(&self, _addr: usize) -> Option<u8> {
+            Some(0)
         }
-        None
     }
+    impl Writeable for Port {}
+    impl MMIODevice for Port {}
+    let mmio = Device::new();
+    mmio.add_device("low", 0x1000, Box::new(Memory::new(0x100))).unwrap();
+    mmio.add_device("high", 0x2000, Box::new(Memory::new(0x1000))).unwrap();
+    mmio.add_device("port", 0x2000_0000, Box::new(Port)).unwrap();
+    assert_eq!(mmio.write_u64(0x10f8, 0x0102030405060708), Some(()));
+    assert_eq!(mmio.read_u32(0x10fc), Some(0x01020304));
+    // past the end, in the gap, across two regions
+    assert_eq!(mmio.read_u64(0x10fc), None);
+    assert_eq!(mmio.read_u8(0x1800), None);
+    assert_eq!(mmio.read_u8(0x0fff), None);
+    assert_eq!(mmio.write_u16(0x2fff, 0), None);
+    assert!(!mmio.take_device_access());
+    mmio.read_u8(0x2000_0005);
+    assert!(mmio.take_device_access());
+    // writes while recording go through the undo log
+    mmio.checkpoint();
+    mmio.write_u32(0x2ffc, 0xdeadbeef).unwrap();
+    assert_eq!(mmio.read_u32(0x2ffc), Some(0xdeadbeef));
+    mmio.rollback(0);
+    assert_eq!(mmio.read_u32(0x2ffc), Some(0));
+    mmio.discard_checkpoints();
+    mmio.write_u32(0x2ffc, 0xdeadbeef).unwrap();
+    assert_eq!(mmio.read_u32(0x2ffc), Some(0xdeadbeef));
+}
 
-    fn write_u64(&self, addr: usize, value: u64) -> Option<()> {
-        for (start_addr, i) in self.device_table.range(0..addr+1) {
-            if addr >= *start_addr && addr + 7 < *start_addr + i.get_length() {
-                unsafe {i.unchecked_write_u64(addr - start_addr, value)};
-                return Some(());
-            }
+#[test]
+fn test_pma() {
+    use crate::memory::Memory;
+    struct Uart;
+    impl LengthInfo for Uart {
+        fn get_length(&self) -> usize {
+            8
         }
-        None
     }
+    impl Readable for Uart {
+        fn read_u8(&self, _addr: usize) -> Option<u8> {
+            Some(0)
+        }
+    }
+    impl Writeable for Uart {}
+    impl MMIODevice for Uart {}
+    let mmio = Device::new();
+    mmio.add_device("rom", 0x1000, Box::new(Memory::from([0u8; 0x100].as_ref()).with_pma(Pma::ROM))).unwrap();
+    mmio.add_device("ram", 0x2000, Box::new(Memory::new(0x1000))).unwrap();
+    mmio.add_device("uart", 0x1000_0000, Box::new(Uart)).unwrap();
+    assert!(mmio.allows(Access::Exec, 0x2002, 4));
+    assert!(mmio.allows(Access::Write, 0x2ffc, 4));
+    assert!(!mmio.allows(Access::Write, 0x2ffe, 4));
+    assert!(mmio.allows(Access::Exec, 0x1010, 4));
+    assert!(!mmio.allows(Access::Write, 0x1010, 1));
+    assert!(!mmio.allows(Access::Amo, 0x1010, 8));
+    assert!(mmio.allows(Access::Read, 0x1000_0000, 1));
+    assert!(!mmio.allows(Access::Exec, 0x1000_0000, 4));
+    assert!(!mmio.allows(Access::Read, 0x1000_0001, 2));
+    assert!(!mmio.allows(Access::Read, 0x3000, 1));
+    assert_eq!(mmio.pma(0x1000_0004), Some(Pma::IO));
+    // the monitor may still patch ROM
+    assert_eq!(mmio.write_u8(0x1010, 1), Some(()));
+    assert_eq!(mmio.read_u8(0x1010), Some(1));
 }
 
-impl LengthInfo for Device {
-    fn get_length(&self) -> usize {
-        self.device_table.iter().map(|(_, d)| d.get_length()).sum()
+#[test]
+fn test_mapping() {
+    use crate::memory::Memory;
+    let mmio = Device::new();
+    mmio.add_device("ram", 0x1000, Box::new(Memory::new(0x1000))).unwrap();
+    mmio.add_device("rom", 0x4000, Box::new(Memory::new(0x100).with_pma(Pma::ROM))).unwrap();
+    let overlap = |name: &str, start, len| mmio.add_device(name, start, Box::new(Memory::new(len)));
+    assert_eq!(overlap("a", 0x1fff, 2), Err(MapError::Overlap("a".to_string(), "ram".to_string())));
+    assert_eq!(overlap("a", 0x0800, 0x1000), Err(MapError::Overlap("a".to_string(), "ram".to_string())));
+    assert_eq!(overlap("a", 0x0, 0x8000), Err(MapError::Overlap("a".to_string(), "rom".to_string())));
+    assert_eq!(overlap("a", usize::MAX, 2), Err(MapError::OutOfRange("a".to_string())));
+    assert_eq!(overlap("ram", 0x8000, 1), Err(MapError::Exists("ram".to_string())));
+    overlap("a", 0x2000, 0x2000).unwrap();
+    // an alias sees the same bytes
+    mmio.alias("ram.hi", 0x1_0000_1000, "ram").unwrap();
+    mmio.write_u32(0x1010, 0x12345678).unwrap();
+    assert_eq!(mmio.read_u32(0x1_0000_1010), Some(0x12345678));
+    assert_eq!(mmio.regions().last().unwrap().alias_of.as_deref(), Some("ram"));
+    assert_eq!(mmio.get_length(), 0x1_0000_2000);
+    // moving the BAR of a device, or taking it away
+    mmio.remap("ram.hi", 0x8000).unwrap();
+    assert_eq!(mmio.read_u32(0x8010), Some(0x12345678));
+    assert_eq!(mmio.read_u32(0x1_0000_1010), None);
+    assert_eq!(mmio.remap("ram.hi", 0x4080), Err(MapError::Overlap("ram.hi".to_string(), "rom".to_string())));
+    assert_eq!(mmio.read_u32(0x8010), Some(0x12345678));
+    mmio.remove("ram").unwrap();
+    assert_eq!(mmio.read_u32(0x1010), None);
+    assert_eq!(mmio.read_u32(0x8010), Some(0x12345678));
+    assert_eq!(mmio.remove("ram").err(), Some(MapError::NotFound("ram".to_string())));
+    let names: Vec<_> = mmio.regions().into_iter().map(|x| (x.start, x.name, x.alias_of)).collect();
+    assert_eq!(names, [(0x2000, "a".to_string(), None), (0x4000, "rom".to_string(), None), (0x8000, "ram.hi".to_string(), None)]);
+    assert_eq!(mmio.get_length(), 0x9000);
+}
+
+#[test]
+fn test_remap_from_device() {
+    use std::rc::Weak;
+    /// A function whose BAR, the register at 0, moves its own window.
+    struct Bar(Weak<Device>);
+    impl LengthInfo for Bar {
+        fn get_length(&self) -> usize {
+            0x10
+        }
+    }
+    impl Readable for Bar {
+        fn read_u32(&self, addr: usize) -> Option<u32> {
+            Some(0xf000 | addr as u32)
+        }
+    }
+    impl Writeable for Bar {
+        fn write_u32(&self, addr: usize, value: u32) -> Option<()> {
+            match addr {
+                0 => self.0.upgrade()?.remap("bar", value as usize).ok(),
+                _ => None,
+            }
+        }
+    }
+    impl MMIODevice for Bar {
+        fn access_sizes(&self) -> usize {
+            4
+        }
     }
+    let mmio = Rc::new(Device::new());
+    mmio.add_device("bar", 0x1000, Box::new(Bar(Rc::downgrade(&mmio)))).unwrap();
+    assert_eq!(mmio.write_u32(0x1000, 0x8000), Some(()));
+    assert_eq!(mmio.read_u32(0x8004), Some(0xf004));
+    assert_eq!(mmio.read_u32(0x1004), None);
+    assert_eq!(mmio.regions()[0].start, 0x8000);
 }
 
-impl MMIODevice for Device {}
\ No newline at end of file
+#[test]
+fn test_access_width() {
+    use crate::memory::Memory;
+    /// A 32-bit register that counts the reads it sees, and a byte-wide
+    /// FIFO that takes nothing but writes.
+    #[derive(Default)]
+    struct Timer {
+        reads: Cell<u32>,
+        ticks: Cell<u64>,
+    }
+    impl LengthInfo for Timer {
+        fn get_length(&self) -> usize {
+            8
+        }
+    }
+    impl Readable for Timer {
+        fn read_u32(&self, _addr: usize) -> Option<u32> {
+            self.reads.set(self.reads.get() + 1);
+            Some(self.reads.get())
+        }
+    }
+    impl Writeable for Timer {}
+    impl MMIODevice for Timer {
+        fn reset(&self) {
+            self.reads.set(0);
+        }
+        fn tick(&self, now: u64) {
+            self.ticks.set(now);
+        }
+        fn access_sizes(&self) -> usize {
+            4
+        }
+    }
+    struct Fifo(RefCell<Vec<u8>>);
+    impl LengthInfo for Fifo {
+        fn get_length(&self) -> usize {
+            4
+        }
+    }
+    impl Readable for Fifo {}
+    impl Writeable for Fifo {
+        fn write_u8(&self, _addr: usize, value: u8) -> Option<()> {
+            self.0.borrow_mut().push(value);
+            Some(())
+        }
+    }
+    impl MMIODevice for Fifo {
+        fn width_policy(&self) -> WidthPolicy {
+            WidthPolicy::Split
+        }
+    }
+    let mmio = Device::new();
+    let timer = Rc::new(Timer::default());
+    mmio.insert("timer", 0x1000, timer.clone()).unwrap();
+    let fifo = Rc::new(Fifo(RefCell::new(Vec::new())));
+    mmio.insert("fifo", 0x2000, fifo.clone()).unwrap();
+    mmio.add_device("ram", 0x3000, Box::new(Memory::new(0x10))).unwrap();
+    // the register is neither split nor read twice
+    assert_eq!(mmio.load(0x1004, 4), Ok(1));
+    assert_eq!(mmio.load(0x1000, 8), Err(BusError::Width(0x1000)));
+    assert_eq!(mmio.load(0x1002, 2), Err(BusError::Width(0x1002)));
+    assert_eq!(timer.reads.get(), 1);
+    // split into bytes, lowest first; reads are refused by the device
+    mmio.store(0x2000, 4, 0x44332211).unwrap();
+    assert_eq!(*fifo.0.borrow(), [0x11, 0x22, 0x33, 0x44]);
+    assert_eq!(mmio.load(0x2001, 1), Err(BusError::Rejected(0x2001)));
+    assert_eq!(mmio.load(0x1800, 1), Err(BusError::Unmapped(0x1800)));
+    assert_eq!(mmio.store(0x300c, 8, 0), Err(BusError::Unmapped(0x300c)));
+    assert_eq!(mmio.read_u16(0x1000), None);
+    let names: Vec<_> = mmio.regions().into_iter().map(|x| x.device).collect();
+    assert_eq!(names, ["Timer", "Fifo", "memory"]);
+    mmio.tick(100);
+    assert_eq!(timer.ticks.get(), 100);
+    mmio.reset();
+    assert_eq!(mmio.read_u32(0x1000), Some(1));
+}
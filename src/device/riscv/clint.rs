@@ -0,0 +1,241 @@
+//! The core-local interruptor, SiFive layout: a software interrupt bit and
+//! a timer compare register per hart, and `mtime`, which is the virtual
+//! clock.
+
+use std::{cell::{Cell, RefCell}, rc::Rc};
+
+use crate::{
+    abstract_machine::{LengthInfo, Readable, Writeable},
+    clock::{Clock, EventId},
+    device::{MMIODevice, BusError, irq::HartIrq},
+    interpreter::riscv64::irq::RawInstrrupt,
+};
+
+use super::truncate;
+
+
+pub const CLINT_BASE: usize = 0x200_0000;
+const CLINT_SIZE: usize = 0x1_0000;
+const MSIP: usize = 0x0;
+const MTIMECMP: usize = 0x4000;
+const MTIME: usize = 0xbff8;
+
+const MSI: u64 = RawInstrrupt::MachineSoftwareInterrupt as u64;
+const MTI: u64 = RawInstrrupt::MachineTimerInterrupt as u64;
+
+enum Reg {
+    Msip(usize),
+    /// with the shift of the half accessed
+    Mtimecmp(usize, u32),
+    Mtime(u32),
+}
+
+/// What software can change, at a reverse execution checkpoint.
+struct State {
+    msip: Vec<bool>,
+    mtimecmp: Vec<u64>,
+    timers: Vec<Option<EventId>>,
+    offset: u64,
+}
+
+pub struct Clint {
+    clock: Rc<Clock>,
+    harts: Vec<Rc<HartIrq>>,
+    msip: Vec<Cell<bool>>,
+    mtimecmp: Vec<Cell<u64>>,
+    /// when each hart's timer goes off
+    timers: Vec<Cell<Option<EventId>>>,
+    /// `mtime` less the clock, as software may set it
+    offset: Cell<u64>,
+    marks: RefCell<Vec<State>>,
+}
+
+impl Clint {
+    pub fn new(clock: Rc<Clock>, harts: Vec<Rc<HartIrq>>) -> Clint {
+        let n = harts.len();
+        Clint {
+            clock,
+            harts,
+            msip: (0..n).map(|_| Cell::new(false)).collect(),
+            mtimecmp: (0..n).map(|_| Cell::new(u64::MAX)).collect(),
+            timers: (0..n).map(|_| Cell::new(None)).collect(),
+            offset: Cell::new(0),
+            marks: RefCell::new(Vec::new()),
+        }
+    }
+
+    #[inline]
+    fn mtime(&self) -> u64 {
+        self.clock.now().wrapping_add(self.offset.get())
+    }
+
+    fn reg(&self, addr: usize, size: usize) -> Result<Reg, BusError> {
+        let n = self.harts.len();
+        let half = |offset: usize| match (offset % 8, size) {
+            (0, 8) | (0, 4) => Ok(0),
+            (4, 4) => Ok(32),
+            _ => Err(BusError::Rejected(addr)),
+        };
+        match addr {
+            _ if addr < MSIP + 4 * n => match (addr % 4, size) {
+                (0, 4) => Ok(Reg::Msip(addr / 4)),
+                _ => Err(BusError::Rejected(addr)),
+            },
+            MTIMECMP.. if addr < MTIMECMP + 8 * n => Ok(Reg::Mtimecmp((addr - MTIMECMP) / 8, half(addr)?)),
+            MTIME.. if addr < MTIME + 8 => Ok(Reg::Mtime(half(addr)?)),
+            _ => Err(BusError::Rejected(addr)),
+        }
+    }
+
+    /// MTIP for `hart` after a change of its compare value or of `mtime`,
+    /// and the timer event that raises it later.
+    fn set_timer(&self, hart: usize) {
+        if let Some(id) = self.timers[hart].take() {
+            self.clock.cancel(id);
+        }
+        let cmp = self.mtimecmp[hart].get();
+        let due = self.mtime() >= cmp;
+        self.harts[hart].set(MTI, due);
+        if !due {
+            let irq = self.harts[hart].clone();
+            let at = cmp.wrapping_sub(self.offset.get());
+            self.timers[hart].set(Some(self.clock.schedule(at, move |_| irq.set(MTI, true))));
+        }
+    }
+}
+
+impl LengthInfo for Clint {
+    fn get_length(&self) -> usize {
+        CLINT_SIZE
+    }
+}
+
+impl Readable for Clint {}
+
+impl Writeable for Clint {}
+
+impl MMIODevice for Clint {
+    fn name(&self) -> &str {
+        "clint"
+    }
+
+    fn reset(&self) {
+        self.offset.set(0);
+        for hart in 0..self.harts.len() {
+            self.msip[hart].set(false);
+            self.harts[hart].set(MSI, false);
+            self.mtimecmp[hart].set(u64::MAX);
+            self.set_timer(hart);
+        }
+    }
+
+    #[inline]
+    fn access_sizes(&self) -> usize {
+        4 | 8
+    }
+
+    fn checkpoint(&self) {
+        self.marks.borrow_mut().push(State {
+            msip: self.msip.iter().map(Cell::get).collect(),
+            mtimecmp: self.mtimecmp.iter().map(Cell::get).collect(),
+            timers: self.timers.iter().map(Cell::get).collect(),
+            offset: self.offset.get(),
+        });
+    }
+
+    /// The bus rolls the clock back with us, so the timer events are
+    /// there again; the harts restore their own MSIP and MTIP.
+    fn rollback(&self, n: usize) {
+        let mut marks = self.marks.borrow_mut();
+        marks.truncate(n + 1);
+        let state = &marks[n];
+        for hart in 0..self.harts.len() {
+            self.msip[hart].set(state.msip[hart]);
+            self.mtimecmp[hart].set(state.mtimecmp[hart]);
+            self.timers[hart].set(state.timers[hart]);
+        }
+        self.offset.set(state.offset);
+    }
+
+    fn drop_checkpoint(&self, n: usize) {
+        self.marks.borrow_mut().remove(n);
+    }
+
+    fn discard_checkpoints(&self) {
+        self.marks.borrow_mut().clear();
+    }
+
+    fn load(&self, addr: usize, size: usize) -> Result<u64, BusError> {
+        let value = match self.reg(addr, size)? {
+            Reg::Msip(hart) => self.msip[hart].get() as u64,
+            Reg::Mtimecmp(hart, shift) => self.mtimecmp[hart].get() >> shift,
+            Reg::Mtime(shift) => self.mtime() >> shift,
+        };
+        Ok(truncate(value, size))
+    }
+
+    fn store(&self, addr: usize, size: usize, value: u64) -> Result<(), BusError> {
+        // the other half of a 32-bit write stays
+        let merge = |old: u64, shift: u32| {
+            let mask = truncate(u64::MAX, size) << shift;
+            (old & !mask) | (value << shift & mask)
+        };
+        match self.reg(addr, size)? {
+            Reg::Msip(hart) => {
+                self.msip[hart].set(value & 1 == 1);
+                self.harts[hart].set(MSI, value & 1 == 1);
+            },
+            Reg::Mtimecmp(hart, shift) => {
+                self.mtimecmp[hart].set(merge(self.mtimecmp[hart].get(), shift));
+                self.set_timer(hart);
+            },
+            Reg::Mtime(shift) => {
+                let mtime = merge(self.mtime(), shift);
+                self.offset.set(mtime.wrapping_sub(self.clock.now()));
+                (0..self.harts.len()).for_each(|hart| self.set_timer(hart));
+            },
+        }
+        Ok(())
+    }
+}
+
+
+#[test]
+fn test_clint() {
+    use crate::device::Device;
+    let mmio = Device::new();
+    let clock = mmio.clock().unwrap();
+    let hart = Rc::new(HartIrq::default());
+    mmio.add_device("clint", CLINT_BASE, Box::new(Clint::new(clock.clone(), vec![hart.clone()]))).unwrap();
+    let mip = |cause: u64| hart.get() >> cause & 1 == 1;
+    mmio.store(CLINT_BASE + MSIP, 4, 1).unwrap();
+    assert!(mip(MSI));
+    assert_eq!(mmio.load(CLINT_BASE + MSIP, 4), Ok(1));
+    assert_eq!(mmio.load(CLINT_BASE + MSIP, 1), Err(BusError::Width(CLINT_BASE + MSIP)));
+    mmio.store(CLINT_BASE + MSIP, 4, 0).unwrap();
+    assert!(!mip(MSI));
+    // mtime is the clock, and the timer goes off with it
+    clock.advance(100);
+    assert_eq!(mmio.load(CLINT_BASE + MTIME, 8), Ok(100));
+    assert!(!mip(MTI));
+    mmio.store(CLINT_BASE + MTIMECMP, 8, 150).unwrap();
+    clock.advance(49);
+    assert!(!mip(MTI));
+    clock.advance(1);
+    assert!(mip(MTI));
+    // moving the compare value on clears it, in 32-bit halves too
+    mmio.store(CLINT_BASE + MTIMECMP + 4, 4, 1).unwrap();
+    assert_eq!(mmio.load(CLINT_BASE + MTIMECMP, 8), Ok(1 << 32 | 150));
+    assert!(!mip(MTI));
+    // so does setting mtime back, and forward raises it at once
+    mmio.store(CLINT_BASE + MTIMECMP, 8, 1000).unwrap();
+    mmio.store(CLINT_BASE + MTIME, 8, 2000).unwrap();
+    assert!(mip(MTI));
+    assert_eq!(mmio.load(CLINT_BASE + MTIME + 4, 4), Ok(0));
+    mmio.store(CLINT_BASE + MTIME, 8, 0).unwrap();
+    assert!(!mip(MTI));
+    clock.advance(1000);
+    assert!(mip(MTI));
+    // a hart that is not there
+    assert_eq!(mmio.load(CLINT_BASE + MSIP + 4, 4), Err(BusError::Rejected(CLINT_BASE + 4)));
+}
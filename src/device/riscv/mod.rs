@@ -0,0 +1,15 @@
+//! The interrupt controllers of a RISC-V platform, at the addresses QEMU's
+//! `virt` machine and spike put them.
+
+pub mod clint;
+pub mod plic;
+
+/// A `size` byte register value, with the bytes above it dropped.
+#[inline]
+fn truncate(value: u64, size: usize) -> u64 {
+    if size >= 8 {
+        value
+    } else {
+        value & ((1 << (8 * size)) - 1)
+    }
+}
@@ -0,0 +1,318 @@
+//! The platform-level interrupt controller: device interrupt lines in,
+//! MEIP and SEIP of each hart out, one context for each.
+
+use std::{cell::{Cell, RefCell}, rc::Rc};
+
+use crate::{
+    abstract_machine::{LengthInfo, Readable, Writeable},
+    device::{MMIODevice, BusError, irq::{HartIrq, IrqLine, IrqSink, Trigger}},
+    interpreter::riscv64::irq::RawInstrrupt,
+};
+
+
+pub const PLIC_BASE: usize = 0xc00_0000;
+/// Source 0 means none.
+pub const PLIC_SOURCES: usize = 64;
+const PLIC_SIZE: usize = 0x400_0000;
+const PRIORITY: usize = 0x0;
+const PENDING: usize = 0x1000;
+const ENABLE: usize = 0x2000;
+const ENABLE_STRIDE: usize = 0x80;
+const CONTEXT: usize = 0x20_0000;
+const CONTEXT_STRIDE: usize = 0x1000;
+/// priorities 0 (never) to 7
+const PRIORITY_MASK: u32 = 7;
+
+enum Reg {
+    Priority(usize),
+    /// which half of the bits
+    Pending(usize),
+    Enable(usize, usize),
+    Threshold(usize),
+    Claim(usize),
+}
+
+/// What the guest can change, at a reverse execution checkpoint.
+struct State {
+    priority: [u32; PLIC_SOURCES],
+    level: u64,
+    pending: u64,
+    claimed: u64,
+    enable: Vec<u64>,
+    threshold: Vec<u32>,
+}
+
+pub struct Plic {
+    /// the MEIP and SEIP of a hart are contexts 2n and 2n + 1
+    harts: Vec<Rc<HartIrq>>,
+    priority: RefCell<[u32; PLIC_SOURCES]>,
+    trigger: RefCell<[Trigger; PLIC_SOURCES]>,
+    /// line levels, sources pending, and sources claimed and not completed
+    level: Cell<u64>,
+    pending: Cell<u64>,
+    claimed: Cell<u64>,
+    enable: RefCell<Vec<u64>>,
+    threshold: RefCell<Vec<u32>>,
+    marks: RefCell<Vec<State>>,
+}
+
+impl Plic {
+    pub fn new(harts: Vec<Rc<HartIrq>>) -> Plic {
+        let contexts = 2 * harts.len();
+        Plic {
+            harts,
+            priority: RefCell::new([0; PLIC_SOURCES]),
+            trigger: RefCell::new([Trigger::Level; PLIC_SOURCES]),
+            level: Cell::new(0),
+            pending: Cell::new(0),
+            claimed: Cell::new(0),
+            enable: RefCell::new(vec![0; contexts]),
+            threshold: RefCell::new(vec![0; contexts]),
+            marks: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// The line into `source`, which is triggered the way it says.
+    pub fn line(self: &Rc<Self>, source: usize, trigger: Trigger) -> IrqLine {
+        assert!(source > 0 && source < PLIC_SOURCES, "no PLIC source {}", source);
+        self.trigger.borrow_mut()[source] = trigger;
+        IrqLine::new(self.clone(), source)
+    }
+
+    fn contexts(&self) -> usize {
+        2 * self.harts.len()
+    }
+
+    fn reg(&self, addr: usize) -> Result<Reg, BusError> {
+        let contexts = self.contexts();
+        let reg = match addr {
+            _ if !addr.is_multiple_of(4) => None,
+            PRIORITY.. if addr < PRIORITY + 4 * PLIC_SOURCES => Some(Reg::Priority(addr / 4)),
+            PENDING.. if addr < PENDING + PLIC_SOURCES / 8 => Some(Reg::Pending((addr - PENDING) / 4)),
+            ENABLE.. if addr < ENABLE + ENABLE_STRIDE * contexts => {
+                let (context, offset) = ((addr - ENABLE) / ENABLE_STRIDE, (addr - ENABLE) % ENABLE_STRIDE);
+                (offset < PLIC_SOURCES / 8).then_some(Reg::Enable(context, offset / 4))
+            },
+            CONTEXT.. if addr < CONTEXT + CONTEXT_STRIDE * contexts => {
+                let context = (addr - CONTEXT) / CONTEXT_STRIDE;
+                match (addr - CONTEXT) % CONTEXT_STRIDE {
+                    0 => Some(Reg::Threshold(context)),
+                    4 => Some(Reg::Claim(context)),
+                    _ => None,
+                }
+            },
+            _ => None,
+        };
+        reg.ok_or(BusError::Rejected(addr))
+    }
+
+    /// The source `context` would claim: the highest priority pending,
+    /// enabled and above its threshold, the lowest numbered of those.
+    fn best(&self, context: usize) -> Option<usize> {
+        let candidates = self.pending.get() & self.enable.borrow()[context];
+        let threshold = self.threshold.borrow()[context];
+        let priority = self.priority.borrow();
+        (1..PLIC_SOURCES)
+            .filter(|x| candidates >> x & 1 == 1 && priority[*x] > threshold)
+            .min_by_key(|x| std::cmp::Reverse(priority[*x]))
+    }
+
+    /// Drives MEIP and SEIP from what each context could claim.
+    fn update(&self) {
+        let causes = [RawInstrrupt::MachineExternalInterrupt, RawInstrrupt::SupervisorExternalInterrupt];
+        for context in 0..self.contexts() {
+            self.harts[context / 2].set(causes[context % 2] as u64, self.best(context).is_some());
+        }
+    }
+
+    fn claim(&self, context: usize) -> u64 {
+        let source = match self.best(context) {
+            Some(source) => source,
+            None => return 0,
+        };
+        self.pending.set(self.pending.get() & !(1 << source));
+        self.claimed.set(self.claimed.get() | 1 << source);
+        self.update();
+        source as u64
+    }
+
+    /// The gateway takes `source` again; a level-triggered line still high
+    /// pends at once.
+    fn complete(&self, source: usize) {
+        if source == 0 || source >= PLIC_SOURCES || self.claimed.get() >> source & 1 == 0 {
+            return;
+        }
+        self.claimed.set(self.claimed.get() & !(1 << source));
+        if self.trigger.borrow()[source] == Trigger::Level && self.level.get() >> source & 1 == 1 {
+            self.pending.set(self.pending.get() | 1 << source);
+        }
+        self.update();
+    }
+}
+
+impl IrqSink for Plic {
+    fn set_irq(&self, source: usize, level: bool) {
+        if source == 0 || source >= PLIC_SOURCES {
+            return;
+        }
+        let bit = 1 << source;
+        let rising = level && self.level.get() & bit == 0;
+        self.level.set(if level { self.level.get() | bit } else { self.level.get() & !bit });
+        let pend = match self.trigger.borrow()[source] {
+            Trigger::Level => level && self.claimed.get() & bit == 0,
+            Trigger::Edge => rising,
+        };
+        if pend {
+            self.pending.set(self.pending.get() | bit);
+        } else if self.trigger.borrow()[source] == Trigger::Level {
+            self.pending.set(self.pending.get() & !bit);
+        }
+        self.update();
+    }
+}
+
+impl LengthInfo for Plic {
+    fn get_length(&self) -> usize {
+        PLIC_SIZE
+    }
+}
+
+impl Readable for Plic {}
+
+impl Writeable for Plic {}
+
+impl MMIODevice for Plic {
+    fn name(&self) -> &str {
+        "plic"
+    }
+
+    fn reset(&self) {
+        *self.priority.borrow_mut() = [0; PLIC_SOURCES];
+        self.pending.set(0);
+        self.claimed.set(0);
+        self.enable.borrow_mut().fill(0);
+        self.threshold.borrow_mut().fill(0);
+        self.update();
+    }
+
+    #[inline]
+    fn access_sizes(&self) -> usize {
+        4
+    }
+
+    fn checkpoint(&self) {
+        self.marks.borrow_mut().push(State {
+            priority: *self.priority.borrow(),
+            level: self.level.get(),
+            pending: self.pending.get(),
+            claimed: self.claimed.get(),
+            enable: self.enable.borrow().clone(),
+            threshold: self.threshold.borrow().clone(),
+        });
+    }
+
+    /// The harts restore their own MEIP and SEIP.
+    fn rollback(&self, n: usize) {
+        let mut marks = self.marks.borrow_mut();
+        marks.truncate(n + 1);
+        let state = &marks[n];
+        *self.priority.borrow_mut() = state.priority;
+        self.level.set(state.level);
+        self.pending.set(state.pending);
+        self.claimed.set(state.claimed);
+        self.enable.borrow_mut().clone_from(&state.enable);
+        self.threshold.borrow_mut().clone_from(&state.threshold);
+    }
+
+    fn drop_checkpoint(&self, n: usize) {
+        self.marks.borrow_mut().remove(n);
+    }
+
+    fn discard_checkpoints(&self) {
+        self.marks.borrow_mut().clear();
+    }
+
+    /// Reading the claim register claims.
+    fn load(&self, addr: usize, _size: usize) -> Result<u64, BusError> {
+        let value = match self.reg(addr)? {
+            Reg::Priority(source) => self.priority.borrow()[source] as u64,
+            Reg::Pending(half) => self.pending.get() >> (32 * half) & 0xffff_ffff,
+            Reg::Enable(context, half) => self.enable.borrow()[context] >> (32 * half) & 0xffff_ffff,
+            Reg::Threshold(context) => self.threshold.borrow()[context] as u64,
+            Reg::Claim(context) => self.claim(context),
+        };
+        Ok(value)
+    }
+
+    fn store(&self, addr: usize, _size: usize, value: u64) -> Result<(), BusError> {
+        let value = value as u32;
+        match self.reg(addr)? {
+            // source 0 does not exist
+            Reg::Priority(0) => {},
+            Reg::Priority(source) => self.priority.borrow_mut()[source] = value & PRIORITY_MASK,
+            // pending bits are the gateways'
+            Reg::Pending(_) => {},
+            Reg::Enable(context, half) => {
+                let mut enable = self.enable.borrow_mut();
+                let bits = (value as u64) << (32 * half) & !1;
+                enable[context] = (enable[context] & !(0xffff_ffff << (32 * half))) | bits;
+            },
+            Reg::Threshold(context) => self.threshold.borrow_mut()[context] = value & PRIORITY_MASK,
+            Reg::Claim(_) => self.complete(value as usize),
+        }
+        self.update();
+        Ok(())
+    }
+}
+
+
+#[test]
+fn test_plic() {
+    use crate::device::Device;
+    const MEI: u64 = RawInstrrupt::MachineExternalInterrupt as u64;
+    const SEI: u64 = RawInstrrupt::SupervisorExternalInterrupt as u64;
+    let hart = Rc::new(HartIrq::default());
+    let plic = Rc::new(Plic::new(vec![hart.clone()]));
+    let mmio = Device::new();
+    mmio.add_shared("plic", PLIC_BASE, plic.clone()).unwrap();
+    let (uart, disk) = (plic.line(10, Trigger::Level), plic.line(33, Trigger::Edge));
+    let write = |offset: usize, value: u64| mmio.store(PLIC_BASE + offset, 4, value).unwrap();
+    let read = |offset: usize| mmio.load(PLIC_BASE + offset, 4).unwrap();
+    let (m_claim, s_claim) = (CONTEXT + 4, CONTEXT + CONTEXT_STRIDE + 4);
+    let mip = |cause: u64| hart.get() >> cause & 1 == 1;
+    write(PRIORITY + 4 * 10, 1);
+    write(PRIORITY + 4 * 33, 2);
+    // M-mode takes the UART, S-mode the disk
+    write(ENABLE, 1 << 10);
+    write(ENABLE + ENABLE_STRIDE + 4, 1 << 1);
+    uart.raise();
+    assert!(mip(MEI) && !mip(SEI));
+    assert_eq!(read(PENDING), 1 << 10);
+    // claimed until completed, then pending again while the line is high
+    assert_eq!(read(m_claim), 10);
+    assert!(!mip(MEI));
+    assert_eq!(read(m_claim), 0);
+    write(m_claim, 10);
+    assert!(mip(MEI));
+    uart.lower();
+    assert!(!mip(MEI));
+    // one edge, one interrupt
+    disk.pulse();
+    assert!(mip(SEI));
+    assert_eq!(read(PENDING + 4), 1 << 1);
+    assert_eq!(read(s_claim), 33);
+    write(s_claim, 33);
+    assert!(!mip(SEI));
+    // the threshold masks priorities up to it; the highest priority wins
+    write(ENABLE, 1 << 10);
+    write(ENABLE + 4, 1 << 1);
+    write(CONTEXT, 1);
+    uart.raise();
+    assert!(!mip(MEI));
+    disk.pulse();
+    assert!(mip(MEI));
+    write(CONTEXT, 0);
+    assert_eq!(read(m_claim), 33);
+    assert_eq!(read(m_claim), 10);
+    assert_eq!(mmio.load(PLIC_BASE + 2, 4), Err(BusError::Rejected(PLIC_BASE + 2)));
+}
@@ -0,0 +1,223 @@
+//! Lock-step comparison against a reference model (NEMU, Spike, ...) built
+//! as a shared library with the NEMU difftest ABI:
+//!
+//! ```c
+//! void difftest_init(int port);
+//! void difftest_memcpy(uint64_t addr, void *buf, size_t n, bool direction);
+//! void difftest_regcpy(void *regs, bool direction);
+//! void difftest_exec(uint64_t n);
+//! void difftest_raise_intr(uint64_t NO);    // optional
+//! ```
+//!
+//! `regs` is `uint64_t gpr[32]; uint64_t pc;` followed by the CSRs given to
+//! `RefModel::load`, in that order.
+
+use std::ffi::c_void;
+
+use libloading::Library;
+
+use crate::{abstract_machine::{RegInfo, Execable, ExceptionAttr}, device::MMIODevice};
+
+
+const TO_DUT: bool = false;
+const TO_REF: bool = true;
+
+/// Bytes per `difftest_memcpy` while copying RAM over.
+const CHUNK: usize = 1 << 20;
+
+type MemcpyFn = unsafe extern "C" fn(u64, *mut c_void, usize, bool);
+type RegcpyFn = unsafe extern "C" fn(*mut c_void, bool);
+type ExecFn = unsafe extern "C" fn(u64);
+type RaiseIntrFn = unsafe extern "C" fn(u64);
+
+pub struct RefModel {
+    /// keeps the functions below alive
+    _lib: Option<Library>,
+    memcpy: MemcpyFn,
+    regcpy: RegcpyFn,
+    exec: ExecFn,
+    /// takes an interrupt by its `mcause`; without it the reference just
+    /// takes lemu's registers after one
+    raise_intr: Option<RaiseIntrFn>,
+    /// gpr, pc, then these
+    csrs: Vec<String>,
+}
+
+/// A register both sides disagree on, as (name, lemu, reference).
+pub type Mismatch = (String, u64, u64);
+
+impl RefModel {
+    pub fn load(path: &str, csrs: Vec<String>) -> Result<RefModel, String> {
+        unsafe {
+            let lib = Library::new(path).map_err(|e| e.to_string())?;
+            let init = *lib.get::<unsafe extern "C" fn(i32)>(b"difftest_init").map_err(|e| e.to_string())?;
+            let memcpy = *lib.get::<MemcpyFn>(b"difftest_memcpy").map_err(|e| e.to_string())?;
+            let regcpy = *lib.get::<RegcpyFn>(b"difftest_regcpy").map_err(|e| e.to_string())?;
+            let exec = *lib.get::<ExecFn>(b"difftest_exec").map_err(|e| e.to_string())?;
+            let raise_intr = lib.get::<RaiseIntrFn>(b"difftest_raise_intr").ok().map(|x| *x);
+            init(0);
+            Ok(RefModel { _lib: Some(lib), memcpy, regcpy, exec, raise_intr, csrs })
+        }
+    }
+
+    #[inline]
+    fn names<'a>(&'a self, machine: &'a impl RegInfo) -> impl Iterator<Item = &'a str> {
+        // reg_names is pc then x0-x31
+        machine.reg_names()[1..33].iter().copied()
+            .chain(std::iter::once("pc"))
+            .chain(self.csrs.iter().map(|x| x.as_str()))
+    }
+
+    pub fn sync_regs(&self, machine: &impl RegInfo) {
+        let mut regs = self.names(machine).map(|x| machine.get_reg_value(x).unwrap_or(0)).collect::<Vec<u64>>();
+        unsafe { (self.regcpy)(regs.as_mut_ptr().cast(), TO_REF) };
+    }
+
+    /// Copies all RAM to the reference; device contents stay with lemu.
+    pub fn sync_memory(&self, memory: &dyn MMIODevice) {
+        let mut buf = Vec::with_capacity(CHUNK);
+        for (start, len) in memory.ram_regions() {
+            for chunk in (start..start + len).step_by(CHUNK) {
+                buf.clear();
+                buf.extend((chunk..(chunk + CHUNK).min(start + len)).map(|x| memory.read_u8(x).unwrap_or(0)));
+                unsafe { (self.memcpy)(chunk as u64, buf.as_mut_ptr().cast(), buf.len(), TO_REF) };
+            }
+        }
+    }
+
+    #[inline]
+    pub fn sync(&self, machine: &impl RegInfo, memory: &dyn MMIODevice) {
+        self.sync_memory(memory);
+        self.sync_regs(machine);
+    }
+
+    /// Runs the reference over the instruction lemu just executed, and the
+    /// interrupt taken before it, and compares. Instructions that touched a
+    /// device are not run there, the reference just takes lemu's registers.
+    pub fn step<E: ExceptionAttr + Clone>(&self, machine: &(impl RegInfo + Execable<E>), memory: &dyn MMIODevice) -> Vec<Mismatch> {
+        let interrupt = machine.take_interrupt();
+        if memory.take_device_access() || (interrupt.is_some() && self.raise_intr.is_none()) {
+            self.sync_regs(machine);
+            return Vec::new();
+        }
+        let mut regs = vec![0u64; 33 + self.csrs.len()];
+        unsafe {
+            if let (Some(cause), Some(raise_intr)) = (interrupt, self.raise_intr) {
+                raise_intr(cause);
+            }
+            (self.exec)(1);
+            (self.regcpy)(regs.as_mut_ptr().cast(), TO_DUT);
+        }
+        self.names(machine).zip(regs)
+            .filter_map(|(name, r)| {
+                let v = machine.get_reg_value(name).unwrap_or(0);
+                (v != r).then(|| (name.to_string(), v, r))
+            })
+            .collect()
+    }
+}
+
+
+#[cfg(test)]
+thread_local! {
+    /// the reference for the tests below: another lemu
+    static REFERENCE: (crate::interpreter::riscv64::machine::MachineModel, crate::memory::Memory) =
+        (crate::interpreter::riscv64::machine::MachineModel::new(0), crate::memory::Memory::new(0x200));
+}
+
+/// `REFERENCE` behind the ABI, comparing mepc and mcause too.
+#[cfg(test)]
+fn reference() -> RefModel {
+    use crate::{
+        interpreter::riscv64::{machine::REG_NAMES, irq::RawInstrrupt},
+        abstract_machine::{Writeable, ExceptionProcessable},
+    };
+    const CSRS: [&str; 2] = ["mepc", "mcause"];
+    unsafe extern "C" fn memcpy(addr: u64, buf: *mut c_void, n: usize, direction: bool) {
+        assert!(direction == TO_REF);
+        let buf = std::slice::from_raw_parts(buf as *const u8, n);
+        REFERENCE.with(|(_, mem)| buf.iter().enumerate().for_each(|(i, x)| {
+            mem.write_u8(addr as usize + i, *x).unwrap();
+        }));
+    }
+    unsafe extern "C" fn regcpy(regs: *mut c_void, direction: bool) {
+        let regs = std::slice::from_raw_parts_mut(regs as *mut u64, 33 + CSRS.len());
+        REFERENCE.with(|(mm, _)| for (i, x) in regs.iter_mut().enumerate() {
+            let name = match i {
+                0..=31 => REG_NAMES[i + 1],
+                32 => "pc",
+                _ => CSRS[i - 33],
+            };
+            match direction {
+                TO_REF => { mm.set_reg_value(name, *x); },
+                _ => *x = mm.get_reg_value(name).unwrap(),
+            }
+        });
+    }
+    unsafe extern "C" fn exec(n: u64) {
+        REFERENCE.with(|(mm, mem)| for _ in 0..n {
+            let r = mm.exec_once(mem);
+            mm.process_exception(r);
+        });
+    }
+    unsafe extern "C" fn raise_intr(cause: u64) {
+        let irq = RawInstrrupt::PRIORITY.into_iter().find(|x| *x as u64 == cause & !(1 << 63)).unwrap();
+        REFERENCE.with(|(mm, _)| mm.interrupt_request(irq));
+    }
+    RefModel { _lib: None, memcpy, regcpy, exec, raise_intr: Some(raise_intr), csrs: CSRS.map(String::from).to_vec() }
+}
+
+#[test]
+fn test_difftest() {
+    use crate::{interpreter::riscv64::machine::MachineModel, memory::Memory, abstract_machine::Readable};
+    let model = reference();
+    let mm = MachineModel::new(0);
+    // addi a0, a0, 1; jal x0, -4
+    let mut inst_list: Vec<u8> = [0x00150513u32, 0xffdff06f]
+        .into_iter().flat_map(|x: u32| x.to_le_bytes()).collect();
+    inst_list.resize(0x200, 0);
+    let mem = Memory::from(inst_list.as_ref());
+    model.sync(&mm, &mem);
+    REFERENCE.with(|(_, mem)| assert_eq!(mem.read_u32(4), Some(0xffdff06f)));
+    for _ in 0..4 {
+        mm.exec_once(&mem).unwrap();
+        assert!(model.step(&mm, &mem).is_empty());
+    }
+    mm.exec_once(&mem).unwrap();
+    mm.gpr.store(10, 7);
+    assert_eq!(model.step(&mm, &mem), vec![("a0".to_string(), 7, 3)]);
+}
+
+#[test]
+fn test_difftest_interrupt() {
+    use crate::{
+        interpreter::riscv64::machine::MachineModel, memory::Memory,
+        device::{Device, riscv::clint::{Clint, CLINT_BASE}},
+    };
+    // addi a0, a0, 1; jal x0, -4, and a handler at 0x100: addi a1, a1, 1;
+    // jal x0, 0
+    let mut program: Vec<u8> = [0x00150513u32, 0xffdff06f]
+        .into_iter().flat_map(|x: u32| x.to_le_bytes()).collect();
+    program.resize(0x200, 0);
+    program[0x100..0x108].copy_from_slice(&[0x93, 0x85, 0x15, 0x00, 0x6f, 0x00, 0x00, 0x00]);
+    let mm = MachineModel::new(0);
+    let mmio = Device::new();
+    mmio.add_device("ram", 0, Box::new(Memory::from(program.as_ref()))).unwrap();
+    mmio.add_device("clint", CLINT_BASE, Box::new(Clint::new(mmio.clock().unwrap(), vec![mm.irq.clone()]))).unwrap();
+    mm.set_reg_value("mtvec", 0x100).unwrap();
+    mm.set_reg_value("mie", 1 << 7).unwrap();
+    mmio.store(CLINT_BASE + 0x4000, 8, 5).unwrap();
+    mmio.take_device_access();
+    let model = reference();
+    model.sync(&mm, &mmio);
+    // which the guest would have set up on both sides
+    REFERENCE.with(|(reference, _)| reference.set_reg_value("mtvec", 0x100)).unwrap();
+    let clock = mmio.clock().unwrap();
+    for _ in 0..10 {
+        mm.exec_once(&mmio).unwrap();
+        clock.advance(1);
+        assert_eq!(model.step(&mm, &mmio), vec![], "at 0x{:x}", mm.pc.read());
+    }
+    assert_eq!((mm.pc.read(), mm.gpr.read(11)), (0x104, 1));
+    assert_eq!((mm.get_reg_value("mcause"), mm.get_reg_value("mepc")), (Some(1 << 63 | 7), Some(4)));
+}
@@ -0,0 +1,159 @@
+use std::collections::HashMap;
+
+use gimli::{
+    BaseAddresses, CfaRule, DebugFrame, EhFrame, EndianSlice, LittleEndian,
+    RegisterRule, UnwindContext, UnwindSection,
+};
+
+use crate::elf::Elf;
+
+
+/// How to recover a register of the caller, relative to the CFA.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegRule {
+    Undefined,
+    SameValue,
+    /// saved at `cfa + offset`
+    Offset(i64),
+    /// is `cfa + offset`
+    ValOffset(i64),
+    Register(u16),
+}
+
+/// CFI for one pc, with DWARF register numbers (x0-x31 on RISC-V).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnwindRow {
+    pub cfa_reg: u16,
+    pub cfa_offset: i64,
+    pub ra_reg: u16,
+    pub rules: Vec<(u16, RegRule)>,
+}
+
+impl UnwindRow {
+    #[inline]
+    pub fn rule(&self, reg: u16) -> RegRule {
+        self.rules.iter().find(|(r, _)| *r == reg).map_or(RegRule::SameValue, |(_, x)| *x)
+    }
+}
+
+/// Line tables and call frame information taken from an ELF.
+#[derive(Debug, Clone, Default)]
+pub struct DebugInfo {
+    files: Vec<String>,
+    /// (address, file index and line), sorted; `None` ends a sequence
+    lines: Vec<(u64, Option<(usize, u64)>)>,
+    eh_frame: Option<(u64, Vec<u8>)>,
+    debug_frame: Option<Vec<u8>>,
+    text: u64,
+}
+
+type Slice<'a> = EndianSlice<'a, LittleEndian>;
+
+impl DebugInfo {
+    pub fn new(elf: &Elf) -> Result<DebugInfo, gimli::Error> {
+        let section = |name: &str| elf.section(name).and_then(|s| elf.section_data(s)).unwrap_or(&[]);
+        let dwarf = gimli::Dwarf::load(|id| -> Result<Slice, gimli::Error> {
+            Ok(EndianSlice::new(section(id.name()), LittleEndian))
+        })?;
+
+        let mut files = Vec::new();
+        let mut file_index = HashMap::new();
+        let mut lines = Vec::new();
+        let mut units = dwarf.units();
+        while let Some(header) = units.next()? {
+            let unit = dwarf.unit(header)?;
+            let program = match unit.line_program.clone() {
+                Some(p) => p,
+                None => continue,
+            };
+            let mut rows = program.rows();
+            while let Some((header, row)) = rows.next_row()? {
+                if row.end_sequence() {
+                    lines.push((row.address(), None));
+                    continue;
+                }
+                let file = match row.file(header) {
+                    Some(f) => f,
+                    None => continue,
+                };
+                let mut path = dwarf.attr_string(&unit, file.path_name())?.to_string_lossy().into_owned();
+                if !path.starts_with('/') {
+                    if let Some(dir) = file.directory(header) {
+                        let dir = dwarf.attr_string(&unit, dir)?.to_string_lossy().into_owned();
+                        if !dir.is_empty() {
+                            path = format!("{}/{}", dir, path);
+                        }
+                    }
+                }
+                let i = *file_index.entry(path.clone()).or_insert_with(|| {
+                    files.push(path);
+                    files.len() - 1
+                });
+                lines.push((row.address(), Some((i, row.line().map_or(0, |x| x.get())))));
+            }
+        }
+        // stable: a sequence end and the next sequence start may share an address
+        lines.sort_by_key(|x| x.0);
+
+        let eh_frame = elf.section(".eh_frame")
+            .and_then(|s| Some((s.addr, elf.section_data(s)?.to_vec())));
+        let debug_frame = elf.section(".debug_frame")
+            .and_then(|s| elf.section_data(s))
+            .map(|x| x.to_vec());
+        let text = elf.section(".text").map_or(0, |s| s.addr);
+        Ok(DebugInfo { files, lines, eh_frame, debug_frame, text })
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.lines.is_empty() && self.eh_frame.is_none() && self.debug_frame.is_none()
+    }
+
+    /// Source file and line of the instruction at `addr`.
+    pub fn line(&self, addr: u64) -> Option<(&str, u64)> {
+        let end = self.lines.partition_point(|x| x.0 <= addr);
+        let (_, loc) = self.lines.get(end.checked_sub(1)?)?;
+        loc.map(|(file, line)| (self.files[file].as_str(), line))
+    }
+
+    /// `.eh_frame` first, then `.debug_frame`.
+    pub fn unwind_row(&self, pc: u64) -> Option<UnwindRow> {
+        let bases = BaseAddresses::default().set_text(self.text);
+        if let Some((addr, data)) = &self.eh_frame {
+            let section = EhFrame::new(data, LittleEndian);
+            if let Some(r) = unwind_row(&section, &bases.clone().set_eh_frame(*addr), pc) {
+                return Some(r);
+            }
+        }
+        let section = DebugFrame::new(self.debug_frame.as_ref()?, LittleEndian);
+        unwind_row(&section, &bases, pc)
+    }
+}
+
+fn unwind_row<'a, S: UnwindSection<Slice<'a>>>(section: &S, bases: &BaseAddresses, pc: u64) -> Option<UnwindRow> {
+    let fde = section.fde_for_address(bases, pc, S::cie_from_offset).ok()?;
+    let mut ctx = UnwindContext::new();
+    let row = fde.unwind_info_for_address(section, bases, &mut ctx, pc).ok()?;
+    let (cfa_reg, cfa_offset) = match row.cfa() {
+        CfaRule::RegisterAndOffset { register, offset } => (register.0, *offset),
+        // DWARF expressions are left to the frame-pointer fallback
+        CfaRule::Expression(_) => return None,
+    };
+    let rules = row.registers().filter_map(|(reg, rule)| {
+        let rule = match rule {
+            RegisterRule::Undefined => RegRule::Undefined,
+            RegisterRule::SameValue => RegRule::SameValue,
+            RegisterRule::Offset(x) => RegRule::Offset(*x),
+            RegisterRule::ValOffset(x) => RegRule::ValOffset(*x),
+            RegisterRule::Register(r) => RegRule::Register(r.0),
+            _ => return None,
+        };
+        Some((reg.0, rule))
+    }).collect();
+    Some(UnwindRow {
+        cfa_reg,
+        cfa_offset,
+        ra_reg: fde.cie().return_address_register().0,
+        rules,
+    })
+}
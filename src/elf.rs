@@ -0,0 +1,334 @@
+use std::{collections::HashMap, fmt::Display};
+
+use crate::device::MMIODevice;
+
+
+const ELF_MAGIC: &[u8] = b"\x7fELF";
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+
+pub const PT_LOAD: u32 = 1;
+
+pub const SHT_SYMTAB: u32 = 2;
+pub const SHT_NOBITS: u32 = 8;
+
+const STT_OBJECT: u8 = 1;
+const STT_FUNC: u8 = 2;
+const STT_SECTION: u8 = 3;
+const STT_FILE: u8 = 4;
+
+const SHN_UNDEF: u16 = 0;
+
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ElfError {
+    BadMagic,
+    Unsupported(&'static str),
+    Truncated(&'static str),
+}
+
+impl Display for ElfError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ElfError::BadMagic => write!(f, "not an ELF file"),
+            ElfError::Unsupported(what) => write!(f, "unsupported ELF: {}", what),
+            ElfError::Truncated(what) => write!(f, "truncated ELF: {} out of range", what),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    pub p_type: u32,
+    pub flags: u32,
+    pub offset: u64,
+    pub vaddr: u64,
+    pub paddr: u64,
+    pub filesz: u64,
+    pub memsz: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Section {
+    pub name: String,
+    pub sh_type: u32,
+    pub flags: u64,
+    pub addr: u64,
+    pub offset: u64,
+    pub size: u64,
+    pub link: u32,
+}
+
+pub struct Elf {
+    data: Vec<u8>,
+    pub machine: u16,
+    pub entry: u64,
+    pub segments: Vec<Segment>,
+    pub sections: Vec<Section>,
+}
+
+#[inline]
+fn read_u16(data: &[u8], off: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(data.get(off..off+2)?.try_into().unwrap()))
+}
+
+#[inline]
+fn read_u32(data: &[u8], off: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(data.get(off..off+4)?.try_into().unwrap()))
+}
+
+#[inline]
+fn read_u64(data: &[u8], off: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(data.get(off..off+8)?.try_into().unwrap()))
+}
+
+fn read_cstr(data: &[u8], off: usize) -> Option<String> {
+    let s = data.get(off..)?;
+    let end = s.iter().position(|x| *x == 0)?;
+    Some(String::from_utf8_lossy(&s[..end]).into_owned())
+}
+
+impl Elf {
+    pub fn parse(data: &[u8]) -> Result<Elf, ElfError> {
+        if data.len() < 64 || &data[..4] != ELF_MAGIC {
+            return Err(ElfError::BadMagic);
+        }
+        if data[4] != ELFCLASS64 {
+            return Err(ElfError::Unsupported("only ELF64 is supported"));
+        }
+        if data[5] != ELFDATA2LSB {
+            return Err(ElfError::Unsupported("only little endian is supported"));
+        }
+        let header = |off| read_u64(data, off).ok_or(ElfError::Truncated("header"));
+        let machine = read_u16(data, 18).unwrap();
+        let entry = header(24)?;
+        let phoff = header(32)? as usize;
+        let shoff = header(40)? as usize;
+        let phentsize = read_u16(data, 54).unwrap() as usize;
+        let phnum = read_u16(data, 56).unwrap() as usize;
+        let shentsize = read_u16(data, 58).unwrap() as usize;
+        let shnum = read_u16(data, 60).unwrap() as usize;
+        let shstrndx = read_u16(data, 62).unwrap() as usize;
+
+        let mut segments = Vec::with_capacity(phnum);
+        for i in 0..phnum {
+            let off = phoff + i * phentsize;
+            let field = |x| read_u64(data, off + x).ok_or(ElfError::Truncated("program header"));
+            segments.push(Segment {
+                p_type: read_u32(data, off).ok_or(ElfError::Truncated("program header"))?,
+                flags: read_u32(data, off + 4).ok_or(ElfError::Truncated("program header"))?,
+                offset: field(8)?,
+                vaddr: field(16)?,
+                paddr: field(24)?,
+                filesz: field(32)?,
+                memsz: field(40)?,
+            });
+        }
+
+        let mut raw_sections = Vec::with_capacity(shnum);
+        for i in 0..shnum {
+            let off = shoff + i * shentsize;
+            let field32 = |x| read_u32(data, off + x).ok_or(ElfError::Truncated("section header"));
+            let field64 = |x| read_u64(data, off + x).ok_or(ElfError::Truncated("section header"));
+            raw_sections.push((field32(0)?, Section {
+                name: String::new(),
+                sh_type: field32(4)?,
+                flags: field64(8)?,
+                addr: field64(16)?,
+                offset: field64(24)?,
+                size: field64(32)?,
+                link: field32(40)?,
+            }));
+        }
+        let shstrtab = raw_sections.get(shstrndx).map(|(_, s)| s.offset as usize);
+        let sections = raw_sections.into_iter().map(|(name, mut s)| {
+            if let Some(base) = shstrtab {
+                s.name = read_cstr(data, base + name as usize).unwrap_or_default();
+            }
+            s
+        }).collect();
+
+        Ok(Elf {
+            data: data.to_vec(),
+            machine,
+            entry,
+            segments,
+            sections,
+        })
+    }
+
+    #[inline]
+    pub fn section(&self, name: &str) -> Option<&Section> {
+        self.sections.iter().find(|s| s.name == name)
+    }
+
+    pub fn section_data(&self, section: &Section) -> Option<&[u8]> {
+        if section.sh_type == SHT_NOBITS {
+            return None;
+        }
+        let start = section.offset as usize;
+        self.data.get(start..start + section.size as usize)
+    }
+
+    #[inline]
+    pub fn segment_data(&self, segment: &Segment) -> Option<&[u8]> {
+        let start = segment.offset as usize;
+        self.data.get(start..start + segment.filesz as usize)
+    }
+
+    /// Copies every `PT_LOAD` segment to its physical address and zeroes
+    /// the rest of `memsz`; `Err` holds the first address that failed.
+    pub fn load(&self, memory: &dyn MMIODevice) -> Result<(), u64> {
+        for seg in self.segments.iter().filter(|s| s.p_type == PT_LOAD) {
+            let data = self.segment_data(seg).ok_or(seg.paddr)?;
+            for i in 0..seg.memsz {
+                let addr = seg.paddr + i;
+                let byte = data.get(i as usize).copied().unwrap_or(0);
+                memory.write_u8(addr as usize, byte).ok_or(addr)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn symbols(&self) -> SymbolTable {
+        let mut r = Vec::new();
+        for symtab in self.sections.iter().filter(|s| s.sh_type == SHT_SYMTAB) {
+            let strtab = match self.sections.get(symtab.link as usize) {
+                Some(s) => s.offset as usize,
+                None => continue,
+            };
+            let data = match self.section_data(symtab) {
+                Some(d) => d,
+                None => continue,
+            };
+            for sym in data.chunks_exact(24) {
+                let info = sym[4];
+                let shndx = read_u16(sym, 6).unwrap();
+                let kind = match info & 0xf {
+                    STT_FUNC => SymbolKind::Func,
+                    STT_OBJECT => SymbolKind::Object,
+                    STT_SECTION | STT_FILE => continue,
+                    _ => SymbolKind::Other,
+                };
+                if shndx == SHN_UNDEF {
+                    continue;
+                }
+                let name = match read_cstr(&self.data, strtab + read_u32(sym, 0).unwrap() as usize) {
+                    // skip mapping symbols ($x, $d) and assembler temporaries
+                    Some(n) if !n.is_empty() && !n.starts_with('$') && !n.starts_with(".L") => n,
+                    _ => continue,
+                };
+                r.push(Symbol {
+                    name,
+                    addr: read_u64(sym, 8).unwrap(),
+                    size: read_u64(sym, 16).unwrap(),
+                    kind,
+                });
+            }
+        }
+        SymbolTable::new(r)
+    }
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolKind {
+    Func,
+    Object,
+    Other,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: String,
+    pub addr: u64,
+    pub size: u64,
+    pub kind: SymbolKind,
+}
+
+/// Symbols sorted by address, with a name index for the monitor.
+#[derive(Debug, Clone, Default)]
+pub struct SymbolTable {
+    symbols: Vec<Symbol>,
+    by_name: HashMap<String, usize>,
+}
+
+impl SymbolTable {
+    pub fn new(mut symbols: Vec<Symbol>) -> SymbolTable {
+        // stable: globals come after locals in .symtab, so later names win
+        symbols.sort_by_key(|x| x.addr);
+        let by_name = symbols.iter().enumerate().map(|(i, s)| (s.name.clone(), i)).collect();
+        SymbolTable { symbols, by_name }
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    #[inline]
+    pub fn iter(&self) -> impl Iterator<Item = &Symbol> {
+        self.symbols.iter()
+    }
+
+    #[inline]
+    pub fn lookup(&self, name: &str) -> Option<&Symbol> {
+        self.by_name.get(name).map(|i| &self.symbols[*i])
+    }
+
+    /// The symbol covering `addr`, falling back to the nearest preceding
+    /// label for sizeless assembly symbols.
+    pub fn find(&self, addr: u64) -> Option<&Symbol> {
+        let end = self.symbols.partition_point(|x| x.addr <= addr);
+        let candidates = &self.symbols[..end];
+        candidates.iter().rev()
+            .find(|s| s.size != 0 && addr < s.addr + s.size)
+            .or_else(|| candidates.iter().rev().find(|s| s.size == 0))
+    }
+
+    /// `name+0xoff` for `addr`, if any symbol covers it.
+    pub fn format_addr(&self, addr: u64) -> Option<String> {
+        let sym = self.find(addr)?;
+        let offset = addr - sym.addr;
+        if offset == 0 {
+            Some(sym.name.clone())
+        } else {
+            Some(format!("{}+0x{:x}", sym.name, offset))
+        }
+    }
+}
+
+
+#[cfg(test)]
+const BBL: &[u8] = include_bytes!("../tests/bbl");
+#[cfg(test)]
+const BBL_BIN: &[u8] = include_bytes!("../tests/bbl.bin");
+
+#[test]
+fn test_elf_parse() {
+    let elf = Elf::parse(BBL).unwrap();
+    assert_eq!(elf.entry, 0x80000000);
+    assert_eq!(elf.section(".text").unwrap().addr, 0x80000000);
+    assert!(elf.segments.iter().any(|s| s.p_type == PT_LOAD && s.paddr == 0x80000000));
+    assert_eq!(Elf::parse(b"not an elf").err(), Some(ElfError::BadMagic));
+}
+
+#[test]
+fn test_elf_load() {
+    use crate::{memory::Memory, device::Device, abstract_machine::Readable};
+    let elf = Elf::parse(BBL).unwrap();
+    let mmio = Device::new();
+    mmio.add_device("ram", 0x80000000, Box::new(Memory::new(1 << 20))).unwrap();
+    assert_eq!(elf.load(&mmio), Ok(()));
+    assert_eq!(mmio.read_u32(0x80000000), Some(u32::from_le_bytes(BBL_BIN[..4].try_into().unwrap())));
+    assert_eq!(elf.load(&Memory::new(16)), Err(0x80000000));
+}
+
+#[test]
+fn test_elf_symbols() {
+    let symbols = Elf::parse(BBL).unwrap().symbols();
+    assert_eq!(symbols.lookup("do_reset").unwrap().addr, 0x800001f8);
+    assert_eq!(symbols.lookup("hart_open").unwrap().kind, SymbolKind::Func);
+    assert_eq!(symbols.format_addr(0x800004a8), Some("hart_open+0x4".to_string()));
+    assert_eq!(symbols.format_addr(0x80000008), Some("trap_vector+0x4".to_string()));
+}
@@ -0,0 +1,70 @@
+//! The bits of the HTIF host interface that test suites use: the guest
+//! stops by writing `tohost`, `1` for a pass and `(n << 1) | 1` for a
+//! failure of test case `n`.
+
+use std::fmt::{self, Write};
+
+use crate::{
+    abstract_machine::{ExceptionAttr, Debuggable},
+    device::MMIODevice,
+};
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Pass,
+    /// number of the failing test case
+    Fail(u64),
+    Timeout,
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Outcome::Pass => write!(f, "pass"),
+            Outcome::Fail(n) => write!(f, "FAIL (case {})", n),
+            Outcome::Timeout => write!(f, "TIMEOUT"),
+        }
+    }
+}
+
+/// Runs until `tohost` is written or `budget` instructions are done; the
+/// outcome and the number of instructions it took.
+pub fn run<E: ExceptionAttr + Clone, M: Debuggable<E>>(machine: &M, memory: &dyn MMIODevice, tohost: usize, budget: u64) -> Result<(Outcome, u64), String> {
+    let clock = memory.clock();
+    for n in 1..=budget {
+        let r = machine.exec_once(memory);
+        if let Some(clock) = &clock {
+            clock.advance(1);
+        }
+        machine.process_exception(r);
+        match memory.read_u64(tohost) {
+            Some(0) => {},
+            Some(1) => return Ok((Outcome::Pass, n)),
+            Some(x) => return Ok((Outcome::Fail(x >> 1), n)),
+            None => return Err(format!("tohost 0x{:x} is not RAM", tohost)),
+        }
+    }
+    Ok((Outcome::Timeout, budget))
+}
+
+/// `[begin, end)` as riscv-arch-test signatures: one 32-bit word per line
+/// in hex, lowest address first. The address that could not be read on
+/// error.
+pub fn signature(memory: &dyn MMIODevice, begin: u64, end: u64) -> Result<String, u64> {
+    let mut out = String::new();
+    for addr in (begin..end).step_by(4) {
+        let word = memory.read_u32(addr as usize).ok_or(addr)?;
+        let _ = writeln!(out, "{:08x}", word);
+    }
+    Ok(out)
+}
+
+
+#[test]
+fn test_signature() {
+    use crate::memory::Memory;
+    let mem = Memory::from([0x78u8, 0x56, 0x34, 0x12, 0xef, 0xbe, 0xad, 0xde].as_ref());
+    assert_eq!(signature(&mem, 0, 8), Ok("12345678\ndeadbeef\n".to_string()));
+    assert_eq!(signature(&mem, 4, 12), Err(8));
+}
@@ -0,0 +1,270 @@
+//! Basic-block engine: straight-line code is decoded once into a block,
+//! blocks remember where they went last so hot loops skip the lookup, and
+//! every instruction still goes through `retire` like `exec_once`.
+
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    rc::{Rc, Weak},
+    str::FromStr,
+};
+
+use crate::device::{MMIODevice, Access};
+
+use super::{machine::MachineModel, irq::Exception, decode::{decode, Inst}};
+
+
+const PAGE_SHIFT: u64 = 12;
+/// Pages holding blocks, direct-mapped; a conflict flushes everything.
+const SETS: usize = 64;
+const MAX_BLOCK: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Engine {
+    /// one `exec_once` at a time
+    Interp,
+    Block,
+    /// x86-64 translation, see `jit`
+    Jit,
+}
+
+impl FromStr for Engine {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Engine, String> {
+        match s {
+            "interp" => Ok(Engine::Interp),
+            "block" => Ok(Engine::Block),
+            "jit" => Ok(Engine::Jit),
+            _ => Err(format!("unknown engine `{}`, expected interp, block or jit", s)),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Block {
+    start: u64,
+    insts: Vec<(u32, Inst)>,
+    /// the last block run after this one
+    next: RefCell<Weak<Block>>,
+}
+
+impl Block {
+    /// Whether the block after this one may follow without going back to
+    /// the caller: system instructions end the run so that whatever they
+    /// changed (CSRs, privilege, code) is looked at first.
+    #[inline]
+    fn chains(&self) -> bool {
+        !matches!(self.insts.last(), Some((_, Inst::Csr { .. } | Inst::Ecall | Inst::Ebreak | Inst::Mret | Inst::Sret | Inst::Wfi | Inst::FenceI | Inst::Illegal)))
+    }
+}
+
+#[inline]
+fn ends_block(inst: &Inst) -> bool {
+    matches!(inst,
+        Inst::Jal { .. } | Inst::Jalr { .. } | Inst::Branch { .. }
+        | Inst::Csr { .. } | Inst::Ecall | Inst::Ebreak | Inst::Mret | Inst::Sret | Inst::Wfi | Inst::FenceI | Inst::Illegal)
+}
+
+#[derive(Debug)]
+pub struct BlockCache {
+    blocks: RefCell<HashMap<u64, Rc<Block>>>,
+    pages: RefCell<Vec<Option<u64>>>,
+    /// bumped on every flush, so a running block notices it was dropped
+    generation: Cell<u64>,
+}
+
+impl Default for BlockCache {
+    fn default() -> BlockCache {
+        BlockCache {
+            blocks: RefCell::new(HashMap::new()),
+            pages: RefCell::new(vec![None; SETS]),
+            generation: Cell::new(0),
+        }
+    }
+}
+
+/// Copies (snapshots) start cold.
+impl Clone for BlockCache {
+    fn clone(&self) -> BlockCache {
+        BlockCache::default()
+    }
+}
+
+impl BlockCache {
+    #[inline]
+    fn get(&self, pc: u64) -> Option<Rc<Block>> {
+        self.blocks.borrow().get(&pc).cloned()
+    }
+
+    fn insert(&self, block: Rc<Block>) {
+        let page = block.start >> PAGE_SHIFT;
+        let set = page as usize & (SETS - 1);
+        let old = self.pages.borrow()[set];
+        if matches!(old, Some(x) if x != page) {
+            self.flush();
+        }
+        self.pages.borrow_mut()[set] = Some(page);
+        self.blocks.borrow_mut().insert(block.start, block);
+    }
+
+    /// `len` bytes at `addr` were written.
+    #[inline]
+    pub fn invalidate(&self, addr: u64, len: u64) {
+        let hit = [addr >> PAGE_SHIFT, addr.wrapping_add(len - 1) >> PAGE_SHIFT].into_iter()
+            .any(|page| self.pages.borrow()[page as usize & (SETS - 1)] == Some(page));
+        if hit {
+            self.flush();
+        }
+    }
+
+    pub fn flush(&self) {
+        self.blocks.borrow_mut().clear();
+        self.pages.borrow_mut().iter_mut().for_each(|x| *x = None);
+        self.generation.set(self.generation.get() + 1);
+    }
+
+    /// Pages holding blocks.
+    pub fn pages(&self) -> Vec<u64> {
+        self.pages.borrow().iter().flatten().copied().collect()
+    }
+}
+
+impl MachineModel {
+    /// Decodes up to the next jump, branch or system instruction, stopping
+    /// early at the end of the page or before anything unreadable.
+    fn translate(&self, pc: u64, memory: &dyn MMIODevice) -> Result<Rc<Block>, Exception> {
+        if !memory.pma(pc as usize).is_some_and(|x| x.cacheable) {
+            // decoded anew every time
+            let code = self.read_code(pc, memory)?;
+            return Ok(Rc::new(Block { start: pc, insts: vec![(code, decode(code))], next: RefCell::new(Weak::new()) }));
+        }
+        let mut insts = Vec::new();
+        let mut addr = pc;
+        loop {
+            let code = match self.read_code(addr, memory) {
+                Ok(code) => code,
+                Err(e) if insts.is_empty() => return Err(e),
+                Err(_) => break,
+            };
+            let inst = decode(code);
+            insts.push((code, inst));
+            addr = addr.wrapping_add(4);
+            if ends_block(&inst) || addr >> PAGE_SHIFT != pc >> PAGE_SHIFT || insts.len() == MAX_BLOCK {
+                break;
+            }
+        }
+        let block = Rc::new(Block { start: pc, insts, next: RefCell::new(Weak::new()) });
+        self.blocks.insert(block.clone());
+        Ok(block)
+    }
+
+    #[inline]
+    fn block_at(&self, pc: u64, memory: &dyn MMIODevice) -> Result<Rc<Block>, Exception> {
+        match self.blocks.get(pc) {
+            Some(block) => Ok(block),
+            None => self.translate(pc, memory),
+        }
+    }
+
+    /// Runs blocks from the pc until `max` instructions, a trap, a system
+    /// instruction or a store that raised an interrupt; how many
+    /// instructions retired.
+    pub fn exec_block(&self, memory: &dyn MMIODevice, max: usize) -> (usize, Result<(), Exception>) {
+        use crate::abstract_machine::Execable;
+        let mut n = 0;
+        if self.pc.read() & 0b11 != 0 {
+            // not worth a block
+            return match self.exec_once(memory) {
+                Ok(()) => (1, Ok(())),
+                Err(e) => (0, Err(e)),
+            };
+        }
+        let mut block = match self.block_at(self.pc.read(), memory) {
+            Ok(block) => block,
+            Err(e) => return (0, Err(e)),
+        };
+        loop {
+            if !self.pmp_allows(Access::Exec, block.start, 4 * block.insts.len() as u64) {
+                // part of it may still run: one at a time, faulting where
+                // the interpreter would
+                if n == 0 {
+                    return match self.exec_once(memory) {
+                        Ok(()) => (1, Ok(())),
+                        Err(e) => (0, Err(e)),
+                    };
+                }
+                return (n, Ok(()));
+            }
+            let generation = self.blocks.generation.get();
+            for &(code, inst) in block.insts.iter() {
+                if n == max {
+                    return (n, Ok(()));
+                }
+                if let Err(e) = self.retire(code, inst, memory) {
+                    return (n, Err(e));
+                }
+                n += 1;
+                if self.blocks.generation.get() != generation {
+                    // the code under us changed
+                    return (n, Ok(()));
+                }
+                if matches!(inst, Inst::Store { .. } | Inst::Sc { .. }) && self.pending_interrupt().is_some() {
+                    // a store to a device raised an interrupt, taken before
+                    // the next instruction
+                    return (n, Ok(()));
+                }
+            }
+            if n == max || !block.chains() || self.pending_interrupt().is_some() {
+                return (n, Ok(()));
+            }
+            let pc = self.pc.read();
+            let next = block.next.borrow().upgrade().filter(|x| x.start == pc);
+            let next = match next {
+                Some(next) => next,
+                None => match self.block_at(pc, memory) {
+                    Ok(next) => {
+                        *block.next.borrow_mut() = Rc::downgrade(&next);
+                        next
+                    },
+                    // fetch faults belong to the next call
+                    Err(_) => return (n, Ok(())),
+                },
+            };
+            block = next;
+        }
+    }
+}
+
+
+#[test]
+fn test_block() {
+    use crate::{memory::Memory, abstract_machine::{Execable, Readable}};
+    // addi a0, a0, 1; sw a1, 0x24(x0); addi a2, a2, 1; bne a0, a3, -12;
+    // addi a4, a4, 1; jal x0, -20 ... with a1 = addi a2, a2, 2 written
+    // into the running code
+    let program = [0x00150513u32, 0x02b02223, 0x00160613, 0xfed51ae3, 0x00170713, 0xfedff06f];
+    let run = |engine: Engine, steps: usize| {
+        let mm = MachineModel::new(0);
+        mm.engine.set(engine);
+        let mut inst_list: Vec<u8> = program.into_iter().flat_map(|x: u32| x.to_le_bytes()).collect();
+        inst_list.resize(0x100, 0);
+        // the store target, the third instruction of the second copy
+        inst_list[0x1c..0x28].copy_from_slice(&[0x13, 0x05, 0x15, 0x00, 0x13, 0x06, 0x16, 0x00, 0x13, 0x06, 0x16, 0x00]);
+        let mem = Memory::from(inst_list.as_ref());
+        mm.gpr.store(11, 0x00260613);
+        mm.gpr.store(13, 5);
+        mm.setp_num(&mem, steps).unwrap();
+        (mm.pc.read(), (10..15).map(|x| mm.gpr.read(x)).collect::<Vec<_>>(), mm.iring.entries(), mem.read_u32(0x24))
+    };
+    for steps in [1, 7, 23, 100] {
+        assert_eq!(run(Engine::Block, steps), run(Engine::Interp, steps));
+    }
+    let mm = MachineModel::new(0);
+    mm.engine.set(Engine::Block);
+    // four nops, then zeros: the trap comes after what retired
+    let mut inst_list: Vec<u8> = [0x00000013u32; 4].into_iter().flat_map(|x: u32| x.to_le_bytes()).collect();
+    inst_list.resize(0x20, 0);
+    let mem = Memory::from(inst_list.as_ref());
+    assert_eq!(mm.exec_block(&mem, 10), (4, Err(Exception::IllegalInstruction)));
+}
@@ -0,0 +1,281 @@
+use lyuu_commons::isa::riscv::inst_binary::*;
+
+use crate::utils::field_range_into_u8;
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AluOp {
+    Add,
+    Sub,
+    Sll,
+    Slt,
+    Sltu,
+    Xor,
+    Srl,
+    Sra,
+    Or,
+    And,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BranchOp {
+    Eq,
+    Ne,
+    Lt,
+    Ge,
+    Ltu,
+    Geu,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CsrOp {
+    Rw,
+    Rs,
+    Rc,
+}
+
+/// An instruction with its fields pulled out and immediates
+/// sign-extended, so executing it again needs no decoding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Inst {
+    Lui { rd: u8, imm: u64 },
+    Auipc { rd: u8, imm: u64 },
+    Jal { rd: u8, offset: i64 },
+    Jalr { rd: u8, rs1: u8, offset: i64 },
+    Branch { op: BranchOp, rs1: u8, rs2: u8, offset: i64 },
+    /// `size` bytes, sign-extended if `signed`
+    Load { rd: u8, rs1: u8, offset: i64, size: u8, signed: bool },
+    Store { rs1: u8, rs2: u8, offset: i64, size: u8 },
+    /// load-reserved, sign-extended
+    Lr { rd: u8, rs1: u8, size: u8 },
+    /// store-conditional, rd is 0 if it stored
+    Sc { rd: u8, rs1: u8, rs2: u8, size: u8 },
+    /// shifts keep the shift amount in `imm`
+    OpImm { op: AluOp, rd: u8, rs1: u8, imm: u64 },
+    OpImmW { op: AluOp, rd: u8, rs1: u8, imm: u64 },
+    Op { op: AluOp, rd: u8, rs1: u8, rs2: u8 },
+    OpW { op: AluOp, rd: u8, rs1: u8, rs2: u8 },
+    Fence,
+    FenceI,
+    Ecall,
+    Ebreak,
+    Mret,
+    Sret,
+    Wfi,
+    /// `src` is rs1, or a 5-bit immediate for the `csrr*i` forms
+    Csr { op: CsrOp, rd: u8, csr: u16, src: u8, imm: bool },
+    Illegal,
+}
+
+pub fn decode(code: u32) -> Inst {
+    let bytes = code.to_le_bytes();
+    match field_range_into_u8(code, 6, 0) {
+        0b0110111 => {
+            let inst = UType::from_bytes(bytes);
+            Inst::Lui { rd: inst.rd(), imm: inst.imm().overflowing_shl(12).0 as i32 as i64 as u64 }
+        },
+        0b0010111 => {
+            let inst = UType::from_bytes(bytes);
+            Inst::Auipc { rd: inst.rd(), imm: inst.imm().overflowing_shl(12).0 as i32 as i64 as u64 }
+        },
+        0b1101111 => {
+            let inst = JType::from_bytes(bytes);
+            Inst::Jal { rd: inst.rd(), offset: inst.get_offset() as i64 }
+        },
+        0b1100111 => {
+            let inst = IType::from_bytes(bytes);
+            Inst::Jalr { rd: inst.rd(), rs1: inst.rs1(), offset: inst.sext_imm() as i64 }
+        },
+        0b1100011 => {
+            let inst = BType::from_bytes(bytes);
+            let op = match inst.funct3() {
+                0b000 => BranchOp::Eq,
+                0b001 => BranchOp::Ne,
+                0b100 => BranchOp::Lt,
+                0b101 => BranchOp::Ge,
+                0b110 => BranchOp::Ltu,
+                0b111 => BranchOp::Geu,
+                _ => return Inst::Illegal,
+            };
+            Inst::Branch { op, rs1: inst.rs1(), rs2: inst.rs2(), offset: inst.sext_offset() as i64 }
+        },
+        0b0000011 => {
+            let inst = IType::from_bytes(bytes);
+            let (size, signed) = match inst.funct3() {
+                0b000 => (1, true),     // lb
+                0b001 => (2, true),     // lh
+                0b010 => (4, true),     // lw
+                0b011 => (8, true),     // ld
+                0b100 => (1, false),    // lbu
+                0b101 => (2, false),    // lhu
+                0b110 => (4, false),    // lwu
+                _ => return Inst::Illegal,
+            };
+            Inst::Load { rd: inst.rd(), rs1: inst.rs1(), offset: inst.sext_imm() as i64, size, signed }
+        },
+        0b0100011 => {
+            let inst = SType::from_bytes(bytes);
+            if inst.funct3() > 0b011 {
+                return Inst::Illegal;
+            }
+            Inst::Store { rs1: inst.rs1(), rs2: inst.rs2(), offset: inst.sext_imm() as i64, size: 1 << inst.funct3() }
+        },
+        0b0101111 => {
+            let inst = RType::from_bytes(bytes);
+            let size = match inst.funct3() {
+                0b010 => 4,
+                0b011 => 8,
+                _ => return Inst::Illegal,
+            };
+            // aq and rl ask nothing of harts that run one at a time; the
+            // other AMOs are not implemented
+            match (inst.funct7() >> 2, inst.rs2()) {
+                (0b00010, 0) => Inst::Lr { rd: inst.rd(), rs1: inst.rs1(), size },
+                (0b00011, _) => Inst::Sc { rd: inst.rd(), rs1: inst.rs1(), rs2: inst.rs2(), size },
+                _ => Inst::Illegal,
+            }
+        },
+        0b0010011 => {
+            let inst = IType::from_bytes(bytes);
+            let imm = inst.sext_imm() as i64 as u64;
+            let funct6 = field_range_into_u8(inst.imm().into(), 11, 6);
+            let (op, imm) = match (inst.funct3(), funct6) {
+                (0b000, _) => (AluOp::Add, imm),
+                (0b010, _) => (AluOp::Slt, imm),
+                (0b011, _) => (AluOp::Sltu, imm),
+                (0b100, _) => (AluOp::Xor, imm),
+                (0b110, _) => (AluOp::Or, imm),
+                (0b111, _) => (AluOp::And, imm),
+                (0b001, 0b000000) => (AluOp::Sll, imm & 0x3f),
+                (0b101, 0b000000) => (AluOp::Srl, imm & 0x3f),
+                (0b101, 0b010000) => (AluOp::Sra, imm & 0x3f),
+                _ => return Inst::Illegal,
+            };
+            Inst::OpImm { op, rd: inst.rd(), rs1: inst.rs1(), imm }
+        },
+        0b0011011 => {
+            let inst = IType::from_bytes(bytes);
+            let imm = inst.sext_imm() as i64 as u64;
+            let funct7 = field_range_into_u8(inst.imm().into(), 11, 5);
+            let (op, imm) = match (inst.funct3(), funct7) {
+                (0b000, _) => (AluOp::Add, imm),
+                (0b001, 0b0000000) => (AluOp::Sll, imm & 0x1f),
+                (0b101, 0b0000000) => (AluOp::Srl, imm & 0x1f),
+                (0b101, 0b0100000) => (AluOp::Sra, imm & 0x1f),
+                _ => return Inst::Illegal,
+            };
+            Inst::OpImmW { op, rd: inst.rd(), rs1: inst.rs1(), imm }
+        },
+        0b0110011 => {
+            let inst = RType::from_bytes(bytes);
+            let op = match (inst.funct3(), inst.funct7()) {
+                (0b000, 0b0000000) => AluOp::Add,
+                (0b000, 0b0100000) => AluOp::Sub,
+                (0b001, 0b0000000) => AluOp::Sll,
+                (0b010, 0b0000000) => AluOp::Slt,
+                (0b011, 0b0000000) => AluOp::Sltu,
+                (0b100, 0b0000000) => AluOp::Xor,
+                (0b101, 0b0000000) => AluOp::Srl,
+                (0b101, 0b0100000) => AluOp::Sra,
+                (0b110, 0b0000000) => AluOp::Or,
+                (0b111, 0b0000000) => AluOp::And,
+                _ => return Inst::Illegal,
+            };
+            Inst::Op { op, rd: inst.rd(), rs1: inst.rs1(), rs2: inst.rs2() }
+        },
+        0b0111011 => {
+            let inst = RType::from_bytes(bytes);
+            let op = match (inst.funct3(), inst.funct7()) {
+                (0b000, 0b0000000) => AluOp::Add,
+                (0b000, 0b0100000) => AluOp::Sub,
+                (0b001, 0b0000000) => AluOp::Sll,
+                (0b101, 0b0000000) => AluOp::Srl,
+                (0b101, 0b0100000) => AluOp::Sra,
+                _ => return Inst::Illegal,
+            };
+            Inst::OpW { op, rd: inst.rd(), rs1: inst.rs1(), rs2: inst.rs2() }
+        },
+        0b0001111 => match IType::from_bytes(bytes).funct3() {
+            0b001 => Inst::FenceI,
+            _ => Inst::Fence,
+        },
+        0b1110011 => {
+            let inst = IType::from_bytes(bytes);
+            let op = match inst.funct3() {
+                0b000 => return match inst.imm() {
+                    0b0 => Inst::Ecall,
+                    0b1 => Inst::Ebreak,
+                    0x102 => Inst::Sret,
+                    0x105 => Inst::Wfi,
+                    0x302 => Inst::Mret,
+                    _ => Inst::Illegal,
+                },
+                0b001 | 0b101 => CsrOp::Rw,
+                0b010 | 0b110 => CsrOp::Rs,
+                0b011 | 0b111 => CsrOp::Rc,
+                _ => return Inst::Illegal,
+            };
+            Inst::Csr { op, rd: inst.rd(), csr: inst.csr(), src: inst.rs1(), imm: inst.funct3() & 0b100 != 0 }
+        },
+        _ => Inst::Illegal,
+    }
+}
+
+/// The 64-bit result of `op`.
+#[inline]
+pub fn alu(op: AluOp, a: u64, b: u64) -> u64 {
+    match op {
+        AluOp::Add => a.wrapping_add(b),
+        AluOp::Sub => a.wrapping_sub(b),
+        AluOp::Sll => a << (b & 0x3f),
+        AluOp::Slt => ((a as i64) < (b as i64)) as u64,
+        AluOp::Sltu => (a < b) as u64,
+        AluOp::Xor => a ^ b,
+        AluOp::Srl => a >> (b & 0x3f),
+        AluOp::Sra => ((a as i64) >> (b & 0x3f)) as u64,
+        AluOp::Or => a | b,
+        AluOp::And => a & b,
+    }
+}
+
+/// The `*w` forms: 32-bit operation, sign-extended result.
+#[inline]
+pub fn alu_w(op: AluOp, a: u64, b: u64) -> u64 {
+    let r = match op {
+        AluOp::Add => a.wrapping_add(b) as i32,
+        AluOp::Sub => a.wrapping_sub(b) as i32,
+        AluOp::Sll => ((a as u32) << (b & 0x1f)) as i32,
+        AluOp::Srl => ((a as u32) >> (b & 0x1f)) as i32,
+        AluOp::Sra => (a as i32) >> (b & 0x1f),
+        // not produced by the decoder
+        _ => alu(op, a, b) as i32,
+    };
+    r as i64 as u64
+}
+
+
+#[test]
+fn test_decode() {
+    // addi a0, a0, -1
+    assert_eq!(decode(0xfff50513), Inst::OpImm { op: AluOp::Add, rd: 10, rs1: 10, imm: u64::MAX });
+    // srai a0, a0, 3
+    assert_eq!(decode(0x40355513), Inst::OpImm { op: AluOp::Sra, rd: 10, rs1: 10, imm: 3 });
+    // sd a0, 0x100(x0)
+    assert_eq!(decode(0x10a03023), Inst::Store { rs1: 0, rs2: 10, offset: 0x100, size: 8 });
+    // jalr x0, 0(ra)
+    assert_eq!(decode(0x00008067), Inst::Jalr { rd: 0, rs1: 1, offset: 0 });
+    // csrrwi x0, mstatus, 0
+    assert_eq!(decode(0x30005073), Inst::Csr { op: CsrOp::Rw, rd: 0, csr: 0x300, src: 0, imm: true });
+    assert_eq!(decode(0x0000100f), Inst::FenceI);
+    assert_eq!(decode(0x30200073), Inst::Mret);
+    assert_eq!(decode(0x10200073), Inst::Sret);
+    assert_eq!(decode(0x10500073), Inst::Wfi);
+    // lr.d a0, (s0); sc.w.aqrl a3, a4, (s0); amoadd.w
+    assert_eq!(decode(0x1004352f), Inst::Lr { rd: 10, rs1: 8, size: 8 });
+    assert_eq!(decode(0x1ee426af), Inst::Sc { rd: 13, rs1: 8, rs2: 14, size: 4 });
+    assert_eq!(decode(0x00e426af), Inst::Illegal);
+    // mul is not implemented
+    assert_eq!(decode(0x02b50533), Inst::Illegal);
+    assert_eq!(alu_w(AluOp::Add, 0x7fffffff, 1), 0xffffffff80000000);
+    assert_eq!(alu_w(AluOp::Sra, 0x80000000, 4), 0xfffffffff8000000);
+}
@@ -1,21 +1,19 @@
-use std::ops::BitAnd;
-
-use lyuu_commons::isa::riscv::{
-    inst_binary::*,
-    RiscV,
-};
+#[cfg(feature = "itrace")]
 use lyuu_commons::disassembly::riscv::disassembly;
 
 use crate::{
     abstract_machine::Execable,
-    utils::{
-        field_range_into_u8,
-        field_range_into_u16,
-    },
-    device::MMIODevice
+    device::{MMIODevice, Access},
+    clock::Clock,
 };
 
-use super::{machine::MachineModel, irq::Exception};
+use super::{
+    machine::MachineModel,
+    irq::Exception,
+    decode::{decode, alu, alu_w, Inst, BranchOp, CsrOp},
+    block::Engine,
+    pmp::is_pmp,
+};
 
 
 macro_rules! gpr {
@@ -26,19 +24,11 @@ macro_rules! gpr {
 
 macro_rules! wgpr {
     ($this:ident, $offset:expr, $v:expr) => {
-        $this.gpr.store($offset as usize, $v as u64)
-    };
-}
-
-macro_rules! csr {
-    ($this:ident, $offset:expr) => {
-        $this.csr.read($offset as usize)
-    };
-}
-
-macro_rules! wcsr {
-    ($this:ident, $offset:expr, $v:expr) => {
-        $this.csr.store($offset as usize, $v as u64)
+        {
+            let (reg, value) = ($offset as usize, $v as u64);
+            $this.iring.note_write(reg, value);
+            $this.gpr.store(reg, value)
+        }
     };
 }
 
@@ -65,323 +55,321 @@ macro_rules! addpc {
 
 impl MachineModel {
 
-    /// lui
-    #[inline]
-    fn inst_0110111(&self, inst: &UType) {
-        let imm = inst.imm().overflowing_shl(12).0 as i32 as i64 as u64;
-        wgpr!(self, inst.rd(), imm);
-        addpc!(self, 4);
-    }
-
-    /// auipc
-    #[inline]
-    fn inst_0010111(&self, inst: &UType) {
-        let imm = inst.imm().overflowing_shl(12).0 as i32 as i64 as u64;
-        wgpr!(self, inst.rd(), pc!(self) + imm);
-        addpc!(self, 4);
-    }
-
-    /// jal
-    #[inline]
-    fn inst_1101111(&self, inst: &JType) {
-        wgpr!(self, inst.rd(), pc!(self) + 4);
-        let imm = inst.get_offset();
-        let next_pc = pc!(self) as i64 + imm as i64;
-        wpc!(self, next_pc);
+    /// Return-address stack hints of the spec: a link register (x1/x5)
+    /// as rd is a call, as rs1 a return, both (but different) a
+    /// coroutine swap.
+    #[cfg(feature = "ftrace")]
+    fn ftrace_jump(&self, rd: usize, rs1: Option<usize>, target: u64) {
+        let is_link = |r: usize| r == 1 || r == 5;
+        if matches!(rs1, Some(r) if is_link(r) && r != rd) {
+            crate::trace::ftrace_ret(pc!(self), target, gpr!(self, 10));
+        }
+        if is_link(rd) {
+            let args: Vec<u64> = (10..18).map(|r| gpr!(self, r)).collect();
+            crate::trace::ftrace_call(pc!(self), target, &args);
+        }
     }
 
-    /// jalr
+    /// `size` bytes at `addr`, sign-extended if `signed`
     #[inline]
-    fn inst_1100111(&self, inst: &IType) {
-        wgpr!(self, inst.rd(), pc!(self) + 4);
-        let next_pc = gpr!(self, inst.rs1()) as i64 + inst.sext_offset() as i64;
-        wpc!(self, next_pc);
+    pub(super) fn read(&self, addr: u64, size: u8, signed: bool, memory: &dyn MMIODevice) -> Result<u64, Exception> {
+        let naddr = addr as usize;
+        if !memory.allows(Access::Read, naddr, size as usize) || !self.pmp_allows(Access::Read, addr, size as u64) {
+            return Err(Exception::LoadAccessFault(addr));
+        }
+        let r = memory.load(naddr, size as usize).map_err(|_| Exception::LoadAccessFault(addr))?;
+        let r = match (size, signed) {
+            (1, true) => r as i8 as i64 as u64,     // lb
+            (2, true) => r as i16 as i64 as u64,    // lh
+            (4, true) => r as i32 as i64 as u64,    // lw
+            _ => r,     // lbu, lhu, lwu, ld
+        };
+        mtrace!("read  0x{:016x} -> 0x{:x}", naddr, r);
+        Ok(r)
     }
 
-    /// branch
+    /// load
     #[inline]
-    fn inst_1100011(&self, inst: &BType) -> Result<(), Exception> {
-        let rs1 = gpr!(self, inst.rs1());
-        let rs2 = gpr!(self, inst.rs2());
-
-        let cond = match inst.funct3() {
-            0b000 => rs1 == rs2,    // beq
-            0b001 => rs1 != rs2,    // bne
-            0b111 => rs1 >= rs2,    // bgeu
-            0b110 => rs1 < rs2,     // bltu
-            0b101 => (rs1 as i64) >= (rs2 as i64),  // bge
-            0b100 => (rs1 as i64) < (rs2 as i64),   // blt
-            _ => return Err(Exception::IllegalInstruction),
-        };
-        if cond {
-            let next_pc = pc!(self) as i64 + inst.sext_offset() as i64;
-            wpc!(self, next_pc);
-        } else {
-            addpc!(self, 4);
-        }
+    fn load(&self, rd: u8, addr: u64, size: u8, signed: bool, memory: &dyn MMIODevice) -> Result<(), Exception> {
+        let r = self.read(addr, size, signed, memory)?;
+        wgpr!(self, rd, r);
         Ok(())
     }
 
-    /// load
+    /// store
     #[inline]
-    fn inst_0000011(&self, inst: &IType, memory: &dyn MMIODevice) -> Result<(), Exception> {
-        let addr = gpr!(self, inst.rs1());
-        let offset = inst.sext_imm() as i64;
-        let addr = addr as i64 + offset;
+    pub(super) fn store(&self, addr: u64, value: u64, size: u8, memory: &dyn MMIODevice) -> Result<(), Exception> {
         let naddr = addr as usize;
-        let r = match inst.funct3() {
-            0b000 => memory.read_u8(naddr).map(|x| x as i8 as i64 as u64),    // lb
-            0b001 => memory.read_u16(naddr).map(|x| x as i16 as i64 as u64), // lh
-            0b010 => memory.read_u32(naddr).map(|x| x as i32 as i64 as u64), // lw
-            0b011 => memory.read_u64(naddr), // ld
-            0b100 => memory.read_u8(naddr).map(|x| x as u64),   // lbu
-            0b101 => memory.read_u16(naddr).map(|x| x as u64),  // lhu
-            0b110 => memory.read_u32(naddr).map(|x| x as u64),  // lwu
-            _ => return Err(Exception::IllegalInstruction),
-        };
-        if r.is_none() {
-            return Err(Exception::LoadAccessFault(addr as u64));
+        if !memory.allows(Access::Write, naddr, size as usize) || !self.pmp_allows(Access::Write, addr, size as u64) {
+            return Err(Exception::StoreAccessFault(addr));
         }
-        let r = r.unwrap();
-        wgpr!(self, inst.rd(), r);
-        addpc!(self, 4);
+        mtrace!("write 0x{:016x} <- 0x{:x}", naddr, value);
+        memory.store(naddr, size as usize, value).map_err(|_| Exception::StoreAccessFault(addr))?;
+        self.invalidate_code(addr, size as u64);
+        self.shared.stored(addr, size as u64);
         Ok(())
     }
 
-    /// store
+    /// `len` bytes at `addr` were written, by this hart or another.
     #[inline]
-    fn inst_0100011(&self, inst: &SType, memory: &dyn MMIODevice) -> Result<(), Exception> {
-        let addr = gpr!(self, inst.rs1());
-        let sext_offset = inst.sext_imm();
-        let addr = addr as i64 + sext_offset as i64;
-        let addr = addr as u64 as usize;
-        match inst.funct3() {
-            0b000 => memory.write_u8(addr, inst.rs2() as u8),   // sb
-            0b001 => memory.write_u16(addr, inst.rs2() as u16), // sh
-            0b010 => memory.write_u32(addr, inst.rs2() as u32), // sw
-            0b011 => memory.write_u64(addr, inst.rs2() as u64), // sd
-            _ => return Err(Exception::IllegalInstruction),
-        };
-        addpc!(self, 4);
-        Ok(())
+    pub(super) fn invalidate_code(&self, addr: u64, len: u64) {
+        self.icache.invalidate(addr, len);
+        self.blocks.invalidate(addr, len);
+        self.jit.invalidate(addr, len);
     }
 
-    /// op imm
+    /// Runs `inst` as the instruction at the pc.
     #[inline]
-    fn inst_0010011(&self, inst: &IType) -> Result<(), Exception> {
-        let rs1 = gpr!(self, inst.rs1());
-        let sext_offset = inst.sext_imm();
-        let value = match inst.funct3() {
-            0b000 => (rs1 as i64 + sext_offset as i64) as u64,      // addi
-            0b010 => ((rs1 as i64) < (sext_offset as i64)) as u64,  // slti
-            0b011 => (rs1 < (sext_offset as i64 as u64)) as u64,    // sltiu
-            0b100 => (rs1 as i64 ^ sext_offset as i64) as u64,      // xori
-            0b110 => (rs1 as i64 | sext_offset as i64) as u64,      // ori
-            0b111 => (rs1 as i64 & sext_offset as i64) as u64,      // andi
-            0b001 => //((rs1 as i64) << (sext_offset as i64)) as u64, // slli
-            // /*
-            match field_range_into_u8(inst.imm().into(), 12, 6) {
-                0b000000 => ((rs1 as i64) << (sext_offset as i64)) as u64, // slli
-                _ => return Err(Exception::IllegalInstruction),
+    pub fn exec(&self, inst: Inst, memory: &dyn MMIODevice) -> Result<(), Exception> {
+        match inst {
+            Inst::Lui { rd, imm } => wgpr!(self, rd, imm),
+            Inst::Auipc { rd, imm } => wgpr!(self, rd, pc!(self).wrapping_add(imm)),
+            Inst::Jal { rd, offset } => {
+                let target = pc!(self).wrapping_add(offset as u64);
+                #[cfg(feature = "ftrace")]
+                self.ftrace_jump(rd as usize, None, target);
+                wgpr!(self, rd, pc!(self) + 4);
+                wpc!(self, target);
+                return Ok(());
             },
-            // */
-            0b101 => match field_range_into_u8(inst.imm().into(), 12, 6) {
-                0b000000 => rs1 >> (sext_offset as u64), // srli
-                0b010000 => (rs1 as i64 >> sext_offset as i64) as u64, // srai
-                _ =>  return Err(Exception::IllegalInstruction),
+            Inst::Jalr { rd, rs1, offset } => {
+                let target = gpr!(self, rs1).wrapping_add(offset as u64) & !1;
+                #[cfg(feature = "ftrace")]
+                self.ftrace_jump(rd as usize, Some(rs1 as usize), target);
+                wgpr!(self, rd, pc!(self) + 4);
+                wpc!(self, target);
+                return Ok(());
             },
-            _ =>  return Err(Exception::IllegalInstruction),
-        };
-        wgpr!(self, inst.rd(), value);
-        addpc!(self, 4);
-        Ok(())
-    }
-
-    /// op imm word
-    #[inline]
-    fn inst_0011011(&self, inst: &IType) -> Result<(), Exception> {
-        let rs1 = gpr!(self, inst.rs1());
-        let sext_offset = inst.sext_imm();
-        let value = match inst.funct3() {
-            0b000 => (rs1 as i64 + sext_offset as i64) as u64 as u32 as i64,      // addiw
-            0b001 => match field_range_into_u16(inst.imm().into(), 12, 5) {
-                0b0000000 => ((rs1 as i64) << (sext_offset as i64)) as u64 as u32 as i64, // slliw
-                _ => return Err(Exception::IllegalInstruction),
+            Inst::Branch { op, rs1, rs2, offset } => {
+                let (rs1, rs2) = (gpr!(self, rs1), gpr!(self, rs2));
+                let cond = match op {
+                    BranchOp::Eq => rs1 == rs2,
+                    BranchOp::Ne => rs1 != rs2,
+                    BranchOp::Lt => (rs1 as i64) < (rs2 as i64),
+                    BranchOp::Ge => (rs1 as i64) >= (rs2 as i64),
+                    BranchOp::Ltu => rs1 < rs2,
+                    BranchOp::Geu => rs1 >= rs2,
+                };
+                if cond {
+                    wpc!(self, pc!(self).wrapping_add(offset as u64));
+                    return Ok(());
+                }
             },
-            0b101 => match field_range_into_u16(inst.imm().into(), 12, 5) {
-                0b0000000 => (rs1 >> (sext_offset as u64)) as u32 as i64, // srliw
-                0b0100000 => (rs1 as i64 >> sext_offset as i64) as u64 as u32 as i64, // sraiw
-                _ =>  return Err(Exception::IllegalInstruction),
+            Inst::Load { rd, rs1, offset, size, signed } =>
+                self.load(rd, gpr!(self, rs1).wrapping_add(offset as u64), size, signed, memory)?,
+            Inst::Store { rs1, rs2, offset, size } =>
+                self.store(gpr!(self, rs1).wrapping_add(offset as u64), gpr!(self, rs2), size, memory)?,
+            Inst::Lr { rd, rs1, size } => {
+                let addr = gpr!(self, rs1);
+                if !addr.is_multiple_of(size as u64) {
+                    return Err(Exception::LoadAddressMisaligned(addr));
+                }
+                let r = self.read(addr, size, true, memory)?;
+                self.shared.reserve(self.hart_id(), addr);
+                wgpr!(self, rd, r);
             },
-            _ =>  return Err(Exception::IllegalInstruction),
-        };
-        wgpr!(self, inst.rd(), value);
+            Inst::Sc { rd, rs1, rs2, size } => {
+                let addr = gpr!(self, rs1);
+                if !addr.is_multiple_of(size as u64) {
+                    return Err(Exception::StoreAddressMisaligned(addr));
+                }
+                let held = self.shared.unreserve(self.hart_id()) == Some(addr);
+                if held {
+                    self.store(addr, gpr!(self, rs2), size, memory)?;
+                }
+                wgpr!(self, rd, !held as u64);
+            },
+            Inst::OpImm { op, rd, rs1, imm } => wgpr!(self, rd, alu(op, gpr!(self, rs1), imm)),
+            Inst::OpImmW { op, rd, rs1, imm } => wgpr!(self, rd, alu_w(op, gpr!(self, rs1), imm)),
+            Inst::Op { op, rd, rs1, rs2 } => wgpr!(self, rd, alu(op, gpr!(self, rs1), gpr!(self, rs2))),
+            Inst::OpW { op, rd, rs1, rs2 } => wgpr!(self, rd, alu_w(op, gpr!(self, rs1), gpr!(self, rs2))),
+            Inst::Fence => {},
+            Inst::FenceI => {
+                self.icache.flush();
+                self.blocks.flush();
+                self.jit.flush();
+            },
+            // these set the pc themselves
+            Inst::Ecall => {
+                self.ecall();
+                return Ok(());
+            },
+            Inst::Ebreak => {
+                self.ebreak();
+                return Ok(());
+            },
+            Inst::Mret => {
+                self.mret();
+                return Ok(());
+            },
+            Inst::Sret => {
+                self.sret()?;
+                return Ok(());
+            },
+            Inst::Wfi => self.wfi(memory)?,
+            Inst::Csr { op, rd, csr, src, imm } => {
+                // csrrs/csrrc with x0 or 0 only read
+                let write = op == CsrOp::Rw || src != 0;
+                if !self.csr_allows(csr as usize, write) {
+                    return Err(Exception::IllegalInstruction);
+                }
+                let t = self.csr_read(csr as usize);
+                let v = if imm { src as u64 } else { gpr!(self, src) };
+                let value = match op {
+                    CsrOp::Rw => v,     // csrrw(i)
+                    CsrOp::Rs => t | v, // csrrs(i)
+                    CsrOp::Rc => t & !v,    // csrrc(i)
+                };
+                if !write {
+                    // no side effects either
+                } else if is_pmp(csr as usize) {
+                    self.pmp.write(&self.csr, csr as usize, value);
+                } else if self.csr_write(csr as usize, value).is_none() {
+                    return Err(Exception::IllegalInstruction);
+                }
+                wgpr!(self, rd, t);
+            },
+            Inst::Illegal => return Err(Exception::IllegalInstruction),
+        }
         addpc!(self, 4);
         Ok(())
     }
+}
 
-    /// op
+impl MachineModel {
+    /// The instruction at `pc`, decoded.
     #[inline]
-    fn inst_0110011(&self, inst: &RType) -> Result<(), Exception> {
-        let rs1 = gpr!(self, inst.rs1());
-        let rs2 = gpr!(self, inst.rs2());
-        let value = match inst.funct3() {
-            0b000 => match inst.funct7() {
-                0b0000000 => rs1 + rs2,// add
-                0b0100000 => rs1 - rs2,// sub
-                _ =>  return Err(Exception::IllegalInstruction),
-            },
-            0b001 => rs1.overflowing_shl(rs2.bitand(0b111111) as u32).0,// sll
-            0b010 => ((rs1 as i64) < (rs2 as i64)) as u64,              // slt
-            0b011 => (rs1 < rs2) as u64,    // sltu
-            0b100 => rs1 ^ rs2,             // xor
-            0b101 => match inst.funct7() {
-                0b0000000 => rs1 >> rs2,    // srl
-                0b0100000 => (rs1 as i64).overflowing_shr(rs2.bitand(0b111111) as u32).0 as u64, // sra
-                _ =>  return Err(Exception::IllegalInstruction),
+    pub fn fetch(&self, pc: u64, memory: &dyn MMIODevice) -> Result<(u32, Inst), Exception> {
+        if let Some(x) = self.icache.get(pc) {
+            if !self.pmp_allows(Access::Exec, pc, 4) {
+                return Err(Exception::InstructionAccessFault(pc));
             }
-            0b110 => rs1 | rs2,             // or
-            0b111 => rs1 & rs2,             // and
-            _ => return Err(Exception::IllegalInstruction),
-        };
-        wgpr!(self, inst.rd(), value);
-        addpc!(self, 4);
-        Ok(())
+            return Ok(x);
+        }
+        let code = self.read_code(pc, memory)?;
+        let inst = decode(code);
+        if memory.pma(pc as usize).is_some_and(|x| x.cacheable) {
+            self.icache.insert(pc, code, inst);
+        }
+        Ok((code, inst))
     }
 
-    /// op word
+    /// The instruction word at `pc`, if the bus lets it be executed.
     #[inline]
-    fn inst_0111011(&self, inst: &RType) -> Result<(), Exception> {
-        let rs1 = gpr!(self, inst.rs1());
-        let rs2 = gpr!(self, inst.rs2());
-        let value = match inst.funct3() {
-            0b000 => match inst.funct7() {
-                0b0000000 => (rs1 + rs2) as u32 as i64,// addw
-                0b0100000 => (rs1 - rs2) as u32 as i64,// subw
-                _ =>  return Err(Exception::IllegalInstruction),
-            },
-            0b001 => rs1.overflowing_shl(rs2.bitand(0b111111) as u32).0 as u32 as i64,// sllw
-            0b101 => match inst.funct7() {
-                0b0000000 => (rs1 >> rs2) as u32 as i64,    // srlw
-                0b0100000 => (rs1 as i64).overflowing_shr(rs2.bitand(0b111111) as u32).0 as u64 as u32 as i64, // sraw
-                _ =>  return Err(Exception::IllegalInstruction),
-            }
-            _ => return Err(Exception::IllegalInstruction),
-        };
-        wgpr!(self, inst.rd(), value);
-        addpc!(self, 4);
-        Ok(())
+    pub fn read_code(&self, pc: u64, memory: &dyn MMIODevice) -> Result<u32, Exception> {
+        if !memory.allows(Access::Exec, pc as usize, 4) || !self.pmp_allows(Access::Exec, pc, 4) {
+            return Err(Exception::InstructionAccessFault(pc));
+        }
+        memory.load(pc as usize, 4).map(|x| x as u32).map_err(|_| Exception::InstructionAccessFault(pc))
     }
 
-    /// fence
-    #[inline(always)]
-    fn inst_0001111(&self, _inst: &IType) {
-        // nop
-        addpc!(self, 4);
+    /// Everything `exec_once` does after fetching: iring, traces, execution.
+    #[inline]
+    pub fn retire(&self, code: u32, inst: Inst, memory: &dyn MMIODevice) -> Result<(), Exception> {
+        let pc = self.pc.read();
+        self.iring.push(pc, code);
+        #[cfg(feature = "tracer")]
+        crate::trace::set_context(pc, self.mode.get() as u8);
+        itrace!("0x{:016x}:    {}\t{}",
+            pc,
+            code.to_le_bytes().into_iter().map(|x| format!("{:02x}", x)).collect::<Vec<_>>().join(" "),
+            disassembly(code).map_or("unimp".to_string(), |x| x.0.to_string()));
+        self.exec(inst, memory)
     }
+}
 
-    // privileged
-    #[inline]
-    fn inst_1110011(&self, inst: &IType, _memory: &dyn MMIODevice) -> Result<(), Exception> {
-        // let rd = self.gpr.read(inst.rd() as usize);
-        // let zimm = inst.rs1();
-        match inst.funct3() {
-            0b000 => match inst.imm() {
-                0b0 => self.ecall(),
-                0b1 => self.ebreak(),
-                _ => return Err(Exception::IllegalInstruction),
+impl MachineModel {
+    /// Up to `max` instructions with the chosen engine, stopping early when
+    /// an event of the `clock` is due, which gets a tick for each of them
+    /// and for a trap. How many retired.
+    pub(super) fn run_for(&self, memory: &dyn MMIODevice, clock: Option<&Clock>, max: usize) -> (usize, Result<(), Exception>) {
+        if self.waiting.get() {
+            return self.wait(clock);
+        }
+        let max = clock.map_or(max, |x| max.min(x.until_next().max(1).try_into().unwrap_or(usize::MAX)));
+        let (n, r) = match self.engine.get() {
+            Engine::Interp => {
+                let mut n = 0;
+                let mut r = Ok(());
+                while n < max && !self.waiting.get() {
+                    r = self.exec_once(memory);
+                    if r.is_err() {
+                        break;
+                    }
+                    n += 1;
+                }
+                (n, r)
+            },
+            Engine::Block => {
+                self.check_interrupts();
+                self.exec_block(memory, max)
+            },
+            Engine::Jit => {
+                self.check_interrupts();
+                self.exec_jit(memory, max)
             },
-            0b001 => {
-                let t = csr!(self, inst.csr());
-                wcsr!(self, inst.csr(), gpr!(self, inst.rs1()));
-                wgpr!(self, inst.rd(), t)
-            },   // csrrw
-            0b010 => {
-                let t = csr!(self, inst.csr());
-                wcsr!(self, inst.csr(), t|gpr!(self, inst.rs1()));
-                wgpr!(self, inst.rd(), t)
-            },   // csrrs
-            0b011 => {
-                let t = csr!(self, inst.csr());
-                wcsr!(self, inst.csr(), t&!gpr!(self, inst.rs1()));
-                wgpr!(self, inst.rd(), t)
-            },   // csrrc
-            0b101 => {
-                wgpr!(self, inst.rd(), csr!(self, inst.csr()));
-                let zimm = inst.rs1() as u64;
-                wcsr!(self, inst.csr() as usize, zimm);
-            },   // csrrwi
-            0b110 => {
-                let t = csr!(self, inst.csr());
-                let zimm = inst.rs1() as u64;
-                wcsr!(self, inst.csr(), t|zimm);
-                wgpr!(self, inst.rd(), t);
-            },   // csrrsi
-            0b111 => {
-                let t = csr!(self, inst.csr());
-                let zimm = inst.rs1() as u64;
-                wcsr!(self, inst.csr(), t&!zimm);
-                wgpr!(self, inst.rd(), t);
-            },   // csrrci
-            _ => return Err(Exception::IllegalInstruction),
+        };
+        if let Some(clock) = clock {
+            clock.advance(n as u64 + r.is_err() as u64);
         }
-        addpc!(self, 4);
-        Ok(())
+        (n, r)
+    }
+
+    /// A hart in `wfi` goes on once woken, and otherwise lets the clock run
+    /// to its next event; that wait counts as a step, so that stepping a
+    /// stopped hart ends.
+    fn wait(&self, clock: Option<&Clock>) -> (usize, Result<(), Exception>) {
+        if self.wake() {
+            self.waiting.set(false);
+            return (0, Ok(()));
+        }
+        if let Some(clock) = clock {
+            clock.advance(clock.until_next().max(1));
+        }
+        (1, Ok(()))
     }
 }
 
 impl Execable<Exception> for MachineModel {
     fn exec_once(&self, memory: &dyn MMIODevice) -> Result<(), Exception> {
-        let pc = self.pc.read();
-        let code = memory.read_u32(pc as usize);
-        if code.is_none() {
-            return Err(Exception::LoadAccessFault(pc));
+        if self.waiting.get() {
+            if !self.wake() {
+                return Ok(());
+            }
+            self.waiting.set(false);
         }
-        let code = code.unwrap();
-        println!("[lemu:itrace]  0x{:016x}:    {}\t{}",
-            self.pc.read(),
-            code.to_le_bytes().into_iter().map(|x| format!("{:02x}", x)).collect::<Vec<_>>().join(" "),
-            disassembly(code).map_or("unimp".to_string(), |x| x.0.to_string()));
+        self.check_interrupts();
+        let (code, inst) = self.fetch(self.pc.read(), memory)?;
+        self.retire(code, inst, memory)
+    }
 
-        match field_range_into_u8(code, 6, 0) {
-            0b0110111 => self.inst_0110111(&UType::from_bytes(code.to_le_bytes())),
-            0b0010111 => self.inst_0010111(&UType::from_bytes(code.to_le_bytes())),
-            0b1101111 => self.inst_1101111(&JType::from_bytes(code.to_le_bytes())),
-            0b1100111 => self.inst_1100111(&IType::from_bytes(code.to_le_bytes())),
-            0b1100011 => self.inst_1100011(&BType::from_bytes(code.to_le_bytes()))?,
-            0b0000011 => self.inst_0000011(&IType::from_bytes(code.to_le_bytes()), memory)?,
-            0b0100011 => self.inst_0100011(&SType::from_bytes(code.to_le_bytes()), memory)?,
-            0b0010011 => self.inst_0010011(&IType::from_bytes(code.to_le_bytes()))?,
-            0b0011011 => self.inst_0011011(&IType::from_bytes(code.to_le_bytes()))?,
-            0b0110011 => self.inst_0110011(&RType::from_bytes(code.to_le_bytes()))?,
-            0b0111011 => self.inst_0111011(&RType::from_bytes(code.to_le_bytes()))?,
-            0b0001111 => self.inst_0001111(&IType::from_bytes(code.to_le_bytes())),
-            0b1110011 => self.inst_1110011(&IType::from_bytes(code.to_le_bytes()), memory)?,
-            _ => return Err(Exception::IllegalInstruction),
-        };
+    #[inline]
+    fn take_interrupt(&self) -> Option<u64> {
+        self.interrupted.take()
+    }
+
+    fn exec_catch_interrupt_loop(&self, memory: &dyn MMIODevice) -> Result<(), Exception> {
+        let clock = memory.clock();
+        loop {
+            self.run_for(memory, clock.as_deref(), usize::MAX).1?;
+        }
+    }
+
+    fn setp_num(&self, memory: &dyn MMIODevice, num: usize) -> Result<(), Exception> {
+        let clock = memory.clock();
+        let mut done = 0;
+        while done < num {
+            let (n, r) = self.run_for(memory, clock.as_deref(), num - done);
+            r?;
+            done += n;
+        }
         Ok(())
     }
-}
 
-impl MachineModel {
-    fn exec_inst(&self, inst: RiscV, _memory: &dyn MMIODevice) -> Result<(), Exception> {
-        match inst {
-            RiscV::Lui(_, _) => todo!(),
-            RiscV::Auipc(_, _) => todo!(),
-            RiscV::Jal(_, _) => todo!(),
-            RiscV::Jalr(_, _, _) => todo!(),
-            RiscV::Branch(_, _, _, _) => todo!(),
-            RiscV::Load(_, _, _, _) => todo!(),
-            RiscV::Store(_, _, _, _) => todo!(),
-            RiscV::OpI(_, _, _, _) => todo!(),
-            RiscV::Op(_, _, _, _) => todo!(),
-            RiscV::Fence(_, _, _) => todo!(),
-            RiscV::EOp(_) => todo!(),
-            RiscV::CsrOp(_, _, _, _) => todo!(),
-            RiscV::CsrOpI(_, _, _, _) => todo!(),
-            RiscV::OpIW(_, _, _, _) => todo!(),
-            RiscV::OpW(_, _, _, _) => todo!(),
-        };
-        // Ok(())
+    #[inline]
+    fn flush_code_cache(&self) {
+        self.icache.flush();
+        self.blocks.flush();
+        self.jit.flush();
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,134 @@
+use std::cell::RefCell;
+
+use super::decode::Inst;
+
+
+const PAGE_SHIFT: u64 = 12;
+/// 4-byte instructions per page
+const SLOTS: usize = 1 << (PAGE_SHIFT - 2);
+/// Pages kept, direct-mapped on the page number.
+const SETS: usize = 256;
+
+/// A page number and its decoded instructions.
+type Page = (u64, Box<[Option<(u32, Inst)>]>);
+
+/// Decoded instructions by page, each with its raw encoding for the iring
+/// and traces. Stores drop the instructions they overwrite, FENCE.I drops
+/// everything.
+#[derive(Debug)]
+pub struct ICache {
+    sets: RefCell<Vec<Option<Page>>>,
+}
+
+impl Default for ICache {
+    fn default() -> ICache {
+        ICache { sets: RefCell::new(vec![None; SETS]) }
+    }
+}
+
+/// Copies (snapshots) start cold.
+impl Clone for ICache {
+    fn clone(&self) -> ICache {
+        ICache::default()
+    }
+}
+
+impl ICache {
+    #[inline]
+    pub fn get(&self, pc: u64) -> Option<(u32, Inst)> {
+        if pc & 0b11 != 0 {
+            return None;
+        }
+        let page = pc >> PAGE_SHIFT;
+        match &self.sets.borrow()[set(page)] {
+            Some((tag, slots)) if *tag == page => slots[slot(pc)],
+            _ => None,
+        }
+    }
+
+    #[inline]
+    pub fn insert(&self, pc: u64, code: u32, inst: Inst) {
+        if pc & 0b11 != 0 {
+            return;
+        }
+        let page = pc >> PAGE_SHIFT;
+        let mut sets = self.sets.borrow_mut();
+        let entry = &mut sets[set(page)];
+        if !matches!(entry, Some((tag, _)) if *tag == page) {
+            *entry = Some((page, vec![None; SLOTS].into_boxed_slice()));
+        }
+        if let Some((_, slots)) = entry {
+            slots[slot(pc)] = Some((code, inst));
+        }
+    }
+
+    /// `len` bytes at `addr` were written; only the words they touch are
+    /// dropped, as code and data often share pages.
+    #[inline]
+    pub fn invalidate(&self, addr: u64, len: u64) {
+        let mut sets = self.sets.borrow_mut();
+        let mut word = addr & !0b11;
+        while word < addr.wrapping_add(len) {
+            let page = word >> PAGE_SHIFT;
+            if let Some((tag, slots)) = &mut sets[set(page)] {
+                if *tag == page {
+                    slots[slot(word)] = None;
+                }
+            }
+            word += 4;
+        }
+    }
+
+    pub fn flush(&self) {
+        self.sets.borrow_mut().iter_mut().for_each(|x| *x = None);
+    }
+
+    /// Pages with instructions kept.
+    pub fn pages(&self) -> Vec<u64> {
+        self.sets.borrow().iter().flatten().map(|(tag, _)| *tag).collect()
+    }
+}
+
+#[inline]
+fn set(page: u64) -> usize {
+    page as usize & (SETS - 1)
+}
+
+#[inline]
+fn slot(pc: u64) -> usize {
+    ((pc as usize) & ((1 << PAGE_SHIFT) - 1)) >> 2
+}
+
+
+#[test]
+fn test_icache() {
+    use crate::{interpreter::riscv64::machine::MachineModel, memory::Memory, abstract_machine::{Execable, Writeable}};
+    let mm = MachineModel::new(0);
+    // addi a0, a0, 1; sw a1, 0(x0); jal x0, -8
+    let mut inst_list: Vec<u8> = [0x00150513u32, 0x00b02023, 0xff9ff06f]
+        .into_iter().flat_map(|x: u32| x.to_le_bytes()).collect();
+    inst_list.resize(0x2000, 0);
+    let mem = Memory::from(inst_list.as_ref());
+    // the store rewrites the first instruction into the same one
+    mm.gpr.store(11, 0x00150513);
+    for _ in 0..6 {
+        mm.exec_once(&mem).unwrap();
+    }
+    assert_eq!(mm.gpr.read(10), 2);
+    // addi a0, a0, 2: a store through the guest is picked up
+    mm.gpr.store(11, 0x00250513);
+    for _ in 0..6 {
+        mm.exec_once(&mem).unwrap();
+    }
+    assert_eq!(mm.gpr.read(10), 5);
+    // one behind the guest's back needs a FENCE.I (or a flush)
+    mm.exec_once(&mem).unwrap();
+    mem.write_u32(0, 0x00350513).unwrap();
+    mm.pc.store(0);
+    mm.exec_once(&mem).unwrap();
+    assert_eq!(mm.gpr.read(10), 9);
+    mm.icache.flush();
+    mm.pc.store(0);
+    mm.exec_once(&mem).unwrap();
+    assert_eq!(mm.gpr.read(10), 12);
+}
@@ -0,0 +1,102 @@
+use std::{cell::{Cell, RefCell}, collections::VecDeque};
+
+use lyuu_commons::disassembly::riscv::disassembly;
+
+use super::machine::REG_NAMES;
+
+
+pub const DEFAULT_IRING_SIZE: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IRingEntry {
+    pub pc: u64,
+    pub code: u32,
+    /// GPR written by the instruction and its new value
+    pub write: Option<(usize, u64)>,
+}
+
+/// The last few executed instructions, cheap enough to keep on all the
+/// time and dumped when something goes wrong.
+#[derive(Debug, Clone)]
+pub struct IRing {
+    entries: RefCell<VecDeque<IRingEntry>>,
+    size: Cell<usize>,
+}
+
+impl IRing {
+    #[inline]
+    pub fn new(size: usize) -> IRing {
+        IRing {
+            entries: RefCell::new(VecDeque::with_capacity(size)),
+            size: Cell::new(size),
+        }
+    }
+
+    /// 0 turns the ring off.
+    pub fn set_size(&self, size: usize) {
+        let mut entries = self.entries.borrow_mut();
+        while entries.len() > size {
+            entries.pop_front();
+        }
+        self.size.set(size);
+    }
+
+    #[inline]
+    pub fn push(&self, pc: u64, code: u32) {
+        if self.size.get() == 0 {
+            return;
+        }
+        let mut entries = self.entries.borrow_mut();
+        if entries.len() == self.size.get() {
+            entries.pop_front();
+        }
+        entries.push_back(IRingEntry { pc, code, write: None });
+    }
+
+    /// Attaches a register write to the newest entry.
+    #[inline]
+    pub fn note_write(&self, reg: usize, value: u64) {
+        if let Some(e) = self.entries.borrow_mut().back_mut() {
+            e.write = Some((reg, value));
+        }
+    }
+
+    #[inline]
+    pub fn entries(&self) -> Vec<IRingEntry> {
+        self.entries.borrow().iter().copied().collect()
+    }
+
+    /// Oldest first, the newest (usually the faulting one) marked `-->`.
+    pub fn dump(&self) {
+        let entries = self.entries.borrow();
+        if entries.is_empty() {
+            return;
+        }
+        eprintln!("[lemu] last {} instructions:", entries.len());
+        for (i, e) in entries.iter().enumerate() {
+            let marker = if i + 1 == entries.len() { "-->" } else { "   " };
+            let text = disassembly(e.code).map_or("unimp".to_string(), |x| x.0.to_string());
+            let write = e.write
+                .filter(|(reg, _)| *reg != 0)
+                .map_or(String::new(), |(reg, v)| format!("\t{} = 0x{:x}", REG_NAMES[reg + 1], v));
+            eprintln!("{} 0x{:016x}:    {:08x}\t{}{}", marker, e.pc, e.code, text, write);
+        }
+    }
+}
+
+
+#[test]
+fn test_iring() {
+    let ring = IRing::new(2);
+    ring.push(0, 0x13);
+    ring.push(4, 0x13);
+    ring.note_write(10, 1);
+    ring.push(8, 0x13);
+    assert_eq!(ring.entries(), vec![
+        IRingEntry { pc: 4, code: 0x13, write: Some((10, 1)) },
+        IRingEntry { pc: 8, code: 0x13, write: None },
+    ]);
+    ring.set_size(0);
+    ring.push(12, 0x13);
+    assert!(ring.entries().is_empty());
+}
@@ -2,7 +2,13 @@ use lyuu_commons::disassembly::riscv::disassembly;
 
 use crate::{abstract_machine::{ExceptionProcessable, ExceptionAttr}, device::MMIODevice};
 
-use super::{machine::MachineModel, reg::{csrmap::{MSTATUS, MIE, MIP, MEPC, MTVEC, MTVAL}, csr::{mstatus::{MStatus, MachineMode}, mie_mip::{Mie, Mip}, mtvec::Tvec}}};
+use super::{
+    machine::MachineModel,
+    reg::{
+        csrmap::{MSTATUS, MEPC, MCAUSE, MTVEC, MTVAL, MIP, MIE, MIDELEG, SSTATUS, SEPC, SCAUSE, STVEC, STVAL, SIE, SIP},
+        csr::{SSTATUS_MASK, write_mask, mstatus::{MStatus, MachineMode, SUMachineMode}, mtvec::Tvec, mcause::MCause},
+    },
+};
 
 
 #[repr(u64)]
@@ -23,6 +29,19 @@ pub enum RawInstrrupt {
     MachineExternalInterrupt = 11,
 }
 
+impl RawInstrrupt {
+    /// Interrupts pending for the same mode at once, the one taken first
+    /// first.
+    pub const PRIORITY: [RawInstrrupt; 6] = [
+        RawInstrrupt::MachineExternalInterrupt,
+        RawInstrrupt::MachineSoftwareInterrupt,
+        RawInstrrupt::MachineTimerInterrupt,
+        RawInstrrupt::SupervisorExternalInterrupt,
+        RawInstrrupt::SupervisorSoftwareInterrupt,
+        RawInstrrupt::SupervisorTimerInterrupt,
+    ];
+}
+
 
 #[repr(u64)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
@@ -46,7 +65,7 @@ pub enum RawException {
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Exception {
-    InstructionAccessFault,
+    InstructionAccessFault(u64),
     IllegalInstruction,
     LoadAccessFault(u64),
     StoreAccessFault(u64),
@@ -65,13 +84,24 @@ impl ExceptionAttr for Exception {
     fn is_debugger_trap(&self) -> bool {
         self == &Exception::Breakpoint
     }
+
+    fn is_fatal(&self) -> bool {
+        // page faults are routine once paging is on
+        matches!(self,
+            Exception::InstructionAccessFault(_)
+            | Exception::IllegalInstruction
+            | Exception::LoadAccessFault(_)
+            | Exception::StoreAccessFault(_)
+            | Exception::LoadAddressMisaligned(_)
+            | Exception::StoreAddressMisaligned(_))
+    }
 }
 
 impl Exception {
     #[inline]
     pub fn as_cause_tval(&self) -> (RawException, u64) {
         match self {
-            Exception::InstructionAccessFault => (RawException::InstructionAccessFault, 0),
+            Exception::InstructionAccessFault(u) => (RawException::InstructionAccessFault, *u),
             Exception::IllegalInstruction => (RawException::IllegalInstruction, 0),
             Exception::LoadAccessFault(u) => (RawException::LoadAccessFault, *u),
             Exception::StoreAccessFault(u) => (RawException::StoreAccessFault, *u),
@@ -79,8 +109,8 @@ impl Exception {
             Exception::SupervisorEcall => (RawException::EnvironmentCallFromSMode, 0),
             Exception::MachineEcall => (RawException::EnvironmentCallFromMMode, 0),
             Exception::Breakpoint => (RawException::Breakpoint, 0),
-            // Exception::LoadAddressMisaligned(_) => todo!(),
-            // Exception::StoreAddressMisaligned(_) => todo!(),
+            Exception::LoadAddressMisaligned(u) => (RawException::LoadAddressMisaligned, *u),
+            Exception::StoreAddressMisaligned(u) => (RawException::StoreAddressMisaligned, *u),
             // Exception::InstructionPageFault(_) => todo!(),
             // Exception::LoadPageFault(_) => todo!(),
             // Exception::StorePageFault(_) => todo!(),
@@ -91,47 +121,195 @@ impl Exception {
 
 
 impl MachineModel {
+    /// Takes the trap in M-mode; there is no delegation of exceptions yet.
     #[inline]
     pub fn exception_request(&self, e: Exception) -> Option<()> {
+        let (cause, tval) = e.as_cause_tval();
+        etrace!("{:?} at 0x{:016x}, tval 0x{:x}", cause, self.pc.read(), tval);
+        self.trap(MachineMode::Machine, RawTrapType::Exception, cause as u64, tval);
+        Some(())
+    }
 
-        // todo: checks
-        let mut mstatus = MStatus::from_bytes(self.csr.read(MSTATUS).to_le_bytes());
-        let mie = Mie::from_bytes(self.csr.read(MIE).to_le_bytes());
-        let mip = Mip::from_bytes(self.csr.read(MIP).to_le_bytes());
-        let mie = mie.msie();
-        let mip = mip.msie();
+    /// Takes `irq` in S-mode if `mideleg` hands it down, in M-mode
+    /// otherwise.
+    pub fn interrupt_request(&self, irq: RawInstrrupt) {
+        etrace!("{:?} at 0x{:016x}", irq, self.pc.read());
+        let target = if self.csr.read(MIDELEG) >> irq as u64 & 1 == 1 {
+            MachineMode::Supervisor
+        } else {
+            MachineMode::Machine
+        };
+        self.trap(target, RawTrapType::Interrupt, irq as u64, 0);
+    }
 
-        let tvec = Tvec::from_bytes(self.csr.read(MTVEC).to_le_bytes());
+    /// `mip` as software sees it: what it wrote, and what the controllers
+    /// drive.
+    #[inline]
+    pub fn mip(&self) -> u64 {
+        self.csr.read(MIP) | self.irq.get()
+    }
 
-        let (cause, tval) = e.as_cause_tval();
-        let cause = cause as u64;
+    /// The M-mode register under an S-mode view of it, and the bits shown.
+    #[inline]
+    pub(super) fn s_view(&self, reg: usize) -> Option<(usize, u64)> {
+        match reg {
+            SSTATUS => Some((MSTATUS, SSTATUS_MASK)),
+            SIE => Some((MIE, self.csr.read(MIDELEG))),
+            SIP => Some((MIP, self.csr.read(MIDELEG))),
+            _ => None,
+        }
+    }
+
+    /// `reg` as software reads it, views and controller bits included.
+    #[inline]
+    pub fn csr_read(&self, reg: usize) -> u64 {
+        let (reg, mask) = self.s_view(reg).unwrap_or((reg, u64::MAX));
+        let value = if reg == MIP { self.mip() } else { self.csr.read(reg) };
+        value & mask
+    }
+
+    /// Whether the current mode may access `reg`: csr[9:8] is the lowest
+    /// privilege allowed, csr[11:10] == 0b11 marks it read-only.
+    #[inline]
+    pub fn csr_allows(&self, reg: usize, write: bool) -> bool {
+        (reg >> 8) & 0b11 <= self.mode.get() as usize && !(write && reg >> 10 == 0b11)
+    }
+
+    /// Software write of `reg`, through a view to what is under it; `None`
+    /// if it is read-only.
+    pub fn csr_write(&self, reg: usize, value: u64) -> Option<()> {
+        let writable = write_mask(reg)?;
+        match self.s_view(reg) {
+            Some((reg, mask)) => {
+                let mask = mask & writable;
+                self.csr.store(reg, (self.csr.read(reg) & !mask) | (value & mask));
+            },
+            None => self.csr.write(reg, value)?,
+        }
+        Some(())
+    }
+
+    /// The interrupt to take before the next instruction: the first in
+    /// `RawInstrrupt::PRIORITY` that is pending and enabled in `mie`, those
+    /// for M-mode before those delegated to S-mode.
+    #[inline]
+    pub fn pending_interrupt(&self) -> Option<RawInstrrupt> {
+        let pending = self.mip() & self.csr.read(MIE);
+        if pending == 0 {
+            return None;
+        }
+        self.select_interrupt(pending)
+    }
+
+    /// An interrupt for a mode is masked by its global enable while running
+    /// in it, always taken from below and never from above.
+    fn select_interrupt(&self, pending: u64) -> Option<RawInstrrupt> {
+        let mstatus = MStatus::from_bytes(self.csr.read(MSTATUS).to_le_bytes());
+        let mideleg = self.csr.read(MIDELEG);
+        let mode = self.mode.get();
+        let m_enabled = mode < MachineMode::Machine || mstatus.mie() == 1;
+        let s_enabled = mode < MachineMode::Supervisor || (mode == MachineMode::Supervisor && mstatus.sie() == 1);
+        let first = |bits: u64| RawInstrrupt::PRIORITY.into_iter().find(|x| bits >> *x as u64 & 1 == 1);
+        m_enabled.then(|| first(pending & !mideleg)).flatten()
+            .or_else(|| s_enabled.then(|| first(pending & mideleg)).flatten())
+    }
+
+    /// Before each instruction: takes the interrupt that is due, if any.
+    #[inline]
+    pub fn check_interrupts(&self) {
+        if let Some(irq) = self.pending_interrupt() {
+            self.interrupted.set(Some(1 << 63 | irq as u64));
+            self.interrupt_request(irq);
+        }
+    }
 
-        if mstatus.mie() == 1 && mie == 1 && mip == 1 {
-            self.csr.store(MEPC, self.pc.read());
-            self.pc.store(tvec.get_pc(RawTrapType::Exception, cause));
+    /// Enters the handler for `code` in `target` mode, stacking the
+    /// interrupt enable and the privilege in its half of `mstatus`.
+    fn trap(&self, target: MachineMode, trap: RawTrapType, code: u64, tval: u64) {
+        let mut mstatus = MStatus::from_bytes(self.csr.read(MSTATUS).to_le_bytes());
+        let cause = MCause::new()
+            .with_exception_code(code)
+            .with_is_interrupt(trap as u8);
+        let (epc, cause_reg, tval_reg, tvec) = match target {
+            MachineMode::Supervisor => (SEPC, SCAUSE, STVAL, STVEC),
+            _ => (MEPC, MCAUSE, MTVAL, MTVEC),
+        };
+        let tvec = Tvec::from_bytes(self.csr.read(tvec).to_le_bytes());
+
+        self.csr.store(epc, self.pc.read());
+        self.csr.store(cause_reg, u64::from_le_bytes(cause.into_bytes()));
+        self.csr.store(tval_reg, tval);
+        self.pc.store(tvec.get_pc(trap, code));
+        if target == MachineMode::Supervisor {
+            mstatus.set_spie(mstatus.sie());
+            mstatus.set_sie(0);
+            mstatus.set_spp(if self.mode.get() == MachineMode::User { SUMachineMode::User } else { SUMachineMode::Supervisor });
+        } else {
             mstatus.set_mpie(mstatus.mie());
             mstatus.set_mie(0);
             mstatus.set_mpp(self.mode.get());
-            self.csr.store(MTVAL, tval);
-            self.csr.store(MSTATUS, u64::from_le_bytes(mstatus.into_bytes()));
         }
-
-        Some(())
+        self.csr.store(MSTATUS, u64::from_le_bytes(mstatus.into_bytes()));
+        self.mode.set(target);
+        self.waiting.set(false);
     }
 
     #[inline]
     pub fn mret(&self) {
         let mut mstatus = MStatus::from_bytes(self.csr.read(MSTATUS).to_le_bytes());
         mstatus.set_mie(mstatus.mpie());
+        mstatus.set_mpie(1);
         self.mode.set(mstatus.mpp());
+        mstatus.set_mpp(MachineMode::User);
         self.csr.store(MSTATUS, u64::from_le_bytes(mstatus.into_bytes()));
         self.pc.store(self.csr.read(MEPC));
     }
 
+    /// Illegal below S-mode, and in it with `mstatus.TSR` set.
+    #[inline]
+    pub fn sret(&self) -> Result<(), Exception> {
+        let mut mstatus = MStatus::from_bytes(self.csr.read(MSTATUS).to_le_bytes());
+        let mode = self.mode.get();
+        if mode < MachineMode::Supervisor || (mode == MachineMode::Supervisor && mstatus.tsr() == 1) {
+            return Err(Exception::IllegalInstruction);
+        }
+        mstatus.set_sie(mstatus.spie());
+        mstatus.set_spie(1);
+        self.mode.set(match mstatus.spp() {
+            SUMachineMode::User => MachineMode::User,
+            SUMachineMode::Supervisor => MachineMode::Supervisor,
+        });
+        mstatus.set_spp(SUMachineMode::User);
+        self.csr.store(MSTATUS, u64::from_le_bytes(mstatus.into_bytes()));
+        self.pc.store(self.csr.read(SEPC));
+        Ok(())
+    }
+
+    /// Stops the hart until `wake`, unless there is no clock to wait on;
+    /// illegal below M-mode with `mstatus.TW` set.
+    #[inline]
+    pub fn wfi(&self, memory: &dyn MMIODevice) -> Result<(), Exception> {
+        let mstatus = MStatus::from_bytes(self.csr.read(MSTATUS).to_le_bytes());
+        if self.mode.get() < MachineMode::Machine && mstatus.tw() == 1 {
+            return Err(Exception::IllegalInstruction);
+        }
+        if !self.wake() && memory.clock().is_some() {
+            self.waiting.set(true);
+        }
+        Ok(())
+    }
+
+    /// Whether a hart in `wfi` may go on: an interrupt is pending and
+    /// enabled in `mie`, taken or not.
+    #[inline]
+    pub fn wake(&self) -> bool {
+        self.mip() & self.csr.read(MIE) != 0
+    }
+
     #[inline]
     pub fn check_inst_access(&self, mode: MachineMode) {
         if self.mode.get() < mode {
-            self.exception_request(Exception::InstructionAccessFault);
+            self.exception_request(Exception::InstructionAccessFault(self.pc.read()));
         }
     }
 
@@ -140,8 +318,8 @@ impl MachineModel {
         let e = match self.mode.get() {
             MachineMode::User => Exception::UserEcall,
             MachineMode::Supervisor => Exception::SupervisorEcall,
-            MachineMode::Hypervisor => todo!(),
-            MachineMode::Machine => Exception::MachineEcall,
+            // never entered, `CSR::write` keeps MPP off H-mode
+            MachineMode::Hypervisor | MachineMode::Machine => Exception::MachineEcall,
         };
         self.exception_request(e);
     }
@@ -162,8 +340,15 @@ impl ExceptionProcessable<Exception> for MachineModel {
 
     fn exception_log(&self, memory: &dyn MMIODevice, e: Result<(), Exception>) -> Result<(), Exception> {
         if let Err(e) = e {
+            let unhandled = !e.is_debugger_trap() && self.csr.read(MTVEC) & !0b11 == 0;
+            if e.is_fatal() || unhandled {
+                self.iring.dump();
+            }
+            if unhandled {
+                eprintln!("[lemu] no trap handler installed (mtvec is 0)");
+            }
             match e {
-                Exception::InstructionAccessFault => eprintln!("[lemu] InstructionAccessFault, pc at {:8x}", self.pc.read()),
+                Exception::InstructionAccessFault(tval) => eprintln!("[lemu] InstructionAccessFault at {:8x}, pc at 0x{:8x}", tval, self.pc.read()),
                 Exception::IllegalInstruction => {
                     let inst = memory.read_u32(self.pc.read() as usize).unwrap();
                     eprintln!("[lemu] IllegalInstruction 0x{:8x}, pc at 0x{:8x}", inst, self.pc.read());
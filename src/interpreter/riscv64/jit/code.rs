@@ -0,0 +1,73 @@
+//! The code cache: one executable mapping filled front to back and emptied
+//! in one go.
+
+
+/// Bytes of host code kept before everything is thrown away.
+pub const CODE_SIZE: usize = 16 * 1024 * 1024;
+
+#[derive(Debug)]
+pub struct CodeBuffer {
+    ptr: *mut u8,
+    used: usize,
+}
+
+impl CodeBuffer {
+    /// None where the host will not hand out executable memory.
+    #[cfg(all(unix, target_arch = "x86_64"))]
+    pub fn new() -> Option<CodeBuffer> {
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                CODE_SIZE,
+                libc::PROT_READ | libc::PROT_WRITE | libc::PROT_EXEC,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0)
+        };
+        if ptr == libc::MAP_FAILED {
+            return None;
+        }
+        Some(CodeBuffer { ptr: ptr as *mut u8, used: 0 })
+    }
+
+    #[cfg(not(all(unix, target_arch = "x86_64")))]
+    pub fn new() -> Option<CodeBuffer> {
+        None
+    }
+
+    /// Where the next `push` lands.
+    #[inline]
+    pub fn next(&self) -> usize {
+        self.ptr as usize + self.used
+    }
+
+    /// Copies `code` in; None when it is full.
+    pub fn push(&mut self, code: &[u8]) -> Option<usize> {
+        if self.used + code.len() > CODE_SIZE {
+            return None;
+        }
+        let at = self.next();
+        unsafe { std::ptr::copy_nonoverlapping(code.as_ptr(), at as *mut u8, code.len()) };
+        self.used += code.len();
+        Some(at)
+    }
+
+    /// Overwrites code already pushed.
+    pub fn patch(&mut self, at: usize, code: &[u8]) {
+        assert!(at >= self.ptr as usize && at + code.len() <= self.next());
+        unsafe { std::ptr::copy_nonoverlapping(code.as_ptr(), at as *mut u8, code.len()) };
+    }
+
+    pub fn clear(&mut self) {
+        self.used = 0;
+    }
+}
+
+impl Drop for CodeBuffer {
+    fn drop(&mut self) {
+        #[cfg(all(unix, target_arch = "x86_64"))]
+        unsafe {
+            libc::munmap(self.ptr as *mut libc::c_void, CODE_SIZE);
+        }
+    }
+}
@@ -0,0 +1,515 @@
+//! Dynamic translation of hot RV64 basic blocks to x86-64: a pc is run by
+//! the interpreter until it was reached `HOT` times. Only RV64I is
+//! translated, which is all `misa` claims; LR/SC run in the interpreter,
+//! and M, the AMOs and C are not decoded at all.
+//!
+//! Guest registers live in a [`Context`] that the host code addresses
+//! through rbx. Loads and stores to the largest host-backed RAM region are
+//! done inline, anything else calls back into the `Device`. Blocks jump
+//! straight into each other once both ends are translated. Instructions the
+//! translator does not handle (CSRs, traps, FENCE.I) and faulting accesses
+//! are run by the interpreter, so exceptions come out of `exec_once` as
+//! usual; the iring only sees those. Traces need every instruction to go
+//! through `retire`, so builds with tracing fall back to the block engine,
+//! as do non-x86-64 hosts.
+
+pub mod x86;
+pub mod code;
+pub mod translate;
+
+use std::{
+    cell::{Cell, RefCell},
+    collections::{HashMap, HashSet},
+};
+
+use crate::device::{MMIODevice, Access};
+
+use super::{machine::MachineModel, irq::Exception, decode::decode};
+use code::CodeBuffer;
+
+
+const PAGE_SHIFT: u64 = 12;
+const MAX_BLOCK: usize = 64;
+/// Visits to a pc before the block there is translated.
+const HOT: u32 = 16;
+
+/// The pc is set, find the block there.
+pub const EXIT_RETURN: u32 = 1;
+/// Not enough budget left for the block at the pc.
+pub const EXIT_BUDGET: u32 = 2;
+/// Leave the instruction at the pc to the interpreter.
+pub const EXIT_INTERP: u32 = 3;
+// anything else is the address of a jump to link
+
+pub const STATUS_OK: u32 = 0;
+pub const STATUS_FAULT: u32 = 1;
+/// done, but translated code was overwritten
+pub const STATUS_STOP: u32 = 2;
+
+/// `push rbx; mov rbx, rdi`, which linked jumps skip.
+const PROLOGUE: usize = 4;
+
+#[repr(C)]
+#[derive(Debug)]
+pub struct Context {
+    pub gpr: [u64; 32],
+    pub pc: u64,
+    /// instructions the run may still retire
+    pub budget: u64,
+    /// guest address of the RAM on the fast path
+    ram_base: u64,
+    ram_host: *mut u8,
+    /// offsets below which accesses of 1, 2, 4 and 8 bytes stay in the
+    /// RAM, 0 without any
+    ram_limit: [u64; 4],
+    /// a byte per RAM page, nonzero where there is translated code or
+    /// code the other engines decoded
+    code_pages: *const u8,
+    /// nonzero while a hart holds an LR reservation, which any store may
+    /// break
+    reserved: *const u8,
+    /// the `Env` of the run
+    env: *const (),
+}
+
+// field offsets for the host code
+pub const GPR: i32 = 0;
+pub const PC: i32 = 256;
+pub const BUDGET: i32 = 264;
+pub const RAM_BASE: i32 = 272;
+pub const RAM_HOST: i32 = 280;
+pub const RAM_LIMIT: i32 = 288;
+pub const CODE_PAGES: i32 = 320;
+pub const RESERVED: i32 = 328;
+
+struct Env<'a> {
+    machine: &'a MachineModel,
+    memory: &'a dyn MMIODevice,
+}
+
+type Entry = unsafe extern "sysv64" fn(*mut Context) -> u64;
+/// (context, address, what to access) to a `STATUS_*`
+pub type Helper = extern "sysv64" fn(&mut Context, u64, u32) -> u32;
+
+/// Translated blocks and the code cache behind them. Dropping translations
+/// is deferred to the next `exec_jit` iteration, as the code asking for it
+/// may be running.
+#[derive(Debug, Default)]
+pub struct Jit {
+    code: RefCell<Option<CodeBuffer>>,
+    /// whether `code` was asked for yet
+    mapped: Cell<bool>,
+    /// host entry of the block at each pc, None where the interpreter has
+    /// to run the first instruction
+    blocks: RefCell<HashMap<u64, Option<usize>>>,
+    /// visits to the pcs not in `blocks` yet
+    visits: RefCell<HashMap<u64, u32>>,
+    /// guest pages holding translated code
+    pages: RefCell<HashSet<u64>>,
+    /// the same for the fast RAM, plus the pages of the icache and the
+    /// block cache, as `Context::code_pages`
+    page_map: RefCell<Vec<u8>>,
+    /// base, length and host address of the fast RAM
+    ram: Cell<(u64, u64, usize)>,
+    dirty: Cell<bool>,
+}
+
+/// Copies (snapshots) start cold.
+impl Clone for Jit {
+    fn clone(&self) -> Jit {
+        Jit::default()
+    }
+}
+
+impl Jit {
+    /// `len` bytes at `addr` were written.
+    #[inline]
+    pub fn invalidate(&self, addr: u64, len: u64) {
+        let pages = self.pages.borrow();
+        if pages.is_empty() {
+            return;
+        }
+        if pages.contains(&(addr >> PAGE_SHIFT)) || pages.contains(&(addr.wrapping_add(len - 1) >> PAGE_SHIFT)) {
+            self.dirty.set(true);
+        }
+    }
+
+    #[inline]
+    pub fn flush(&self) {
+        self.dirty.set(true);
+    }
+
+    fn reset(&self) {
+        if let Some(code) = self.code.borrow_mut().as_mut() {
+            code.clear();
+        }
+        self.blocks.borrow_mut().clear();
+        self.visits.borrow_mut().clear();
+        self.pages.borrow_mut().clear();
+        self.page_map.borrow_mut().iter_mut().for_each(|x| *x = 0);
+        self.dirty.set(false);
+    }
+
+    /// Maps the code cache on first use; whether there is one.
+    fn ready(&self) -> bool {
+        if !self.mapped.replace(true) {
+            *self.code.borrow_mut() = CodeBuffer::new();
+        }
+        self.code.borrow().is_some()
+    }
+
+    /// Picks the largest RAM region the host can hand out, dropping all
+    /// translations if that changed.
+    fn map_ram(&self, memory: &dyn MMIODevice) {
+        let ram = memory.host_ram().into_iter()
+            .max_by_key(|x| x.1)
+            .map_or((0, 0, 0), |(start, len, host)| (start as u64, len as u64, host as usize));
+        if ram != self.ram.get() {
+            self.ram.set(ram);
+            *self.page_map.borrow_mut() = vec![0; (ram.1 >> PAGE_SHIFT) as usize + 1];
+            self.reset();
+        }
+    }
+
+    fn context(&self, env: &Env) -> Context {
+        let (base, len, host) = self.ram.get();
+        let limit = |size: u64| (len + 1).saturating_sub(size);
+        Context {
+            gpr: [0; 32],
+            pc: 0,
+            budget: 0,
+            ram_base: base,
+            ram_host: host as *mut u8,
+            ram_limit: [limit(1), limit(2), limit(4), limit(8)],
+            code_pages: self.page_map.borrow().as_ptr(),
+            reserved: env.machine.shared.reserved_flag(),
+            env: env as *const Env as *const (),
+        }
+    }
+
+    /// The host entry of the block at `pc`, translating it once it is hot.
+    fn lookup(&self, pc: u64, memory: &dyn MMIODevice) -> Option<usize> {
+        if let Some(entry) = self.blocks.borrow().get(&pc) {
+            return *entry;
+        }
+        let mut visits = self.visits.borrow_mut();
+        let n = visits.entry(pc).or_default();
+        *n += 1;
+        if *n < HOT {
+            return None;
+        }
+        visits.remove(&pc);
+        drop(visits);
+        let entry = self.translate(pc, memory);
+        self.blocks.borrow_mut().insert(pc, entry);
+        entry
+    }
+
+    fn translate(&self, pc: u64, memory: &dyn MMIODevice) -> Option<usize> {
+        if pc & 0b11 != 0 {
+            return None;
+        }
+        if !memory.pma(pc as usize).is_some_and(|x| x.cacheable) {
+            return None;
+        }
+        let mut insts = Vec::new();
+        let mut addr = pc;
+        while let Some(code) = memory.read_u32(addr as usize).filter(|_| memory.allows(Access::Exec, addr as usize, 4)) {
+            let inst = decode(code);
+            if !translate::supported(&inst) {
+                break;
+            }
+            insts.push(inst);
+            addr += 4;
+            if translate::ends_block(&inst) || addr >> PAGE_SHIFT != pc >> PAGE_SHIFT || insts.len() == MAX_BLOCK {
+                break;
+            }
+        }
+        if insts.is_empty() {
+            return None;
+        }
+        self.mark(pc, addr);
+        let host = translate::translate(pc, &insts);
+        let mut code = self.code.borrow_mut();
+        let code = code.as_mut()?;
+        let at = match code.push(&host) {
+            Some(at) => at,
+            None => {
+                // full: start over, keeping only this block
+                code.clear();
+                self.blocks.borrow_mut().clear();
+                code.push(&host)?
+            },
+        };
+        Some(at)
+    }
+
+    /// Notes code in `[start, end)`.
+    fn mark(&self, start: u64, end: u64) {
+        self.pages.borrow_mut().insert(start >> PAGE_SHIFT);
+        self.guard(&[start >> PAGE_SHIFT, (end - 1) >> PAGE_SHIFT]);
+    }
+
+    /// Sends stores to `pages` down the slow path, which keeps the code
+    /// caches up to date.
+    fn guard(&self, pages: &[u64]) {
+        let (base, len, _) = self.ram.get();
+        let mut map = self.page_map.borrow_mut();
+        for page in pages {
+            let offset = (page << PAGE_SHIFT).wrapping_sub(base);
+            if offset < len {
+                map[(offset >> PAGE_SHIFT) as usize] = 1;
+            }
+        }
+    }
+
+    /// Turns the jump at `site` into one to the block at `target`, if that
+    /// is translated.
+    fn link(&self, site: usize, target: u64) {
+        let to = match self.blocks.borrow().get(&target) {
+            Some(Some(to)) => *to,
+            _ => return,
+        };
+        if self.dirty.get() {
+            return;
+        }
+        if let Some(code) = self.code.borrow_mut().as_mut() {
+            code.patch(site, &x86::jmp_bytes(site, to + PROLOGUE));
+        }
+    }
+}
+
+/// Loads for the host code that missed the fast path.
+extern "sysv64" fn jit_load(ctx: &mut Context, addr: u64, info: u32) -> u32 {
+    let env = unsafe { &*(ctx.env as *const Env) };
+    let (rd, size, signed) = (info as u8 as usize, (info >> 8) as u8, info >> 16 != 0);
+    match env.machine.read(addr, size, signed, env.memory) {
+        Ok(value) => {
+            if rd != 0 {
+                ctx.gpr[rd] = value;
+            }
+            STATUS_OK
+        },
+        Err(_) => STATUS_FAULT,
+    }
+}
+
+/// Stores for the host code that missed the fast path.
+extern "sysv64" fn jit_store(ctx: &mut Context, addr: u64, info: u32) -> u32 {
+    let env = unsafe { &*(ctx.env as *const Env) };
+    let (rs2, size) = (info as u8 as usize, (info >> 8) as u8);
+    match env.machine.store(addr, ctx.gpr[rs2], size, env.memory) {
+        // a store to a device may have raised an interrupt
+        Ok(()) if env.machine.jit.dirty.get() || env.machine.pending_interrupt().is_some() => STATUS_STOP,
+        Ok(()) => STATUS_OK,
+        Err(_) => STATUS_FAULT,
+    }
+}
+
+impl MachineModel {
+    /// Runs translated code from the pc until `max` instructions or a trap;
+    /// how many instructions retired.
+    pub fn exec_jit(&self, memory: &dyn MMIODevice, max: usize) -> (usize, Result<(), Exception>) {
+        // the inline RAM accesses know nothing of PMP, nor of other harts
+        if cfg!(feature = "tracer") || !self.jit.ready() || self.pmp_enforced() || self.shared.has_peers() {
+            return self.exec_block(memory, max);
+        }
+        self.jit.map_ram(memory);
+        self.guard_code_caches();
+        let env = Env { machine: self, memory };
+        let mut ctx = self.jit.context(&env);
+        ctx.gpr = self.gpr.get_all();
+        ctx.pc = self.pc.read();
+        ctx.budget = max as u64;
+        let r = self.jit_loop(&mut ctx, memory);
+        self.gpr.set_all(&ctx.gpr);
+        self.pc.store(ctx.pc);
+        (max - ctx.budget as usize, r)
+    }
+
+    fn jit_loop(&self, ctx: &mut Context, memory: &dyn MMIODevice) -> Result<(), Exception> {
+        while ctx.budget > 0 {
+            if self.pmp_enforced() {
+                // the interpreter changed mode or PMP, see `exec_jit`
+                break;
+            }
+            if self.waiting.get() || self.pending_interrupt().is_some() {
+                break;
+            }
+            if self.jit.dirty.get() {
+                self.jit.reset();
+                self.guard_code_caches();
+            }
+            let entry = match self.jit.lookup(ctx.pc, memory) {
+                Some(entry) => entry,
+                None => {
+                    self.jit_interp(ctx, memory)?;
+                    continue;
+                },
+            };
+            let entry: Entry = unsafe { std::mem::transmute(entry) };
+            match unsafe { entry(ctx) } {
+                x if x == EXIT_RETURN as u64 => {},
+                x if x == EXIT_INTERP as u64 => self.jit_interp(ctx, memory)?,
+                // less than a block to go
+                x if x == EXIT_BUDGET as u64 => while ctx.budget > 0 && !self.waiting.get() {
+                    self.jit_interp(ctx, memory)?;
+                },
+                site => self.jit.link(site as usize, ctx.pc),
+            }
+        }
+        Ok(())
+    }
+
+    /// The fast path writes RAM behind the back of the icache and the block
+    /// cache, so it stays off their pages.
+    fn guard_code_caches(&self) {
+        self.jit.guard(&self.icache.pages());
+        self.jit.guard(&self.blocks.pages());
+    }
+
+    /// One instruction at the context's pc through the interpreter; the
+    /// icache is skipped as it may be stale.
+    fn jit_interp(&self, ctx: &mut Context, memory: &dyn MMIODevice) -> Result<(), Exception> {
+        self.gpr.set_all(&ctx.gpr);
+        self.pc.store(ctx.pc);
+        let r = self.read_code(ctx.pc, memory).and_then(|code| self.retire(code, decode(code), memory));
+        ctx.gpr = self.gpr.get_all();
+        ctx.pc = self.pc.read();
+        if r.is_ok() {
+            ctx.budget -= 1;
+        }
+        r
+    }
+}
+
+
+#[test]
+fn test_context_layout() {
+    let ctx = Context {
+        gpr: [0; 32], pc: 0, budget: 0, ram_base: 0, ram_host: std::ptr::null_mut(),
+        ram_limit: [0; 4], code_pages: std::ptr::null(), reserved: std::ptr::null(), env: std::ptr::null(),
+    };
+    let offset = |field: *const u8| field as usize - &ctx as *const Context as usize;
+    assert_eq!(offset(&ctx.pc as *const u64 as *const u8), PC as usize);
+    assert_eq!(offset(&ctx.budget as *const u64 as *const u8), BUDGET as usize);
+    assert_eq!(offset(&ctx.ram_base as *const u64 as *const u8), RAM_BASE as usize);
+    assert_eq!(offset(&ctx.ram_host as *const *mut u8 as *const u8), RAM_HOST as usize);
+    assert_eq!(offset(&ctx.ram_limit as *const [u64; 4] as *const u8), RAM_LIMIT as usize);
+    assert_eq!(offset(&ctx.code_pages as *const *const u8 as *const u8), CODE_PAGES as usize);
+    assert_eq!(offset(&ctx.reserved as *const *const u8 as *const u8), RESERVED as usize);
+}
+
+#[test]
+fn test_jit() {
+    use crate::{
+        device::Device, memory::Memory,
+        abstract_machine::{Execable, Readable},
+        interpreter::riscv64::block::Engine,
+    };
+    // sums into RAM on the fast path and a smaller region on the slow one,
+    // patches its own loop and runs it again until the stores leave RAM
+    let program = [
+        0x06400593u32,  // li a1, 100
+        0x00000513,     // li a0, 0
+        0x00001297,     // auipc t0, 1
+        0x00b50533,     // 1: add a0, a0, a1
+        0x00a2b023,     // sd a0, 0(t0)
+        0x00828293,     // addi t0, t0, 8
+        0xfff58593,     // addi a1, a1, -1
+        0xfe0598e3,     // bnez a1, 1b
+        0x00004317,     // auipc t1, 4
+        0x00a33023,     // sd a0, 0(t1)
+        0x00033603,     // ld a2, 0(t1)
+        0x00300593,     // li a1, 3
+        0x40b506b7,     // lui a3, 0x40b50
+        0x53368693,     // addi a3, a3, 0x533
+        0x00000397,     // auipc t2, 0
+        0xfcd3aa23,     // sw a3, -44(t2): 1b becomes sub a0, a0, a1
+        0xfcdff06f,     // j 1b
+    ];
+    let run = |engine: Engine, steps: usize| {
+        let mm = MachineModel::new(0);
+        mm.engine.set(engine);
+        let mut image: Vec<u8> = program.into_iter().flat_map(|x: u32| x.to_le_bytes()).collect();
+        image.resize(0x2000, 0);
+        let mmio = Device::new();
+        mmio.add_device("ram", 0x80000000, Box::new(Memory::from(image.as_ref()))).unwrap();
+        mmio.add_device("scratch", 0x80004000, Box::new(Memory::new(0x100))).unwrap();
+        mm.pc.store(0x80000000);
+        let r = mm.setp_num(&mmio, steps);
+        let regs = (0..32).map(|x| mm.gpr.read(x)).collect::<Vec<_>>();
+        (r, mm.pc.read(), regs, mmio.read_u64(0x80001008 + 8 * 99), mmio.read_u64(0x80001ff8), mmio.read_u32(0x8000000c))
+    };
+    for steps in [1, 5, 17, 200, 503, 520, 600, 5000] {
+        assert_eq!(run(Engine::Jit, steps), run(Engine::Interp, steps), "after {} steps", steps);
+    }
+    assert_eq!(run(Engine::Jit, 5000).0, Err(Exception::StoreAccessFault(0x80002000)));
+    // a store between lr and sc fails the sc, translated or not
+    let program = [
+        0x00001297u32,  // auipc t0, 1
+        0x1002b52f,     // 1: lr.d a0, (t0)
+        0x00b2b023,     // sd a1, 0(t0)
+        0x18b2b62f,     // sc.d a2, a1, (t0)
+        0x00c686b3,     // add a3, a3, a2
+        0xff1ff06f,     // j 1b
+    ];
+    let run = |engine: Engine, steps: usize| {
+        let mm = MachineModel::new(0);
+        mm.engine.set(engine);
+        let mut image: Vec<u8> = program.into_iter().flat_map(|x: u32| x.to_le_bytes()).collect();
+        image.resize(0x2000, 0);
+        let mmio = Device::new();
+        mmio.add_device("ram", 0x80000000, Box::new(Memory::from(image.as_ref()))).unwrap();
+        mm.pc.store(0x80000000);
+        mm.setp_num(&mmio, steps).unwrap();
+        (mm.pc.read(), mm.gpr.read(12), mm.gpr.read(13))
+    };
+    assert_eq!(run(Engine::Jit, 1001), run(Engine::Interp, 1001));
+    assert_eq!(run(Engine::Jit, 1001), (0x80000004, 1, 200));
+    // translated code rewrites code the interpreter decoded, which keeps
+    // the rest
+    let program = [
+        0x00001297u32,  // auipc t0, 1
+        0x00b2a023,     // 1: sw a1, 0(t0)
+        0xfff60613,     // addi a2, a2, -1
+        0xfe061ce3,     // bnez a2, 1b
+        0x0000006f,     // j .
+    ];
+    let mm = MachineModel::new(0);
+    let mut image: Vec<u8> = program.into_iter().flat_map(|x: u32| x.to_le_bytes()).collect();
+    image.resize(0x2000, 0);
+    // at 0x1000: addi a0, a0, 1; addi a0, a0, 1
+    image[0x1000..0x1008].copy_from_slice(&[0x13, 0x05, 0x15, 0x00, 0x13, 0x05, 0x15, 0x00]);
+    let mmio = Device::new();
+    mmio.add_device("ram", 0x80000000, Box::new(Memory::from(image.as_ref()))).unwrap();
+    mm.pc.store(0x80001000);
+    mm.setp_num(&mmio, 2).unwrap();
+    mm.engine.set(Engine::Jit);
+    mm.pc.store(0x80000000);
+    // addi a0, a0, 2
+    mm.gpr.store(11, 0x00250513);
+    mm.gpr.store(12, 100);
+    mm.setp_num(&mmio, 1 + 3 * 100).unwrap();
+    assert!(mm.icache.get(0x80001004).is_some());
+    mm.engine.set(Engine::Interp);
+    mm.pc.store(0x80001000);
+    mm.setp_num(&mmio, 2).unwrap();
+    assert_eq!(mm.gpr.read(10), 5);
+    if cfg!(feature = "tracer") {
+        // which never translates
+        return;
+    }
+    // cold code is interpreted
+    let mm = MachineModel::new(0);
+    mm.engine.set(Engine::Jit);
+    // addi a0, a0, 1; jal x0, -4
+    let image: Vec<u8> = [0x00150513u32, 0xffdff06f].into_iter().flat_map(|x: u32| x.to_le_bytes()).collect();
+    let mem = Memory::from(image.as_ref());
+    mm.setp_num(&mem, 2 * (HOT as usize - 1)).unwrap();
+    assert!(mm.jit.blocks.borrow().is_empty());
+    mm.setp_num(&mem, 2).unwrap();
+    assert_eq!(mm.jit.blocks.borrow().len(), 1);
+    mm.setp_num(&mem, 100).unwrap();
+    assert_eq!((mm.gpr.read(10), mm.jit.blocks.borrow().len()), (HOT as u64 + 50, 1));
+}
@@ -0,0 +1,288 @@
+//! RV64 block to x86-64. The code is position independent, helpers are
+//! called through absolute addresses, and every way out leaves the pc and
+//! the budget right for the instruction it stopped at.
+
+use super::{
+    x86::{Asm, Reg, Cond, Arith, Shift},
+    GPR, PC, BUDGET, RAM_BASE, RAM_HOST, RAM_LIMIT, CODE_PAGES, RESERVED,
+    EXIT_RETURN, EXIT_BUDGET, EXIT_INTERP, STATUS_FAULT,
+    jit_load, jit_store, Helper,
+};
+use super::super::decode::{Inst, AluOp, BranchOp};
+
+
+#[inline]
+fn gpr(r: u8) -> i32 {
+    GPR + 8 * r as i32
+}
+
+/// The `ram_limit` entry for accesses of `size` bytes.
+#[inline]
+fn ram_limit(size: u8) -> i32 {
+    RAM_LIMIT + 8 * size.trailing_zeros() as i32
+}
+
+/// Whether the translator handles `inst`; the rest is left to the
+/// interpreter.
+pub fn supported(inst: &Inst) -> bool {
+    !matches!(inst, Inst::Lr { .. } | Inst::Sc { .. } | Inst::Csr { .. } | Inst::Ecall | Inst::Ebreak | Inst::Mret | Inst::Sret | Inst::Wfi | Inst::FenceI | Inst::Illegal)
+}
+
+/// Whether `inst` is the last of a block.
+pub fn ends_block(inst: &Inst) -> bool {
+    matches!(inst, Inst::Jal { .. } | Inst::Jalr { .. } | Inst::Branch { .. })
+}
+
+/// Host code for the block of `insts` at `start`.
+pub fn translate(start: u64, insts: &[Inst]) -> Vec<u8> {
+    let mut asm = Asm::default();
+    let n = insts.len() as i32;
+    // the prologue, skipped by linked jumps
+    asm.push_rbx();
+    asm.mov(Reg::Rbx, Reg::Rdi);
+    asm.arith_mem_imm(Arith::Cmp, BUDGET, n);
+    let short = asm.jcc(Cond::B);
+    asm.arith_mem_imm(Arith::Sub, BUDGET, n);
+    let mut pc = start;
+    let mut open = true;
+    for (i, inst) in insts.iter().enumerate() {
+        // this one and those after it
+        let left = n - i as i32;
+        match *inst {
+            Inst::Lui { rd, imm } => set(&mut asm, rd, imm),
+            Inst::Auipc { rd, imm } => set(&mut asm, rd, pc.wrapping_add(imm)),
+            Inst::Jal { rd, offset } => {
+                set(&mut asm, rd, pc + 4);
+                link(&mut asm, pc.wrapping_add(offset as u64));
+                open = false;
+            },
+            Inst::Jalr { rd, rs1, offset } => {
+                asm.load(Reg::Rax, gpr(rs1));
+                asm.arith_imm(Arith::Add, true, Reg::Rax, offset as i32);
+                asm.arith_imm(Arith::And, true, Reg::Rax, -2);
+                if rd != 0 {
+                    asm.mov_imm(Reg::Rcx, pc + 4);
+                    asm.store(gpr(rd), Reg::Rcx);
+                }
+                asm.store(PC, Reg::Rax);
+                asm.mov_imm32(Reg::Rax, EXIT_RETURN);
+                asm.pop_rbx();
+                asm.ret();
+                open = false;
+            },
+            Inst::Branch { op, rs1, rs2, offset } => {
+                asm.load(Reg::Rax, gpr(rs1));
+                asm.arith(Arith::Cmp, true, Reg::Rax, gpr(rs2));
+                let cond = match op {
+                    BranchOp::Eq => Cond::E,
+                    BranchOp::Ne => Cond::Ne,
+                    BranchOp::Lt => Cond::L,
+                    BranchOp::Ge => Cond::Ge,
+                    BranchOp::Ltu => Cond::B,
+                    BranchOp::Geu => Cond::Ae,
+                };
+                let taken = asm.jcc(cond);
+                link(&mut asm, pc + 4);
+                asm.bind(taken);
+                link(&mut asm, pc.wrapping_add(offset as u64));
+                open = false;
+            },
+            Inst::Load { rd, rs1, offset, size, signed } => {
+                address(&mut asm, rs1, offset);
+                let slow = in_ram(&mut asm, size);
+                asm.arith(Arith::Add, true, Reg::Rcx, RAM_HOST);
+                asm.load_host(size, signed);
+                if rd != 0 {
+                    asm.store(gpr(rd), Reg::Rax);
+                }
+                let done = asm.jmp();
+                asm.bind(slow);
+                call(&mut asm, jit_load as Helper as usize, rd as u32 | (size as u32) << 8 | (signed as u32) << 16);
+                check(&mut asm, pc, left);
+                asm.bind(done);
+            },
+            Inst::Store { rs1, rs2, offset, size } => {
+                address(&mut asm, rs1, offset);
+                let mut slow = vec![in_ram(&mut asm, size)];
+                // stores while a reservation is held take the slow path,
+                // which breaks it where the interpreter would
+                asm.load(Reg::Rdx, RESERVED);
+                asm.cmp_byte_rdx_zero();
+                slow.push(asm.jcc(Cond::Ne));
+                // pages with translated or decoded code take the slow path,
+                // which drops what the store overwrote
+                asm.mov(Reg::Rdx, Reg::Rcx);
+                slow.push(code_page(&mut asm));
+                if size > 1 {
+                    asm.lea_rdx_rcx(size as i8 - 1);
+                    slow.push(code_page(&mut asm));
+                }
+                asm.arith(Arith::Add, true, Reg::Rcx, RAM_HOST);
+                asm.load(Reg::Rdx, gpr(rs2));
+                asm.store_host(size);
+                let done = asm.jmp();
+                slow.into_iter().for_each(|x| asm.bind(x));
+                call(&mut asm, jit_store as Helper as usize, rs2 as u32 | (size as u32) << 8);
+                check(&mut asm, pc, left);
+                asm.bind(done);
+            },
+            Inst::OpImm { op, rd, rs1, imm } if rd != 0 => {
+                asm.load(Reg::Rax, gpr(rs1));
+                alu_imm(&mut asm, op, true, imm);
+                asm.store(gpr(rd), Reg::Rax);
+            },
+            Inst::OpImmW { op, rd, rs1, imm } if rd != 0 => {
+                asm.load(Reg::Rax, gpr(rs1));
+                alu_imm(&mut asm, op, false, imm);
+                asm.sext_rax();
+                asm.store(gpr(rd), Reg::Rax);
+            },
+            Inst::Op { op, rd, rs1, rs2 } if rd != 0 => {
+                asm.load(Reg::Rax, gpr(rs1));
+                alu(&mut asm, op, true, rs2);
+                asm.store(gpr(rd), Reg::Rax);
+            },
+            Inst::OpW { op, rd, rs1, rs2 } if rd != 0 => {
+                asm.load(Reg::Rax, gpr(rs1));
+                alu(&mut asm, op, false, rs2);
+                asm.sext_rax();
+                asm.store(gpr(rd), Reg::Rax);
+            },
+            // nops, fences
+            _ => {},
+        }
+        pc += 4;
+    }
+    if open {
+        link(&mut asm, pc);
+    }
+    asm.bind(short);
+    exit(&mut asm, start, EXIT_BUDGET);
+    asm.buf
+}
+
+/// rd = imm
+fn set(asm: &mut Asm, rd: u8, imm: u64) {
+    if rd != 0 {
+        asm.mov_imm(Reg::Rax, imm);
+        asm.store(gpr(rd), Reg::Rax);
+    }
+}
+
+/// Leaves for the caller with the pc at `pc`.
+fn exit(asm: &mut Asm, pc: u64, code: u32) {
+    asm.mov_imm(Reg::Rax, pc);
+    asm.store(PC, Reg::Rax);
+    asm.mov_imm32(Reg::Rax, code);
+    asm.pop_rbx();
+    asm.ret();
+}
+
+/// Goes on at `target`: returns its own address so the caller can turn
+/// the first five bytes into a jump once `target` is translated.
+fn link(asm: &mut Asm, target: u64) {
+    asm.here_rax();
+    asm.mov_imm(Reg::Rcx, target);
+    asm.store(PC, Reg::Rcx);
+    asm.pop_rbx();
+    asm.ret();
+}
+
+/// rax = rs1 + offset
+fn address(asm: &mut Asm, rs1: u8, offset: i64) {
+    asm.load(Reg::Rax, gpr(rs1));
+    if offset != 0 {
+        asm.arith_imm(Arith::Add, true, Reg::Rax, offset as i32);
+    }
+}
+
+/// rcx = the offset of rax into the fast RAM; the jump taken when the
+/// `size` bytes there are not all in it.
+fn in_ram(asm: &mut Asm, size: u8) -> usize {
+    asm.mov(Reg::Rcx, Reg::Rax);
+    asm.arith(Arith::Sub, true, Reg::Rcx, RAM_BASE);
+    asm.arith(Arith::Cmp, true, Reg::Rcx, ram_limit(size));
+    asm.jcc(Cond::Ae)
+}
+
+/// The jump taken when the RAM page at offset rdx holds code.
+fn code_page(asm: &mut Asm) -> usize {
+    asm.shift_imm(Shift::Shr, true, Reg::Rdx, 12);
+    asm.arith(Arith::Add, true, Reg::Rdx, CODE_PAGES);
+    asm.cmp_byte_rdx_zero();
+    asm.jcc(Cond::Ne)
+}
+
+/// helper(ctx, rax, info)
+fn call(asm: &mut Asm, helper: usize, info: u32) {
+    asm.mov(Reg::Rdi, Reg::Rbx);
+    asm.mov(Reg::Rsi, Reg::Rax);
+    asm.mov_imm32(Reg::Rdx, info);
+    asm.mov_imm(Reg::Rax, helper as u64);
+    asm.call_rax();
+}
+
+/// After a helper for the instruction at `pc`: a fault goes back to the
+/// interpreter to be raised there, a store that hit code stops the block.
+fn check(asm: &mut Asm, pc: u64, left: i32) {
+    asm.test_eax();
+    let ok = asm.jcc(Cond::E);
+    asm.cmp_eax(STATUS_FAULT as i8);
+    let stop = asm.jcc(Cond::Ne);
+    asm.arith_mem_imm(Arith::Add, BUDGET, left);
+    exit(asm, pc, EXIT_INTERP);
+    asm.bind(stop);
+    asm.arith_mem_imm(Arith::Add, BUDGET, left - 1);
+    exit(asm, pc + 4, EXIT_RETURN);
+    asm.bind(ok);
+}
+
+/// rax = rax op imm, 32-bit unless `wide`
+fn alu_imm(asm: &mut Asm, op: AluOp, wide: bool, imm: u64) {
+    let arith = |asm: &mut Asm, x| asm.arith_imm(x, wide, Reg::Rax, imm as i32);
+    match op {
+        AluOp::Add => arith(asm, Arith::Add),
+        AluOp::Sub => arith(asm, Arith::Sub),
+        AluOp::Xor => arith(asm, Arith::Xor),
+        AluOp::Or => arith(asm, Arith::Or),
+        AluOp::And => arith(asm, Arith::And),
+        AluOp::Slt => {
+            arith(asm, Arith::Cmp);
+            asm.set_rax(Cond::L);
+        },
+        AluOp::Sltu => {
+            arith(asm, Arith::Cmp);
+            asm.set_rax(Cond::B);
+        },
+        AluOp::Sll => asm.shift_imm(Shift::Shl, wide, Reg::Rax, imm as u8),
+        AluOp::Srl => asm.shift_imm(Shift::Shr, wide, Reg::Rax, imm as u8),
+        AluOp::Sra => asm.shift_imm(Shift::Sar, wide, Reg::Rax, imm as u8),
+    }
+}
+
+/// rax = rax op rs2, 32-bit unless `wide`
+fn alu(asm: &mut Asm, op: AluOp, wide: bool, rs2: u8) {
+    let arith = |asm: &mut Asm, x| asm.arith(x, wide, Reg::Rax, gpr(rs2));
+    let shift = |asm: &mut Asm, x| {
+        asm.load(Reg::Rcx, gpr(rs2));
+        asm.shift(x, wide, Reg::Rax);
+    };
+    match op {
+        AluOp::Add => arith(asm, Arith::Add),
+        AluOp::Sub => arith(asm, Arith::Sub),
+        AluOp::Xor => arith(asm, Arith::Xor),
+        AluOp::Or => arith(asm, Arith::Or),
+        AluOp::And => arith(asm, Arith::And),
+        AluOp::Slt => {
+            arith(asm, Arith::Cmp);
+            asm.set_rax(Cond::L);
+        },
+        AluOp::Sltu => {
+            arith(asm, Arith::Cmp);
+            asm.set_rax(Cond::B);
+        },
+        AluOp::Sll => shift(asm, Shift::Shl),
+        AluOp::Srl => shift(asm, Shift::Shr),
+        AluOp::Sra => shift(asm, Shift::Sar),
+    }
+}
@@ -0,0 +1,265 @@
+//! Just enough of an x86-64 assembler for the translator. Memory operands
+//! are `[rbx + disp32]`, rbx holding the context, or `[rcx]` for host RAM.
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reg {
+    Rax = 0,
+    Rcx = 1,
+    Rdx = 2,
+    Rbx = 3,
+    Rsi = 6,
+    Rdi = 7,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cond {
+    B = 0x2,
+    Ae = 0x3,
+    E = 0x4,
+    Ne = 0x5,
+    L = 0xc,
+    Ge = 0xd,
+}
+
+/// The `/digit` of the group-1 instructions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Arith {
+    Add = 0,
+    Or = 1,
+    And = 4,
+    Sub = 5,
+    Xor = 6,
+    Cmp = 7,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Shift {
+    Shl = 4,
+    Shr = 5,
+    Sar = 7,
+}
+
+/// `rm` of a `[rbx + disp32]` operand
+const RBX_DISP32: u8 = 0b10_000_011;
+
+#[derive(Debug, Default)]
+pub struct Asm {
+    pub buf: Vec<u8>,
+}
+
+impl Asm {
+    #[inline]
+    pub fn pos(&self) -> usize {
+        self.buf.len()
+    }
+
+    #[inline]
+    fn rex_w(&mut self, wide: bool) {
+        if wide {
+            self.buf.push(0x48);
+        }
+    }
+
+    #[inline]
+    fn disp(&mut self, disp: i32) {
+        self.buf.extend_from_slice(&disp.to_le_bytes());
+    }
+
+    /// `reg` as the reg field of a `[rbx + disp]` operand
+    #[inline]
+    fn ctx(&mut self, reg: u8, disp: i32) {
+        self.buf.push(RBX_DISP32 | reg << 3);
+        self.disp(disp);
+    }
+
+    /// mov reg, [rbx + disp]
+    pub fn load(&mut self, reg: Reg, disp: i32) {
+        self.buf.extend_from_slice(&[0x48, 0x8b]);
+        self.ctx(reg as u8, disp);
+    }
+
+    /// mov [rbx + disp], reg
+    pub fn store(&mut self, disp: i32, reg: Reg) {
+        self.buf.extend_from_slice(&[0x48, 0x89]);
+        self.ctx(reg as u8, disp);
+    }
+
+    /// mov reg, imm64
+    pub fn mov_imm(&mut self, reg: Reg, imm: u64) {
+        self.buf.extend_from_slice(&[0x48, 0xb8 + reg as u8]);
+        self.buf.extend_from_slice(&imm.to_le_bytes());
+    }
+
+    /// mov e?x, imm32, zero-extended
+    pub fn mov_imm32(&mut self, reg: Reg, imm: u32) {
+        self.buf.push(0xb8 + reg as u8);
+        self.buf.extend_from_slice(&imm.to_le_bytes());
+    }
+
+    /// mov dst, src
+    pub fn mov(&mut self, dst: Reg, src: Reg) {
+        self.buf.extend_from_slice(&[0x48, 0x89, 0xc0 | (src as u8) << 3 | dst as u8]);
+    }
+
+    /// op reg, [rbx + disp]
+    pub fn arith(&mut self, op: Arith, wide: bool, reg: Reg, disp: i32) {
+        self.rex_w(wide);
+        self.buf.push((op as u8) << 3 | 0x03);
+        self.ctx(reg as u8, disp);
+    }
+
+    /// op reg, simm32
+    pub fn arith_imm(&mut self, op: Arith, wide: bool, reg: Reg, imm: i32) {
+        self.rex_w(wide);
+        self.buf.extend_from_slice(&[0x81, 0xc0 | (op as u8) << 3 | reg as u8]);
+        self.disp(imm);
+    }
+
+    /// op qword [rbx + disp], simm32
+    pub fn arith_mem_imm(&mut self, op: Arith, disp: i32, imm: i32) {
+        self.buf.extend_from_slice(&[0x48, 0x81]);
+        self.ctx(op as u8, disp);
+        self.disp(imm);
+    }
+
+    /// op reg, cl
+    pub fn shift(&mut self, op: Shift, wide: bool, reg: Reg) {
+        self.rex_w(wide);
+        self.buf.extend_from_slice(&[0xd3, 0xc0 | (op as u8) << 3 | reg as u8]);
+    }
+
+    /// op reg, imm8
+    pub fn shift_imm(&mut self, op: Shift, wide: bool, reg: Reg, imm: u8) {
+        self.rex_w(wide);
+        self.buf.extend_from_slice(&[0xc1, 0xc0 | (op as u8) << 3 | reg as u8, imm]);
+    }
+
+    /// setcc al; movzx eax, al
+    pub fn set_rax(&mut self, cond: Cond) {
+        self.buf.extend_from_slice(&[0x0f, 0x90 | cond as u8, 0xc0, 0x0f, 0xb6, 0xc0]);
+    }
+
+    /// movsxd rax, eax
+    pub fn sext_rax(&mut self) {
+        self.buf.extend_from_slice(&[0x48, 0x63, 0xc0]);
+    }
+
+    /// lea rdx, [rcx + disp8]
+    pub fn lea_rdx_rcx(&mut self, disp: i8) {
+        self.buf.extend_from_slice(&[0x48, 0x8d, 0x51, disp as u8]);
+    }
+
+    /// rax = `size` bytes at [rcx], extended
+    pub fn load_host(&mut self, size: u8, signed: bool) {
+        let op: &[u8] = match (size, signed) {
+            (1, false) => &[0x48, 0x0f, 0xb6, 0x01],
+            (1, true) => &[0x48, 0x0f, 0xbe, 0x01],
+            (2, false) => &[0x48, 0x0f, 0xb7, 0x01],
+            (2, true) => &[0x48, 0x0f, 0xbf, 0x01],
+            (4, false) => &[0x8b, 0x01],
+            (4, true) => &[0x48, 0x63, 0x01],
+            _ => &[0x48, 0x8b, 0x01],
+        };
+        self.buf.extend_from_slice(op);
+    }
+
+    /// the low `size` bytes of rdx to [rcx]
+    pub fn store_host(&mut self, size: u8) {
+        let op: &[u8] = match size {
+            1 => &[0x88, 0x11],
+            2 => &[0x66, 0x89, 0x11],
+            4 => &[0x89, 0x11],
+            _ => &[0x48, 0x89, 0x11],
+        };
+        self.buf.extend_from_slice(op);
+    }
+
+    /// cmp byte [rdx], 0
+    pub fn cmp_byte_rdx_zero(&mut self) {
+        self.buf.extend_from_slice(&[0x80, 0x3a, 0x00]);
+    }
+
+    /// test eax, eax
+    pub fn test_eax(&mut self) {
+        self.buf.extend_from_slice(&[0x85, 0xc0]);
+    }
+
+    /// cmp eax, imm8
+    pub fn cmp_eax(&mut self, imm: i8) {
+        self.buf.extend_from_slice(&[0x83, 0xf8, imm as u8]);
+    }
+
+    /// lea rax, [rip - 7]: the address of this instruction
+    pub fn here_rax(&mut self) {
+        self.buf.extend_from_slice(&[0x48, 0x8d, 0x05]);
+        self.disp(-7);
+    }
+
+    pub fn call_rax(&mut self) {
+        self.buf.extend_from_slice(&[0xff, 0xd0]);
+    }
+
+    pub fn push_rbx(&mut self) {
+        self.buf.push(0x53);
+    }
+
+    pub fn pop_rbx(&mut self) {
+        self.buf.push(0x5b);
+    }
+
+    pub fn ret(&mut self) {
+        self.buf.push(0xc3);
+    }
+
+    /// jcc rel32 to be bound later; where the displacement goes.
+    pub fn jcc(&mut self, cond: Cond) -> usize {
+        self.buf.extend_from_slice(&[0x0f, 0x80 | cond as u8]);
+        self.disp(0);
+        self.pos() - 4
+    }
+
+    /// jmp rel32 to be bound later; where the displacement goes.
+    pub fn jmp(&mut self) -> usize {
+        self.buf.push(0xe9);
+        self.disp(0);
+        self.pos() - 4
+    }
+
+    /// Points the jump with its displacement at `at` here.
+    pub fn bind(&mut self, at: usize) {
+        let rel = (self.pos() - (at + 4)) as i32;
+        self.buf[at..at + 4].copy_from_slice(&rel.to_le_bytes());
+    }
+}
+
+/// `jmp rel32` from `from` to `to`, five bytes.
+pub fn jmp_bytes(from: usize, to: usize) -> [u8; 5] {
+    let rel = (to as i64 - (from as i64 + 5)) as i32;
+    let rel = rel.to_le_bytes();
+    [0xe9, rel[0], rel[1], rel[2], rel[3]]
+}
+
+
+#[test]
+fn test_x86() {
+    let mut asm = Asm::default();
+    asm.load(Reg::Rax, 0x50);
+    asm.arith(Arith::Add, true, Reg::Rax, 0x58);
+    asm.arith_imm(Arith::And, true, Reg::Rax, -2);
+    asm.shift(Shift::Sar, false, Reg::Rax);
+    asm.mov(Reg::Rdi, Reg::Rbx);
+    assert_eq!(asm.buf, [
+        0x48, 0x8b, 0x83, 0x50, 0, 0, 0,        // mov rax, [rbx+0x50]
+        0x48, 0x03, 0x83, 0x58, 0, 0, 0,        // add rax, [rbx+0x58]
+        0x48, 0x81, 0xe0, 0xfe, 0xff, 0xff, 0xff,   // and rax, -2
+        0xd3, 0xf8,                             // sar eax, cl
+        0x48, 0x89, 0xdf,                       // mov rdi, rbx
+    ]);
+    let mut asm = Asm::default();
+    let at = asm.jcc(Cond::Ne);
+    asm.ret();
+    asm.bind(at);
+    assert_eq!(asm.buf, [0x0f, 0x85, 1, 0, 0, 0, 0xc3]);
+    assert_eq!(jmp_bytes(0x1000, 0x1000), [0xe9, 0xfb, 0xff, 0xff, 0xff]);
+}
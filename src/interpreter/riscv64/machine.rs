@@ -1,22 +1,46 @@
-use std::cell::Cell;
+use std::{any::Any, cell::Cell, rc::Rc};
 
-use crate::abstract_machine::RegInfo;
+use lyuu_commons::disassembly::riscv::disassembly;
 
-use super::reg::{REG_MAP, RegType, csr::{CSR, base_misa, BaseISA, misa_flag, mstatus::MachineMode}, gpr::GPR, pc::PC};
+use crate::{abstract_machine::{RegInfo, Disassembler, Snapshot, InstRing, Harts}, device::{MMIODevice, irq::HartIrq}};
+
+use super::iring::{IRing, DEFAULT_IRING_SIZE};
+use super::icache::ICache;
+use super::block::{BlockCache, Engine};
+use super::jit::Jit;
+use super::pmp::{Pmp, is_pmp};
+use super::smp::Shared;
+use super::reg::{REG_MAP, RegType, csrmap::MHARTID, csr::{CSR, base_misa, BaseISA, mstatus::MachineMode}, gpr::GPR, fpr::FPR, pc::PC};
 
 #[derive(Debug, Clone)]
 pub struct MachineModel {
     pub gpr: GPR,
+    pub fpr: FPR,
     pub csr: CSR,
     pub pc: PC,
     pub mode: Cell<MachineMode>,
+    pub iring: IRing,
+    pub icache: ICache,
+    pub blocks: BlockCache,
+    pub engine: Cell<Engine>,
+    pub jit: Jit,
+    pub pmp: Pmp,
+    /// driven by the interrupt controllers, shared by snapshots
+    pub irq: Rc<HartIrq>,
+    /// stopped in `wfi`
+    pub waiting: Cell<bool>,
+    /// see `Execable::take_interrupt`
+    pub interrupted: Cell<Option<u64>>,
+    /// with the other harts of an `Smp`
+    pub shared: Rc<Shared>,
 }
 
 const MISA64: u64
     = base_misa(BaseISA::RV64I)
-    | misa_flag(b'm')
+    // not decoded yet; `disassemble` fetches 32 bits until c is
+    // | misa_flag(b'm')
     // | misa_flag(b'a')
-    | misa_flag(b'c')
+    // | misa_flag(b'c')
     ;
 
 impl MachineModel {
@@ -24,37 +48,161 @@ impl MachineModel {
     pub fn new(hart_id: u64) -> MachineModel {
         MachineModel {
             gpr: GPR::new(),
+            fpr: FPR::new(),
             csr: CSR::new(MISA64, hart_id),
             pc: PC::new(0),
             mode: Cell::new(MachineMode::Machine),
+            iring: IRing::new(DEFAULT_IRING_SIZE),
+            icache: ICache::default(),
+            blocks: BlockCache::default(),
+            engine: Cell::new(Engine::Interp),
+            jit: Jit::default(),
+            pmp: Pmp::default(),
+            irq: Rc::new(HartIrq::default()),
+            waiting: Cell::new(false),
+            interrupted: Cell::new(None),
+            shared: Rc::new(Shared::default()),
         }
     }
+
+    #[inline]
+    pub fn hart_id(&self) -> u64 {
+        self.csr.read(MHARTID)
+    }
+}
+
+pub const REG_NAMES: [&str; 33] = [
+    "pc",
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2",
+    "s0", "s1", "a0", "a1", "a2", "a3", "a4", "a5",
+    "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7",
+    "s8", "s9", "s10", "s11", "t3", "t4", "t5", "t6",
+];
+
+const CSR_NAMES: [&str; 20] = [
+    "mstatus", "misa", "medeleg", "mideleg", "mie", "mtvec", "mscratch",
+    "mepc", "mcause", "mtval", "mip", "mhartid",
+    "sstatus", "sie", "stvec", "sscratch", "sepc", "scause", "stval", "satp",
+];
+
+/// `$5` is x5, everything else goes through the name table.
+#[inline]
+fn lookup_reg(reg: &str) -> Option<(RegType, usize)> {
+    if let Ok(x) = reg.parse::<usize>() {
+        return if x < 32 {
+            Some((RegType::Gpr, x))
+        } else {
+            None
+        };
+    }
+    REG_MAP.get(reg).cloned()
 }
 
 impl RegInfo for MachineModel {
     #[inline]
     fn get_reg_value(&self, reg: &str) -> Option<u64> {
-        match reg {
-            "pc" => Some(self.pc.read()),
-            _ => {
-                if let Ok(x) = reg.parse::<usize>() {
-                    return if x < 32 {
-                        Some(self.gpr.read(x))
-                    } else {
-                        None
-                    };
-                }
-                let map = &REG_MAP;
-                let (rt, r) = map.get(reg)?;
-                if rt == &RegType::Gpr {
-                    Some(self.gpr.read(*r))
-                } else if rt == &RegType::Csr {
-                    Some(self.csr.read(*r))
-                } else {
-                    unimplemented!()
+        if reg == "pc" {
+            return Some(self.pc.read());
+        }
+        let (rt, r) = lookup_reg(reg)?;
+        match rt {
+            RegType::Gpr => Some(self.gpr.read(r)),
+            RegType::Fpr => Some(self.fpr.read(r)),
+            RegType::Csr => Some(self.csr_read(r)),
+        }
+    }
+
+    fn set_reg_value(&self, reg: &str, value: u64) -> Option<()> {
+        if reg == "pc" {
+            self.pc.store(value);
+            return Some(());
+        }
+        let (rt, r) = lookup_reg(reg)?;
+        match rt {
+            // x0 stays hardwired, GPR::store drops the write
+            RegType::Gpr => self.gpr.store(r, value),
+            RegType::Fpr => self.fpr.store(r, value),
+            RegType::Csr => {
+                // the monitor is not held to lock bits
+                self.csr_write(r, value)?;
+                if is_pmp(r) {
+                    self.pmp.flush();
                 }
             },
         }
+        Some(())
+    }
+
+    #[inline]
+    fn reg_names(&self) -> &'static [&'static str] {
+        &REG_NAMES
+    }
+
+    #[inline]
+    fn csr_names(&self) -> &'static [&'static str] {
+        &CSR_NAMES
+    }
+}
+
+impl Disassembler for MachineModel {
+    fn disassemble(&self, memory: &dyn MMIODevice, addr: u64) -> Option<(Vec<u8>, String)> {
+        let code = memory.read_u32(addr as usize)?;
+        let text = disassembly(code).map_or("unimp".to_string(), |x| x.0.to_string());
+        Some((code.to_le_bytes().to_vec(), text))
+    }
+}
+
+/// What a checkpoint keeps of a hart: the architectural state, no caches.
+struct HartState {
+    gpr: GPR,
+    fpr: FPR,
+    csrs: Vec<(u16, u64)>,
+    pc: u64,
+    mode: MachineMode,
+    waiting: bool,
+    /// the interrupt inputs, shared with the controllers
+    irq: u64,
+}
+
+impl Snapshot for MachineModel {
+    #[inline]
+    fn snapshot(&self) -> Box<dyn Any> {
+        Box::new(HartState {
+            gpr: self.gpr.clone(),
+            fpr: self.fpr.clone(),
+            csrs: self.csr.nonzero(),
+            pc: self.pc.read(),
+            mode: self.mode.get(),
+            waiting: self.waiting.get(),
+            irq: self.irq.get(),
+        })
+    }
+
+    fn restore(&self, state: &dyn Any) {
+        if let Some(state) = state.downcast_ref::<HartState>() {
+            self.irq.load(state.irq);
+            self.gpr.load(&state.gpr);
+            self.fpr.load(&state.fpr);
+            self.csr.load(&state.csrs);
+            self.pc.store(state.pc);
+            self.mode.set(state.mode);
+            self.waiting.set(state.waiting);
+            // reservations are not worth keeping, SC may fail anyway
+            self.shared.unreserve(self.hart_id());
+            self.pmp.flush();
+            // memory is rolled back along with us
+            self.icache.flush();
+            self.blocks.flush();
+            self.jit.flush();
+        }
     }
 }
 
+impl Harts for MachineModel {}
+
+impl InstRing for MachineModel {
+    #[inline]
+    fn dump_inst_ring(&self) {
+        self.iring.dump();
+    }
+}
@@ -1,6 +1,12 @@
 pub mod reg;
 pub mod mmu;
-pub mod plic;
+pub mod pmp;
 pub mod irq;
 pub mod machine;
 pub mod evaluate;
+pub mod decode;
+pub mod icache;
+pub mod block;
+pub mod jit;
+pub mod iring;
+pub mod smp;
@@ -0,0 +1,247 @@
+//! Physical memory protection: sixteen entries, the lowest matching one
+//! deciding, that bind S- and U-mode and, once locked, M-mode too. Entries
+//! are decoded when the CSRs change rather than on every access.
+
+use std::cell::{Cell, RefCell};
+
+use crate::device::Access;
+
+use super::{
+    machine::MachineModel,
+    reg::{
+        csrmap::{MSTATUS, PMPCFG0, PMPCFG2, PMPCFG3, PMPADDR0, PMPADDR15},
+        csr::{CSR, PMPADDR_MASK, pmpcfg::{PmpCfg, AddrMatch}, mstatus::{MStatus, MachineMode}},
+    },
+};
+
+
+pub const PMP_ENTRIES: usize = 16;
+const MPRV: u64 = 1 << 17;
+
+#[derive(Debug, Clone, Copy)]
+struct Region {
+    start: u64,
+    /// inclusive, so that a region may end at the top of the address space
+    last: u64,
+    cfg: PmpCfg,
+}
+
+#[derive(Debug)]
+pub struct Pmp {
+    /// the entries that are not off, highest priority first
+    regions: RefCell<Vec<Region>>,
+    /// whether any of them binds M-mode
+    locked: Cell<bool>,
+    dirty: Cell<bool>,
+}
+
+impl Default for Pmp {
+    fn default() -> Pmp {
+        Pmp {
+            regions: RefCell::new(Vec::new()),
+            locked: Cell::new(false),
+            dirty: Cell::new(true),
+        }
+    }
+}
+
+/// Copies decode the CSRs they are given afresh.
+impl Clone for Pmp {
+    fn clone(&self) -> Pmp {
+        Pmp::default()
+    }
+}
+
+#[inline]
+pub fn is_pmp(reg: usize) -> bool {
+    matches!(reg, PMPCFG0..=PMPCFG3 | PMPADDR0..=PMPADDR15)
+}
+
+/// The configuration of entry `i`.
+#[inline]
+fn cfg(csr: &CSR, i: usize) -> PmpCfg {
+    let reg = PMPCFG0 + i / 8 * 2;
+    PmpCfg::from_bytes([(csr.read(reg) >> (i % 8 * 8)) as u8])
+}
+
+impl Pmp {
+    /// The CSRs changed behind our back.
+    #[inline]
+    pub fn flush(&self) {
+        self.dirty.set(true);
+    }
+
+    /// A write by the hart: locked entries keep their configuration and
+    /// address, and so does the address below a locked TOR entry.
+    pub fn write(&self, csr: &CSR, reg: usize, value: u64) {
+        match reg {
+            PMPCFG0 | PMPCFG2 => {
+                let old = csr.read(reg).to_le_bytes();
+                let mut new = value.to_le_bytes();
+                for (old, new) in old.into_iter().zip(new.iter_mut()) {
+                    *new = if PmpCfg::from_bytes([old]).l() == 1 {
+                        old
+                    } else {
+                        let cfg = PmpCfg::from_bytes([*new & 0x9f]);
+                        // W without R is reserved
+                        cfg.with_w(cfg.w() & cfg.r()).into_bytes()[0]
+                    };
+                }
+                csr.store(reg, u64::from_le_bytes(new));
+            },
+            PMPADDR0..=PMPADDR15 => {
+                let i = reg - PMPADDR0;
+                let locked_tor = i + 1 < PMP_ENTRIES
+                    && matches!(cfg(csr, i + 1), x if x.l() == 1 && x.a() == AddrMatch::Tor);
+                if cfg(csr, i).l() == 0 && !locked_tor {
+                    csr.store(reg, value & PMPADDR_MASK);
+                }
+            },
+            // pmpcfg1 and pmpcfg3 are RV32 only
+            _ => {},
+        }
+        self.flush();
+    }
+
+    fn decode(&self, csr: &CSR) {
+        let mut regions = self.regions.borrow_mut();
+        regions.clear();
+        let mut prev = 0;
+        for i in 0..PMP_ENTRIES {
+            let cfg = cfg(csr, i);
+            let addr = csr.read(PMPADDR0 + i) & PMPADDR_MASK;
+            let range = match cfg.a() {
+                AddrMatch::Off => None,
+                AddrMatch::Tor => (prev < addr).then(|| (prev << 2, (addr << 2) - 1)),
+                AddrMatch::Na4 => Some((addr << 2, (addr << 2) + 3)),
+                AddrMatch::Napot => {
+                    let mask = (1u64 << (addr.trailing_ones() + 3)) - 1;
+                    let start = (addr << 2) & !mask;
+                    Some((start, start | mask))
+                },
+            };
+            prev = addr;
+            if let Some((start, last)) = range {
+                regions.push(Region { start, last, cfg });
+            }
+        }
+        self.locked.set(regions.iter().any(|x| x.cfg.l() == 1));
+        self.dirty.set(false);
+    }
+
+    /// Whether accesses in `mode` are checked at all.
+    #[inline]
+    pub fn enforced(&self, csr: &CSR, mode: MachineMode) -> bool {
+        if self.dirty.get() {
+            self.decode(csr);
+        }
+        mode != MachineMode::Machine || self.locked.get()
+    }
+
+    /// Whether an `access` to the `size` bytes at `addr` in `mode` is
+    /// allowed: the first entry matching any of them has to match all.
+    #[inline]
+    pub fn check(&self, csr: &CSR, access: Access, addr: u64, size: u64, mode: MachineMode) -> bool {
+        !self.enforced(csr, mode) || self.lookup(access, addr, size, mode)
+    }
+
+    fn lookup(&self, access: Access, addr: u64, size: u64, mode: MachineMode) -> bool {
+        let last = match addr.checked_add(size - 1) {
+            Some(last) => last,
+            None => return false,
+        };
+        let regions = self.regions.borrow();
+        let region = match regions.iter().find(|x| addr <= x.last && last >= x.start) {
+            Some(region) => region,
+            // M-mode only gets here with a locked entry elsewhere
+            None => return mode == MachineMode::Machine,
+        };
+        if addr < region.start || last > region.last {
+            return false;
+        }
+        let cfg = region.cfg;
+        if mode == MachineMode::Machine && cfg.l() == 0 {
+            return true;
+        }
+        match access {
+            Access::Read => cfg.r() == 1,
+            Access::Write => cfg.w() == 1,
+            Access::Exec => cfg.x() == 1,
+            Access::Amo => cfg.r() == 1 && cfg.w() == 1,
+        }
+    }
+}
+
+impl MachineModel {
+    /// The privilege loads and stores run with: MPP under MPRV.
+    #[inline]
+    fn data_mode(&self) -> MachineMode {
+        let mode = self.mode.get();
+        if mode != MachineMode::Machine || self.csr.read(MSTATUS) & MPRV == 0 {
+            return mode;
+        }
+        MStatus::from_bytes(self.csr.read(MSTATUS).to_le_bytes()).mpp()
+    }
+
+    #[inline]
+    pub fn pmp_allows(&self, access: Access, addr: u64, size: u64) -> bool {
+        let mode = match access {
+            Access::Exec => self.mode.get(),
+            _ => self.data_mode(),
+        };
+        self.pmp.check(&self.csr, access, addr, size, mode)
+    }
+
+    /// Whether fetches or data accesses are checked right now, so that
+    /// nothing may skip `read`, `store` and `read_code`.
+    #[inline]
+    pub fn pmp_enforced(&self) -> bool {
+        self.pmp.enforced(&self.csr, self.mode.get()) || self.pmp.enforced(&self.csr, self.data_mode())
+    }
+}
+
+
+#[test]
+fn test_pmp() {
+    use super::reg::csrmap::{PMPADDR1, PMPADDR2};
+    let csr = CSR::new(0, 0);
+    let pmp = Pmp::default();
+    let (u, m) = (MachineMode::User, MachineMode::Machine);
+    // nothing set up: U-mode gets nothing, M-mode everything
+    assert!(!pmp.check(&csr, Access::Read, 0x8000_0000, 4, u));
+    assert!(pmp.check(&csr, Access::Write, 0x8000_0000, 4, m));
+    // 0: TOR [0, 0x1000) r, 1: NA4 at 0x2000 rw, 2: NAPOT 0x8000_0000 + 64K rwx
+    pmp.write(&csr, PMPADDR0, 0x1000 >> 2);
+    pmp.write(&csr, PMPADDR1, 0x2000 >> 2);
+    pmp.write(&csr, PMPADDR2, (0x8000_0000 >> 2) | 0x1fff);
+    pmp.write(&csr, PMPCFG0, 0x1f_13_09);
+    assert!(pmp.check(&csr, Access::Read, 0xffc, 4, u));
+    assert!(!pmp.check(&csr, Access::Write, 0xffc, 4, u));
+    // straddling the end of entry 0
+    assert!(!pmp.check(&csr, Access::Read, 0xffe, 4, u));
+    assert!(pmp.check(&csr, Access::Write, 0x2000, 4, u));
+    assert!(!pmp.check(&csr, Access::Exec, 0x2000, 4, u));
+    assert!(!pmp.check(&csr, Access::Read, 0x2004, 1, u));
+    assert!(pmp.check(&csr, Access::Exec, 0x8000_fffc, 4, u));
+    assert!(!pmp.check(&csr, Access::Exec, 0x8001_0000, 4, u));
+    assert!(pmp.check(&csr, Access::Write, 0xffc, 4, m));
+    // W without R reads back as neither
+    pmp.write(&csr, PMPCFG2, 0x0a);
+    assert_eq!(csr.read(PMPCFG2), 0x08);
+    // locking entry 1, now TOR [0x1000, 0x2000), binds M-mode there and
+    // freezes it and the address below
+    pmp.write(&csr, PMPCFG0, 0x1f_8b_09);
+    pmp.write(&csr, PMPADDR1, 0);
+    pmp.write(&csr, PMPCFG0, 0);
+    assert_eq!(csr.read(PMPADDR1), 0x2000 >> 2);
+    assert_eq!(csr.read(PMPCFG0) >> 8 & 0xff, 0x8b);
+    assert!(!pmp.check(&csr, Access::Exec, 0x1000, 4, m));
+    assert!(pmp.check(&csr, Access::Write, 0x1ffc, 4, m));
+    assert!(pmp.check(&csr, Access::Exec, 0x8000_0000, 4, m));
+    // an all-ones NAPOT address covers all 56 address bits
+    pmp.write(&csr, PMPADDR0, u64::MAX);
+    assert_eq!(csr.read(PMPADDR0), 0x1000 >> 2);
+    pmp.write(&csr, PMPADDR2, u64::MAX);
+    pmp.write(&csr, PMPCFG0, 0x1f_8b_1f);
+    assert!(pmp.check(&csr, Access::Exec, 0xff_ffff_ffff_fffc, 4, u));
+}
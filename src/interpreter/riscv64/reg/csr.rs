@@ -5,10 +5,11 @@ pub mod mideleg;
 pub mod medeleg;
 pub mod mcause;
 pub mod mie_mip;
+pub mod pmpcfg;
 
 use std::cell::RefCell;
 
-use self::{mstatus::MStatus, mie_mip::{Mie, Mip}};
+use self::{mstatus::{MStatus, MachineMode}, mie_mip::Mie};
 
 use super::{Reg, Xlen, csrmap};
 
@@ -38,6 +39,46 @@ pub const fn misa_flag(x: u8) -> u64 {
 // const marchid64: u64 = 0;
 // const mimpid: u64 = 0;
 
+/// SIE MIE SPIE MPIE SPP MPP FS MPRV SUM MXR TVM TW TSR
+const MSTATUS_MASK: u64 = 0x7e79aa;
+const MPP_MASK: u64 = 0b11 << 11;
+/// SIE SPIE SPP FS SUM MXR
+pub const SSTATUS_MASK: u64 = 0x0c6122;
+/// SSI STI SEI
+const S_INTERRUPT_MASK: u64 = 0x222;
+/// SSI MSI STI MTI SEI MEI
+const M_INTERRUPT_MASK: u64 = 0xaaa;
+/// address bits 55:2
+pub const PMPADDR_MASK: u64 = (1 << 54) - 1;
+/// every exception except ecall from M-mode
+const MEDELEG_MASK: u64 = 0xb3ff;
+
+/// Writable bits of `reg`, `None` if the CSR is read-only.
+pub fn write_mask(reg: usize) -> Option<u64> {
+    // csr[11:10] == 0b11 marks read-only CSRs
+    if reg >> 10 == 0b11 {
+        return None;
+    }
+    let mask = match reg {
+        csrmap::MSTATUS => MSTATUS_MASK,
+        csrmap::SSTATUS => SSTATUS_MASK,
+        csrmap::MISA => 0,
+        csrmap::MEDELEG => MEDELEG_MASK,
+        csrmap::MIDELEG => S_INTERRUPT_MASK,
+        csrmap::MIE => M_INTERRUPT_MASK,
+        csrmap::MIP => S_INTERRUPT_MASK,
+        csrmap::SIE => S_INTERRUPT_MASK,
+        csrmap::SIP => 0b10,
+        csrmap::MTVEC | csrmap::STVEC => !0b10,
+        csrmap::MEPC | csrmap::SEPC => !0b1,
+        // RV32 only
+        csrmap::PMPCFG1 | csrmap::PMPCFG3 => 0,
+        csrmap::PMPADDR0..=csrmap::PMPADDR15 => PMPADDR_MASK,
+        _ => u64::MAX,
+    };
+    Some(mask)
+}
+
 
 impl CSR {
 
@@ -53,16 +94,29 @@ impl CSR {
             .with_mpie(1);
         let mie = Mie::new()
             .with_msie(1);
-        let mip = Mip::new()
-            .with_msie(1);
         r.store(csrmap::MSTATUS, u64::from_le_bytes(mstatus.into_bytes()));
         r.store(csrmap::MIE, u64::from_le_bytes(mie.into_bytes()));
-        r.store(csrmap::MIP, u64::from_le_bytes(mip.into_bytes()));
+        // mip starts clear, its M-mode bits are the controllers'
 
         // todo
         r
     }
 
+    /// The CSRs that are not 0, which are the few in use.
+    pub fn nonzero(&self) -> Vec<(u16, Reg)> {
+        self.0.borrow().iter().enumerate()
+            .filter(|(_, x)| **x != 0)
+            .map(|(i, x)| (i as u16, *x))
+            .collect()
+    }
+
+    /// Back from `nonzero`.
+    pub fn load(&self, csrs: &[(u16, Reg)]) {
+        let mut regs = self.0.borrow_mut();
+        regs.fill(0);
+        csrs.iter().for_each(|(i, x)| regs[*i as usize] = *x);
+    }
+
     #[inline]
     pub fn read(&self, reg: usize) -> Reg {
         self.0.borrow()[reg]
@@ -75,4 +129,20 @@ impl CSR {
         }
     }
 
+    /// Software write: read-only CSRs are rejected and WARL fields keep
+    /// their bits outside `write_mask`, or their old value when the new
+    /// one is not legal.
+    #[inline]
+    pub fn write(&self, reg: usize, value: Xlen) -> Option<()> {
+        let mask = write_mask(reg)?;
+        let old = self.read(reg);
+        let mut value = (old & !mask) | (value & mask);
+        if reg == csrmap::MSTATUS && (value >> 11) & 0b11 == MachineMode::Hypervisor as u64 {
+            // no H-mode for mret to go to
+            value = (value & !MPP_MASK) | (old & MPP_MASK);
+        }
+        self.store(reg, value);
+        Some(())
+    }
+
 }
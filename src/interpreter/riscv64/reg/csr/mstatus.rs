@@ -21,32 +21,32 @@ pub enum MachineMode {
 #[bitfield(bits = 64)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct MStatus {
-    pub sd: B1,
-    #[skip] __: B25,
-    pub mbe: B1,
-    pub sbe: B1,
-    pub sxl: B2,
-    pub uxl: B2,
-    #[skip] __: B9,
-    pub tsr: B1,
-    pub tw: B1,
-    pub tvm: B1,
-    pub mxr: B1,
-    pub sum: B1,
-    pub mprv: B1,
-    pub xs: B2,
-    pub fs: B2,
-    #[bits=2]
-    pub mpp: MachineMode,
-    pub vs: B2,
-    #[bits=1]
-    pub spp: SUMachineMode,
-    pub mpie: B1,
-    pub ube: B1,
-    pub spie: B1,
-    #[skip] __: B1,
-    pub mie: B1,
     #[skip] __: B1,
     pub sie: B1,
     #[skip] __: B1,
+    pub mie: B1,
+    #[skip] __: B1,
+    pub spie: B1,
+    pub ube: B1,
+    pub mpie: B1,
+    #[bits=1]
+    pub spp: SUMachineMode,
+    pub vs: B2,
+    #[bits=2]
+    pub mpp: MachineMode,
+    pub fs: B2,
+    pub xs: B2,
+    pub mprv: B1,
+    pub sum: B1,
+    pub mxr: B1,
+    pub tvm: B1,
+    pub tw: B1,
+    pub tsr: B1,
+    #[skip] __: B9,
+    pub uxl: B2,
+    pub sxl: B2,
+    pub sbe: B1,
+    pub mbe: B1,
+    #[skip] __: B25,
+    pub sd: B1,
 }
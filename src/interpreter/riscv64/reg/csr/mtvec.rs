@@ -16,9 +16,9 @@ pub enum TVMode {
 #[bitfield(bits = 64)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Tvec {
-    pub base: B62,
     #[bits=2]
     pub mode: TVMode,
+    pub base: B62,
 }
 
 impl Tvec {
@@ -0,0 +1,28 @@
+use modular_bitfield::prelude::*;
+
+
+
+#[repr(u8)]
+#[derive(BitfieldSpecifier)]
+#[bits=2]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum AddrMatch {
+    Off = 0,
+    /// top of range, from the previous pmpaddr
+    Tor = 1,
+    Na4 = 2,
+    Napot = 3,
+}
+
+/// One byte of pmpcfg.
+#[bitfield(bits = 8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PmpCfg {
+    pub r: B1,
+    pub w: B1,
+    pub x: B1,
+    #[bits=2]
+    pub a: AddrMatch,
+    #[skip] __: B2,
+    pub l: B1,
+}
@@ -12,10 +12,10 @@ pub enum SatpMode32 {
 
 #[bitfield(bits = 32)]
 pub struct Satp32 {
+    pub ppn: B22,
+    pub asid: B9,
     #[bits=1]
     pub mode: SatpMode32,
-    pub asid: B9,
-    pub ppn: B22,
 }
 
 
@@ -35,10 +35,10 @@ pub enum SatpMode {
 #[bitfield(bits = 64)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Satp {
+    pub ppn: B44,
+    pub asid: B16,
     #[bits=4]
     pub mode: SatpMode,
-    pub asid: B16,
-    pub ppn: B44,
 }
 
 impl Satp {
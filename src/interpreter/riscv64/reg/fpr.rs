@@ -0,0 +1,30 @@
+use std::cell::RefCell;
+
+use super::Reg;
+
+
+/// Raw bits of f0-f31, NaN-boxed values included.
+#[derive(Debug, Clone)]
+pub struct FPR(RefCell<[Reg; 32]>);
+
+impl FPR {
+    #[inline]
+    pub fn new() -> FPR {
+        FPR(RefCell::new([0; 32]))
+    }
+
+    #[inline]
+    pub fn load(&self, other: &FPR) {
+        self.0.borrow_mut().copy_from_slice(&*other.0.borrow());
+    }
+
+    #[inline]
+    pub fn read(&self, reg: usize) -> Reg {
+        self.0.borrow()[reg]
+    }
+
+    #[inline]
+    pub fn store(&self, reg: usize, value: Reg) {
+        self.0.borrow_mut()[reg] = value;
+    }
+}
@@ -12,6 +12,25 @@ impl GPR {
         GPR(RefCell::new([0; 32]))
     }
 
+    /// Copies every register of `other`, for checkpoint restore.
+    #[inline]
+    pub fn load(&self, other: &GPR) {
+        self.0.borrow_mut().copy_from_slice(&*other.0.borrow());
+    }
+
+    /// All 32 at once, for code that keeps them elsewhere for a while.
+    #[inline]
+    pub fn get_all(&self) -> [Reg; 32] {
+        *self.0.borrow()
+    }
+
+    /// Back from `get_all`; x0 stays 0.
+    #[inline]
+    pub fn set_all(&self, regs: &[Reg; 32]) {
+        let mut gpr = self.0.borrow_mut();
+        gpr[1..].copy_from_slice(&regs[1..]);
+    }
+
     #[inline]
     pub fn read(&self, reg: usize) -> Reg {
         self.0.borrow()[reg]
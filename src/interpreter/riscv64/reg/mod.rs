@@ -1,4 +1,5 @@
 pub mod gpr;
+pub mod fpr;
 pub mod csr;
 pub mod pc;
 
@@ -20,40 +21,24 @@ pub enum RegType {
 unsafe impl Sync for RegType {}
 unsafe impl Send for RegType {}
 
+fn parse_reg_def(def: &'static str, rt: RegType) -> impl Iterator<Item = (&'static str, (RegType, usize))> {
+    def.trim().lines().map(move |x| {
+        let mut r = x.split_whitespace();
+        let name = r.next().unwrap();
+        let num = r.next().unwrap();
+        let num = match num.strip_prefix("0x") {
+            Some(hex) => usize::from_str_radix(hex, 16).unwrap(),
+            None => num.parse::<usize>().unwrap(),
+        };
+        (name, (rt, num))
+    })
+}
+
 pub static REG_MAP: Lazy<HashMap<&str, (RegType, usize)>> = Lazy::new(|| {
     let mut map = HashMap::new();
-
-    let gpr_def = include_str!("./gpr_def");
-    let gpr_def = gpr_def.trim().split('\n').map(|x| {
-        let mut r = x.trim().split(' ');
-        (
-            r.next().unwrap(),
-            (RegType::Gpr, r.next().unwrap().parse::<usize>().unwrap()),
-        )
-    });
-    map.extend(gpr_def);
-
-    let csr_def = include_str!("./csr_def");
-    let csr_def = csr_def.trim().split('\n').map(|x| {
-        let mut r = x.trim().split(' ');
-        (
-            r.next().unwrap(),
-            (RegType::Csr, r.next().unwrap().parse::<usize>().unwrap()),
-        )
-    });
-    map.extend(csr_def);
-
-    /*
-    let fpr_def = include_str!("./reg_def/fpr_def");
-    let fpr_def = fpr_def.trim().split("\n").map(
-        |x| {
-            let mut r = x.trim().split(" ");
-            (r.next().unwrap(), (RegType::FPR, r.next().unwrap().parse::<usize>().unwrap()))
-        }
-    );
-    map.extend(fpr_def);
-    //  */
-
+    map.extend(parse_reg_def(include_str!("./gpr_def"), RegType::Gpr));
+    map.extend(parse_reg_def(include_str!("./csr_def"), RegType::Csr));
+    map.extend(parse_reg_def(include_str!("./fpr_def"), RegType::Fpr));
     map
 });
 
@@ -129,7 +114,20 @@ pub mod csrmap {
     pub const MVENDORID: usize = 0x0f11;
     pub const PMPADDR0: usize = 0x03b0;
     pub const PMPADDR1: usize = 0x03b1;
+    pub const PMPADDR10: usize = 0x03ba;
+    pub const PMPADDR11: usize = 0x03bb;
+    pub const PMPADDR12: usize = 0x03bc;
+    pub const PMPADDR13: usize = 0x03bd;
+    pub const PMPADDR14: usize = 0x03be;
     pub const PMPADDR15: usize = 0x03bf;
+    pub const PMPADDR2: usize = 0x03b2;
+    pub const PMPADDR3: usize = 0x03b3;
+    pub const PMPADDR4: usize = 0x03b4;
+    pub const PMPADDR5: usize = 0x03b5;
+    pub const PMPADDR6: usize = 0x03b6;
+    pub const PMPADDR7: usize = 0x03b7;
+    pub const PMPADDR8: usize = 0x03b8;
+    pub const PMPADDR9: usize = 0x03b9;
     pub const PMPCFG0: usize = 0x03a0;
     pub const PMPCFG1: usize = 0x03a1;
     pub const PMPCFG2: usize = 0x03a2;
@@ -144,7 +142,7 @@ pub mod csrmap {
     pub const SIP: usize = 0x0144;
     pub const SSCRATCH: usize = 0x0140;
     pub const SSTATUS: usize = 0x0100;
-    pub const STXAL: usize = 0x0143;
+    pub const STVAL: usize = 0x0143;
     pub const STVEC: usize = 0x0105;
     pub const TDATA1: usize = 0x07a1;
     pub const TDATA2: usize = 0x07a2;
@@ -0,0 +1,321 @@
+//! Several harts on one bus, run one at a time in a fixed order, each for
+//! a quantum of instructions, so that runs are repeatable. Virtual time is
+//! what all of them retired together.
+
+use std::{any::Any, cell::{Cell, RefCell}, rc::Rc};
+
+use crate::{
+    abstract_machine::{RegInfo, Disassembler, Snapshot, InstRing, Execable, ExceptionProcessable, Harts},
+    clock::Clock,
+    device::MMIODevice,
+};
+
+use super::{machine::MachineModel, irq::Exception};
+
+
+/// Instructions a hart runs before the next one takes its turn, unless
+/// `--quantum`.
+pub const DEFAULT_QUANTUM: usize = 1000;
+/// A store anywhere in the reserved doubleword breaks a reservation.
+const GRANULE: u64 = 8;
+
+/// What the harts of a machine share besides the bus: their LR
+/// reservations, and the stores of the one running, which the code caches
+/// of the others have to see.
+#[derive(Debug, Default)]
+pub struct Shared {
+    /// hart and reserved address
+    reservations: RefCell<Vec<(u64, u64)>>,
+    /// whether there are any, for the store fast path
+    reserved: Cell<bool>,
+    /// kept only when there are other harts
+    stores: RefCell<Vec<(u64, u64)>>,
+    peers: Cell<bool>,
+}
+
+impl Shared {
+    #[inline]
+    pub fn has_peers(&self) -> bool {
+        self.peers.get()
+    }
+
+    pub fn reserve(&self, hart: u64, addr: u64) {
+        let mut held = self.reservations.borrow_mut();
+        held.retain(|x| x.0 != hart);
+        held.push((hart, addr));
+        self.reserved.set(true);
+    }
+
+    /// The address `hart` had reserved; it holds none after.
+    pub fn unreserve(&self, hart: u64) -> Option<u64> {
+        let mut held = self.reservations.borrow_mut();
+        let i = held.iter().position(|x| x.0 == hart)?;
+        let (_, addr) = held.swap_remove(i);
+        self.reserved.set(!held.is_empty());
+        Some(addr)
+    }
+
+    /// Where `reserved` is kept, for translated code.
+    #[inline]
+    pub(super) fn reserved_flag(&self) -> *const u8 {
+        self.reserved.as_ptr() as *const u8
+    }
+
+    /// `len` bytes at `addr` were written by the hart running.
+    #[inline]
+    pub fn stored(&self, addr: u64, len: u64) {
+        if self.reserved.get() {
+            let mut held = self.reservations.borrow_mut();
+            held.retain(|(_, x)| {
+                let start = x & !(GRANULE - 1);
+                addr.wrapping_add(len) <= start || addr >= start + GRANULE
+            });
+            self.reserved.set(!held.is_empty());
+        }
+        if self.peers.get() {
+            self.stores.borrow_mut().push((addr, len));
+        }
+    }
+
+    fn take_stores(&self) -> Vec<(u64, u64)> {
+        std::mem::take(&mut *self.stores.borrow_mut())
+    }
+}
+
+pub struct Smp {
+    harts: Vec<MachineModel>,
+    quantum: Cell<usize>,
+    /// the hart whose turn it is, and what is left of the turn
+    turn: Cell<usize>,
+    left: Cell<usize>,
+    /// the hart the monitor shows, switched to the one that trapped
+    current: Cell<usize>,
+}
+
+struct SmpState {
+    harts: Vec<Box<dyn Any>>,
+    turn: usize,
+    left: usize,
+    current: usize,
+}
+
+impl Smp {
+    pub fn new(n: usize) -> Smp {
+        assert!(n > 0, "no harts");
+        let shared = Rc::new(Shared::default());
+        shared.peers.set(n > 1);
+        let harts = (0..n)
+            .map(|i| MachineModel { shared: shared.clone(), ..MachineModel::new(i as u64) })
+            .collect();
+        Smp {
+            harts,
+            quantum: Cell::new(DEFAULT_QUANTUM),
+            turn: Cell::new(0),
+            left: Cell::new(DEFAULT_QUANTUM),
+            current: Cell::new(0),
+        }
+    }
+
+    #[inline]
+    pub fn harts(&self) -> &[MachineModel] {
+        &self.harts
+    }
+
+    /// Cuts the turn running short if it is longer.
+    pub fn set_quantum(&self, n: usize) {
+        self.quantum.set(n.max(1));
+        self.left.set(self.left.get().min(self.quantum.get()));
+    }
+
+    #[inline]
+    fn hart(&self) -> &MachineModel {
+        &self.harts[self.current.get()]
+    }
+
+    /// Hands the turn on, once the code caches of the others have seen
+    /// what the last one wrote.
+    fn next_turn(&self) {
+        let from = self.turn.get();
+        let stores = self.harts[from].shared.take_stores();
+        for (_, hart) in self.harts.iter().enumerate().filter(|(i, _)| *i != from) {
+            stores.iter().for_each(|&(addr, len)| hart.invalidate_code(addr, len));
+        }
+        self.turn.set((from + 1) % self.harts.len());
+        self.left.set(self.quantum.get());
+    }
+
+    /// `MachineModel::run_for` for the hart whose turn it is, at most to
+    /// the end of the turn. A hart stopped in `wfi` gives its turn away,
+    /// unless all are, when the clock skips ahead.
+    fn run_for(&self, memory: &dyn MMIODevice, clock: Option<&Clock>, max: usize) -> (usize, Result<(), Exception>) {
+        if let [hart] = &self.harts[..] {
+            return hart.run_for(memory, clock, max);
+        }
+        let hart = &self.harts[self.turn.get()];
+        let stopped = |x: &MachineModel| x.waiting.get() && !x.wake();
+        if stopped(hart) && !self.harts.iter().all(stopped) {
+            self.next_turn();
+            return (0, Ok(()));
+        }
+        let (n, r) = hart.run_for(memory, clock, max.min(self.left.get()));
+        self.left.set(self.left.get() - n);
+        if r.is_err() {
+            self.current.set(self.turn.get());
+        } else if self.left.get() == 0 || hart.waiting.get() {
+            self.next_turn();
+        }
+        (n, r)
+    }
+}
+
+impl Execable<Exception> for Smp {
+    /// One instruction of the hart whose turn it is.
+    fn exec_once(&self, memory: &dyn MMIODevice) -> Result<(), Exception> {
+        loop {
+            let (n, r) = self.run_for(memory, None, 1);
+            if n > 0 || r.is_err() {
+                return r;
+            }
+        }
+    }
+
+    fn flush_code_cache(&self) {
+        self.harts.iter().for_each(|x| x.flush_code_cache());
+    }
+
+    fn take_interrupt(&self) -> Option<u64> {
+        self.harts.iter().filter_map(|x| x.take_interrupt()).last()
+    }
+
+    fn exec_catch_interrupt_loop(&self, memory: &dyn MMIODevice) -> Result<(), Exception> {
+        let clock = memory.clock();
+        loop {
+            self.run_for(memory, clock.as_deref(), usize::MAX).1?;
+        }
+    }
+
+    fn setp_num(&self, memory: &dyn MMIODevice, num: usize) -> Result<(), Exception> {
+        let clock = memory.clock();
+        let mut done = 0;
+        while done < num {
+            let (n, r) = self.run_for(memory, clock.as_deref(), num - done);
+            r?;
+            done += n;
+        }
+        Ok(())
+    }
+}
+
+/// Traps are the current hart's, see `run_for`.
+impl ExceptionProcessable<Exception> for Smp {
+    #[inline]
+    fn process_exception(&self, e: Result<(), Exception>) {
+        self.hart().process_exception(e);
+    }
+
+    fn exception_log(&self, memory: &dyn MMIODevice, e: Result<(), Exception>) -> Result<(), Exception> {
+        self.hart().exception_log(memory, e)
+    }
+}
+
+impl RegInfo for Smp {
+    #[inline]
+    fn get_reg_value(&self, i: &str) -> Option<u64> {
+        self.hart().get_reg_value(i)
+    }
+
+    fn set_reg_value(&self, i: &str, value: u64) -> Option<()> {
+        self.hart().set_reg_value(i, value)
+    }
+
+    fn reg_names(&self) -> &'static [&'static str] {
+        self.hart().reg_names()
+    }
+
+    fn csr_names(&self) -> &'static [&'static str] {
+        self.hart().csr_names()
+    }
+}
+
+impl Disassembler for Smp {
+    fn disassemble(&self, memory: &dyn MMIODevice, addr: u64) -> Option<(Vec<u8>, String)> {
+        self.hart().disassemble(memory, addr)
+    }
+}
+
+impl InstRing for Smp {
+    fn dump_inst_ring(&self) {
+        self.hart().dump_inst_ring();
+    }
+}
+
+impl Snapshot for Smp {
+    fn snapshot(&self) -> Box<dyn Any> {
+        Box::new(SmpState {
+            harts: self.harts.iter().map(|x| x.snapshot()).collect(),
+            turn: self.turn.get(),
+            left: self.left.get(),
+            current: self.current.get(),
+        })
+    }
+
+    fn restore(&self, state: &dyn Any) {
+        if let Some(state) = state.downcast_ref::<SmpState>() {
+            self.harts.iter().zip(state.harts.iter()).for_each(|(x, state)| x.restore(state.as_ref()));
+            // the code caches were flushed with them
+            self.harts[0].shared.take_stores();
+            self.turn.set(state.turn);
+            self.left.set(state.left);
+            self.current.set(state.current);
+        }
+    }
+}
+
+impl Harts for Smp {
+    fn hart_count(&self) -> usize {
+        self.harts.len()
+    }
+
+    fn current_hart(&self) -> usize {
+        self.current.get()
+    }
+
+    fn select_hart(&self, n: usize) -> Option<()> {
+        (n < self.harts.len()).then(|| self.current.set(n))
+    }
+}
+
+
+#[test]
+fn test_smp() {
+    use crate::{device::{Device, TICK_PERIOD}, memory::Memory};
+    // addi a1, a1, 1 all the way, and at 0x200: wfi; jal x0, 0
+    let mut program: Vec<u8> = [0x00158593u32; 0x80].into_iter().flat_map(|x| x.to_le_bytes()).collect();
+    program.resize(0x300, 0);
+    program[0x200..0x208].copy_from_slice(&[0x10500073u32.to_le_bytes(), 0x0000006fu32.to_le_bytes()].concat());
+    let smp = Smp::new(2);
+    smp.set_quantum(3);
+    let mmio = Device::new();
+    let clock = mmio.clock().unwrap();
+    mmio.add_device("ram", 0, Box::new(Memory::from(program.as_ref()))).unwrap();
+    let [h0, h1] = smp.harts() else { unreachable!() };
+    // three each, and two of the next turn
+    smp.setp_num(&mmio, 20).unwrap();
+    assert_eq!((h0.gpr.read(11), h1.gpr.read(11)), (11, 9));
+    assert_eq!(clock.now(), 20);
+    // a hart in wfi gives its turns away
+    h1.pc.store(0x200);
+    smp.setp_num(&mmio, 10).unwrap();
+    assert_eq!((h0.gpr.read(11), h1.gpr.read(11)), (20, 9));
+    assert!(h1.waiting.get());
+    assert_eq!(h1.pc.read(), 0x204);
+    // with all of them waiting, time runs to the next event
+    h0.pc.store(0x200);
+    smp.setp_num(&mmio, 2).unwrap();
+    assert!(h0.waiting.get());
+    assert_eq!(clock.now(), TICK_PERIOD);
+    // the monitor follows
+    assert_eq!(smp.select_hart(1), Some(()));
+    assert_eq!(smp.get_reg_value("mhartid"), Some(1));
+    assert_eq!(smp.select_hart(2), None);
+}
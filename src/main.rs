@@ -1,5 +1,12 @@
 #![allow(dead_code)]
+#[macro_use]
+mod trace;
 mod memory;
+mod clock;
+mod elf;
+mod dwarf;
+mod difftest;
+mod htif;
 mod monitor;
 mod device;
 mod abstract_machine;
@@ -8,37 +15,356 @@ mod interpreter;
 mod utils;
 #[cfg(test)]
 mod tests;
+#[cfg(test)]
+mod riscv_tests;
 
 
-use std::io::{stdout, Write};
+use std::{path::Path, process::exit};
 
-// use clap::Parser;
+use clap::{Command, Arg};
 // use disassembly::riscv::disassembly;
 
 use crate::{
-    abstract_machine::*,
-    interpreter::riscv64::machine::MachineModel,
-    device::{Device}, memory::Memory,
+    interpreter::riscv64::{machine::MachineModel, smp::Smp},
+    device::{Device, MMIODevice, Pma, riscv::{clint::{Clint, CLINT_BASE}, plic::{Plic, PLIC_BASE}}},
+    memory::Memory,
+    abstract_machine::RegInfo,
+    elf::{Elf, SymbolTable},
+    dwarf::DebugInfo,
+    difftest::RefModel,
+    htif::Outcome,
+    trace::TraceConfig,
+    monitor::Monitor,
+    utils::parse_size,
 };
 
 const BL: &[u8] = include_bytes!("../tests/bbl.bin");
 // const BL: &[u8] = include_bytes!("../tests/rv64ui-p-addi.bin");
+/// Where the writable segment and the payload of `BL` start, see
+/// `readelf -l tests/bbl`.
+const BL_DATA: usize = 0x8000a000;
+const BL_PAYLOAD: usize = 0x8000e000;
+
+/// Guest RAM unless `--memory`.
+const DEFAULT_RAM: usize = 128 * 1024 * 1024;
+
+/// Instructions a `--signature` run gets by default.
+const SIGNATURE_BUDGET: u64 = 100_000_000;
+
+/// Monitor commands run on start, unless `--no-init`.
+const INIT_FILE: &str = ".lemuinit";
 
 fn main() {
+    let matches = Command::new("lemu")
+        .about("The Lyzh's machine emulator")
+        .arg(Arg::new("elf")
+            .long("elf")
+            .value_name("FILE")
+            .takes_value(true)
+            .help("Load an ELF image into RAM and start at its entry [default: builtin bbl]"))
+        .arg(Arg::new("image")
+            .long("image")
+            .value_name("FILE")
+            .takes_value(true)
+            .conflicts_with("elf")
+            .help("Map a raw image copy-on-write at 0x80000000 and start there [default: builtin bbl]"))
+        .arg(Arg::new("memory")
+            .long("memory")
+            .value_name("SIZE")
+            .takes_value(true)
+            .help("Guest RAM, e.g. 512M or 2G; only pages touched take host memory [default: 128M]"))
+        .arg(Arg::new("harts")
+            .long("harts")
+            .value_name("N")
+            .takes_value(true)
+            .help("Number of harts, all starting at the same entry [default: 1]"))
+        .arg(Arg::new("quantum")
+            .long("quantum")
+            .value_name("N")
+            .takes_value(true)
+            .help("Instructions a hart runs before the next one takes its turn [default: 1000]"))
+        .arg(Arg::new("clock-hz")
+            .long("clock-hz")
+            .value_name("HZ")
+            .takes_value(true)
+            .help("Keep the virtual clock in step with the host at HZ ticks a second [default: a tick per instruction, unpaced]"))
+        .arg(Arg::new("trace")
+            .long("trace")
+            .value_name("CHANNELS")
+            .takes_value(true)
+            .help("Enable trace channels: itrace,mtrace,dtrace,etrace,ftrace or all"))
+        .arg(Arg::new("trace-file")
+            .long("trace-file")
+            .value_name("FILE")
+            .takes_value(true)
+            .help("Write traces to FILE instead of stderr"))
+        .arg(Arg::new("trace-range")
+            .long("trace-range")
+            .value_name("START:END")
+            .takes_value(true)
+            .help("Only trace instructions with pc in [START, END)"))
+        .arg(Arg::new("trace-mode")
+            .long("trace-mode")
+            .value_name("MODES")
+            .takes_value(true)
+            .help("Only trace in these privilege modes: m,s,u"))
+        .arg(Arg::new("iring")
+            .long("iring")
+            .value_name("N")
+            .takes_value(true)
+            .help("Keep the last N instructions for crash dumps, 0 to disable [default: 16]"))
+        .arg(Arg::new("difftest")
+            .long("difftest")
+            .value_name("REF_SO")
+            .takes_value(true)
+            .help("Check every instruction against a reference model with the NEMU difftest ABI"))
+        .arg(Arg::new("difftest-csr")
+            .long("difftest-csr")
+            .value_name("CSRS")
+            .takes_value(true)
+            .help("CSRs the reference model passes after the pc, e.g. mstatus,mepc,mcause"))
+        .arg(Arg::new("signature")
+            .long("signature")
+            .value_name("FILE")
+            .takes_value(true)
+            .requires("elf")
+            .help("Run the ELF to tohost and write the memory between begin_signature and end_signature to FILE"))
+        .arg(Arg::new("max-insts")
+            .long("max-insts")
+            .value_name("N")
+            .takes_value(true)
+            .help("Give up on --signature runs after N instructions [default: 100000000]"))
+        .arg(Arg::new("engine")
+            .long("engine")
+            .value_name("ENGINE")
+            .takes_value(true)
+            .help("How to run guest code: interp, one instruction at a time, block, by basic blocks, or jit, translated to x86-64 [default: interp]"))
+        .arg(Arg::new("script")
+            .long("script")
+            .value_name("FILE")
+            .takes_value(true)
+            .help("Run monitor commands from FILE and exit with its status"))
+        .arg(Arg::new("init")
+            .long("init")
+            .value_name("FILE")
+            .takes_value(true)
+            .help("Init file run before anything else [default: .lemuinit]"))
+        .arg(Arg::new("no-init")
+            .long("no-init")
+            .help("Do not run the init file"))
+        .get_matches();
+
+    if let Some(channels) = matches.value_of("trace") {
+        let config = TraceConfig::parse_channels(channels).and_then(|channels| Ok(TraceConfig {
+            channels,
+            range: matches.value_of("trace-range").map(TraceConfig::parse_range).transpose()?,
+            modes: matches.value_of("trace-mode").map(TraceConfig::parse_modes).transpose()?.unwrap_or_default(),
+        }));
+        let r = config.and_then(|config| trace::init(&config, matches.value_of("trace-file")).map_err(|e| e.to_string()));
+        if let Err(e) = r {
+            eprintln!("[lemu] --trace: {}", e);
+            exit(2);
+        }
+    }
+
     println!("Welecome to lemu!");
-    let mm = MachineModel::new(0);
-    mm.pc.store(0x80000000);
-    let mut mmio = Device::new();
-    let bootloader = Memory::from(BL);
-    mmio.add_device(0x80000000, Box::new(bootloader));
-    let mem = Memory::new(128*1024*1024); // init 128Kb
-    mmio.add_device(0x80020000, Box::new(mem));
-    loop {
-        mm.logged_process_exception(&mmio, mm.exec_catch_interrupt_loop(&mmio));
-        // mm.logged_process_exception(&mmio, mm.exec_loop_otherwise_debugger_trap(&mmio));
-        print!("Type enter continue: ");
-        stdout().flush().unwrap();
-        let mut _i = String::new();
-        std::io::stdin().read_line(&mut _i).unwrap();
+    let harts = match matches.value_of("harts").map_or(Ok(1), |x| x.parse()) {
+        Ok(n) if n > 0 => n,
+        _ => {
+            eprintln!("[lemu] --harts: bad number `{}`", matches.value_of("harts").unwrap());
+            exit(2);
+        },
+    };
+    let smp = Smp::new(harts);
+    if let Some(n) = matches.value_of("quantum") {
+        match n.parse() {
+            Ok(n) if n > 0 => smp.set_quantum(n),
+            _ => {
+                eprintln!("[lemu] --quantum: bad number `{}`", n);
+                exit(2);
+            },
+        }
+    }
+    let set_pc = |pc: u64| smp.harts().iter().for_each(|x| x.pc.store(pc));
+    set_pc(0x80000000);
+    if let Some(n) = matches.value_of("iring") {
+        match n.parse() {
+            Ok(n) => smp.harts().iter().for_each(|x| x.iring.set_size(n)),
+            Err(_) => {
+                eprintln!("[lemu] --iring: bad number `{}`", n);
+                exit(2);
+            },
+        }
+    }
+    if let Some(engine) = matches.value_of("engine") {
+        match engine.parse() {
+            Ok(engine) => smp.harts().iter().for_each(|x| x.engine.set(engine)),
+            Err(e) => {
+                eprintln!("[lemu] --engine: {}", e);
+                exit(2);
+            },
+        }
+    }
+    let ram = match matches.value_of("memory").map_or(Ok(DEFAULT_RAM), parse_size) {
+        Ok(n) => n,
+        Err(e) => {
+            eprintln!("[lemu] --memory: {}", e);
+            exit(2);
+        },
+    };
+    let mmio = Device::new();
+    if let Some(hz) = matches.value_of("clock-hz") {
+        match hz.parse() {
+            Ok(hz) if hz > 0 => mmio.clock().unwrap().set_realtime(Some(hz)),
+            _ => {
+                eprintln!("[lemu] --clock-hz: bad frequency `{}`", hz);
+                exit(2);
+            },
+        }
+    }
+    let mut symbols = SymbolTable::default();
+    let mut debug_info = DebugInfo::default();
+    if let Some(path) = matches.value_of("elf") {
+        let elf = match std::fs::read(path).map_err(|e| e.to_string())
+            .and_then(|data| Elf::parse(&data).map_err(|e| e.to_string())) {
+            Ok(elf) => elf,
+            Err(e) => {
+                eprintln!("[lemu] cannot load {}: {}", path, e);
+                exit(2);
+            },
+        };
+        map(&mmio, "ram", 0x80000000, Box::new(Memory::new(ram)));
+        if let Err(addr) = elf.load(&mmio) {
+            eprintln!("[lemu] cannot load {}: address 0x{:x} is not RAM", path, addr);
+            exit(2);
+        }
+        set_pc(elf.entry);
+        symbols = elf.symbols();
+        trace::set_symbols(symbols.clone());
+        debug_info = DebugInfo::new(&elf).unwrap_or_else(|e| {
+            eprintln!("[lemu] ignoring broken debug info in {}: {}", path, e);
+            DebugInfo::default()
+        });
+    } else if let Some(path) = matches.value_of("image") {
+        match Memory::from_file(Path::new(path), ram) {
+            Ok(mem) => map(&mmio, "ram", 0x80000000, Box::new(mem)),
+            Err(e) => {
+                eprintln!("[lemu] cannot load {}: {}", path, e);
+                exit(2);
+            },
+        }
+    } else {
+        map_bootloader(&mmio);
+        map(&mmio, "ram", 0x80020000, Box::new(Memory::new(ram)));
+    }
+    map_platform(&mmio, smp.harts());
+    if let Some(path) = matches.value_of("signature") {
+        let budget = match matches.value_of("max-insts").map_or(Ok(SIGNATURE_BUDGET), |x| x.parse()) {
+            Ok(n) => n,
+            Err(_) => {
+                eprintln!("[lemu] --max-insts: bad number `{}`", matches.value_of("max-insts").unwrap());
+                exit(2);
+            },
+        };
+        let code = write_signature(&smp, &mmio, &symbols, path, budget);
+        trace::flush();
+        exit(code);
+    }
+    let mut monitor = Monitor::new(symbols);
+    monitor.debug_info = debug_info;
+    if let Some(path) = matches.value_of("difftest") {
+        if harts > 1 {
+            eprintln!("[lemu] --difftest: the reference model has a single hart");
+            exit(2);
+        }
+        let csrs = matches.value_of("difftest-csr")
+            .map_or(Vec::new(), |x| x.split(',').map(|x| x.to_string()).collect());
+        if let Some(csr) = csrs.iter().find(|x| !smp.csr_names().contains(&x.as_str())) {
+            eprintln!("[lemu] --difftest-csr: unknown CSR `{}`", csr);
+            exit(2);
+        }
+        match RefModel::load(path, csrs) {
+            Ok(model) => monitor.difftest = Some(model),
+            Err(e) => {
+                eprintln!("[lemu] cannot load {}: {}", path, e);
+                exit(2);
+            },
+        }
+        monitor.difftest_sync(&smp, &mmio);
+    }
+
+    if !matches.is_present("no-init") {
+        let init = matches.value_of("init").unwrap_or(INIT_FILE);
+        // a missing default init file is fine, an explicit one is not
+        if matches.is_present("init") || Path::new(init).exists() {
+            if let Err(code) = monitor.source(init, &smp, &mmio) {
+                trace::flush();
+                exit(code);
+            }
+        }
+    }
+    if let Some(script) = matches.value_of("script") {
+        let code = monitor.source(script, &smp, &mmio).err().unwrap_or(0);
+        trace::flush();
+        exit(code);
+    }
+    monitor.repl(&smp, &mmio);
+}
+
+/// The builtin bbl at 0x80000000: code, read-only data and the payload
+/// are ROM, its writable segment (.htif, .data, .bss) is RAM.
+fn map_bootloader(mmio: &Device) {
+    let (data, payload) = (BL_DATA - 0x80000000, BL_PAYLOAD - 0x80000000);
+    map(mmio, "bbl", 0x80000000, Box::new(Memory::from(&BL[..data]).with_pma(Pma::ROM)));
+    map(mmio, "bbl.data", BL_DATA, Box::new(Memory::from(&BL[data..payload])));
+    map(mmio, "bbl.payload", BL_PAYLOAD, Box::new(Memory::from(&BL[payload..]).with_pma(Pma::ROM)));
+}
+
+/// The interrupt controllers, with a CLINT hart and two PLIC contexts for
+/// each of `harts`.
+fn map_platform(mmio: &Device, harts: &[MachineModel]) {
+    let irqs: Vec<_> = harts.iter().map(|x| x.irq.clone()).collect();
+    map(mmio, "clint", CLINT_BASE, Box::new(Clint::new(mmio.clock().unwrap(), irqs.clone())));
+    map(mmio, "plic", PLIC_BASE, Box::new(Plic::new(irqs)));
+}
+
+fn map(mmio: &Device, name: &str, start: usize, device: Box<dyn MMIODevice>) {
+    if let Err(e) = mmio.add_device(name, start, device) {
+        eprintln!("[lemu] cannot map {}: {}", name, e);
+        exit(2);
+    }
+}
+
+/// `--signature`: runs to `tohost` and dumps the signature the way
+/// riscv-arch-test wants it. The exit code for lemu.
+fn write_signature(smp: &Smp, mmio: &Device, symbols: &SymbolTable, path: &str, budget: u64) -> i32 {
+    let lookup = |name| symbols.lookup(name).map(|x| x.addr).ok_or(name);
+    let (tohost, begin, end) = match (lookup("tohost"), lookup("begin_signature"), lookup("end_signature")) {
+        (Ok(tohost), Ok(begin), Ok(end)) => (tohost, begin, end),
+        (Err(name), _, _) | (_, Err(name), _) | (_, _, Err(name)) => {
+            eprintln!("[lemu] --signature: no `{}` symbol", name);
+            return 2;
+        },
+    };
+    let outcome = match htif::run(smp, mmio, tohost as usize, budget) {
+        Ok((outcome, _)) => outcome,
+        Err(e) => {
+            eprintln!("[lemu] --signature: {}", e);
+            return 2;
+        },
+    };
+    let r = htif::signature(mmio, begin, end)
+        .map_err(|addr| format!("cannot read signature at 0x{:x}", addr))
+        .and_then(|sig| std::fs::write(path, sig).map_err(|e| format!("cannot write {}: {}", path, e)));
+    if let Err(e) = r {
+        eprintln!("[lemu] --signature: {}", e);
+        return 2;
+    }
+    match outcome {
+        Outcome::Pass => 0,
+        outcome => {
+            eprintln!("[lemu] {}", outcome);
+            1
+        },
     }
 }
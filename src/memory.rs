@@ -1,33 +1,202 @@
-use std::cell::{
-    // Cell,
-    RefCell
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fs::File,
+    io,
+    ops::{Deref, DerefMut},
+    path::Path,
 };
 
-use crate::{abstract_machine::{LengthInfo, Readable, Writeable}, device::MMIODevice};
+use crate::{abstract_machine::{LengthInfo, Readable, Writeable}, device::{MMIODevice, Pma}};
 
 
 
+/// Granularity of the checkpoint undo log.
+const PAGE_SHIFT: usize = 12;
+
+/// Guest RAM on the host: an anonymous mapping that only takes host memory
+/// for the pages touched, with a file mapped copy-on-write at its start.
+struct Backing {
+    ptr: *mut u8,
+    len: usize,
+}
+
+impl Backing {
+    #[cfg(unix)]
+    fn anonymous(len: usize) -> io::Result<Backing> {
+        #[cfg(target_os = "linux")]
+        const NORESERVE: libc::c_int = libc::MAP_NORESERVE;
+        #[cfg(not(target_os = "linux"))]
+        const NORESERVE: libc::c_int = 0;
+        if len == 0 {
+            // mmap refuses empty mappings
+            return Ok(Backing { ptr: std::ptr::NonNull::dangling().as_ptr(), len });
+        }
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | NORESERVE,
+                -1,
+                0)
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Backing { ptr: ptr as *mut u8, len })
+    }
+
+    #[cfg(unix)]
+    fn file(file: &File, len: usize) -> io::Result<Backing> {
+        use std::os::unix::io::AsRawFd;
+        let backing = Backing::anonymous(len)?;
+        let file_len = file.metadata()?.len() as usize;
+        if file_len > 0 {
+            let ptr = unsafe {
+                libc::mmap(
+                    backing.ptr as *mut libc::c_void,
+                    file_len,
+                    libc::PROT_READ | libc::PROT_WRITE,
+                    libc::MAP_PRIVATE | libc::MAP_FIXED,
+                    file.as_raw_fd(),
+                    0)
+            };
+            if ptr == libc::MAP_FAILED {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(backing)
+    }
+
+    /// Host bytes in use.
+    #[cfg(unix)]
+    fn resident(&self) -> usize {
+        let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let mut pages = vec![0; self.len.div_ceil(page)];
+        let r = unsafe { libc::mincore(self.ptr as *mut libc::c_void, self.len, pages.as_mut_ptr() as *mut _) };
+        if r != 0 {
+            return self.len;
+        }
+        pages.iter().filter(|x| **x & 1 != 0).count() * page
+    }
+
+    #[cfg(not(unix))]
+    fn anonymous(len: usize) -> io::Result<Backing> {
+        let ptr = Box::into_raw(vec![0u8; len].into_boxed_slice()) as *mut u8;
+        Ok(Backing { ptr, len })
+    }
+
+    #[cfg(not(unix))]
+    fn file(mut file: &File, len: usize) -> io::Result<Backing> {
+        use std::io::Read;
+        let mut backing = Backing::anonymous(len)?;
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+        backing[..data.len()].copy_from_slice(&data);
+        Ok(backing)
+    }
+
+    #[cfg(not(unix))]
+    fn resident(&self) -> usize {
+        self.len
+    }
+}
+
+impl Deref for Backing {
+    type Target = [u8];
+
+    #[inline]
+    fn deref(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+impl DerefMut for Backing {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.ptr, self.len) }
+    }
+}
+
+impl Drop for Backing {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        if self.len > 0 {
+            unsafe {
+                libc::munmap(self.ptr as *mut libc::c_void, self.len);
+            }
+        }
+        #[cfg(not(unix))]
+        unsafe {
+            drop(Box::from_raw(std::ptr::slice_from_raw_parts_mut(self.ptr, self.len)));
+        }
+    }
+}
+
 pub struct Memory {
-    mem: RefCell<Vec<u8>>,
+    mem: RefCell<Backing>,
+    /// One map per checkpoint: pre-images of the pages first written
+    /// after it. Empty when not recording.
+    undo: RefCell<Vec<HashMap<usize, Box<[u8]>>>>,
+    pma: Pma,
 }
 
 impl Memory {
+    /// `limit` bytes of zeroes, taking host memory as they are written.
     #[inline]
     pub fn new(limit: usize) -> Memory {
-        assert!(limit > 0);
-        let mem = Memory {
-            mem: RefCell::new(Vec::new()),
-        };
-        mem.mem.borrow_mut().resize(limit, 0);
-        mem
+        let mem = Backing::anonymous(limit)
+            .unwrap_or_else(|e| panic!("cannot reserve {} bytes of guest RAM: {}", limit, e));
+        Memory {
+            mem: RefCell::new(mem),
+            undo: RefCell::new(Vec::new()),
+            pma: Pma::RAM,
+        }
+    }
+
+    /// The file at `path` mapped copy-on-write, with zeroes up to `limit`
+    /// bytes if it is shorter: guest writes never reach the file.
+    pub fn from_file(path: &Path, limit: usize) -> io::Result<Memory> {
+        let file = File::open(path)?;
+        let limit = limit.max(file.metadata()?.len() as usize);
+        if limit == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "empty image"));
+        }
+        Ok(Memory {
+            mem: RefCell::new(Backing::file(&file, limit)?),
+            undo: RefCell::new(Vec::new()),
+            pma: Pma::RAM,
+        })
+    }
+
+    /// Anything but RAM, e.g. `Pma::ROM`.
+    pub fn with_pma(mut self, pma: Pma) -> Memory {
+        self.pma = pma;
+        self
     }
 }
 
 impl From<&[u8]> for Memory {
     #[inline(always)]
     fn from(i: &[u8]) -> Self {
-        Memory {
-            mem: RefCell::new(i.to_vec()),
+        let mem = Memory::new(i.len());
+        mem.mem.borrow_mut().copy_from_slice(i);
+        mem
+    }
+}
+
+impl Memory {
+    #[inline]
+    fn save_page(&self, addr: usize) {
+        let mut undo = self.undo.borrow_mut();
+        if let Some(pages) = undo.last_mut() {
+            let page = addr >> PAGE_SHIFT;
+            pages.entry(page).or_insert_with(|| {
+                let mem = self.mem.borrow();
+                let start = page << PAGE_SHIFT;
+                mem[start..mem.len().min(start + (1 << PAGE_SHIFT))].into()
+            });
         }
     }
 }
@@ -39,33 +208,239 @@ impl LengthInfo for Memory {
     }
 }
 
+impl Memory {
+    /// `N` bytes at `addr`, little-endian order.
+    #[inline]
+    fn read_array<const N: usize>(&self, addr: usize) -> Option<[u8; N]> {
+        self.mem.borrow().get(addr..addr.checked_add(N)?)?.try_into().ok()
+    }
+
+    #[inline]
+    fn write_array<const N: usize>(&self, addr: usize, value: [u8; N]) -> Option<()> {
+        if addr.checked_add(N)? > self.get_length() {
+            return None;
+        }
+        self.save_page(addr);
+        self.save_page(addr + N - 1);
+        self.mem.borrow_mut()[addr..addr + N].copy_from_slice(&value);
+        Some(())
+    }
+}
+
 impl Readable for Memory {
     fn read_u8(&self, addr: usize) -> Option<u8> {
         self.mem.borrow().get(addr).cloned()
     }
 
+    #[inline]
+    fn read_u16(&self, addr: usize) -> Option<u16> {
+        self.read_array(addr).map(u16::from_le_bytes)
+    }
+
+    #[inline]
+    fn read_u32(&self, addr: usize) -> Option<u32> {
+        self.read_array(addr).map(u32::from_le_bytes)
+    }
+
+    #[inline]
+    fn read_u64(&self, addr: usize) -> Option<u64> {
+        self.read_array(addr).map(u64::from_le_bytes)
+    }
+
     unsafe fn unchecked_read_u8(&self, addr: usize) -> u8 {
         *self.mem.borrow().get_unchecked(addr)
     }
 
+    #[inline]
+    unsafe fn unchecked_read_u16(&self, addr: usize) -> u16 {
+        u16::from_le((self.mem.borrow().as_ptr().add(addr) as *const u16).read_unaligned())
+    }
+
+    #[inline]
+    unsafe fn unchecked_read_u32(&self, addr: usize) -> u32 {
+        u32::from_le((self.mem.borrow().as_ptr().add(addr) as *const u32).read_unaligned())
+    }
+
+    #[inline]
+    unsafe fn unchecked_read_u64(&self, addr: usize) -> u64 {
+        u64::from_le((self.mem.borrow().as_ptr().add(addr) as *const u64).read_unaligned())
+    }
 }
 
 impl Writeable for Memory {
     fn write_u8(&self, addr: usize, value: u8) -> Option<()> {
+        if addr >= self.get_length() {
+            return None;
+        }
+        self.save_page(addr);
         *self.mem.borrow_mut().get_mut(addr)? = value;
         Some(())
     }
 
+    #[inline]
+    fn write_u16(&self, addr: usize, value: u16) -> Option<()> {
+        self.write_array(addr, value.to_le_bytes())
+    }
+
+    #[inline]
+    fn write_u32(&self, addr: usize, value: u32) -> Option<()> {
+        self.write_array(addr, value.to_le_bytes())
+    }
+
+    #[inline]
+    fn write_u64(&self, addr: usize, value: u64) -> Option<()> {
+        self.write_array(addr, value.to_le_bytes())
+    }
+
     unsafe fn unchecked_write_u8(&self, addr: usize, value: u8) {
+        self.save_page(addr);
         *self.mem.borrow_mut().get_unchecked_mut(addr) = value;
     }
+
+    #[inline]
+    unsafe fn unchecked_write_u16(&self, addr: usize, value: u16) {
+        self.write_u16(addr, value);
+    }
+
+    #[inline]
+    unsafe fn unchecked_write_u32(&self, addr: usize, value: u32) {
+        self.write_u32(addr, value);
+    }
+
+    #[inline]
+    unsafe fn unchecked_write_u64(&self, addr: usize, value: u64) {
+        self.write_u64(addr, value);
+    }
 }
 
-impl MMIODevice for Memory {}
+impl MMIODevice for Memory {
+    fn name(&self) -> &str {
+        "memory"
+    }
+
+    #[inline]
+    fn access_sizes(&self) -> usize {
+        1 | 2 | 4 | 8
+    }
+
+    #[inline]
+    fn is_ram(&self) -> bool {
+        true
+    }
+
+    fn checkpoint(&self) {
+        self.undo.borrow_mut().push(HashMap::new());
+    }
+
+    fn rollback(&self, n: usize) {
+        let mut undo = self.undo.borrow_mut();
+        let mut mem = self.mem.borrow_mut();
+        // newest first, so older pre-images win
+        for pages in undo.drain(n..).rev() {
+            for (page, data) in pages {
+                let start = page << PAGE_SHIFT;
+                mem[start..start + data.len()].copy_from_slice(&data);
+            }
+        }
+        undo.push(HashMap::new());
+    }
+
+    fn drop_checkpoint(&self, n: usize) {
+        let mut undo = self.undo.borrow_mut();
+        let pages = undo.remove(n);
+        // the older pre-images win here too
+        for (page, data) in pages {
+            undo[n - 1].entry(page).or_insert(data);
+        }
+    }
+
+    fn discard_checkpoints(&self) {
+        self.undo.borrow_mut().clear();
+    }
+
+    fn ram_usage(&self) -> Vec<(usize, usize, usize)> {
+        vec![(0, self.get_length(), self.mem.borrow().resident())]
+    }
+
+    #[inline]
+    fn pma(&self, _addr: usize) -> Option<Pma> {
+        Some(self.pma)
+    }
+
+    fn host_ram(&self) -> Vec<(usize, usize, *mut u8)> {
+        if !self.undo.borrow().is_empty() || self.pma != Pma::RAM {
+            return Vec::new();
+        }
+        // never resized, so the pointer stays good
+        let mut mem = self.mem.borrow_mut();
+        vec![(0, mem.len(), mem.as_mut_ptr())]
+    }
+}
 
 #[test]
 fn demo() {
     let a = 4 as u64;
     let b = (a as i64 + (-4)) as u64;
     assert_eq!(b, 0);
-}
\ No newline at end of file
+}
+#[test]
+fn test_wide_access() {
+    let mem = Memory::new(0x2000);
+    mem.write_u64(0xffc, 0x1122334455667788).unwrap();
+    assert_eq!(mem.read_u8(0xffc), Some(0x88));
+    assert_eq!(mem.read_u32(0x1000), Some(0x11223344));
+    assert_eq!(mem.read_u16(0xffe), Some(0x5566));
+    assert_eq!(mem.read_u64(0x1ff9), None);
+    assert_eq!(mem.write_u32(0x1ffd, 0), None);
+    // a write across pages saves both
+    mem.checkpoint();
+    mem.write_u64(0xffc, 0).unwrap();
+    mem.rollback(0);
+    assert_eq!(mem.read_u64(0xffc), Some(0x1122334455667788));
+}
+#[test]
+fn test_drop_checkpoint() {
+    let mem = Memory::new(0x3000);
+    mem.write_u8(0, 1).unwrap();
+    mem.checkpoint();
+    mem.write_u8(0, 2).unwrap();
+    mem.checkpoint();
+    mem.write_u8(0, 3).unwrap();
+    mem.write_u8(0x2000, 4).unwrap();
+    mem.checkpoint();
+    mem.write_u8(0x1000, 5).unwrap();
+    // the second is merged into the first
+    mem.drop_checkpoint(1);
+    mem.rollback(1);
+    assert_eq!((mem.read_u8(0), mem.read_u8(0x1000), mem.read_u8(0x2000)), (Some(3), Some(0), Some(4)));
+    mem.rollback(0);
+    assert_eq!((mem.read_u8(0), mem.read_u8(0x2000)), (Some(1), Some(0)));
+}
+#[test]
+fn test_sparse() {
+    let mem = Memory::new(1 << 30);
+    assert!(mem.ram_usage()[0].2 < 1 << 20);
+    mem.write_u64(0x3000_0000, 1).unwrap();
+    assert!(mem.ram_usage()[0].2 >= 1 << 12);
+    // images are mapped copy-on-write and padded with zeroes
+    let path = std::env::temp_dir().join(format!("lemu-image-{}", std::process::id()));
+    std::fs::write(&path, [1, 2, 3, 4]).unwrap();
+    let mem = Memory::from_file(&path, 0x2000).unwrap();
+    assert_eq!(mem.get_length(), 0x2000);
+    assert_eq!(mem.read_u32(0), Some(0x04030201));
+    assert_eq!(mem.read_u32(0x1000), Some(0));
+    mem.write_u8(0, 0xff).unwrap();
+    assert_eq!(mem.read_u8(0), Some(0xff));
+    drop(mem);
+    assert_eq!(std::fs::read(&path).unwrap(), [1, 2, 3, 4]);
+    std::fs::remove_file(&path).unwrap();
+}
+#[test]
+fn test_empty() {
+    let mem = Memory::from(&[][..]);
+    assert_eq!(mem.get_length(), 0);
+    assert_eq!(mem.read_u8(0), None);
+    assert_eq!(mem.write_u8(0, 1), None);
+    mem.checkpoint();
+    mem.rollback(0);
+}
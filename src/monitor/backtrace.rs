@@ -0,0 +1,137 @@
+use crate::{
+    abstract_machine::RegInfo,
+    device::MMIODevice,
+    dwarf::{RegRule, UnwindRow},
+};
+
+use super::Monitor;
+
+
+/// Deeper than this is taken as a corrupted stack.
+const MAX_FRAMES: usize = 64;
+
+const RA: usize = 1;
+const SP: usize = 2;
+const FP: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Frame {
+    pub pc: u64,
+    pub sp: u64,
+}
+
+type Regs = [u64; 32];
+
+impl Monitor {
+    /// Walks the guest stack with CFI where the ELF has it and the
+    /// frame-pointer chain (`ra` at `fp-8`, caller's `fp` at `fp-16`)
+    /// everywhere else.
+    pub fn unwind(&self, machine: &impl RegInfo, memory: &dyn MMIODevice) -> Vec<Frame> {
+        let mut regs: Regs = [0; 32];
+        for (i, r) in regs.iter_mut().enumerate() {
+            *r = machine.get_reg_value(&i.to_string()).unwrap_or(0);
+        }
+        let mut pc = machine.get_reg_value("pc").unwrap_or(0);
+        let mut frames = Vec::new();
+        while frames.len() < MAX_FRAMES {
+            frames.push(Frame { pc, sp: regs[SP] });
+            let (next_pc, next) = match self.debug_info.unwind_row(lookup_pc(pc, frames.len() == 1)) {
+                Some(row) => step_cfi(&row, &regs, memory),
+                None => step_fp(&regs, memory),
+            }.unwrap_or((0, regs));
+            // the stack only grows towards callers
+            if next_pc == 0 || next[SP] < regs[SP] || (next[SP], next_pc) == (regs[SP], pc) {
+                break;
+            }
+            pc = next_pc;
+            regs = next;
+        }
+        frames
+    }
+
+    pub fn backtrace(&self, machine: &impl RegInfo, memory: &dyn MMIODevice) {
+        for (i, frame) in self.unwind(machine, memory).into_iter().enumerate() {
+            let addr = lookup_pc(frame.pc, i == 0);
+            let func = self.symbols.format_addr(frame.pc).unwrap_or_else(|| "??".to_string());
+            match self.debug_info.line(addr) {
+                Some((file, line)) => println!("#{:<2} 0x{:016x} in {} at {}:{}", i, frame.pc, func, file, line),
+                None => println!("#{:<2} 0x{:016x} in {}", i, frame.pc, func),
+            }
+        }
+    }
+}
+
+/// Callers are looked up just before the return address, which may
+/// already belong to the next function or line.
+#[inline]
+fn lookup_pc(pc: u64, innermost: bool) -> u64 {
+    if innermost { pc } else { pc.wrapping_sub(1) }
+}
+
+fn step_cfi(row: &UnwindRow, regs: &Regs, memory: &dyn MMIODevice) -> Option<(u64, Regs)> {
+    let cfa = regs.get(row.cfa_reg as usize)?.wrapping_add(row.cfa_offset as u64);
+    let mut next = *regs;
+    for (i, r) in next.iter_mut().enumerate() {
+        *r = match row.rule(i as u16) {
+            RegRule::Undefined if i == row.ra_reg as usize => return None,
+            RegRule::Undefined | RegRule::SameValue => regs[i],
+            RegRule::Offset(x) => memory.read_u64(cfa.wrapping_add(x as u64) as usize)?,
+            RegRule::ValOffset(x) => cfa.wrapping_add(x as u64),
+            RegRule::Register(x) => *regs.get(x as usize)?,
+        };
+    }
+    next[SP] = cfa;
+    Some((*next.get(row.ra_reg as usize)?, next))
+}
+
+fn step_fp(regs: &Regs, memory: &dyn MMIODevice) -> Option<(u64, Regs)> {
+    let fp = regs[FP];
+    if fp == 0 || fp & 7 != 0 {
+        return None;
+    }
+    let mut next = *regs;
+    next[RA] = memory.read_u64(fp.checked_sub(8)? as usize)?;
+    next[FP] = memory.read_u64(fp.checked_sub(16)? as usize)?;
+    next[SP] = fp;
+    Some((next[RA], next))
+}
+
+
+#[test]
+fn test_unwind_fp() {
+    use crate::{interpreter::riscv64::machine::MachineModel, memory::Memory, elf::SymbolTable, abstract_machine::Writeable};
+    let mm = MachineModel::new(0);
+    let mem = Memory::new(0x100);
+    // main (fp 0x100) -> f (fp 0xc0) -> g (fp 0x80, pc 0x40)
+    mem.write_u64(0x100 - 8, 0).unwrap();
+    mem.write_u64(0x100 - 16, 0).unwrap();
+    mem.write_u64(0xc0 - 8, 0x20).unwrap();
+    mem.write_u64(0xc0 - 16, 0x100).unwrap();
+    mem.write_u64(0x80 - 8, 0x30).unwrap();
+    mem.write_u64(0x80 - 16, 0xc0).unwrap();
+    mm.set_reg_value("pc", 0x40).unwrap();
+    mm.set_reg_value("sp", 0x60).unwrap();
+    mm.set_reg_value("s0", 0x80).unwrap();
+    let monitor = Monitor::new(SymbolTable::default());
+    assert_eq!(monitor.unwind(&mm, &mem), vec![
+        Frame { pc: 0x40, sp: 0x60 },
+        Frame { pc: 0x30, sp: 0x80 },
+        Frame { pc: 0x20, sp: 0xc0 },
+    ]);
+}
+
+#[test]
+fn test_unwind_cfi() {
+    use crate::{memory::Memory, abstract_machine::Writeable};
+    let mem = Memory::new(0x100);
+    // addi sp, sp, -16; sd ra, 8(sp)
+    let row = UnwindRow { cfa_reg: SP as u16, cfa_offset: 16, ra_reg: RA as u16, rules: vec![(RA as u16, RegRule::Offset(-8))] };
+    mem.write_u64(0x78, 0x1234).unwrap();
+    let mut regs = [0; 32];
+    regs[SP] = 0x70;
+    let (pc, next) = step_cfi(&row, &regs, &mem).unwrap();
+    assert_eq!((pc, next[SP], next[RA]), (0x1234, 0x80, 0x1234));
+    let leaf = UnwindRow { cfa_reg: SP as u16, cfa_offset: 0, ra_reg: RA as u16, rules: vec![] };
+    regs[RA] = 0x42;
+    assert_eq!(step_cfi(&leaf, &regs, &mem).map(|x| x.0), Some(0x42));
+}
@@ -0,0 +1,39 @@
+use crate::{
+    abstract_machine::{ExceptionAttr, Debuggable},
+    device::MMIODevice,
+};
+
+use super::Monitor;
+
+
+impl Monitor {
+    /// Hands the whole guest state to the reference model, after loading
+    /// or whenever the monitor changed it.
+    pub fn difftest_sync<E: ExceptionAttr + Clone, M: Debuggable<E>>(&self, machine: &M, memory: &dyn MMIODevice) {
+        if let Some(model) = &self.difftest {
+            model.sync(machine, memory);
+        }
+    }
+
+    /// Steps the reference past the instruction lemu just ran. On a
+    /// mismatch prints both sides and returns false; the reference then
+    /// takes lemu's registers so a `c` carries on comparing from here.
+    pub fn difftest_check<E: ExceptionAttr + Clone, M: Debuggable<E>>(&self, machine: &M, memory: &dyn MMIODevice) -> bool {
+        let model = match &self.difftest {
+            Some(model) => model,
+            None => return true,
+        };
+        let diff = model.step(machine, memory);
+        if diff.is_empty() {
+            return true;
+        }
+        machine.dump_inst_ring();
+        eprintln!("[lemu] difftest: lemu and the reference disagree");
+        eprintln!("{:<10}{:<22}reference", "", "lemu");
+        for (name, dut, reference) in diff {
+            eprintln!("{:<10}0x{:016x}    0x{:016x}", name, dut, reference);
+        }
+        model.sync_regs(machine);
+        false
+    }
+}
@@ -1,74 +1,480 @@
 pub mod sdb;
 pub mod parser;
+pub mod script;
+pub mod view;
+pub mod backtrace;
+pub mod reverse;
+pub mod difftest;
 
 
 use std::{
     process::exit,
-    collections::VecDeque,
+    io::{stdout, Write},
     // convert::identity
 };
 
 use crate::{
-    abstract_machine::{RegInfo, Execable, ExceptionProcessable, ExceptionAttr}, device::MMIODevice
+    abstract_machine::{RegInfo, ExceptionAttr, Debuggable}, device::MMIODevice,
+    elf::SymbolTable, dwarf::DebugInfo, trace,
+    difftest::RefModel,
+    utils::human_size,
 };
 
-use self::sdb::{SDB, Expr};
+use self::sdb::{SDB, SUBCMD, Expr, EvalError, MemType};
+use self::parser::parse_sdb;
+use self::reverse::History;
 
 
+pub struct Monitor {
+    pub symbols: SymbolTable,
+    pub debug_info: DebugInfo,
+    /// `display` expressions as (number, source text, expr)
+    displays: Vec<(usize, String, Expr)>,
+    next_display: usize,
+    /// `watch` expressions as (number, source text, expr, last value)
+    watchpoints: Vec<(usize, String, Expr, Result<u64, EvalError>)>,
+    next_watchpoint: usize,
+    /// register values when execution last resumed and stopped, for `context`
+    resume_regs: Vec<u64>,
+    stop_regs: Vec<u64>,
+    /// `None` unless `record` is on
+    history: Option<History>,
+    /// lock-step reference model, with `--difftest`
+    pub difftest: Option<RefModel>,
+}
+
+impl Monitor {
+    pub fn new(symbols: SymbolTable) -> Monitor {
+        Monitor {
+            symbols,
+            debug_info: DebugInfo::default(),
+            displays: Vec::new(),
+            next_display: 1,
+            watchpoints: Vec::new(),
+            next_watchpoint: 1,
+            resume_regs: Vec::new(),
+            stop_regs: Vec::new(),
+            history: None,
+            difftest: None,
+        }
+    }
+
+    #[inline]
+    pub fn exec<E: ExceptionAttr + Clone, M: Debuggable<E>>(
+        &mut self,
+        cmd: &SDB,
+        machine: &M,
+        memory: &dyn MMIODevice,
+    ) {
+        cmd.eval_sdb(self, machine, memory);
+    }
+
+    /// Reads commands from stdin until EOF or `q`.
+    pub fn repl<E: ExceptionAttr + Clone, M: Debuggable<E>>(
+        &mut self,
+        machine: &M,
+        memory: &dyn MMIODevice,
+    ) {
+        loop {
+            print!("(lemu) ");
+            stdout().flush().unwrap();
+            let mut line = String::new();
+            if std::io::stdin().read_line(&mut line).unwrap() == 0 {
+                break;
+            }
+            if line.trim().is_empty() {
+                continue;
+            }
+            match parse_sdb(&line) {
+                Ok(cmd) => self.exec(&cmd, machine, memory),
+                Err(e) => eprintln!("[lemu] {}", e),
+            }
+        }
+    }
+}
+
+
+const HELP: &str = "\
+help, h                     show this message
+continue, c                 run until the next trap
+quit, q                     exit lemu
+si [N]                      step N instructions
+info r|csr|mem|mtree|iring  print registers, CSRs, RAM, the bus or recent instructions
+x N EXPR                    examine N words at EXPR
+print, p EXPR               evaluate EXPR
+watch, w EXPR               stop when EXPR changes
+d N                         delete watchpoint N
+set $REG = EXPR             write a register or CSR
+set *(TYPE*)ADDR = EXPR     write memory, TYPE is u8/u16/u32/u64
+load FILE ADDR              copy a raw file into memory
+dump ADDR LEN FILE          save memory to a raw file
+disas, l [ADDR [N]]         disassemble N instructions at ADDR (default $pc)
+display [EXPR]              show EXPR after every stop, or all displays
+undisplay N                 remove display N
+context, ctx                changed registers and upcoming instructions
+backtrace, bt               show the guest call stack
+record [stop]               start or stop recording for reverse execution
+rsi [N]                     step N instructions backwards
+rc                          run backwards to the previous trap
+hart [N]                    show or switch the hart being looked at";
+
 impl SDB {
-    pub fn eval_sdb<E: ExceptionAttr + Clone>(&self, breakpoint_list: &mut VecDeque<()>, machine: impl RegInfo + Execable<E> + ExceptionProcessable<E>, memory: &dyn MMIODevice) {
+    pub fn eval_sdb<E: ExceptionAttr + Clone, M: Debuggable<E>>(
+        &self,
+        monitor: &mut Monitor,
+        machine: &M,
+        memory: &dyn MMIODevice,
+    ) {
         // machine.get_reg_value(i)
+        let symbols = &monitor.symbols;
         match self {
-            SDB::H => todo!(),
+            SDB::H => println!("{}", HELP),
             SDB::C => {
-                machine.logged_process_exception(memory, machine.exec_once(memory));
+                monitor.resuming(machine);
+                let r = monitor.run(machine, memory, None);
+                monitor.trapped(machine, memory, r);
+            },
+            SDB::Q => {
+                trace::flush();
+                exit(0)
             },
-            SDB::Q => exit(0),
             SDB::Si(num) => {
-                machine.logged_process_exception(memory, machine.setp_num(memory, *num));
+                monitor.resuming(machine);
+                let r = monitor.run(machine, memory, Some(*num));
+                monitor.trapped(machine, memory, r);
+            },
+            SDB::Info(SUBCMD::Reg) => print_regs(machine, machine.reg_names()),
+            SDB::Info(SUBCMD::Csr) => print_regs(machine, machine.csr_names()),
+            SDB::Info(SUBCMD::Mem) => print_ram(memory),
+            SDB::Info(SUBCMD::MTree) => print_mtree(memory),
+            SDB::Info(SUBCMD::IRing) => machine.dump_inst_ring(),
+            SDB::X(num, expr) => {
+                if let Err(e) = examine(*num, expr, machine, memory, symbols) {
+                    eprintln!("[lemu] {}", e);
+                }
             },
-            SDB::Info(_) => todo!(),
-            SDB::X(_, _) => todo!(),
             SDB::P(expr) => {
-                let r = expr.eval(&machine, memory);
-                println!("{:?}", r);
+                match expr.eval(machine, memory, symbols) {
+                    Ok(r) => println!("{} (0x{:x})", r, r),
+                    Err(e) => eprintln!("[lemu] {}", e),
+                }
+            },
+            SDB::W(text, expr) => monitor.watch(machine, memory, text, expr),
+            SDB::D(n) => {
+                let len = monitor.watchpoints.len();
+                monitor.watchpoints.retain(|(i, _, _, _)| i != n);
+                if monitor.watchpoints.len() == len {
+                    eprintln!("[lemu] no watchpoint number {}", n);
+                }
+            },
+            SDB::SetReg(reg, expr) => {
+                match expr.eval(machine, memory, symbols) {
+                    Ok(v) => if machine.set_reg_value(reg, v).is_none() {
+                        eprintln!("[lemu] unknown or read-only register `${}`", reg);
+                    } else {
+                        monitor.state_changed(machine, memory);
+                    },
+                    Err(e) => eprintln!("[lemu] {}", e),
+                }
+            },
+            SDB::SetMem(ty, addr, expr) => {
+                match set_mem(*ty, addr, expr, machine, memory, symbols) {
+                    Ok(()) => monitor.state_changed(machine, memory),
+                    Err(e) => eprintln!("[lemu] {}", e),
+                }
+            },
+            SDB::Load(path, addr) => {
+                let r = addr.eval(machine, memory, symbols).map_err(|e| e.to_string())
+                    .and_then(|addr| load_file(path, addr, memory));
+                match r {
+                    Ok(()) => monitor.state_changed(machine, memory),
+                    Err(e) => eprintln!("[lemu] {}", e),
+                }
+            },
+            SDB::Dump(addr, len, path) => {
+                let r = addr.eval(machine, memory, symbols)
+                    .and_then(|addr| Ok((addr, len.eval(machine, memory, symbols)?)))
+                    .map_err(|e| e.to_string())
+                    .and_then(|(addr, len)| dump_file(path, addr, len, memory));
+                if let Err(e) = r {
+                    eprintln!("[lemu] {}", e);
+                }
+            },
+            SDB::Disas(addr, count) => {
+                let addr = match addr {
+                    Some(addr) => addr.eval(machine, memory, symbols),
+                    None => Ok(machine.get_reg_value("pc").unwrap()),
+                };
+                match addr {
+                    Ok(addr) => monitor.disas(machine, memory, addr, count.unwrap_or(8)),
+                    Err(e) => eprintln!("[lemu] {}", e),
+                }
+            },
+            SDB::Display(Some((text, expr))) => {
+                let n = monitor.next_display;
+                monitor.next_display += 1;
+                monitor.displays.push((n, text.clone(), expr.clone()));
+                monitor.print_display(machine, memory, n, text, expr);
+            },
+            SDB::Display(None) => monitor.print_displays(machine, memory),
+            SDB::Undisplay(n) => {
+                let len = monitor.displays.len();
+                monitor.displays.retain(|(i, _, _)| i != n);
+                if monitor.displays.len() == len {
+                    eprintln!("[lemu] no display number {}", n);
+                }
             },
-            SDB::W(_) => todo!(),
-            SDB::D(offset) => {
-                breakpoint_list.remove(*offset);
+            SDB::Context => monitor.context(machine, memory),
+            SDB::Bt => monitor.backtrace(machine, memory),
+            SDB::Record(true) => monitor.record(machine, memory),
+            SDB::Record(false) => monitor.record_stop(memory),
+            SDB::Rsi(num) => {
+                monitor.resuming(machine);
+                monitor.reverse_step(machine, memory, *num);
+            },
+            SDB::Rc => {
+                monitor.resuming(machine);
+                monitor.reverse_continue(machine, memory);
+            },
+            SDB::Hart(None) => println!("hart {} of {}", machine.current_hart(), machine.hart_count()),
+            SDB::Hart(Some(n)) => match machine.select_hart(*n) {
+                Some(()) => {
+                    println!("hart {} at 0x{:x}", n, machine.get_reg_value("pc").unwrap());
+                    monitor.print_displays(machine, memory);
+                },
+                None => eprintln!("[lemu] no hart {}, there are {}", n, machine.hart_count()),
+            },
+        }
+    }
+}
+
+fn print_regs(machine: &impl RegInfo, names: &[&str]) {
+    for name in names {
+        if let Some(v) = machine.get_reg_value(name) {
+            println!("{:<10}0x{:016x}    {}", name, v, v as i64);
+        }
+    }
+}
+
+/// RAM regions and the host memory behind them.
+fn print_ram(memory: &dyn MMIODevice) {
+    let ram = memory.ram_usage();
+    println!("{:<20}{:<20}{:>10}{:>10}", "start", "end", "size", "resident");
+    for &(start, len, resident) in ram.iter() {
+        println!("0x{:016x}  0x{:016x}  {:>10}{:>10}", start, start + len, human_size(len), human_size(resident));
+    }
+    let (len, resident) = ram.iter().fold((0, 0), |(a, b), x| (a + x.1, b + x.2));
+    println!("{:<40}{:>10}{:>10}", "total", human_size(len), human_size(resident));
+}
+
+/// Every region on the bus, with its attributes.
+fn print_mtree(memory: &dyn MMIODevice) {
+    for region in memory.regions() {
+        let pma = region.pma.map_or("?".to_string(), |x| x.to_string());
+        print!("0x{:016x}-0x{:016x}  {:>8}  {}  {} [{}]", region.start, region.start + region.len - 1, human_size(region.len), pma, region.name, region.device);
+        match region.alias_of {
+            Some(of) => println!(" (alias of {})", of),
+            None => println!(),
+        }
+    }
+}
+
+fn examine(num: usize, expr: &Expr, machine: &impl RegInfo, memory: &dyn MMIODevice, symbols: &SymbolTable) -> Result<(), EvalError> {
+    let addr = expr.eval(machine, memory, symbols)?;
+    for line in 0..num.div_ceil(4) {
+        let line_addr = addr.wrapping_add(line as u64 * 16);
+        print!("0x{:016x}:", line_addr);
+        for i in 0..(num - line * 4).min(4) {
+            let word_addr = line_addr.wrapping_add(i as u64 * 4);
+            let word = memory.read_u32(word_addr as usize).ok_or(EvalError::MemoryAccess(word_addr));
+            match word {
+                Ok(word) => print!("    0x{:08x}", word),
+                Err(e) => {
+                    println!();
+                    return Err(e);
+                },
             }
         }
+        println!();
+    }
+    Ok(())
+}
+
+fn set_mem(ty: MemType, addr: &Expr, expr: &Expr, machine: &impl RegInfo, memory: &dyn MMIODevice, symbols: &SymbolTable) -> Result<(), EvalError> {
+    let addr = addr.eval(machine, memory, symbols)?;
+    let v = expr.eval(machine, memory, symbols)?;
+    let naddr = addr as usize;
+    let r = match ty.size() {
+        1 => memory.write_u8(naddr, v as u8),
+        2 => memory.write_u16(naddr, v as u16),
+        4 => memory.write_u32(naddr, v as u32),
+        _ => memory.write_u64(naddr, v),
+    };
+    r.ok_or(EvalError::MemoryAccess(addr))
+}
+
+fn load_file(path: &str, addr: u64, memory: &dyn MMIODevice) -> Result<(), String> {
+    let data = std::fs::read(path).map_err(|e| format!("cannot read {}: {}", path, e))?;
+    for (i, byte) in data.iter().enumerate() {
+        let byte_addr = addr.wrapping_add(i as u64);
+        memory.write_u8(byte_addr as usize, *byte)
+            .ok_or_else(|| EvalError::MemoryAccess(byte_addr).to_string())?;
     }
+    println!("loaded {} bytes at 0x{:x}", data.len(), addr);
+    Ok(())
+}
+
+fn dump_file(path: &str, addr: u64, len: u64, memory: &dyn MMIODevice) -> Result<(), String> {
+    let data = (0..len).map(|i| {
+        let byte_addr = addr.wrapping_add(i);
+        memory.read_u8(byte_addr as usize).ok_or_else(|| EvalError::MemoryAccess(byte_addr).to_string())
+    }).collect::<Result<Vec<u8>, String>>()?;
+    std::fs::write(path, &data).map_err(|e| format!("cannot write {}: {}", path, e))?;
+    println!("dumped {} bytes from 0x{:x}", data.len(), addr);
+    Ok(())
 }
 
 impl Expr {
-    pub fn eval(&self, machine: &impl RegInfo, memory: &dyn MMIODevice) -> Option<u64> {
-        // machine.get_reg_value(i)
+    /// Evaluates with C semantics on `u64`: arithmetic wraps, comparisons
+    /// and logical operators yield 0 or 1.
+    pub fn eval(&self, machine: &impl RegInfo, memory: &dyn MMIODevice, symbols: &SymbolTable) -> Result<u64, EvalError> {
+        macro_rules! eval {
+            ($e:expr) => {
+                $e.eval(machine, memory, symbols)?
+            };
+        }
         let r = match self {
-            Expr::Reg(r) => return machine.get_reg_value(r),
+            Expr::Reg(r) => machine.get_reg_value(r).ok_or_else(|| EvalError::UnknownReg(r.clone()))?,
             Expr::Num(num) => *num,
-            Expr::Deref(addr) => return memory.read_u64(addr.eval(machine, memory)? as usize),
-            Expr::Leq   (e1, e2) =>
-                (e1.eval(machine, memory)? <= e2.eval(machine, memory)?) as u64,
-            Expr::Lt    (e1, e2) =>
-                (e1.eval(machine, memory)? < e2.eval(machine, memory)?) as u64,
-            Expr::Geq   (e1, e2) =>
-                (e1.eval(machine, memory)? >= e2.eval(machine, memory)?) as u64,
-            Expr::Gt    (e1, e2) =>
-                (e1.eval(machine, memory)? > e2.eval(machine, memory)?) as u64,
-            Expr::Eq    (e1, e2) =>
-                (e1.eval(machine, memory)? == e2.eval(machine, memory)?) as u64,
-            Expr::Ne    (e1, e2) =>
-                (e1.eval(machine, memory)? != e2.eval(machine, memory)?) as u64,
-            Expr::And   (e1, e2) => e1.eval(machine, memory)? & e2.eval(machine, memory)?,
-                Expr::Or    (e1, e2) => e1.eval(machine, memory)? | e2.eval(machine, memory)?,
-            Expr::Add   (e1, e2) => e1.eval(machine, memory)? + e2.eval(machine, memory)?,
-            Expr::Sub   (e1, e2) => e1.eval(machine, memory)? - e2.eval(machine, memory)?,
-            Expr::Mul   (e1, e2) => e1.eval(machine, memory)? * e2.eval(machine, memory)?,
-            Expr::Div   (e1, e2) => e1.eval(machine, memory)? / e2.eval(machine, memory)?,
-            Expr::Mod   (e1, e2) => e1.eval(machine, memory)? % e2.eval(machine, memory)?,
-            Expr::Pow   (e1, e2) => e1.eval(machine, memory)?.pow(e2.eval(machine, memory)? as u32),
+            Expr::Symbol(s) => symbols.lookup(s).ok_or_else(|| EvalError::UnknownSymbol(s.clone()))?.addr,
+            Expr::Deref(ty, addr) => {
+                let addr = eval!(addr);
+                let naddr = addr as usize;
+                let r = match ty {
+                    MemType::U8  => memory.read_u8(naddr).map(|x| x as u64),
+                    MemType::U16 => memory.read_u16(naddr).map(|x| x as u64),
+                    MemType::U32 => memory.read_u32(naddr).map(|x| x as u64),
+                    MemType::U64 => memory.read_u64(naddr),
+                    MemType::I8  => memory.read_u8(naddr).map(|x| x as i8 as i64 as u64),
+                    MemType::I16 => memory.read_u16(naddr).map(|x| x as i16 as i64 as u64),
+                    MemType::I32 => memory.read_u32(naddr).map(|x| x as i32 as i64 as u64),
+                    MemType::I64 => memory.read_u64(naddr),
+                };
+                r.ok_or(EvalError::MemoryAccess(addr))?
+            },
+            Expr::Neg   (e) => eval!(e).wrapping_neg(),
+            Expr::Not   (e) => (eval!(e) == 0) as u64,
+            Expr::BitNot(e) => !eval!(e),
+            // short-circuit like C, so `$a0 && *$a0` is safe
+            Expr::And   (e1, e2) => (eval!(e1) != 0 && eval!(e2) != 0) as u64,
+            Expr::Or    (e1, e2) => (eval!(e1) != 0 || eval!(e2) != 0) as u64,
+            Expr::BitAnd(e1, e2) => eval!(e1) & eval!(e2),
+            Expr::BitOr (e1, e2) => eval!(e1) | eval!(e2),
+            Expr::Xor   (e1, e2) => eval!(e1) ^ eval!(e2),
+            Expr::Leq   (e1, e2) => (eval!(e1) <= eval!(e2)) as u64,
+            Expr::Lt    (e1, e2) => (eval!(e1) < eval!(e2)) as u64,
+            Expr::Geq   (e1, e2) => (eval!(e1) >= eval!(e2)) as u64,
+            Expr::Gt    (e1, e2) => (eval!(e1) > eval!(e2)) as u64,
+            Expr::Eq    (e1, e2) => (eval!(e1) == eval!(e2)) as u64,
+            Expr::Ne    (e1, e2) => (eval!(e1) != eval!(e2)) as u64,
+            Expr::Shl   (e1, e2) => {
+                let (l, r) = (eval!(e1), eval!(e2));
+                if r < 64 { l << r } else { 0 }
+            },
+            Expr::Shr   (e1, e2) => {
+                let (l, r) = (eval!(e1), eval!(e2));
+                if r < 64 { l >> r } else { 0 }
+            },
+            Expr::Add   (e1, e2) => eval!(e1).wrapping_add(eval!(e2)),
+            Expr::Sub   (e1, e2) => eval!(e1).wrapping_sub(eval!(e2)),
+            Expr::Mul   (e1, e2) => eval!(e1).wrapping_mul(eval!(e2)),
+            Expr::Div   (e1, e2) => eval!(e1).checked_div(eval!(e2)).ok_or(EvalError::DivideByZero)?,
+            Expr::Mod   (e1, e2) => eval!(e1).checked_rem(eval!(e2)).ok_or(EvalError::DivideByZero)?,
+            Expr::Pow   (e1, e2) => {
+                let (l, r) = (eval!(e1), eval!(e2));
+                l.wrapping_pow(r.min(u32::MAX as u64) as u32)
+            },
         };
-        Some(r)
+        Ok(r)
     }
-}
\ No newline at end of file
+}
+
+
+#[cfg(test)]
+fn eval_str(i: &str) -> Result<u64, EvalError> {
+    use crate::{interpreter::riscv64::machine::MachineModel, memory::Memory};
+    let mm = MachineModel::new(0);
+    mm.gpr.store(10, 8);
+    let mem = Memory::from([0x80u8, 0xff, 0xff, 0xff, 0x01, 0, 0, 0, 0xaa].as_ref());
+    parser::parse_expr(i).unwrap().eval(&mm, &mem, &SymbolTable::default())
+}
+
+#[test]
+fn test_eval() {
+    assert_eq!(eval_str("0 - 1"), Ok(u64::MAX));
+    assert_eq!(eval_str("0xffffffffffffffff * 2"), Ok(u64::MAX - 1));
+    assert_eq!(eval_str("6 ^ 3"), Ok(5));
+    assert_eq!(eval_str("1 << 70"), Ok(0));
+    assert_eq!(eval_str("2 && 4"), Ok(1));
+    assert_eq!(eval_str("$a0 + $zero"), Ok(8));
+    assert_eq!(eval_str("*(u8*)0"), Ok(0x80));
+    assert_eq!(eval_str("*(i8*)0"), Ok(-128i64 as u64));
+    assert_eq!(eval_str("*(i32*)0"), Ok(-128i64 as u64));
+    assert_eq!(eval_str("*(u32*)0"), Ok(0xffffff80));
+    assert_eq!(eval_str("*(u8*)$a0"), Ok(0xaa));
+    assert_eq!(eval_str("1 / 0"), Err(EvalError::DivideByZero));
+    assert_eq!(eval_str("*(u64*)$a0"), Err(EvalError::MemoryAccess(8)));
+    assert_eq!(eval_str("$nope"), Err(EvalError::UnknownReg("nope".to_string())));
+    assert_eq!(eval_str("main"), Err(EvalError::UnknownSymbol("main".to_string())));
+    assert_eq!(eval_str("0 && *0x1000"), Ok(0));
+}
+
+#[test]
+fn test_set() {
+    use crate::{interpreter::riscv64::{machine::MachineModel, reg::csrmap}, memory::Memory, abstract_machine::Readable};
+    let mm = MachineModel::new(0);
+    let mem = Memory::new(16);
+    let mut monitor = Monitor::new(SymbolTable::default());
+    let mut run = |i: &str| monitor.exec(&parser::parse_sdb(i).unwrap(), &mm, &mem);
+    run("set $a0 = 3 * 4");
+    assert_eq!(mm.gpr.read(10), 12);
+    run("set $x0 = 1");
+    assert_eq!(mm.gpr.read(0), 0);
+    run("set $pc = 0x1000");
+    assert_eq!(mm.pc.read(), 0x1000);
+    let misa = mm.csr.read(csrmap::MISA);
+    run("set $misa = 0");
+    assert_eq!(mm.csr.read(csrmap::MISA), misa);
+    run("set $mhartid = 1");
+    assert_eq!(mm.csr.read(csrmap::MHARTID), 0);
+    run("set $mtvec = 0x80000003");
+    assert_eq!(mm.csr.read(csrmap::MTVEC), 0x80000001);
+    run("set *(u16*)2 = 0x12345");
+    run("set *8 = -1");
+    assert_eq!(mem.read_u64(0), Some(0x2345_0000));
+    assert_eq!(mem.read_u64(8), Some(u64::MAX));
+}
+
+#[test]
+fn test_watch() {
+    use crate::{interpreter::riscv64::machine::MachineModel, memory::Memory};
+    let mm = MachineModel::new(0);
+    // addi a1, a1, 1; addi a1, a1, 1; addi a0, a0, 1; jal x0, -12
+    let inst_list: Vec<u8> = [0x00158593u32, 0x00158593, 0x00150513, 0xff5ff06f]
+        .into_iter().flat_map(|x: u32| x.to_le_bytes()).collect();
+    let mem = Memory::from(inst_list.as_ref());
+    let mut monitor = Monitor::new(SymbolTable::default());
+    let mut run = |i: &str| monitor.exec(&parser::parse_sdb(i).unwrap(), &mm, &mem);
+    run("watch $a0");
+    run("si 10");
+    assert_eq!((mm.gpr.read(10), mm.gpr.read(11), mm.pc.read()), (1, 2, 0xc));
+    run("si 10");
+    assert_eq!((mm.gpr.read(10), mm.gpr.read(11), mm.pc.read()), (2, 4, 0xc));
+    // a change made from the monitor does not trigger it
+    run("set $a0 = 7");
+    run("si 2");
+    assert_eq!((mm.gpr.read(10), mm.pc.read()), (7, 4));
+    run("d 1");
+    run("si 10");
+    assert_eq!(mm.gpr.read(10), 10);
+}
@@ -1,10 +1,10 @@
 // use pest::error::Error;
-use pest::iterators::{Pair, Pairs};
+use pest::iterators::Pair;
 #[allow(unused_imports)]
 use pest::Parser;
 use pest_derive::*;
 
-use super::sdb::Expr;
+use super::sdb::{SDB, SUBCMD, ScriptLine, Expr, MemType};
 
 
 #[derive(Parser)]
@@ -12,119 +12,215 @@ use super::sdb::Expr;
 pub enum SDBParser {}
 
 
-pub fn get_expr(i: Pair<Rule>) -> Expr {
-    debug_assert_eq!(i.as_rule(), Rule::expr);
-    let mut iter = i.into_inner();
-    let mut r = get_expr_relational(iter.next().unwrap());
-    while let Some((op, rhs)) = get_expr_expend(&mut iter) {
-        r = match op {
-            '&' => Expr::And(Box::new(r), Box::new(rhs)),
-            '|' => Expr::Or(Box::new(r), Box::new(rhs)),
-            _ => unreachable!(),
-        };
-    }
-    r
+pub fn parse_sdb(i: &str) -> Result<SDB, String> {
+    let mut r = SDBParser::parse(Rule::sdb, i.trim()).map_err(|e| {
+        e.renamed_rules(|r| match r {
+            Rule::sdb => "a command (try `help`)".to_string(),
+            r => format!("{:?}", r),
+        }).to_string()
+    })?;
+    let r = r.next().unwrap().into_inner().next().unwrap();
+    Ok(get_sdb(r))
 }
 
-fn get_expr_expend(i: &mut Pairs<Rule>) -> Option<(char, Expr)> {
-    let op = i.next()?;
-    let rhs = get_expr_relational(i.next().unwrap());
-    let op = match op.as_str() {
-        "&&"    => '&',
-        "||"     => '|',
-        _ => unreachable!()
+pub fn parse_script_line(i: &str) -> Result<ScriptLine, String> {
+    let mut r = SDBParser::parse(Rule::script_line, i.trim()).map_err(|e| {
+        e.renamed_rules(|r| match r {
+            Rule::script_line => "a command or statement".to_string(),
+            r => format!("{:?}", r),
+        }).to_string()
+    })?;
+    let i = r.next().unwrap().into_inner().next().unwrap();
+    let r = match i.as_rule() {
+        Rule::stmt_if => ScriptLine::If(get_expr(i.into_inner().nth(1).unwrap())),
+        Rule::stmt_while => ScriptLine::While(get_expr(i.into_inner().nth(1).unwrap())),
+        Rule::stmt_else => ScriptLine::Else,
+        Rule::stmt_end => ScriptLine::End,
+        Rule::stmt_echo => ScriptLine::Echo(i.into_inner().next().map_or(String::new(), |x| x.as_str().to_string())),
+        Rule::stmt_assert => ScriptLine::Assert(get_expr(i.into_inner().nth(1).unwrap())),
+        _ => ScriptLine::Cmd(get_sdb(i)),
     };
-    Some((op, rhs))
+    Ok(r)
 }
 
-fn get_expr_relational(i: Pair<Rule>) -> Expr {
-    debug_assert_eq!(i.as_rule(), Rule::expr_relational);
+fn get_sdb(i: Pair<Rule>) -> SDB {
+    let rule = i.as_rule();
     let mut iter = i.into_inner();
-    let mut r = get_expr_binary_level1(iter.next().unwrap());
-    while let Some((op, rhs)) = get_expr_relational_expend(&mut iter) {
-        r = match op {
-            "<="    => Expr::Leq(Box::new(r), Box::new(rhs)),
-            "<"     => Expr::Lt(Box::new(r), Box::new(rhs)),
-            ">="    => Expr::Geq(Box::new(r), Box::new(rhs)),
-            ">"     => Expr::Gt(Box::new(r), Box::new(rhs)),
-            "=="    => Expr::Eq(Box::new(r), Box::new(rhs)),
-            "!="    => Expr::Ne(Box::new(r), Box::new(rhs)),
-            _ => unreachable!(),
-        };
+    // skip the keyword
+    iter.next();
+    match rule {
+        Rule::cmd_help => SDB::H,
+        Rule::cmd_c => SDB::C,
+        Rule::cmd_q => SDB::Q,
+        Rule::cmd_si => SDB::Si(iter.next().map_or(1, |x| get_number(x) as usize)),
+        Rule::cmd_info => SDB::Info(get_subcmd(iter.next().unwrap())),
+        Rule::cmd_x => {
+            let num = get_number(iter.next().unwrap()) as usize;
+            SDB::X(num, get_expr(iter.next().unwrap()))
+        },
+        Rule::cmd_p => SDB::P(get_expr(iter.next().unwrap())),
+        Rule::cmd_w => {
+            let expr = iter.next().unwrap();
+            SDB::W(expr.as_str().to_string(), get_expr(expr))
+        },
+        Rule::cmd_d => SDB::D(get_number(iter.next().unwrap()) as usize),
+        Rule::cmd_set => {
+            let target = iter.next().unwrap();
+            match target.as_rule() {
+                Rule::set_reg => {
+                    let mut iter = target.into_inner();
+                    let reg = get_reg(iter.next().unwrap());
+                    SDB::SetReg(reg, get_expr(iter.next().unwrap()))
+                },
+                Rule::set_mem => {
+                    let mut iter = target.into_inner().peekable();
+                    let ty = if iter.peek().unwrap().as_rule() == Rule::deref_cast {
+                        get_mem_type(iter.next().unwrap().into_inner().next().unwrap())
+                    } else {
+                        MemType::U64
+                    };
+                    let addr = get_expr_unray(iter.next().unwrap());
+                    SDB::SetMem(ty, addr, get_expr(iter.next().unwrap()))
+                },
+                _ => unreachable!(),
+            }
+        },
+        Rule::cmd_load => {
+            let path = get_path(iter.next().unwrap());
+            SDB::Load(path, get_expr(iter.next().unwrap()))
+        },
+        Rule::cmd_dump => {
+            let addr = get_expr(iter.next().unwrap());
+            let len = get_expr(iter.next().unwrap());
+            SDB::Dump(addr, len, get_path(iter.next().unwrap()))
+        },
+        Rule::cmd_disas => {
+            let addr = iter.next().map(get_expr);
+            SDB::Disas(addr, iter.next().map(|x| get_number(x) as usize))
+        },
+        Rule::cmd_display => SDB::Display(iter.next().map(|x| (x.as_str().to_string(), get_expr(x)))),
+        Rule::cmd_undisplay => SDB::Undisplay(get_number(iter.next().unwrap()) as usize),
+        Rule::cmd_context => SDB::Context,
+        Rule::cmd_bt => SDB::Bt,
+        Rule::cmd_record => SDB::Record(iter.next().is_none()),
+        Rule::cmd_rsi => SDB::Rsi(iter.next().map_or(1, |x| get_number(x) as usize)),
+        Rule::cmd_rc => SDB::Rc,
+        Rule::cmd_hart => SDB::Hart(iter.next().map(|x| get_number(x) as usize)),
+        _ => unreachable!(),
     }
-    r
 }
 
-fn get_expr_relational_expend(i: &mut Pairs<Rule>) -> Option<(&'static str, Expr)> {
-    let op = i.next()?;
-    let rhs = get_expr_binary_level1(i.next().unwrap());
-    // /*
-    let op = match op.as_str() {
-        "<="    => "<=",
-        "<"     => "<",
-        ">="    => ">=",
-        ">"     => ">",
-        "=="    => "==",
-        "!="    => "!=",
-        _ => unreachable!()
-    };
-    //  */
-    Some((op, rhs))
+fn get_subcmd(i: Pair<Rule>) -> SUBCMD {
+    debug_assert_eq!(i.as_rule(), Rule::info_subcmd);
+    match i.as_str() {
+        "reg" | "r" => SUBCMD::Reg,
+        "mem" | "m" => SUBCMD::Mem,
+        "csr"       => SUBCMD::Csr,
+        "mtree"     => SUBCMD::MTree,
+        "iring"     => SUBCMD::IRing,
+        _ => unreachable!(),
+    }
 }
 
-fn get_expr_binary_level1(i: Pair<Rule>) -> Expr {
-    debug_assert_eq!(i.as_rule(), Rule::expr_binary_level1);
-    let mut iter = i.into_inner();
-    let mut r = get_expr_binary_level2(iter.next().unwrap());
-    while let Some((op, rhs)) = get_expr_binary_level1_expend(&mut iter) {
-        r = match op {
-            '+' => Expr::Add(Box::new(r), Box::new(rhs)),
-            '-' => Expr::Sub(Box::new(r), Box::new(rhs)),
-            _ => unreachable!(),
-        };
-    }
-    r
+fn get_path(i: Pair<Rule>) -> String {
+    debug_assert_eq!(i.as_rule(), Rule::path);
+    let s = i.as_str();
+    s.strip_prefix('"').and_then(|x| x.strip_suffix('"')).unwrap_or(s).to_string()
 }
 
-fn get_expr_binary_level1_expend(i: &mut Pairs<Rule>) -> Option<(char, Expr)> {
-    let op = i.next()?;
-    let rhs = get_expr_binary_level2(i.next().unwrap());
-    let op = op.as_str().chars().next().unwrap();
-    Some((op, rhs))
+pub fn parse_expr(i: &str) -> Result<Expr, String> {
+    let mut r = SDBParser::parse(Rule::expr_line, i).map_err(|e| e.to_string())?;
+    let r = r.next().unwrap().into_inner().next().unwrap();
+    Ok(get_expr(r))
 }
 
+pub fn get_expr(i: Pair<Rule>) -> Expr {
+    get_expr_binary(i, Rule::expr, get_expr_logical_and)
+}
+
+fn get_expr_logical_and(i: Pair<Rule>) -> Expr {
+    get_expr_binary(i, Rule::expr_logical_and, get_expr_bit_or)
+}
+
+fn get_expr_bit_or(i: Pair<Rule>) -> Expr {
+    get_expr_binary(i, Rule::expr_bit_or, get_expr_bit_xor)
+}
+
+fn get_expr_bit_xor(i: Pair<Rule>) -> Expr {
+    get_expr_binary(i, Rule::expr_bit_xor, get_expr_bit_and)
+}
+
+fn get_expr_bit_and(i: Pair<Rule>) -> Expr {
+    get_expr_binary(i, Rule::expr_bit_and, get_expr_equality)
+}
+
+fn get_expr_equality(i: Pair<Rule>) -> Expr {
+    get_expr_binary(i, Rule::expr_equality, get_expr_relational)
+}
+
+fn get_expr_relational(i: Pair<Rule>) -> Expr {
+    get_expr_binary(i, Rule::expr_relational, get_expr_shift)
+}
+
+fn get_expr_shift(i: Pair<Rule>) -> Expr {
+    get_expr_binary(i, Rule::expr_shift, get_expr_binary_level1)
+}
+
+fn get_expr_binary_level1(i: Pair<Rule>) -> Expr {
+    get_expr_binary(i, Rule::expr_binary_level1, get_expr_binary_level2)
+}
 
 fn get_expr_binary_level2(i: Pair<Rule>) -> Expr {
-    debug_assert_eq!(i.as_rule(), Rule::expr_binary_level2);
+    get_expr_binary(i, Rule::expr_binary_level2, get_expr_binary_level3)
+}
+
+/// left-associative `operand (op operand)*`
+fn get_expr_binary(i: Pair<Rule>, rule: Rule, operand: fn(Pair<Rule>) -> Expr) -> Expr {
+    debug_assert_eq!(i.as_rule(), rule);
     let mut iter = i.into_inner();
-    let mut r = get_expr_binary_level3(iter.next().unwrap());
-    while let Some((op, rhs)) = get_expr_binary_level2_expend(&mut iter) {
-        r = match op {
-            '*' => Expr::Mul(Box::new(r), Box::new(rhs)),
-            '/' => Expr::Div(Box::new(r), Box::new(rhs)),
-            '%' => Expr::Mod(Box::new(r), Box::new(rhs)),
-            _ => unreachable!(),
-        };
+    let mut r = operand(iter.next().unwrap());
+    while let Some(op) = iter.next() {
+        let rhs = operand(iter.next().unwrap());
+        r = make_binary(op.as_str(), r, rhs);
     }
     r
 }
 
-fn get_expr_binary_level2_expend(i: &mut Pairs<Rule>) -> Option<(char, Expr)> {
-    let op = i.next()?;
-    let rhs = get_expr_binary_level3(i.next().unwrap());
-    let op = op.as_str().chars().next().unwrap();
-    Some((op, rhs))
+fn make_binary(op: &str, lhs: Expr, rhs: Expr) -> Expr {
+    let (l, r) = (Box::new(lhs), Box::new(rhs));
+    match op {
+        "||"    => Expr::Or(l, r),
+        "&&"    => Expr::And(l, r),
+        "|"     => Expr::BitOr(l, r),
+        "^"     => Expr::Xor(l, r),
+        "&"     => Expr::BitAnd(l, r),
+        "=="    => Expr::Eq(l, r),
+        "!="    => Expr::Ne(l, r),
+        "<="    => Expr::Leq(l, r),
+        "<"     => Expr::Lt(l, r),
+        ">="    => Expr::Geq(l, r),
+        ">"     => Expr::Gt(l, r),
+        "<<"    => Expr::Shl(l, r),
+        ">>"    => Expr::Shr(l, r),
+        "+"     => Expr::Add(l, r),
+        "-"     => Expr::Sub(l, r),
+        "*"     => Expr::Mul(l, r),
+        "/"     => Expr::Div(l, r),
+        "%"     => Expr::Mod(l, r),
+        "**"    => Expr::Pow(l, r),
+        _ => unreachable!(),
+    }
 }
 
 fn get_expr_binary_level3(i: Pair<Rule>) -> Expr {
     debug_assert_eq!(i.as_rule(), Rule::expr_binary_level3);
     let mut iter = i.into_inner();
-    let expr = iter.next().unwrap();
+    let expr = get_expr_unray(iter.next().unwrap());
     if iter.next().is_some() {
         let expr2 = iter.next().unwrap();
-        Expr::Pow(Box::new(get_expr_binary_level3(expr)), Box::new(get_expr_binary_level3(expr2)))
+        Expr::Pow(Box::new(expr), Box::new(get_expr_binary_level3(expr2)))
     } else {
-        get_expr_unray(expr)
+        expr
     }
 }
 
@@ -132,15 +228,38 @@ fn get_expr_unray(i: Pair<Rule>) -> Expr {
     debug_assert_eq!(i.as_rule(), Rule::expr_unary);
     let mut iter = i.into_inner();
     let expr = iter.next().unwrap();
-    if expr.as_rule() == Rule::unary_op {
-        let op = expr.as_str();
-        let expr = iter.next().unwrap();
-        match op {
-            "*" => Expr::Deref(Box::new(get_expr_unray(expr))),
-            _ => panic!("unary operator not implemented: {}", op),
-        }
-    } else {
-        get_expr_atom(expr)
+    match expr.as_rule() {
+        Rule::deref_cast => {
+            let ty = get_mem_type(expr.into_inner().next().unwrap());
+            Expr::Deref(ty, Box::new(get_expr_unray(iter.next().unwrap())))
+        },
+        Rule::unary_op => {
+            let op = expr.as_str();
+            let expr = Box::new(get_expr_unray(iter.next().unwrap()));
+            match op {
+                "*" => Expr::Deref(MemType::U64, expr),
+                "-" => Expr::Neg(expr),
+                "!" => Expr::Not(expr),
+                "~" => Expr::BitNot(expr),
+                _ => unreachable!(),
+            }
+        },
+        _ => get_expr_atom(expr),
+    }
+}
+
+fn get_mem_type(i: Pair<Rule>) -> MemType {
+    debug_assert_eq!(i.as_rule(), Rule::mem_type);
+    match i.as_str() {
+        "u8"    => MemType::U8,
+        "u16"   => MemType::U16,
+        "u32"   => MemType::U32,
+        "u64"   => MemType::U64,
+        "i8"    => MemType::I8,
+        "i16"   => MemType::I16,
+        "i32"   => MemType::I32,
+        "i64"   => MemType::I64,
+        _ => unreachable!(),
     }
 }
 
@@ -151,15 +270,14 @@ fn get_expr_atom(i: Pair<Rule>) -> Expr {
         Rule::expr => get_expr(i),
         Rule::reg => Expr::Reg(get_reg(i)),
         Rule::number => Expr::Num(get_number(i)),
+        Rule::symbol => Expr::Symbol(i.as_str().to_string()),
         _ => unreachable!(),
     }
 }
 
 fn get_reg(i: Pair<Rule>) -> String {
     debug_assert_eq!(i.as_rule(), Rule::reg);
-    let mut iters = i.into_inner();
-    iters.next();
-    let r = iters.next().unwrap();
+    let r = i.into_inner().next().unwrap();
     debug_assert!(r.as_rule() == Rule::id || r.as_rule() == Rule::number);
     r.as_str().to_string()
 }
@@ -169,14 +287,19 @@ fn get_id(i: Pair<Rule>) -> String {
     i.as_str().to_string()
 }
 
+/// Literals wider than 64 bits wrap, like the rest of the arithmetic.
 fn get_number(i: Pair<Rule>) -> u64 {
     debug_assert_eq!(i.as_rule(), Rule::number);
     let s = i.as_str();
-    if s.starts_with('-') {
-        s.parse::<i64>().unwrap() as u64
-    } else {
-        s.parse::<u64>().unwrap()
-    }
+    let (radix, digits) = match s.get(..2) {
+        Some("0x") => (16, &s[2..]),
+        Some("0o") => (8, &s[2..]),
+        Some("0b") => (2, &s[2..]),
+        _ => (10, s),
+    };
+    digits.chars().fold(0u64, |acc, c| {
+        acc.wrapping_mul(radix as u64).wrapping_add(c.to_digit(radix).unwrap() as u64)
+    })
 }
 
 
@@ -193,7 +316,7 @@ fn test_parser1() {
     let mut r = SDBParser::parse(Rule::expr, "*114514").unwrap();
     let r = r.next().unwrap();
     let r = get_expr(r);
-    assert_eq!(r, Expr::Deref(Box::new(Expr::Num(114514))));
+    assert_eq!(r, Expr::Deref(MemType::U64, Box::new(Expr::Num(114514))));
 }
 
 #[test]
@@ -204,3 +327,82 @@ fn test_parser2() {
     assert_eq!(r, Expr::Add(Box::new(Expr::Num(1)), Box::new(Expr::Mul(Box::new(Expr::Sub(Box::new(Expr::Num(3)), Box::new(Expr::Num(4)))), Box::new(Expr::Num(2))))));
 }
 
+#[test]
+fn test_parser_precedence() {
+    let num = |x| Box::new(Expr::Num(x));
+    assert_eq!(parse_expr("1 | 2 ^ 3 & 4").unwrap(),
+        Expr::BitOr(num(1), Box::new(Expr::Xor(num(2), Box::new(Expr::BitAnd(num(3), num(4)))))));
+    assert_eq!(parse_expr("1 << 2 + 3").unwrap(),
+        Expr::Shl(num(1), Box::new(Expr::Add(num(2), num(3)))));
+    assert_eq!(parse_expr("1 == 2 && 3 < 4 || 0").unwrap(),
+        Expr::Or(
+            Box::new(Expr::And(Box::new(Expr::Eq(num(1), num(2))), Box::new(Expr::Lt(num(3), num(4))))),
+            num(0)));
+    assert_eq!(parse_expr("2 ** 3 ** 2").unwrap(),
+        Expr::Pow(num(2), Box::new(Expr::Pow(num(3), num(2)))));
+    assert_eq!(parse_expr("-~!0x10").unwrap(),
+        Expr::Neg(Box::new(Expr::BitNot(Box::new(Expr::Not(num(16)))))));
+}
+
+#[test]
+fn test_parser_deref() {
+    assert_eq!(parse_expr("*(i32*)($sp + 8)").unwrap(),
+        Expr::Deref(MemType::I32, Box::new(Expr::Add(Box::new(Expr::Reg("sp".to_string())), Box::new(Expr::Num(8))))));
+    assert_eq!(parse_expr("3 * *(u8*)main").unwrap(),
+        Expr::Mul(Box::new(Expr::Num(3)), Box::new(Expr::Deref(MemType::U8, Box::new(Expr::Symbol("main".to_string()))))));
+    assert_eq!(parse_expr("$f3 - $mstatus").unwrap(),
+        Expr::Sub(Box::new(Expr::Reg("f3".to_string())), Box::new(Expr::Reg("mstatus".to_string()))));
+    assert!(parse_expr("1 +").is_err());
+}
+
+#[test]
+fn test_parse_sdb() {
+    assert_eq!(parse_sdb("si").unwrap(), SDB::Si(1));
+    assert_eq!(parse_sdb("si 10\n").unwrap(), SDB::Si(10));
+    assert_eq!(parse_sdb("info r").unwrap(), SDB::Info(SUBCMD::Reg));
+    assert_eq!(parse_sdb("info iring").unwrap(), SDB::Info(SUBCMD::IRing));
+    assert_eq!(parse_sdb("info mtree").unwrap(), SDB::Info(SUBCMD::MTree));
+    assert_eq!(parse_sdb("info m").unwrap(), SDB::Info(SUBCMD::Mem));
+    assert_eq!(parse_sdb("x 4 $sp").unwrap(), SDB::X(4, Expr::Reg("sp".to_string())));
+    assert_eq!(parse_sdb("p c").unwrap(), SDB::P(Expr::Symbol("c".to_string())));
+    assert_eq!(parse_sdb("set $a0 = 1").unwrap(), SDB::SetReg("a0".to_string(), Expr::Num(1)));
+    assert_eq!(parse_sdb("set *0x80000000 = 1").unwrap(),
+        SDB::SetMem(MemType::U64, Expr::Num(0x80000000), Expr::Num(1)));
+    assert_eq!(parse_sdb("set *(u16*)($sp + 8) = 0xffff").unwrap(),
+        SDB::SetMem(MemType::U16, Expr::Add(Box::new(Expr::Reg("sp".to_string())), Box::new(Expr::Num(8))), Expr::Num(0xffff)));
+    assert_eq!(parse_sdb("load fw.bin 0x80000000").unwrap(),
+        SDB::Load("fw.bin".to_string(), Expr::Num(0x80000000)));
+    assert_eq!(parse_sdb("dump 0x80000000 16 \"out file.bin\"").unwrap(),
+        SDB::Dump(Expr::Num(0x80000000), Expr::Num(16), "out file.bin".to_string()));
+    assert_eq!(parse_sdb("disas").unwrap(), SDB::Disas(None, None));
+    assert_eq!(parse_sdb("watch $a0").unwrap(), SDB::W("$a0".to_string(), Expr::Reg("a0".to_string())));
+    assert_eq!(parse_sdb("l main 4").unwrap(), SDB::Disas(Some(Expr::Symbol("main".to_string())), Some(4)));
+    assert_eq!(parse_sdb("display $a0  +  1").unwrap(),
+        SDB::Display(Some(("$a0  +  1".to_string(), Expr::Add(Box::new(Expr::Reg("a0".to_string())), Box::new(Expr::Num(1)))))));
+    assert_eq!(parse_sdb("undisplay 2").unwrap(), SDB::Undisplay(2));
+    assert_eq!(parse_sdb("ctx").unwrap(), SDB::Context);
+    assert_eq!(parse_sdb("bt").unwrap(), SDB::Bt);
+    assert_eq!(parse_sdb("record").unwrap(), SDB::Record(true));
+    assert_eq!(parse_sdb("record stop").unwrap(), SDB::Record(false));
+    assert_eq!(parse_sdb("hart").unwrap(), SDB::Hart(None));
+    assert_eq!(parse_sdb("hart 1").unwrap(), SDB::Hart(Some(1)));
+    assert_eq!(parse_sdb("rsi 3").unwrap(), SDB::Rsi(3));
+    assert_eq!(parse_sdb("rc").unwrap(), SDB::Rc);
+    assert!(parse_sdb("sii").is_err());
+    assert!(parse_sdb("set $a0").is_err());
+}
+
+#[test]
+fn test_parse_script_line() {
+    assert_eq!(parse_script_line("while $pc != 0x80000000").unwrap(),
+        ScriptLine::While(Expr::Ne(Box::new(Expr::Reg("pc".to_string())), Box::new(Expr::Num(0x80000000)))));
+    assert_eq!(parse_script_line("if 1").unwrap(), ScriptLine::If(Expr::Num(1)));
+    assert_eq!(parse_script_line("else").unwrap(), ScriptLine::Else);
+    assert_eq!(parse_script_line("end").unwrap(), ScriptLine::End);
+    assert_eq!(parse_script_line("echo").unwrap(), ScriptLine::Echo(String::new()));
+    assert_eq!(parse_script_line("echo  hello,  world").unwrap(), ScriptLine::Echo("hello,  world".to_string()));
+    assert_eq!(parse_script_line("assert $a0 == 0").unwrap(),
+        ScriptLine::Assert(Expr::Eq(Box::new(Expr::Reg("a0".to_string())), Box::new(Expr::Num(0)))));
+    assert_eq!(parse_script_line("si 3").unwrap(), ScriptLine::Cmd(SDB::Si(3)));
+    assert!(parse_script_line("ending").is_err());
+}
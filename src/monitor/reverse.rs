@@ -0,0 +1,240 @@
+use std::any::Any;
+
+use crate::{
+    abstract_machine::{ExceptionAttr, Debuggable},
+    device::MMIODevice,
+};
+
+use super::Monitor;
+
+
+/// Instructions between checkpoints, i.e. the most a reverse step replays
+/// in recent history.
+const CHECKPOINT_INTERVAL: u64 = 10000;
+/// Checkpoints kept; past that every other one of the older half goes, so
+/// the further back, the sparser they are.
+const MAX_CHECKPOINTS: usize = 256;
+
+/// Recorded execution: hart snapshots every `CHECKPOINT_INTERVAL`
+/// instructions, with memory undo logs, device input and the clock kept
+/// by the bus itself.
+pub struct History {
+    /// instructions executed since recording started
+    icount: u64,
+    checkpoints: Vec<(u64, Box<dyn Any>)>,
+    /// instruction counts right after each trap, where `rc` stops
+    traps: Vec<u64>,
+}
+
+impl History {
+    fn step<E: ExceptionAttr + Clone, M: Debuggable<E>>(&mut self, machine: &M, memory: &dyn MMIODevice) -> Result<(), E> {
+        if !matches!(self.checkpoints.last(), Some((n, _)) if self.icount < n + CHECKPOINT_INTERVAL) {
+            if self.checkpoints.len() == MAX_CHECKPOINTS {
+                self.thin(memory);
+            }
+            memory.checkpoint();
+            self.checkpoints.push((self.icount, machine.snapshot()));
+        }
+        self.icount += 1;
+        let r = machine.exec_once(memory);
+        // replays move the clock too, so events fire at the same points
+        if let Some(clock) = memory.clock() {
+            clock.advance(1);
+        }
+        r
+    }
+
+    /// Restores the nearest checkpoint at or before `target` and replays
+    /// up to it.
+    fn goto<E: ExceptionAttr + Clone, M: Debuggable<E>>(&mut self, machine: &M, memory: &dyn MMIODevice, target: u64) {
+        let i = self.checkpoints.partition_point(|(n, _)| *n <= target) - 1;
+        self.checkpoints.truncate(i + 1);
+        let (n, state) = &self.checkpoints[i];
+        machine.restore(state.as_ref());
+        memory.rollback(i);
+        self.icount = *n;
+        while self.icount < target {
+            let r = self.step(machine, memory);
+            machine.process_exception(r);
+        }
+        self.traps.retain(|x| *x <= target);
+    }
+
+    /// Drops every other checkpoint of the older half, never the first.
+    fn thin(&mut self, memory: &dyn MMIODevice) {
+        for i in (1..self.checkpoints.len() / 2).step_by(2).rev() {
+            memory.drop_checkpoint(i);
+            self.checkpoints.remove(i);
+        }
+    }
+
+    #[inline]
+    fn start(&self) -> u64 {
+        self.checkpoints.first().map_or(self.icount, |(n, _)| *n)
+    }
+}
+
+impl Monitor {
+    /// `record`: (re)starts recording from the current state.
+    pub fn record<E: ExceptionAttr + Clone, M: Debuggable<E>>(&mut self, machine: &M, memory: &dyn MMIODevice) {
+        self.record_stop(memory);
+        let mut history = History { icount: 0, checkpoints: Vec::new(), traps: Vec::new() };
+        memory.checkpoint();
+        history.checkpoints.push((0, machine.snapshot()));
+        self.history = Some(history);
+    }
+
+    pub fn record_stop(&mut self, memory: &dyn MMIODevice) {
+        if self.history.take().is_some() {
+            memory.discard_checkpoints();
+        }
+    }
+
+    /// The monitor changed guest state behind the recording's back, so the
+    /// old history cannot be replayed any more.
+    pub fn state_changed<E: ExceptionAttr + Clone, M: Debuggable<E>>(&mut self, machine: &M, memory: &dyn MMIODevice) {
+        machine.flush_code_cache();
+        self.difftest_sync(machine, memory);
+        if self.history.is_some() {
+            eprintln!("[lemu] guest state changed, recording restarted");
+            self.record(machine, memory);
+        }
+    }
+
+    /// Runs `steps` instructions, or until a trap, a difftest mismatch or
+    /// a watchpoint; checkpoints along the way while recording.
+    pub fn run<E: ExceptionAttr + Clone, M: Debuggable<E>>(&mut self, machine: &M, memory: &dyn MMIODevice, steps: Option<usize>) -> Result<(), E> {
+        if self.history.is_none() && self.difftest.is_none() && self.watchpoints.is_empty() {
+            return match steps {
+                Some(n) => machine.setp_num(memory, n),
+                None => machine.exec_catch_interrupt_loop(memory),
+            };
+        }
+        // the monitor may have peeked at a device, and runs outside it taken
+        // interrupts, since the last run
+        memory.take_device_access();
+        machine.take_interrupt();
+        self.rearm_watchpoints(machine, memory);
+        let clock = memory.clock();
+        let mut n = 0;
+        while steps != Some(n) {
+            let r = match &mut self.history {
+                Some(history) => history.step(machine, memory),
+                None => {
+                    let r = machine.exec_once(memory);
+                    if let Some(clock) = &clock {
+                        clock.advance(1);
+                    }
+                    r
+                },
+            };
+            if r.is_err() {
+                if let Some(history) = &mut self.history {
+                    history.traps.push(history.icount);
+                }
+                // compared once the trap is taken, see `trapped`
+                return r;
+            }
+            if !self.difftest_check(machine, memory) {
+                return Ok(());
+            }
+            if self.watch_triggered(machine, memory) {
+                return Ok(());
+            }
+            n += 1;
+        }
+        Ok(())
+    }
+
+    /// `rsi N`
+    pub fn reverse_step<E: ExceptionAttr + Clone, M: Debuggable<E>>(&mut self, machine: &M, memory: &dyn MMIODevice, num: usize) {
+        let history = match &mut self.history {
+            Some(h) if h.icount > h.start() => h,
+            Some(_) => return eprintln!("[lemu] no more reverse-execution history"),
+            None => return eprintln!("[lemu] not recording, try `record`"),
+        };
+        let target = history.icount.saturating_sub(num as u64).max(history.start());
+        history.goto(machine, memory, target);
+        self.difftest_sync(machine, memory);
+        self.stopped(machine, memory);
+    }
+
+    /// `rc`: back to the previous trap, or the start of the recording.
+    pub fn reverse_continue<E: ExceptionAttr + Clone, M: Debuggable<E>>(&mut self, machine: &M, memory: &dyn MMIODevice) {
+        let history = match &mut self.history {
+            Some(h) if h.icount > h.start() => h,
+            Some(_) => return eprintln!("[lemu] no more reverse-execution history"),
+            None => return eprintln!("[lemu] not recording, try `record`"),
+        };
+        let target = history.traps.iter().rev()
+            .find(|x| **x < history.icount)
+            .copied()
+            .unwrap_or_else(|| history.start());
+        history.goto(machine, memory, target);
+        self.difftest_sync(machine, memory);
+        self.stopped(machine, memory);
+    }
+}
+
+
+#[test]
+fn test_reverse() {
+    use crate::{interpreter::riscv64::machine::MachineModel, memory::Memory, elf::SymbolTable, abstract_machine::{RegInfo, Readable}};
+    let mm = MachineModel::new(0);
+    // addi a0, a0, 1; sd a0, 0x100(x0); jal x0, -8
+    let mut inst_list: Vec<u8> = [0x00150513u32, 0x10a03023, 0xff9ff06f]
+        .into_iter().flat_map(|x: u32| x.to_le_bytes()).collect();
+    inst_list.resize(0x200, 0);
+    let mem = Memory::from(inst_list.as_ref());
+    let mut monitor = Monitor::new(SymbolTable::default());
+    monitor.record(&mm, &mem);
+    monitor.run(&mm, &mem, Some(3 * 30000)).unwrap();
+    assert_eq!(mm.get_reg_value("a0"), Some(30000));
+    monitor.reverse_step(&mm, &mem, 4);
+    assert_eq!(mm.get_reg_value("a0"), Some(29999));
+    assert_eq!(mm.get_reg_value("pc"), Some(8));
+    assert_eq!(mem.read_u64(0x100), Some(29999));
+    monitor.reverse_continue(&mm, &mem);
+    assert_eq!((mm.get_reg_value("a0"), mm.get_reg_value("pc")), (Some(0), Some(0)));
+    assert_eq!(mem.read_u64(0x100), Some(0));
+    monitor.run(&mm, &mem, Some(5)).unwrap();
+    assert_eq!(mm.get_reg_value("a0"), Some(2));
+    // a long recording keeps a bounded number of checkpoints, sparser
+    // further back, and can still go there
+    monitor.record(&mm, &mem);
+    let steps = (MAX_CHECKPOINTS as u64 + 20) * CHECKPOINT_INTERVAL;
+    monitor.run(&mm, &mem, Some(steps as usize)).unwrap();
+    let history = monitor.history.as_ref().unwrap();
+    assert!(history.checkpoints.len() <= MAX_CHECKPOINTS);
+    assert_eq!(history.checkpoints[1].0, 2 * CHECKPOINT_INTERVAL);
+    monitor.reverse_step(&mm, &mem, steps as usize - 3 * 5000);
+    assert_eq!((mm.get_reg_value("a0"), mm.get_reg_value("pc")), (Some(5002), Some(8)));
+    assert_eq!(mem.read_u64(0x100), Some(5002));
+}
+
+#[test]
+fn test_reverse_clock() {
+    use std::{cell::Cell, rc::Rc};
+    use crate::{interpreter::riscv64::machine::MachineModel, memory::Memory, elf::SymbolTable, device::Device};
+    let mm = MachineModel::new(0);
+    // addi a0, a0, 1; jal x0, -4
+    let program: Vec<u8> = [0x00150513u32, 0xffdff06f]
+        .into_iter().flat_map(|x: u32| x.to_le_bytes()).collect();
+    let mmio = Device::new();
+    mmio.add_device("ram", 0, Box::new(Memory::from(program.as_ref()))).unwrap();
+    let clock = mmio.clock().unwrap();
+    let fired = Rc::new(Cell::new(0));
+    let at = fired.clone();
+    clock.schedule(50, move |clock| at.set(clock.now()));
+    let mut monitor = Monitor::new(SymbolTable::default());
+    monitor.record(&mm, &mmio);
+    monitor.run(&mm, &mmio, Some(100)).unwrap();
+    assert_eq!((clock.now(), fired.get()), (100, 50));
+    // back before the event, which is pending again
+    monitor.reverse_step(&mm, &mmio, 80);
+    assert_eq!(clock.now(), 20);
+    assert_eq!(clock.until_next(), 30);
+    fired.set(0);
+    monitor.run(&mm, &mmio, Some(40)).unwrap();
+    assert_eq!((clock.now(), fired.get()), (60, 50));
+}
@@ -0,0 +1,227 @@
+use std::fmt::Display;
+
+use crate::{
+    abstract_machine::{Debuggable, ExceptionAttr},
+    device::MMIODevice,
+};
+
+use super::{
+    Monitor,
+    sdb::{SDB, ScriptLine, Expr, EvalError},
+    parser::parse_script_line,
+};
+
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Stmt {
+    Cmd(SDB),
+    Echo(String),
+    Assert(usize, String, Expr),
+    If(usize, Expr, Vec<Stmt>, Vec<Stmt>),
+    While(usize, Expr, Vec<Stmt>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScriptError {
+    Syntax(usize, String),
+    Eval(usize, EvalError),
+    AssertFailed(usize, String),
+}
+
+impl ScriptError {
+    /// 1 for a failed `assert`, 2 for a broken script.
+    #[inline]
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ScriptError::AssertFailed(_, _) => 1,
+            _ => 2,
+        }
+    }
+}
+
+impl Display for ScriptError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ScriptError::Syntax(line, e) => write!(f, "{}: {}", line, e),
+            ScriptError::Eval(line, e) => write!(f, "{}: {}", line, e),
+            ScriptError::AssertFailed(line, text) => write!(f, "{}: assertion failed: {}", line, text),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Script {
+    pub stmts: Vec<Stmt>,
+}
+
+type Lines = std::vec::IntoIter<(usize, String, ScriptLine)>;
+
+/// Statements of a block and the `else`/`end` line that closed it.
+type Block = (Vec<Stmt>, Option<(usize, ScriptLine)>);
+
+impl Script {
+    /// Blank lines and lines starting with `#` are skipped.
+    pub fn parse(src: &str) -> Result<Script, ScriptError> {
+        let mut lines = Vec::new();
+        for (n, line) in src.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let r = parse_script_line(line).map_err(|e| ScriptError::Syntax(n + 1, e))?;
+            lines.push((n + 1, line.to_string(), r));
+        }
+        let mut lines = lines.into_iter();
+        match get_block(&mut lines)? {
+            (stmts, None) => Ok(Script { stmts }),
+            (_, Some((n, ScriptLine::Else))) => Err(ScriptError::Syntax(n, "`else` without `if`".to_string())),
+            (_, Some((n, _))) => Err(ScriptError::Syntax(n, "`end` without `if` or `while`".to_string())),
+        }
+    }
+}
+
+/// Collects statements up to the `else`/`end` that closes the block.
+fn get_block(lines: &mut Lines) -> Result<Block, ScriptError> {
+    let mut stmts = Vec::new();
+    while let Some((n, text, line)) = lines.next() {
+        let stmt = match line {
+            ScriptLine::Else | ScriptLine::End => return Ok((stmts, Some((n, line)))),
+            ScriptLine::Cmd(cmd) => Stmt::Cmd(cmd),
+            ScriptLine::Echo(s) => Stmt::Echo(s),
+            ScriptLine::Assert(e) => Stmt::Assert(n, text["assert".len()..].trim().to_string(), e),
+            ScriptLine::If(cond) => {
+                let (then, term) = get_block(lines)?;
+                let otherwise = match term {
+                    Some((_, ScriptLine::End)) => Vec::new(),
+                    Some((_, ScriptLine::Else)) => match get_block(lines)? {
+                        (otherwise, Some((_, ScriptLine::End))) => otherwise,
+                        (_, Some((m, _))) => return Err(ScriptError::Syntax(m, "`else` without `if`".to_string())),
+                        (_, None) => return Err(ScriptError::Syntax(n, "`if` without `end`".to_string())),
+                    },
+                    _ => return Err(ScriptError::Syntax(n, "`if` without `end`".to_string())),
+                };
+                Stmt::If(n, cond, then, otherwise)
+            },
+            ScriptLine::While(cond) => {
+                let (body, term) = get_block(lines)?;
+                match term {
+                    Some((_, ScriptLine::End)) => Stmt::While(n, cond, body),
+                    Some((m, _)) => return Err(ScriptError::Syntax(m, "`else` without `if`".to_string())),
+                    None => return Err(ScriptError::Syntax(n, "`while` without `end`".to_string())),
+                }
+            },
+        };
+        stmts.push(stmt);
+    }
+    Ok((stmts, None))
+}
+
+impl Monitor {
+    pub fn run_script<E: ExceptionAttr + Clone, M: Debuggable<E>>(
+        &mut self,
+        script: &Script,
+        machine: &M,
+        memory: &dyn MMIODevice,
+    ) -> Result<(), ScriptError> {
+        self.run_block(&script.stmts, machine, memory)
+    }
+
+    fn run_block<E: ExceptionAttr + Clone, M: Debuggable<E>>(
+        &mut self,
+        stmts: &[Stmt],
+        machine: &M,
+        memory: &dyn MMIODevice,
+    ) -> Result<(), ScriptError> {
+        let cond = |this: &Self, n: usize, e: &Expr| {
+            e.eval(machine, memory, &this.symbols).map(|x| x != 0).map_err(|e| ScriptError::Eval(n, e))
+        };
+        for stmt in stmts {
+            match stmt {
+                Stmt::Cmd(cmd) => self.exec(cmd, machine, memory),
+                Stmt::Echo(s) => println!("{}", s),
+                Stmt::Assert(n, text, e) => {
+                    if !cond(self, *n, e)? {
+                        return Err(ScriptError::AssertFailed(*n, text.clone()));
+                    }
+                },
+                Stmt::If(n, e, then, otherwise) => {
+                    if cond(self, *n, e)? {
+                        self.run_block(then, machine, memory)?;
+                    } else {
+                        self.run_block(otherwise, machine, memory)?;
+                    }
+                },
+                Stmt::While(n, e, body) => {
+                    while cond(self, *n, e)? {
+                        self.run_block(body, machine, memory)?;
+                    }
+                },
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs a script file, reporting errors on stderr; `Err` carries the
+    /// exit status.
+    pub fn source<E: ExceptionAttr + Clone, M: Debuggable<E>>(
+        &mut self,
+        path: &str,
+        machine: &M,
+        memory: &dyn MMIODevice,
+    ) -> Result<(), i32> {
+        let src = std::fs::read_to_string(path).map_err(|e| {
+            eprintln!("[lemu] cannot read {}: {}", path, e);
+            2
+        })?;
+        Script::parse(&src)
+            .and_then(|script| self.run_script(&script, machine, memory))
+            .map_err(|e| {
+                eprintln!("[lemu] {}:{}", path, e);
+                e.exit_code()
+            })
+    }
+}
+
+
+#[test]
+fn test_script_parse() {
+    let script = Script::parse("
+        # comment
+        if $a0
+            echo yes
+        else
+            while 0
+                si
+            end
+        end
+    ").unwrap();
+    assert_eq!(script.stmts, vec![
+        Stmt::If(3, Expr::Reg("a0".to_string()),
+            vec![Stmt::Echo("yes".to_string())],
+            vec![Stmt::While(6, Expr::Num(0), vec![Stmt::Cmd(SDB::Si(1))])]),
+    ]);
+    assert_eq!(Script::parse("if 1\nsi").err(), Some(ScriptError::Syntax(1, "`if` without `end`".to_string())));
+    assert_eq!(Script::parse("end").err(), Some(ScriptError::Syntax(1, "`end` without `if` or `while`".to_string())));
+    assert_eq!(Script::parse("while 1\nelse\nend").err(), Some(ScriptError::Syntax(2, "`else` without `if`".to_string())));
+}
+
+#[test]
+fn test_script_run() {
+    use crate::{interpreter::riscv64::machine::MachineModel, memory::Memory, elf::SymbolTable};
+    let mm = MachineModel::new(0);
+    // addi a0, a0, 1; jal x0, -4
+    let inst_list: Vec<u8> = [0x00150513u32, 0xffdff06f]
+        .into_iter().flat_map(|x: u32| x.to_le_bytes()).collect();
+    let mem = Memory::from(inst_list.as_ref());
+    let mut monitor = Monitor::new(SymbolTable::default());
+    let script = Script::parse("
+        while $a0 < 5
+            si 2
+        end
+        assert $a0 == 5 && $pc == 0
+        set $a1 = 7
+        assert $a1 == 8
+    ").unwrap();
+    assert_eq!(monitor.run_script(&script, &mm, &mem), Err(ScriptError::AssertFailed(7, "$a1 == 8".to_string())));
+    assert_eq!(mm.gpr.read(10), 5);
+}
@@ -1,3 +1,5 @@
+use std::fmt::Display;
+
 
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -9,8 +11,37 @@ pub enum SDB {
     Info(SUBCMD),
     X(usize, Expr),
     P(Expr),
-    W(Expr),
+    /// source text is kept for printing
+    W(String, Expr),
     D(usize),
+    SetReg(String, Expr),
+    SetMem(MemType, Expr, Expr),
+    Load(String, Expr),
+    Dump(Expr, Expr, String),
+    Disas(Option<Expr>, Option<usize>),
+    /// source text is kept for printing
+    Display(Option<(String, Expr)>),
+    Undisplay(usize),
+    Context,
+    Bt,
+    /// `record` / `record stop`
+    Record(bool),
+    Rsi(usize),
+    Rc,
+    /// `hart` shows which one the monitor looks at, `hart N` switches
+    Hart(Option<usize>),
+}
+
+/// One line of a script file, before blocks are matched up.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScriptLine {
+    Cmd(SDB),
+    If(Expr),
+    While(Expr),
+    Else,
+    End,
+    Echo(String),
+    Assert(Expr),
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -18,17 +49,59 @@ pub enum SUBCMD {
     Reg,
     Mem,
     Csr,
+    /// the bus layout
+    MTree,
+    /// recently executed instructions
+    IRing,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemType {
+    U8,
+    U16,
+    U32,
+    U64,
+    I8,
+    I16,
+    I32,
+    I64,
+}
+
+impl MemType {
+    #[inline]
+    pub fn size(&self) -> usize {
+        match self {
+            MemType::U8 | MemType::I8 => 1,
+            MemType::U16 | MemType::I16 => 2,
+            MemType::U32 | MemType::I32 => 4,
+            MemType::U64 | MemType::I64 => 8,
+        }
+    }
+
+    #[inline]
+    pub fn is_signed(&self) -> bool {
+        matches!(self, MemType::I8 | MemType::I16 | MemType::I32 | MemType::I64)
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Expr {
     Reg(String),
     Num(u64),
-    Deref(Box<Expr>),
+    Symbol(String),
+    Deref(MemType, Box<Expr>),
+
+    Neg(Box<Expr>),
+    Not(Box<Expr>),
+    BitNot(Box<Expr>),
 
     And(Box<Expr>, Box<Expr>),
     Or(Box<Expr>, Box<Expr>),
 
+    BitAnd(Box<Expr>, Box<Expr>),
+    BitOr(Box<Expr>, Box<Expr>),
+    Xor(Box<Expr>, Box<Expr>),
+
     Leq(Box<Expr>, Box<Expr>),
     Lt(Box<Expr>, Box<Expr>),
     Geq(Box<Expr>, Box<Expr>),
@@ -36,10 +109,32 @@ pub enum Expr {
     Eq(Box<Expr>, Box<Expr>),
     Ne(Box<Expr>, Box<Expr>),
 
+    Shl(Box<Expr>, Box<Expr>),
+    Shr(Box<Expr>, Box<Expr>),
+
     Add(Box<Expr>, Box<Expr>),
     Sub(Box<Expr>, Box<Expr>),
     Mul(Box<Expr>, Box<Expr>),
     Div(Box<Expr>, Box<Expr>),
     Mod(Box<Expr>, Box<Expr>),
     Pow(Box<Expr>, Box<Expr>),
-}
\ No newline at end of file
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EvalError {
+    UnknownReg(String),
+    UnknownSymbol(String),
+    MemoryAccess(u64),
+    DivideByZero,
+}
+
+impl Display for EvalError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EvalError::UnknownReg(r) => write!(f, "unknown register `${}`", r),
+            EvalError::UnknownSymbol(s) => write!(f, "no symbol `{}` in the loaded ELF", s),
+            EvalError::MemoryAccess(addr) => write!(f, "cannot access memory at address 0x{:x}", addr),
+            EvalError::DivideByZero => write!(f, "division by zero"),
+        }
+    }
+}
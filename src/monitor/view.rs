@@ -0,0 +1,192 @@
+use crate::{
+    abstract_machine::{ExceptionAttr, Debuggable, RegInfo, Disassembler},
+    device::MMIODevice,
+    trace,
+};
+
+use super::{Monitor, sdb::{Expr, EvalError}};
+
+
+/// Instructions shown by `context` after the pc.
+const CONTEXT_INSTS: usize = 4;
+
+impl Monitor {
+    /// objdump-like listing with a `<symbol>:` header at each function
+    /// entry and `=>` marking the pc.
+    pub fn disas(&self, machine: &(impl Disassembler + RegInfo), memory: &dyn MMIODevice, addr: u64, count: usize) {
+        let (lines, fault) = self.listing(machine, memory, addr, count);
+        lines.iter().for_each(|x| println!("{}", x));
+        if let Some(addr) = fault {
+            eprintln!("[lemu] cannot access memory at address 0x{:x}", addr);
+        }
+    }
+
+    /// The lines of `disas`, and the address it stopped at if it could
+    /// not read all `count` instructions.
+    fn listing(&self, machine: &(impl Disassembler + RegInfo), memory: &dyn MMIODevice, addr: u64, count: usize) -> (Vec<String>, Option<u64>) {
+        let pc = machine.get_reg_value("pc");
+        let mut addr = addr;
+        let mut lines = Vec::new();
+        for _ in 0..count {
+            if let Some(sym) = self.symbols.find(addr).filter(|s| s.addr == addr) {
+                lines.push(format!("<{}>:", sym.name));
+            }
+            let (bytes, text) = match machine.disassemble(memory, addr) {
+                Some(r) => r,
+                None => return (lines, Some(addr)),
+            };
+            let marker = if Some(addr) == pc { "=>" } else { "  " };
+            let label = self.symbols.format_addr(addr).map_or(String::new(), |x| format!(" <{}>", x));
+            lines.push(format!("{} 0x{:016x}{}:    {}\t{}",
+                marker,
+                addr,
+                label,
+                bytes.iter().map(|x| format!("{:02x}", x)).collect::<Vec<_>>().join(" "),
+                text));
+            addr = addr.wrapping_add(bytes.len() as u64);
+        }
+        (lines, None)
+    }
+
+    pub fn print_display(&self, machine: &impl RegInfo, memory: &dyn MMIODevice, n: usize, text: &str, expr: &Expr) {
+        match expr.eval(machine, memory, &self.symbols) {
+            Ok(r) => println!("{}: {} = {} (0x{:x})", n, text, r, r),
+            Err(e) => println!("{}: {} = <{}>", n, text, e),
+        }
+    }
+
+    pub fn print_displays(&self, machine: &impl RegInfo, memory: &dyn MMIODevice) {
+        for (n, text, expr) in self.displays.iter() {
+            self.print_display(machine, memory, *n, text, expr);
+        }
+    }
+
+    pub fn watch(&mut self, machine: &impl RegInfo, memory: &dyn MMIODevice, text: &str, expr: &Expr) {
+        let n = self.next_watchpoint;
+        self.next_watchpoint += 1;
+        let value = expr.eval(machine, memory, &self.symbols);
+        println!("watchpoint {}: {} = {}", n, text, format_value(&value));
+        self.watchpoints.push((n, text.to_string(), expr.clone(), value));
+    }
+
+    /// Takes the current values as the ones to compare against, so what
+    /// the monitor itself changed does not count.
+    pub fn rearm_watchpoints(&mut self, machine: &impl RegInfo, memory: &dyn MMIODevice) {
+        for (_, _, expr, value) in self.watchpoints.iter_mut() {
+            *value = expr.eval(machine, memory, &self.symbols);
+        }
+    }
+
+    /// Re-evaluates every watchpoint, printing the old and new values of
+    /// those that changed.
+    pub fn watch_triggered(&mut self, machine: &impl RegInfo, memory: &dyn MMIODevice) -> bool {
+        let mut hit = false;
+        for (n, text, expr, old) in self.watchpoints.iter_mut() {
+            let new = expr.eval(machine, memory, &self.symbols);
+            if new != *old {
+                println!("watchpoint {}: {}", n, text);
+                println!("old value = {}", format_value(old));
+                println!("new value = {}", format_value(&new));
+                *old = new;
+                hit = true;
+            }
+        }
+        hit
+    }
+
+    /// Called right before `c`/`si` hands control to the machine.
+    pub fn resuming(&mut self, machine: &impl RegInfo) {
+        self.resume_regs = snapshot(machine);
+    }
+
+    /// Logs the trap that ended `c`/`si`, with a backtrace when the guest
+    /// cannot recover from it, then hands it to the machine.
+    pub fn trapped<E: ExceptionAttr + Clone, M: Debuggable<E>>(&mut self, machine: &M, memory: &dyn MMIODevice, r: Result<(), E>) {
+        let fatal = matches!(&r, Err(e) if e.is_fatal());
+        let trap = r.is_err();
+        let r = machine.exception_log(memory, r);
+        if fatal {
+            self.backtrace(machine, memory);
+        }
+        machine.process_exception(r);
+        if trap {
+            self.difftest_check(machine, memory);
+        }
+        self.stopped(machine, memory);
+    }
+
+    /// Called whenever execution returns to the monitor.
+    pub fn stopped<E: ExceptionAttr + Clone, M: Debuggable<E>>(&mut self, machine: &M, memory: &dyn MMIODevice) {
+        self.stop_regs = snapshot(machine);
+        trace::flush();
+        self.print_displays(machine, memory);
+    }
+
+    /// Registers changed by the last `c`/`si`, then the next few
+    /// instructions.
+    pub fn context<E: ExceptionAttr + Clone, M: Debuggable<E>>(&self, machine: &M, memory: &dyn MMIODevice) {
+        println!("{}", self.changed(machine));
+        if let Some(pc) = machine.get_reg_value("pc") {
+            self.disas(machine, memory, pc, CONTEXT_INSTS);
+        }
+    }
+
+    fn changed(&self, machine: &impl RegInfo) -> String {
+        let changed = machine.reg_names().iter()
+            .zip(self.stop_regs.iter().zip(self.resume_regs.iter()))
+            .filter(|(_, (now, before))| now != before)
+            .map(|(name, (now, _))| format!("{}=0x{:x}", name, now))
+            .collect::<Vec<_>>();
+        if changed.is_empty() {
+            "no registers changed".to_string()
+        } else {
+            format!("changed: {}", changed.join("  "))
+        }
+    }
+}
+
+fn format_value(value: &Result<u64, EvalError>) -> String {
+    match value {
+        Ok(v) => format!("{} (0x{:x})", v, v),
+        Err(e) => format!("<{}>", e),
+    }
+}
+
+#[inline]
+fn snapshot(machine: &impl RegInfo) -> Vec<u64> {
+    machine.reg_names().iter().map(|x| machine.get_reg_value(x).unwrap_or(0)).collect()
+}
+
+#[test]
+fn test_listing() {
+    use crate::{interpreter::riscv64::machine::MachineModel, memory::Memory, abstract_machine::Execable, elf::{Symbol, SymbolKind, SymbolTable}};
+    let mm = MachineModel::new(0);
+    // main: addi a0, a0, 1; jal x0, -4
+    let mut inst_list: Vec<u8> = [0x00150513u32, 0xffdff06f]
+        .into_iter().flat_map(|x: u32| x.to_le_bytes()).collect();
+    inst_list.resize(0x10, 0);
+    let mem = Memory::from(inst_list.as_ref());
+    let main = Symbol { name: "main".to_string(), addr: 0, size: 8, kind: SymbolKind::Func };
+    let mut monitor = Monitor::new(SymbolTable::new(vec![main]));
+    // the text is up to the disassembler
+    let prefixes = |lines: &[String]| lines.iter().map(|x| x.split('\t').next().unwrap().to_string()).collect::<Vec<_>>();
+    let (lines, fault) = monitor.listing(&mm, &mem, 0, 3);
+    assert_eq!(prefixes(&lines), [
+        "<main>:",
+        "=> 0x0000000000000000 <main>:    13 05 15 00",
+        "   0x0000000000000004 <main+0x4>:    6f f0 df ff",
+        "   0x0000000000000008:    00 00 00 00",
+    ]);
+    assert_eq!(fault, None);
+    let (lines, fault) = monitor.listing(&mm, &mem, 0xc, 2);
+    assert_eq!(lines.len(), 1);
+    assert_eq!(fault, Some(0x10));
+    // `context` after a step
+    monitor.resuming(&mm);
+    monitor.stopped(&mm, &mem);
+    assert_eq!(monitor.changed(&mm), "no registers changed");
+    mm.exec_once(&mem).unwrap();
+    monitor.stopped(&mm, &mem);
+    assert_eq!(monitor.changed(&mm), "changed: pc=0x4  a0=0x1");
+    assert_eq!(prefixes(&monitor.listing(&mm, &mem, 4, 1).0), ["=> 0x0000000000000004 <main+0x4>:    6f f0 df ff"]);
+}
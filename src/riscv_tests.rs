@@ -0,0 +1,83 @@
+//! Runs the riscv-tests ISA binaries (`rv64ui-p-addi` and friends) found in
+//! `$LEMU_RISCV_TESTS`, or `tests/`, each on a fresh machine. Both the ELF
+//! files and raw `.bin` images loaded at 0x80000000 work.
+
+use std::path::{Path, PathBuf};
+
+use crate::{
+    interpreter::riscv64::machine::MachineModel,
+    device::Device, memory::Memory, elf::Elf,
+    htif::{self, Outcome},
+};
+
+
+const RAM_BASE: usize = 0x80000000;
+const RAM_SIZE: usize = 16 * 1024 * 1024;
+/// Where the riscv-tests link script puts `tohost`, for raw images.
+const DEFAULT_TOHOST: usize = 0x80001000;
+/// Instructions before a test counts as hung.
+const BUDGET: u64 = 1_000_000;
+const SUITES: [&str; 6] = ["rv64ui", "rv64um", "rv64ua", "rv64uf", "rv64ud", "rv64uc"];
+
+/// `rv64ui-p-*`/`rv64ui-v-*` style names, skipping objdump output.
+pub fn discover(dir: &Path) -> Vec<PathBuf> {
+    let mut tests = std::fs::read_dir(dir).into_iter().flatten()
+        .filter_map(|x| x.ok().map(|x| x.path()))
+        .filter(|x| {
+            let name = x.file_name().and_then(|x| x.to_str()).unwrap_or("");
+            let mut parts = name.splitn(3, '-');
+            SUITES.contains(&parts.next().unwrap_or(""))
+                && matches!(parts.next(), Some("p" | "v"))
+                && !name.ends_with(".dump")
+        })
+        .collect::<Vec<_>>();
+    tests.sort();
+    tests
+}
+
+/// The outcome and the number of instructions it took.
+pub fn run(path: &Path) -> Result<(Outcome, u64), String> {
+    let data = std::fs::read(path).map_err(|e| e.to_string())?;
+    let mm = MachineModel::new(0);
+    let mmio = Device::new();
+    let tohost = if data.starts_with(b"\x7fELF") {
+        let elf = Elf::parse(&data).map_err(|e| e.to_string())?;
+        mmio.add_device("ram", RAM_BASE, Box::new(Memory::new(RAM_SIZE))).map_err(|e| e.to_string())?;
+        elf.load(&mmio).map_err(|addr| format!("address 0x{:x} is not RAM", addr))?;
+        mm.pc.store(elf.entry);
+        elf.symbols().lookup("tohost").map_or(DEFAULT_TOHOST, |x| x.addr as usize)
+    } else {
+        let mut image = data;
+        image.resize(RAM_SIZE, 0);
+        mmio.add_device("ram", RAM_BASE, Box::new(Memory::from(image.as_ref()))).map_err(|e| e.to_string())?;
+        mm.pc.store(RAM_BASE as u64);
+        DEFAULT_TOHOST
+    };
+    htif::run(&mm, &mmio, tohost, BUDGET)
+}
+
+
+#[test]
+fn riscv_tests() {
+    let dir = std::env::var("LEMU_RISCV_TESTS").unwrap_or_else(|_| "tests".to_string());
+    let tests = discover(Path::new(&dir));
+    assert!(!tests.is_empty(), "no riscv-tests binaries in {}", dir);
+    let mut failed = Vec::new();
+    println!("{:<24}{:<16}insts", "test", "result");
+    for path in tests {
+        let name = path.file_name().unwrap().to_string_lossy().to_string();
+        match run(&path) {
+            Ok((outcome, n)) => {
+                println!("{:<24}{:<16}{}", name, outcome.to_string(), n);
+                if outcome != Outcome::Pass {
+                    failed.push(name);
+                }
+            },
+            Err(e) => {
+                println!("{:<24}error: {}", name, e);
+                failed.push(name);
+            },
+        }
+    }
+    assert!(failed.is_empty(), "failed: {}", failed.join(", "));
+}
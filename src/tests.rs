@@ -4,7 +4,22 @@ use lyuu_commons::{
     disassembly::riscv::*
 };
 
-use crate::{interpreter::riscv64::machine::MachineModel, memory::Memory, abstract_machine::Execable};
+use crate::{
+    interpreter::riscv64::{machine::MachineModel, block::Engine},
+    device::Device,
+    memory::Memory,
+    abstract_machine::Execable,
+};
+
+/// A hart on `engine` with `program` as RAM at 0 and main's CLINT and PLIC.
+fn platform(engine: Engine, program: &[u8]) -> (MachineModel, Device) {
+    let mm = MachineModel::new(0);
+    mm.engine.set(engine);
+    let mmio = Device::new();
+    mmio.add_device("ram", 0, Box::new(Memory::from(program))).unwrap();
+    crate::map_platform(&mmio, std::slice::from_ref(&mm));
+    (mm, mmio)
+}
 
 #[test]
 #[cfg(debug_assertions)]
@@ -48,6 +63,82 @@ fn test_rv_eval() {
     assert_eq!(mm.gpr.read(1), (114514 << 12));
 }
 
+#[test]
+fn test_store_value() {
+    use crate::{interpreter::riscv64::irq::Exception, abstract_machine::Readable};
+    let mm = MachineModel::new(0);
+    // sd a0, 0x100(x0); sd a0, 0x400(x0)
+    let mut inst_list: Vec<u8> = [0x10a03023u32, 0x40a03023]
+        .into_iter().flat_map(|x: u32| x.to_le_bytes()).collect();
+    inst_list.resize(0x200, 0);
+    let mem = Memory::from(inst_list.as_ref());
+    mm.gpr.store(10, 0x1122334455667788);
+    assert_eq!(mm.exec_once(&mem), Ok(()));
+    assert_eq!(mem.read_u64(0x100), Some(0x1122334455667788));
+    // past the end of memory
+    assert_eq!(mm.exec_once(&mem), Err(Exception::StoreAccessFault(0x400)));
+    assert_eq!(mm.pc.read(), 4);
+}
+
+#[test]
+fn test_w_ops() {
+    let mm = MachineModel::new(0);
+    // addw a2, a0, a1; sub a4, x0, a1; sraiw a3, a5, 4; srl a6, a5, a7
+    let inst_list: Vec<u8> = [0x00b5063bu32, 0x40b00733, 0x4047d69b, 0x0117d833]
+        .into_iter().flat_map(|x: u32| x.to_le_bytes()).collect();
+    let mem = Memory::from(inst_list.as_ref());
+    mm.gpr.store(10, 0x7fffffff);
+    mm.gpr.store(11, 1);
+    mm.gpr.store(15, 0x80000000);
+    mm.gpr.store(17, 65);
+    for _ in 0..4 {
+        mm.exec_once(&mem).unwrap();
+    }
+    assert_eq!(mm.gpr.read(12), 0xffffffff80000000);
+    assert_eq!(mm.gpr.read(14), u64::MAX);
+    assert_eq!(mm.gpr.read(13), 0xfffffffff8000000);
+    // only the low 6 bits of the shift amount count
+    assert_eq!(mm.gpr.read(16), 0x40000000);
+}
+
+#[test]
+fn test_ecall_mret() {
+    use crate::interpreter::riscv64::reg::{csrmap::{MCAUSE, MEPC, MSTATUS, MTVEC}, csr::mstatus::{MStatus, MachineMode}};
+    let mm = MachineModel::new(0);
+    // 0x0: ecall; 0x100: mret
+    let mut inst_list: Vec<u8> = 0x00000073u32.to_le_bytes().to_vec();
+    inst_list.resize(0x100, 0);
+    inst_list.extend(0x30200073u32.to_le_bytes());
+    let mem = Memory::from(inst_list.as_ref());
+    mm.csr.store(MTVEC, 0x100);
+    mm.exec_once(&mem).unwrap();
+    // the trap does not step past the handler entry
+    assert_eq!(mm.pc.read(), 0x100);
+    assert_eq!(mm.csr.read(MCAUSE), 11);
+    assert_eq!(mm.csr.read(MEPC), 0);
+    let mstatus = MStatus::from_bytes(mm.csr.read(MSTATUS).to_le_bytes());
+    assert_eq!((mstatus.mie(), mstatus.mpie(), mstatus.mpp()), (0, 1, MachineMode::Machine));
+    mm.exec_once(&mem).unwrap();
+    assert_eq!(mm.pc.read(), 0);
+    assert_eq!(mm.mode.get(), MachineMode::Machine);
+    let mstatus = MStatus::from_bytes(mm.csr.read(MSTATUS).to_le_bytes());
+    assert_eq!((mstatus.mie(), mstatus.mpie(), mstatus.mpp()), (1, 1, MachineMode::User));
+}
+
+#[test]
+fn test_csr_layout() {
+    use crate::interpreter::riscv64::{irq::RawTrapType, reg::csr::{mcause::MCause, mie_mip::Mie, mtvec::{Tvec, TVMode}, satp::{Satp, SatpMode}}};
+    // fields are declared from bit 0 upwards
+    let cause = MCause::from_bytes((1u64 << 63 | 7).to_le_bytes());
+    assert_eq!((cause.is_interrupt(), cause.exception_code()), (1, 7));
+    assert_eq!(u64::from_le_bytes(Mie::new().with_mtie(1).into_bytes()), 1 << 7);
+    let tvec = Tvec::from_bytes(0x1001u64.to_le_bytes());
+    assert_eq!((tvec.mode(), tvec.base_addr()), (TVMode::Vectored, 0x1000));
+    assert_eq!(tvec.get_pc(RawTrapType::Interrupt, 7), 0x101c);
+    let satp = Satp::from_bytes((8u64 << 60 | 0x80000).to_le_bytes());
+    assert_eq!((satp.mode(), satp.root_addr()), (SatpMode::Sv39, 0x80000 << 12));
+}
+
 #[test]
 fn test_jalr() {
     let mm = MachineModel::new(0);
@@ -92,4 +183,385 @@ fn test_br() {
     assert_eq!(mm.pc.read(), 8);
     mm.exec_once(&mem);
     assert_eq!(mm.pc.read(), 0);
-}
\ No newline at end of file
+}
+#[test]
+fn test_pma_faults() {
+    use crate::{device::Pma, interpreter::riscv64::irq::Exception};
+    // lui a0, 0x10000; lw a1, 1(a0); sw a1, 0(x0); jalr x0, 0(a0)
+    let inst_list: Vec<u8> = [0x10000537u32, 0x00152583, 0x00b02023, 0x00050067]
+        .into_iter().flat_map(|x: u32| x.to_le_bytes()).collect();
+    for engine in [Engine::Interp, Engine::Block, Engine::Jit] {
+        let mm = MachineModel::new(0);
+        mm.engine.set(engine);
+        let mmio = Device::new();
+        mmio.add_device("rom", 0, Box::new(Memory::from(inst_list.as_ref()).with_pma(Pma::ROM))).unwrap();
+        mmio.add_device("uart", 0x10000000, Box::new(Memory::new(8).with_pma(Pma::IO))).unwrap();
+        mm.exec_once(&mmio).unwrap();
+        // misaligned on I/O, writing ROM, running I/O
+        assert_eq!(mm.exec_once(&mmio), Err(Exception::LoadAccessFault(0x10000001)));
+        mm.pc.store(8);
+        assert_eq!(mm.exec_once(&mmio), Err(Exception::StoreAccessFault(0)));
+        mm.pc.store(12);
+        assert_eq!(mm.setp_num(&mmio, 2), Err(Exception::InstructionAccessFault(0x10000000)));
+        assert_eq!(mm.pc.read(), 0x10000000);
+    }
+}
+
+#[test]
+fn test_pmp_faults() {
+    use crate::interpreter::riscv64::{
+        irq::Exception,
+        reg::{csrmap::{PMPADDR0, PMPADDR1, PMPCFG0}, csr::mstatus::MachineMode},
+    };
+    // lw a1, 0x100(x0); sw a1, 0x100(x0); jal x0, 0x100; csrw pmpcfg0, x0
+    let mut program: Vec<u8> = [0x10002583u32, 0x10b02023, 0x0f80006f, 0x3a001073]
+        .into_iter().flat_map(|x: u32| x.to_le_bytes()).collect();
+    program.resize(0x200, 0);
+    for engine in [Engine::Interp, Engine::Block, Engine::Jit] {
+        let (mm, mem) = platform(engine, &program);
+        // TOR [0, 0x100) r-x, NA4 0x100 r--
+        mm.pmp.write(&mm.csr, PMPADDR0, 0x100 >> 2);
+        mm.pmp.write(&mm.csr, PMPADDR1, 0x100 >> 2);
+        mm.pmp.write(&mm.csr, PMPCFG0, 0x11_0d);
+        mm.mode.set(MachineMode::User);
+        mm.setp_num(&mem, 1).unwrap();
+        assert_eq!(mm.setp_num(&mem, 1), Err(Exception::StoreAccessFault(0x100)));
+        mm.pc.store(8);
+        assert_eq!(mm.setp_num(&mem, 2), Err(Exception::InstructionAccessFault(0x100)));
+        // nor may U-mode reprogram them
+        mm.pc.store(0xc);
+        assert_eq!(mm.setp_num(&mem, 1), Err(Exception::IllegalInstruction));
+        assert_eq!(mm.csr.read(PMPCFG0), 0x11_0d);
+        // M-mode is not bound by unlocked entries
+        mm.mode.set(MachineMode::Machine);
+        mm.pc.store(4);
+        mm.setp_num(&mem, 1).unwrap();
+    }
+}
+
+#[test]
+fn test_csr_warl() {
+    // csrw mstatus, a0; csrw misa, a0; csrw mideleg, a1; csrw mtvec, a2;
+    // and csrr a3..a6 of each; jal x0, 0
+    let program: Vec<u8> = [0x30051073u32, 0x30151073, 0x30359073, 0x30561073, 0x300026f3, 0x30102773, 0x303027f3, 0x30502873, 0x0000006f]
+        .into_iter().flat_map(|x: u32| x.to_le_bytes()).collect();
+    for engine in [Engine::Interp, Engine::Block, Engine::Jit] {
+        let (mm, mem) = platform(engine, &program);
+        let misa = mm.csr.read(0x301);
+        // UXL is fixed, M-mode interrupts stay in M-mode, mtvec has no
+        // mode 3
+        mm.gpr.store(10, 1 << 32 | 1 << 3);
+        mm.gpr.store(11, 0xaaa);
+        mm.gpr.store(12, 0x103);
+        mm.setp_num(&mem, 8).unwrap();
+        assert_eq!((13..17).map(|x| mm.gpr.read(x)).collect::<Vec<_>>(), [1 << 3, misa, 0x222, 0x101], "{:?}", engine);
+    }
+}
+
+#[test]
+fn test_mpp_warl() {
+    use crate::{abstract_machine::RegInfo, interpreter::riscv64::reg::{csrmap::{MCAUSE, MEPC}, csr::mstatus::MachineMode}};
+    // csrw mstatus, a0; mret; ecall
+    let inst_list: Vec<u8> = [0x30051073u32, 0x30200073, 0x00000073]
+        .into_iter().flat_map(|x: u32| x.to_le_bytes()).collect();
+    let mem = Memory::from(inst_list.as_ref());
+    let mm = MachineModel::new(0);
+    // 2 is no mode, MPP keeps what it had
+    mm.set_reg_value("mstatus", 1 << 11).unwrap();
+    mm.set_reg_value("mstatus", 2 << 11).unwrap();
+    assert_eq!(mm.get_reg_value("mstatus"), Some(1 << 11));
+    mm.set_reg_value("mstatus", 3 << 11).unwrap();
+    mm.gpr.store(10, 2 << 11);
+    mm.csr.store(MEPC, 8);
+    mm.exec_once(&mem).unwrap();
+    assert_eq!(mm.get_reg_value("mstatus"), Some(3 << 11));
+    mm.exec_once(&mem).unwrap();
+    assert_eq!(mm.mode.get(), MachineMode::Machine);
+    mm.exec_once(&mem).unwrap();
+    assert_eq!(mm.csr.read(MCAUSE), 11);
+}
+
+/// The bundled bootloader with main's memory layout, traps and all, for
+/// `cargo test --release bench_boot -- --ignored --nocapture`.
+#[test]
+#[ignore]
+fn bench_boot() {
+    use crate::abstract_machine::ExceptionProcessable;
+    const BUDGET: usize = 20_000_000;
+    let mm = MachineModel::new(0);
+    let mmio = Device::new();
+    crate::map_bootloader(&mmio);
+    mmio.add_device("ram", 0x80020000, Box::new(Memory::new(128*1024*1024))).unwrap();
+    crate::map_platform(&mmio, std::slice::from_ref(&mm));
+    mm.pc.store(0x80000000);
+    let t = std::time::Instant::now();
+    for _ in 0..BUDGET {
+        let r = mm.exec_once(&mmio);
+        mm.process_exception(r);
+    }
+    let elapsed = t.elapsed();
+    println!("{} instructions in {:?}, {:.1} MIPS", BUDGET, elapsed, BUDGET as f64 / elapsed.as_secs_f64() / 1e6);
+}
+
+#[test]
+fn test_clock_events() {
+    use std::{cell::Cell, rc::Rc};
+    use crate::device::MMIODevice;
+    // addi a0, a0, 1; jal x0, -4
+    let program: Vec<u8> = [0x00150513u32, 0xffdff06f]
+        .into_iter().flat_map(|x: u32| x.to_le_bytes()).collect();
+    for engine in [Engine::Interp, Engine::Block, Engine::Jit] {
+        let (mm, mmio) = platform(engine, &program);
+        let mm = Rc::new(mm);
+        let clock = mmio.clock().unwrap();
+        // blocks and translated code stop right where the event is due
+        let seen = Rc::new(Cell::new(None));
+        let (hart, at) = (mm.clone(), seen.clone());
+        clock.schedule(11, move |clock| at.set(Some((clock.now(), hart.gpr.read(10)))));
+        mm.setp_num(&mmio, 30).unwrap();
+        assert_eq!(seen.get(), Some((11, 6)));
+        assert_eq!(clock.now(), 30);
+    }
+}
+
+#[test]
+fn test_timer_interrupt() {
+    use crate::{abstract_machine::RegInfo, device::{MMIODevice, riscv::clint::CLINT_BASE}};
+    // addi a0, a0, 1; jal x0, -4, and a handler that spins where the
+    // vectored mtvec puts the machine timer interrupt
+    let mut program: Vec<u8> = [0x00150513u32, 0xffdff06f]
+        .into_iter().flat_map(|x: u32| x.to_le_bytes()).collect();
+    program.resize(0x200, 0);
+    program[0x11c..0x120].copy_from_slice(&0x0000006fu32.to_le_bytes());
+    for engine in [Engine::Interp, Engine::Block, Engine::Jit] {
+        let (mm, mmio) = platform(engine, &program);
+        mm.set_reg_value("mtvec", 0x101).unwrap();
+        mm.set_reg_value("mie", 1 << 7).unwrap();
+        mmio.store(CLINT_BASE + 0x4000, 8, 50).unwrap();
+        // masked in M-mode
+        mm.set_reg_value("mstatus", 0).unwrap();
+        mm.setp_num(&mmio, 60).unwrap();
+        assert_eq!(mm.get_reg_value("mip"), Some(1 << 7));
+        assert_eq!(mm.gpr.read(10), 30);
+        // taken before the next instruction once enabled
+        mm.set_reg_value("mstatus", 1 << 3).unwrap();
+        mm.setp_num(&mmio, 1).unwrap();
+        assert_eq!(mm.pc.read(), 0x11c);
+        assert_eq!(mm.gpr.read(10), 30);
+        assert_eq!(mm.get_reg_value("mcause"), Some(1 << 63 | 7));
+        assert_eq!(mm.get_reg_value("mepc"), Some(0));
+        assert_eq!(mm.get_reg_value("mstatus"), Some(3 << 11 | 1 << 7));
+        // and not again with MIE stacked away
+        mm.setp_num(&mmio, 10).unwrap();
+        assert_eq!(mm.pc.read(), 0x11c);
+    }
+}
+
+#[test]
+fn test_store_interrupt() {
+    use crate::{abstract_machine::RegInfo, device::riscv::clint::CLINT_BASE};
+    // sw a1, 0(a0); addi a2, a2, 1; addi a2, a2, 1; jal x0, 0, with the
+    // store raising MSIP mid-block and a handler spinning at 0x100
+    let mut program: Vec<u8> = [0x00b52023u32, 0x00160613, 0x00160613, 0x0000006f]
+        .into_iter().flat_map(|x: u32| x.to_le_bytes()).collect();
+    program.resize(0x200, 0);
+    program[0x100..0x104].copy_from_slice(&0x0000006fu32.to_le_bytes());
+    for engine in [Engine::Interp, Engine::Block, Engine::Jit] {
+        let (mm, mmio) = platform(engine, &program);
+        mm.set_reg_value("mtvec", 0x100).unwrap();
+        mm.set_reg_value("mie", 1 << 3).unwrap();
+        mm.set_reg_value("mstatus", 1 << 3).unwrap();
+        mm.gpr.store(10, CLINT_BASE as u64);
+        mm.gpr.store(11, 1);
+        mm.setp_num(&mmio, 5).unwrap();
+        assert_eq!((mm.pc.read(), mm.gpr.read(12)), (0x100, 0), "{:?}", engine);
+        assert_eq!(mm.get_reg_value("mcause"), Some(1 << 63 | 3));
+        assert_eq!(mm.get_reg_value("mepc"), Some(4));
+    }
+}
+
+#[test]
+fn test_supervisor_interrupt() {
+    use crate::{abstract_machine::RegInfo, interpreter::riscv64::reg::csr::mstatus::MachineMode};
+    // csrsi sip, 2; addi a0, a0, 1; jal x0, 0, and at the software
+    // interrupt entry of a vectored stvec: addi a1, a1, 1; csrci sip, 2;
+    // sret, with an M-mode handler spinning at 0x300
+    let mut program: Vec<u8> = [0x14416073u32, 0x00150513, 0x0000006f]
+        .into_iter().flat_map(|x: u32| x.to_le_bytes()).collect();
+    program.resize(0x400, 0);
+    for (i, inst) in [0x00158593u32, 0x14417073, 0x10200073].into_iter().enumerate() {
+        program[0x204 + 4 * i..0x208 + 4 * i].copy_from_slice(&inst.to_le_bytes());
+    }
+    program[0x300..0x304].copy_from_slice(&0x0000006fu32.to_le_bytes());
+    for engine in [Engine::Interp, Engine::Block, Engine::Jit] {
+        let (mm, mem) = platform(engine, &program);
+        mm.set_reg_value("mtvec", 0x300).unwrap();
+        mm.set_reg_value("stvec", 0x201).unwrap();
+        // TOR [0, 0x1000) rwx for the lower modes
+        mm.set_reg_value("pmpaddr0", 0x1000 >> 2).unwrap();
+        mm.set_reg_value("pmpcfg0", 0x0f).unwrap();
+        mm.set_reg_value("mideleg", 0x222).unwrap();
+        mm.set_reg_value("mie", 1 << 1).unwrap();
+        mm.set_reg_value("mstatus", 0).unwrap();
+        // sstatus and sie are views of mstatus and mie
+        mm.set_reg_value("sstatus", 1 << 1 | 1 << 3).unwrap();
+        assert_eq!(mm.get_reg_value("mstatus"), Some(1 << 1));
+        assert_eq!(mm.get_reg_value("sie"), Some(1 << 1));
+        mm.mode.set(MachineMode::Supervisor);
+        // raised by the csrsi, taken before the addi
+        mm.setp_num(&mem, 2).unwrap();
+        assert_eq!(mm.get_reg_value("sip"), Some(1 << 1));
+        assert_eq!((mm.pc.read(), mm.gpr.read(11), mm.gpr.read(10)), (0x208, 1, 0));
+        assert_eq!(mm.get_reg_value("scause"), Some(1 << 63 | 1));
+        assert_eq!(mm.get_reg_value("sepc"), Some(4));
+        assert_eq!(mm.get_reg_value("sstatus"), Some(1 << 8 | 1 << 5));
+        assert_eq!(mm.get_reg_value("mcause"), Some(0));
+        // the handler clears it and returns
+        mm.setp_num(&mem, 3).unwrap();
+        assert_eq!((mm.pc.read(), mm.gpr.read(10)), (8, 1));
+        assert_eq!(mm.get_reg_value("sstatus"), Some(1 << 5 | 1 << 1));
+        assert_eq!(mm.mode.get(), MachineMode::Supervisor);
+        // never taken in M-mode, always from U-mode
+        mm.set_reg_value("mip", 1 << 1).unwrap();
+        mm.mode.set(MachineMode::Machine);
+        mm.setp_num(&mem, 3).unwrap();
+        assert_eq!(mm.pc.read(), 8);
+        mm.set_reg_value("sstatus", 0).unwrap();
+        mm.mode.set(MachineMode::User);
+        mm.setp_num(&mem, 1).unwrap();
+        assert_eq!((mm.pc.read(), mm.gpr.read(11)), (0x208, 2));
+        assert_eq!(mm.get_reg_value("sstatus"), Some(0));
+        assert_eq!(mm.mode.get(), MachineMode::Supervisor);
+        // M-mode interrupts are taken from S-mode whatever mstatus.MIE
+        mm.irq.set(7, true);
+        mm.set_reg_value("mie", 1 << 7 | 1 << 1).unwrap();
+        mm.setp_num(&mem, 1).unwrap();
+        assert_eq!(mm.pc.read(), 0x300);
+        assert_eq!(mm.get_reg_value("mcause"), Some(1 << 63 | 7));
+        assert_eq!(mm.get_reg_value("mepc"), Some(0x208));
+        assert_eq!(mm.get_reg_value("mstatus"), Some(1 << 11));
+    }
+}
+
+#[test]
+fn test_wfi() {
+    use crate::{abstract_machine::RegInfo, device::{MMIODevice, riscv::clint::CLINT_BASE}};
+    // wfi; addi a0, a0, 1; jal x0, -8
+    let program: Vec<u8> = [0x10500073u32, 0x00150513, 0xff9ff06f]
+        .into_iter().flat_map(|x: u32| x.to_le_bytes()).collect();
+    for engine in [Engine::Interp, Engine::Block, Engine::Jit] {
+        let (mm, mmio) = platform(engine, &program);
+        let clock = mmio.clock().unwrap();
+        mm.set_reg_value("mie", 1 << 7).unwrap();
+        mm.set_reg_value("mstatus", 0).unwrap();
+        mmio.store(CLINT_BASE + 0x4000, 8, 5000).unwrap();
+        // the clock skips to the timer, which wakes the hart though it
+        // may not trap
+        mm.setp_num(&mmio, 1).unwrap();
+        assert!(mm.waiting.get());
+        mm.setp_num(&mmio, 1).unwrap();
+        assert_eq!(clock.now(), 5000);
+        assert_eq!((mm.pc.read(), mm.gpr.read(10)), (4, 0));
+        mm.setp_num(&mmio, 1).unwrap();
+        assert!(!mm.waiting.get());
+        assert_eq!((mm.pc.read(), mm.gpr.read(10)), (8, 1));
+        // and with the timer still pending, wfi does not wait at all
+        mm.setp_num(&mmio, 6).unwrap();
+        assert_eq!(mm.gpr.read(10), 3);
+        assert_eq!(clock.now(), 5007);
+    }
+}
+
+#[test]
+fn test_smp_lr_sc_ipi() {
+    use crate::{
+        abstract_machine::RegInfo,
+        device::{MMIODevice, riscv::clint::CLINT_BASE},
+        interpreter::riscv64::smp::Smp,
+    };
+    // hart 0: lr.d a0, (s0); sc.d a3, a4, (s0); lr.d a0, (s0);
+    // sc.d a3, a4, (s0); sw a5, 0(a6); jal x0, 0, with a6 the msip of
+    // hart 1, which runs sd a2, 0(s0); nop; jal x0, 0 from 0x100 and
+    // spins at 0x180 once interrupted
+    let mut program = vec![0u8; 0x400];
+    let mut put = |addr: usize, code: &[u32]| {
+        for (i, inst) in code.iter().enumerate() {
+            program[addr + 4 * i..addr + 4 * i + 4].copy_from_slice(&inst.to_le_bytes());
+        }
+    };
+    put(0, &[0x1004352f, 0x18e436af, 0x1004352f, 0x18e436af, 0x00f82023, 0x0000006f]);
+    put(0x100, &[0x00c43023, 0x00000013, 0x0000006f]);
+    put(0x180, &[0x0000006f]);
+    for engine in [Engine::Interp, Engine::Block, Engine::Jit] {
+        let smp = Smp::new(2);
+        smp.set_quantum(1);
+        let mmio = Device::new();
+        mmio.add_device("ram", 0, Box::new(Memory::from(program.as_ref()))).unwrap();
+        crate::map_platform(&mmio, smp.harts());
+        let [h0, h1] = smp.harts() else { unreachable!() };
+        for hart in [h0, h1] {
+            hart.engine.set(engine);
+            hart.gpr.store(8, 0x200);
+        }
+        h0.gpr.store(14, 0x2222);
+        h0.gpr.store(15, 1);
+        h0.gpr.store(16, CLINT_BASE as u64 + 4);
+        h1.gpr.store(12, 0x1111);
+        h1.pc.store(0x100);
+        h1.set_reg_value("mtvec", 0x180).unwrap();
+        // the store of hart 1 between them fails the first pair
+        smp.setp_num(&mmio, 3).unwrap();
+        assert_eq!(h0.gpr.read(13), 1);
+        assert_eq!(mmio.load(0x200, 8), Ok(0x1111));
+        smp.setp_num(&mmio, 4).unwrap();
+        assert_eq!((h0.gpr.read(10), h0.gpr.read(13)), (0x1111, 0));
+        assert_eq!(mmio.load(0x200, 8), Ok(0x2222));
+        // hart 0 interrupts hart 1, and no one else
+        smp.setp_num(&mmio, 3).unwrap();
+        assert_eq!(h1.pc.read(), 0x180);
+        assert_eq!(h1.get_reg_value("mcause"), Some(1 << 63 | 3));
+        assert_eq!(h1.get_reg_value("mepc"), Some(0x108));
+        assert_eq!(h0.get_reg_value("mip"), Some(0));
+    }
+}
+
+#[test]
+fn test_reverse_irq() {
+    use std::rc::Rc;
+    use crate::{
+        abstract_machine::RegInfo,
+        device::{MMIODevice, irq::Trigger, riscv::{clint::{Clint, CLINT_BASE}, plic::{Plic, PLIC_BASE}}},
+        elf::SymbolTable,
+        monitor::Monitor,
+    };
+    // sw a1, 0(a0); sd a3, 0(a2); lw a5, 0(a4); jal x0, 0: raise MSIP,
+    // set the timer off at 20 and claim the PLIC source
+    let program: Vec<u8> = [0x00b52023u32, 0x00d63023, 0x00072783, 0x0000006f]
+        .into_iter().flat_map(|x: u32| x.to_le_bytes()).collect();
+    let mm = MachineModel::new(0);
+    let mmio = Device::new();
+    mmio.add_device("ram", 0, Box::new(Memory::from(program.as_ref()))).unwrap();
+    mmio.add_device("clint", CLINT_BASE, Box::new(Clint::new(mmio.clock().unwrap(), vec![mm.irq.clone()]))).unwrap();
+    let plic = Rc::new(Plic::new(vec![mm.irq.clone()]));
+    mmio.add_shared("plic", PLIC_BASE, plic.clone()).unwrap();
+    mmio.store(PLIC_BASE + 4, 4, 1).unwrap();
+    mmio.store(PLIC_BASE + 0x2000, 4, 1 << 1).unwrap();
+    plic.line(1, Trigger::Level).raise();
+    mm.set_reg_value("mstatus", 0).unwrap();
+    for (reg, value) in [(10, CLINT_BASE as u64), (11, 1), (12, CLINT_BASE as u64 + 0x4000), (13, 20), (14, PLIC_BASE as u64 + 0x20_0004)] {
+        mm.gpr.store(reg, value);
+    }
+    let mut monitor = Monitor::new(SymbolTable::default());
+    monitor.record(&mm, &mmio);
+    monitor.run(&mm, &mmio, Some(30)).unwrap();
+    // MSIP and MTIP up, MEIP down with the source claimed
+    assert_eq!((mm.irq.get(), mm.gpr.read(15)), (1 << 3 | 1 << 7, 1));
+    monitor.reverse_step(&mm, &mmio, 30);
+    assert_eq!(mm.irq.get(), 1 << 11);
+    assert_eq!(mmio.load(CLINT_BASE, 4), Ok(0));
+    assert_eq!(mmio.load(CLINT_BASE + 0x4000, 8), Ok(u64::MAX));
+    assert_eq!(mmio.load(PLIC_BASE + 0x1000, 4), Ok(1 << 1));
+    // and it all happens again
+    monitor.run(&mm, &mmio, Some(30)).unwrap();
+    assert_eq!((mm.irq.get(), mm.gpr.read(15)), (1 << 3 | 1 << 7, 1));
+}
@@ -0,0 +1,292 @@
+//! Trace channels. Each one sits behind its own cargo feature (`itrace`,
+//! `mtrace`, `dtrace`, `etrace`, `ftrace`, or `trace` for all of them);
+//! without it the macros expand to nothing.
+
+use std::{
+    cell::RefCell,
+    fmt::{self, Display},
+    fs::File,
+    io::{self, BufWriter, Write},
+    ops::Range,
+};
+
+use crate::elf::SymbolTable;
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+    /// executed instructions
+    Inst,
+    /// loads and stores
+    Mem,
+    /// accesses to devices other than RAM
+    Device,
+    /// exceptions and interrupts taken
+    Trap,
+    /// calls and returns
+    Func,
+}
+
+pub const CHANNELS: [Channel; 5] = [Channel::Inst, Channel::Mem, Channel::Device, Channel::Trap, Channel::Func];
+
+impl Channel {
+    #[inline]
+    pub fn name(&self) -> &'static str {
+        match self {
+            Channel::Inst => "itrace",
+            Channel::Mem => "mtrace",
+            Channel::Device => "dtrace",
+            Channel::Trap => "etrace",
+            Channel::Func => "ftrace",
+        }
+    }
+
+    /// Whether this build has the channel at all.
+    #[inline]
+    pub fn compiled(&self) -> bool {
+        match self {
+            Channel::Inst => cfg!(feature = "itrace"),
+            Channel::Mem => cfg!(feature = "mtrace"),
+            Channel::Device => cfg!(feature = "dtrace"),
+            Channel::Trap => cfg!(feature = "etrace"),
+            Channel::Func => cfg!(feature = "ftrace"),
+        }
+    }
+
+    #[inline]
+    fn bit(&self) -> u8 {
+        1 << (*self as u8)
+    }
+}
+
+impl Display for Channel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// What to trace; events are filtered on the pc and privilege mode of
+/// the instruction that caused them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceConfig {
+    pub channels: Vec<Channel>,
+    pub range: Option<Range<u64>>,
+    /// privilege levels as in `mstatus.MPP`, empty for all
+    pub modes: Vec<u8>,
+}
+
+impl TraceConfig {
+    /// `itrace,mtrace` or `all`.
+    pub fn parse_channels(s: &str) -> Result<Vec<Channel>, String> {
+        if s == "all" {
+            return Ok(CHANNELS.iter().copied().filter(|x| x.compiled()).collect());
+        }
+        s.split(',').map(|name| {
+            let ch = CHANNELS.iter().find(|x| x.name() == name)
+                .ok_or_else(|| format!("unknown trace channel `{}`", name))?;
+            if !ch.compiled() {
+                return Err(format!("{} is not compiled in, rebuild with `--features {}`", ch, ch));
+            }
+            Ok(*ch)
+        }).collect()
+    }
+
+    /// `START:END`, end exclusive.
+    pub fn parse_range(s: &str) -> Result<Range<u64>, String> {
+        let parse = |x: &str| {
+            let x = x.trim();
+            match x.strip_prefix("0x") {
+                Some(hex) => u64::from_str_radix(hex, 16),
+                None => x.parse(),
+            }.map_err(|_| format!("bad address `{}`", x))
+        };
+        let (start, end) = s.split_once(':').ok_or_else(|| "expected START:END".to_string())?;
+        Ok(parse(start)?..parse(end)?)
+    }
+
+    /// `m,s,u`
+    pub fn parse_modes(s: &str) -> Result<Vec<u8>, String> {
+        s.split(',').map(|x| match x {
+            "u" => Ok(0),
+            "s" => Ok(1),
+            "m" => Ok(3),
+            _ => Err(format!("unknown privilege mode `{}`", x)),
+        }).collect()
+    }
+}
+
+struct Tracer {
+    channels: u8,
+    range: Option<Range<u64>>,
+    modes: u8,
+    out: BufWriter<Box<dyn Write>>,
+    /// pc and mode of the instruction being executed
+    pc: u64,
+    mode: u8,
+    /// ftrace call depth and names for call targets
+    depth: usize,
+    symbols: SymbolTable,
+}
+
+thread_local! {
+    static TRACER: RefCell<Option<Tracer>> = const { RefCell::new(None) };
+}
+
+/// Starts tracing to `path`, or stderr.
+pub fn init(config: &TraceConfig, path: Option<&str>) -> io::Result<()> {
+    let out: Box<dyn Write> = match path {
+        Some(path) => Box::new(File::create(path)?),
+        None => Box::new(io::stderr()),
+    };
+    let tracer = Tracer {
+        channels: config.channels.iter().fold(0, |acc, x| acc | x.bit()),
+        range: config.range.clone(),
+        modes: config.modes.iter().fold(0, |acc, x| acc | (1 << x)),
+        out: BufWriter::new(out),
+        pc: 0,
+        mode: 0,
+        depth: 0,
+        symbols: SymbolTable::default(),
+    };
+    TRACER.with(|t| *t.borrow_mut() = Some(tracer));
+    Ok(())
+}
+
+/// Called at the start of every instruction.
+#[inline]
+pub fn set_context(pc: u64, mode: u8) {
+    TRACER.with(|t| if let Some(t) = t.borrow_mut().as_mut() {
+        t.pc = pc;
+        t.mode = mode;
+    });
+}
+
+#[inline]
+pub fn enabled(channel: Channel) -> bool {
+    TRACER.with(|t| match t.borrow().as_ref() {
+        Some(t) => t.channels & channel.bit() != 0
+            && !matches!(&t.range, Some(r) if !r.contains(&t.pc))
+            && (t.modes == 0 || t.modes & (1 << t.mode) != 0),
+        None => false,
+    })
+}
+
+pub fn emit(channel: Channel, args: fmt::Arguments) {
+    TRACER.with(|t| if let Some(t) = t.borrow_mut().as_mut() {
+        // a full disk should not take the guest down
+        let _ = writeln!(t.out, "[lemu:{}] {}", channel, args);
+    });
+}
+
+/// Names for ftrace, once an ELF is loaded.
+pub fn set_symbols(symbols: SymbolTable) {
+    TRACER.with(|t| if let Some(t) = t.borrow_mut().as_mut() {
+        t.symbols = symbols;
+    });
+}
+
+#[inline]
+fn name(symbols: &SymbolTable, addr: u64) -> String {
+    symbols.format_addr(addr).unwrap_or_else(|| format!("0x{:x}", addr))
+}
+
+/// `args` are a0-a7.
+pub fn ftrace_call(pc: u64, target: u64, args: &[u64]) {
+    if !enabled(Channel::Func) {
+        return;
+    }
+    TRACER.with(|t| if let Some(t) = t.borrow_mut().as_mut() {
+        let args = args.iter().map(|x| format!("0x{:x}", x)).collect::<Vec<_>>().join(", ");
+        let _ = writeln!(t.out, "[lemu:ftrace] 0x{:016x}: {:indent$}call {}({})",
+            pc, "", name(&t.symbols, target), args, indent = t.depth * 2);
+        t.depth += 1;
+    });
+}
+
+pub fn ftrace_ret(pc: u64, target: u64, a0: u64) {
+    if !enabled(Channel::Func) {
+        return;
+    }
+    TRACER.with(|t| if let Some(t) = t.borrow_mut().as_mut() {
+        // returns past where tracing started just stay at the left edge
+        t.depth = t.depth.saturating_sub(1);
+        let _ = writeln!(t.out, "[lemu:ftrace] 0x{:016x}: {:indent$}ret  {} -> {} = 0x{:x}",
+            pc, "", name(&t.symbols, pc), name(&t.symbols, target), a0, indent = t.depth * 2);
+    });
+}
+
+pub fn flush() {
+    TRACER.with(|t| if let Some(t) = t.borrow_mut().as_mut() {
+        let _ = t.out.flush();
+    });
+}
+
+
+#[allow(unused_macros)]
+macro_rules! itrace {
+    ($($arg:tt)*) => {
+        #[cfg(feature = "itrace")]
+        if $crate::trace::enabled($crate::trace::Channel::Inst) {
+            $crate::trace::emit($crate::trace::Channel::Inst, format_args!($($arg)*));
+        }
+    };
+}
+
+#[allow(unused_macros)]
+macro_rules! mtrace {
+    ($($arg:tt)*) => {
+        #[cfg(feature = "mtrace")]
+        if $crate::trace::enabled($crate::trace::Channel::Mem) {
+            $crate::trace::emit($crate::trace::Channel::Mem, format_args!($($arg)*));
+        }
+    };
+}
+
+#[allow(unused_macros)]
+macro_rules! dtrace {
+    ($($arg:tt)*) => {
+        #[cfg(feature = "dtrace")]
+        if $crate::trace::enabled($crate::trace::Channel::Device) {
+            $crate::trace::emit($crate::trace::Channel::Device, format_args!($($arg)*));
+        }
+    };
+}
+
+#[allow(unused_macros)]
+macro_rules! etrace {
+    ($($arg:tt)*) => {
+        #[cfg(feature = "etrace")]
+        if $crate::trace::enabled($crate::trace::Channel::Trap) {
+            $crate::trace::emit($crate::trace::Channel::Trap, format_args!($($arg)*));
+        }
+    };
+}
+
+#[test]
+fn test_trace_config() {
+    assert_eq!(TraceConfig::parse_range("0x80000000:0x80001000"), Ok(0x80000000..0x80001000));
+    assert!(TraceConfig::parse_range("0x80000000").is_err());
+    assert_eq!(TraceConfig::parse_modes("m,u"), Ok(vec![3, 0]));
+    assert!(TraceConfig::parse_channels("xtrace").is_err());
+    assert_eq!(TraceConfig::parse_channels("itrace").is_ok(), cfg!(feature = "itrace"));
+}
+
+#[test]
+#[cfg(feature = "ftrace")]
+fn test_ftrace() {
+    use crate::elf::{Symbol, SymbolKind};
+    let path = std::env::temp_dir().join(format!("lemu-ftrace-{}", std::process::id()));
+    let config = TraceConfig { channels: vec![Channel::Func], range: None, modes: vec![] };
+    init(&config, path.to_str()).unwrap();
+    let func = |name: &str, addr| Symbol { name: name.to_string(), addr, size: 0x10, kind: SymbolKind::Func };
+    set_symbols(SymbolTable::new(vec![func("main", 0x100), func("foo", 0x200)]));
+    ftrace_call(0x104, 0x200, &[1, 2]);
+    ftrace_ret(0x20c, 0x108, 3);
+    flush();
+    let out = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(out, "\
+[lemu:ftrace] 0x0000000000000104: call foo(0x1, 0x2)
+[lemu:ftrace] 0x000000000000020c: ret  foo+0xc -> main+0x8 = 0x3
+");
+}
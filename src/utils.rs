@@ -16,6 +16,30 @@ make_get_field_range!(field_range_into_u8, u8);
 make_get_field_range!(field_range_into_u16, u16);
 make_get_field_range!(field_range_into_u32, u32);
 
+/// `128M`, `64k`, `1G` or plain bytes.
+pub fn parse_size(s: &str) -> Result<usize, String> {
+    let (digits, shift) = match s.as_bytes().last() {
+        Some(b'k' | b'K') => (&s[..s.len() - 1], 10),
+        Some(b'm' | b'M') => (&s[..s.len() - 1], 20),
+        Some(b'g' | b'G') => (&s[..s.len() - 1], 30),
+        _ => (s, 0),
+    };
+    digits.parse::<usize>().ok()
+        .and_then(|x| x.checked_mul(1 << shift))
+        .filter(|x| *x > 0)
+        .ok_or_else(|| format!("bad size `{}`", s))
+}
+
+/// Bytes in the largest unit that keeps one decimal meaningful.
+pub fn human_size(n: usize) -> String {
+    match n {
+        n if n >= 1 << 30 => format!("{:.1}G", n as f64 / (1u64 << 30) as f64),
+        n if n >= 1 << 20 => format!("{:.1}M", n as f64 / (1u64 << 20) as f64),
+        n if n >= 1 << 10 => format!("{:.1}K", n as f64 / (1u64 << 10) as f64),
+        n => format!("{}B", n),
+    }
+}
+
 /*
 #[inline]
 pub fn get_field_range(i: u32, left: usize, right: usize) -> u32 {
@@ -32,3 +56,15 @@ fn bitfield_range_test() {
     let bf = field_range_into_u8(0b1010111111101010, 10, 6);
     assert_eq!(bf, 0b11111);
 }
+
+#[test]
+fn test_size() {
+    assert_eq!(parse_size("128M"), Ok(128 << 20));
+    assert_eq!(parse_size("4k"), Ok(4096));
+    assert_eq!(parse_size("100"), Ok(100));
+    assert!(parse_size("0").is_err());
+    assert!(parse_size("M").is_err());
+    assert!(parse_size("1T").is_err());
+    assert_eq!(human_size(512), "512B");
+    assert_eq!(human_size(3 << 19), "1.5M");
+}
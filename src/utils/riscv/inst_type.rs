@@ -119,16 +119,15 @@ impl JType {
         } else {
             0b111111111111 << 20
         };
-        let imm19_12 = dbg!(self.imm19_12()) as u32;
-        let imm11 = dbg!(self.imm11()) as u32;
-        let imm10_1 = dbg!(self.imm10_1()) as u32;
-        let r = (
+        let imm19_12 = self.imm19_12() as u32;
+        let imm11 = self.imm11() as u32;
+        let imm10_1 = self.imm10_1() as u32;
+        (
             (imm10_1 << 1)  |
             (imm11 << 11)   |
             (imm19_12 << 12)|
             filling
-        ) as i32;
-        dbg!(r)
+        ) as i32
 
     }
 }